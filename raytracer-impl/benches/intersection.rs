@@ -0,0 +1,191 @@
+//! Ray/mesh intersection benchmarks.
+//!
+//! Run with `cargo bench -p raytracer-impl --bench intersection`
+
+use std::f32::consts::PI;
+use std::time::{ Duration, Instant };
+
+use rand::{ Rng, SeedableRng };
+use rand::rngs::StdRng;
+
+use raytracer_impl::bvh::{ Bvh, Bvh4, BvhBounds, BvhBuildStrategy, BvhHit, BvhObject };
+use raytracer_impl::implementation::{ Hitable, MatId, TexId, AABB };
+use raytracer_impl::shapes::{ Mesh, MeshFace, MeshObject };
+use raytracer_impl::types::{ Ray, V3 };
+
+/// Creates a closed, unit radius UV sphere made of triangles with shared vertices
+fn make_uv_sphere(segments: usize, rings: usize) -> Mesh {
+    let mut mesh = Mesh::default();
+    for ring in 0..=rings {
+        for segment in 0..segments {
+            let theta = segment as f32 / segments as f32 * 2.0 * PI;
+            let phi = ring as f32 / rings as f32 * PI;
+            mesh.vertices.push(V3(phi.sin() * theta.cos(), phi.cos(), phi.sin() * theta.sin()));
+        }
+    }
+    // NOTE: Wrap around to the first segment so the seam shares vertices
+    let index = |segment: usize, ring: usize| (ring * segments + (segment % segments)) as u32;
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let a = index(segment, ring);
+            let b = index(segment + 1, ring);
+            let c = index(segment + 1, ring + 1);
+            let d = index(segment, ring + 1);
+            mesh.faces.push(MeshFace { vertices: [a, b, c], ..Default::default() });
+            mesh.faces.push(MeshFace { vertices: [a, c, d], ..Default::default() });
+        }
+    }
+    mesh
+}
+
+/// Adds a large ground plane made of two triangles under the mesh,
+/// giving the mesh a mix of very large and very small triangles
+fn add_ground_plane(mut mesh: Mesh) -> Mesh {
+    let first = mesh.vertices.len() as u32;
+    for (x, z) in [(-100.0, -100.0), (100.0, -100.0), (100.0, 100.0), (-100.0, 100.0)] {
+        mesh.vertices.push(V3(x, -1.0, z));
+    }
+    mesh.faces.push(MeshFace { vertices: [first, first + 1, first + 2], ..Default::default() });
+    mesh.faces.push(MeshFace { vertices: [first, first + 2, first + 3], ..Default::default() });
+    mesh
+}
+
+/// A triangle with its own copy of the vertices, for benchmarking BVH traversal without the mesh
+struct BenchTri([V3; 3]);
+
+impl BvhObject for BenchTri {
+    fn calculate_bounds(&self) -> BvhBounds {
+        let [a, b, c] = self.0;
+        BvhBounds { centroid: (a + b + c) * (1.0 / 3.0), aabb: AABB::from_vertices(&self.0) }
+    }
+}
+
+struct BenchHit(f32);
+
+impl BvhHit for BenchHit {
+    fn t(&self) -> f32 {
+        self.0
+    }
+}
+
+/// Möller–Trumbore ray/triangle intersection, returning {t}
+fn intersect_tri(ray: Ray, [a, b, c]: [V3; 3]) -> Option<f32> {
+    let edge_ab = b - a;
+    let edge_ac = c - a;
+    let p = V3::cross(ray.direction, edge_ac);
+    let det = V3::dot(edge_ab, p);
+    if det.abs() < 1.0e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - a;
+    let u = V3::dot(s, p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = V3::cross(s, edge_ab);
+    let v = V3::dot(ray.direction, q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    Some(V3::dot(edge_ac, q) * inv_det)
+}
+
+/// Casts rays from outside the unit sphere towards random points inside it
+fn make_rays(rng: &mut impl Rng, ray_count: usize) -> Vec<Ray> {
+    (0..ray_count)
+        .map(|_| {
+            let mut random_v3 = || V3(rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5);
+            let origin = random_v3().unit() * 3.0;
+            let target = random_v3() * 0.5;
+            Ray::new(origin, target - origin)
+        })
+        .collect()
+}
+
+fn print_throughput(name: &str, ray_count: usize, elapsed: Duration, misses: usize) {
+    println!(
+        "{name}: {ray_count} rays in {:>8.2?} ({:>6.2} Mrays/s), {misses} misses",
+        elapsed,
+        ray_count as f64 / elapsed.as_secs_f64() / 1.0e6,
+    );
+}
+
+/// Prints BVH build statistics, which are reported through the log crate
+struct StdoutLogger;
+
+impl log::Log for StdoutLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool { true }
+    fn log(&self, record: &log::Record) { println!("  {}", record.args()); }
+    fn flush(&self) {}
+}
+
+fn main() {
+    log::set_logger(&StdoutLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let mut rng = StdRng::seed_from_u64(12345);
+
+    let meshes = [
+        ("uv sphere", make_uv_sphere(16, 8)),
+        ("uv sphere", make_uv_sphere(128, 64)),
+        ("uv sphere", make_uv_sphere(1024, 512)),
+        ("uv sphere + ground", add_ground_plane(make_uv_sphere(128, 64))),
+    ];
+
+    for ((name, mesh), strategy) in meshes.iter().cloned().flat_map(|m| [(m.clone(), BvhBuildStrategy::MeanSplit), (m, BvhBuildStrategy::default())]) {
+        let tri_count = mesh.faces.len();
+        let strategy_name = match strategy {
+            BvhBuildStrategy::MeanSplit => "mean",
+            BvhBuildStrategy::Sah(_) => "sah",
+        };
+
+        let start = Instant::now();
+        let object = MeshObject::new_with_bvh_strategy(mesh, MatId::default(), TexId::default(), strategy);
+        let build_time = start.elapsed();
+
+        // Every ray must hit the closed mesh.
+        let ray_count = 200_000;
+        let rays = make_rays(&mut rng, ray_count);
+
+        let start = Instant::now();
+        let misses = rays.iter().filter(|&&ray| object.hit(ray, 0.0, f32::MAX).is_none()).count();
+        let elapsed = start.elapsed();
+
+        print_throughput(&format!("{name} {tri_count:>8} tris ({strategy_name}): build {:>8.2?}", build_time), ray_count, elapsed, misses);
+    }
+
+    // Compare traversal of the binary and wide BVH layouts over the same triangles
+    println!();
+    for (name, mesh) in &meshes {
+        let tris = mesh.faces.iter()
+            .map(|face| BenchTri(mesh.face_vertices(face)))
+            .collect::<Vec<_>>();
+        let tri_count = tris.len();
+        let bvh = Bvh::from(&tris);
+        let bvh4 = Bvh4::from(&tris);
+
+        let ray_count = 200_000;
+        let rays = make_rays(&mut rng, ray_count);
+        let tris = &tris;
+        let hit_tri = |ray: Ray| move |i: usize, t_max: f32| {
+            intersect_tri(ray, tris[i].0).filter(|t| (0.0..=t_max).contains(t)).map(BenchHit)
+        };
+
+        let start = Instant::now();
+        let misses = rays.iter().filter(|&&ray| bvh.closest_hit(ray, 0.0, f32::MAX, hit_tri(ray)).is_none()).count();
+        print_throughput(&format!("{name} {tri_count:>8} tris (binary closest)"), ray_count, start.elapsed(), misses);
+
+        let start = Instant::now();
+        let misses = rays.iter().filter(|&&ray| bvh4.closest_hit(ray, 0.0, f32::MAX, hit_tri(ray)).is_none()).count();
+        print_throughput(&format!("{name} {tri_count:>8} tris (wide closest)  "), ray_count, start.elapsed(), misses);
+
+        let start = Instant::now();
+        let misses = rays.iter().filter(|&&ray| !bvh.any_hit(ray, 0.0, f32::MAX, |i| hit_tri(ray)(i, f32::MAX).is_some())).count();
+        print_throughput(&format!("{name} {tri_count:>8} tris (binary any)    "), ray_count, start.elapsed(), misses);
+
+        let start = Instant::now();
+        let misses = rays.iter().filter(|&&ray| !bvh4.any_hit(ray, 0.0, f32::MAX, |i| hit_tri(ray)(i, f32::MAX).is_some())).count();
+        print_throughput(&format!("{name} {tri_count:>8} tris (wide any)      "), ray_count, start.elapsed(), misses);
+    }
+}
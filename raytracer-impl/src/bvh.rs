@@ -1,6 +1,12 @@
+use std::fmt;
+use std::io::{ self, Read, Write };
 use std::ops::Range;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
 
 use arrayvec::ArrayVec;
+use log::info;
+use wide::{ f32x4, CmpLe };
 
 use crate::implementation::AABB;
 use crate::types::{Ray, V3};
@@ -15,61 +21,202 @@ pub trait BvhObject {
     fn calculate_bounds(&self) -> BvhBounds;
 }
 
-struct BvhNode {
-    aabb: AABB,
-    data: BvhNodeData,
+/// A hit found while searching the BVH for the closest object
+pub trait BvhHit {
+    /// The distance along the ray at which the hit occurred
+    fn t(&self) -> f32;
 }
 
-#[derive(Clone)]
-struct BvhBranch {
-    // Index of left/right nodes in Node collection
-    left_offset: usize,
-    right_offset: usize,
+/// A node of the binary BVH, packed into 32 bytes so that two nodes fit in a cache line.
+/// The children of a branch are always stored next to each other, so only the left child is referenced.
+#[derive(Clone, Debug, PartialEq)]
+#[repr(C, align(32))]
+struct BvhNode {
+    min: [f32; 3],
+    // Branch: index of the left child in Node collection (the right child follows it)
+    // Leaf: index of the first object in Object index collection
+    offset: u32,
+    max: [f32; 3],
+    // Branch: the axis the objects were split on.
+    // Objects in the left node have smaller centroids on this axis than objects in the right node.
+    // Leaf: the number of objects which this node includes, shifted above the LEAF_TAG bits
+    data: u32,
 }
 
-#[derive(Clone)]
-struct BvhLeaf {
-    // Index of object in Object collection
-    offset: usize,
-    // Number of objects in object collection which this node includes
-    length: usize,
-}
+const LEAF_TAG: u32 = 0b11;
+
+/// The most objects a BVH can hold, limited by the size of the leaf object count.
+const MAX_OBJECTS: usize = (u32::MAX >> 2) as usize;
+
+impl BvhNode {
+    fn leaf(aabb: &AABB, offset: usize, length: usize) -> BvhNode {
+        BvhNode {
+            min: aabb.min.xyz(),
+            offset: offset as u32,
+            max: aabb.max.xyz(),
+            data: ((length as u32) << 2) | LEAF_TAG,
+        }
+    }
+
+    fn set_aabb(&mut self, aabb: &AABB) {
+        self.min = aabb.min.xyz();
+        self.max = aabb.max.xyz();
+    }
+
+    fn set_branch(&mut self, left_offset: usize, axis: Axis) {
+        self.offset = left_offset as u32;
+        self.data = axis as u32;
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.data & LEAF_TAG == LEAF_TAG
+    }
+
+    // Leaf nodes
+
+    fn length(&self) -> usize {
+        (self.data >> 2) as usize
+    }
 
-impl BvhLeaf {
     fn range(&self) -> Range<usize> {
-        self.offset..(self.offset + self.length)
+        let offset = self.offset as usize;
+        offset..(offset + self.length())
+    }
+
+    // Branch nodes
+
+    fn left_offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    fn right_offset(&self) -> usize {
+        self.offset as usize + 1
+    }
+
+    fn axis(&self) -> Axis {
+        [Axis::X, Axis::Y, Axis::Z][self.data as usize]
+    }
+
+    fn aabb(&self) -> AABB {
+        AABB::from_min_max(V3(self.min[0], self.min[1], self.min[2]), V3(self.max[0], self.max[1], self.max[2]))
+    }
+
+    /// Returns the {t} at which the ray enters the node, if it does so within {t_min..t_max}
+    fn hit(&self, ray: &BvhRay, mut t_min: f32, mut t_max: f32) -> Option<f32> {
+        for axis in 0..3 {
+            let (near, far) = match ray.negative[axis] {
+                false => (self.min[axis], self.max[axis]),
+                true => (self.max[axis], self.min[axis]),
+            };
+            // NOTE: A ray starting on the plane of a face it runs parallel to produces NaN (0 * inf),
+            // which f32::max/min ignore, leaving that axis unbounded (as AABB::hit_aabb_range does)
+            t_min = t_min.max((near - ray.origin[axis]) * ray.inv_direction[axis]);
+            t_max = t_max.min((far - ray.origin[axis]) * ray.inv_direction[axis]);
+        }
+        (t_min <= t_max).then_some(t_min)
     }
 }
 
-#[derive(Clone)]
-enum BvhNodeData {
-    Branch(BvhBranch),
-    Leaf(BvhLeaf),
+/// A ray prepared for testing against many boxes, with the division for each axis done up front
+struct BvhRay {
+    origin: [f32; 3],
+    inv_direction: [f32; 3],
+    // Whether the ray travels in the negative direction on each axis, so enters boxes through their max plane
+    negative: [bool; 3],
 }
 
-impl BvhNode {
-    fn leaf_data(&self) -> &BvhLeaf {
-        match self.data {
-            BvhNodeData::Leaf(ref leaf) => leaf,
-            _ => panic!("Not a leaf node"),
+impl BvhRay {
+    fn new(ray: Ray) -> BvhRay {
+        let inv_direction = ray.direction.xyz().map(|d| 1.0 / d);
+        BvhRay {
+            origin: ray.origin.xyz(),
+            inv_direction,
+            negative: inv_direction.map(|d| d < 0.0),
         }
     }
 }
 
+/// The maximum depth of any BVH, counting the root node.
+/// Traversal stacks are sized to fit a tree of this depth.
+const MAX_DEPTH: usize = 64;
+
+/// Beyond this depth nodes are always split at the median object, which halves the node at each level.
+/// This guarantees that any tree of up to 2^32 objects fits within {MAX_DEPTH}.
+const MEDIAN_SPLIT_DEPTH: usize = MAX_DEPTH - 32;
+
+/// A stack of nodes waiting to be visited during traversal.
+/// NOTE: A depth-first search holds at most one pending sibling for each level of the tree,
+/// plus the children of the current node.
+type TraversalStack<T> = ArrayVec<T, MAX_DEPTH>;
+
 struct BvhObjectBounds {
     object_index: usize,
     bounds: BvhBounds,
 }
 
+/// Selects how the BVH builder decides where to split each node
+#[derive(Clone, Copy, Debug)]
+pub enum BvhBuildStrategy {
+    /// Split on the mean centroid of the longest axis, down to two objects per leaf
+    MeanSplit,
+    /// Split where the Surface Area Heuristic estimates the cheapest traversal
+    Sah(SahOptions),
+}
+
+impl Default for BvhBuildStrategy {
+    fn default() -> Self {
+        BvhBuildStrategy::Sah(SahOptions::default())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SahOptions {
+    /// The number of bins candidate splits are evaluated between, on each axis
+    pub bin_count: usize,
+    /// The estimated cost of visiting a branch node
+    pub traversal_cost: f32,
+    /// The estimated cost of testing a ray against a single object
+    pub intersection_cost: f32,
+    /// Nodes with more objects than this are always split
+    pub max_leaf_size: usize,
+}
+
+impl Default for SahOptions {
+    fn default() -> Self {
+        SahOptions {
+            bin_count: 16,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            max_leaf_size: 8,
+        }
+    }
+}
+
 pub struct Bvh {
-    object_bounds: Vec<BvhObjectBounds>,
+    // Index of the object referenced by each leaf entry, in leaf order
+    object_indices: Vec<u32>,
     nodes: Vec<BvhNode>,
+    strategy: BvhBuildStrategy,
+    // The SAH cost of the tree when it was built, which refitting is compared against
+    built_sah_cost: f32,
 }
 
+/// Refitting rebuilds the tree instead when it would raise the SAH cost of the tree by more than this factor,
+/// as the objects have moved too far from where the tree was built around them
+const REFIT_REBUILD_COST_RATIO: f32 = 1.5;
+
 impl Bvh {
+    pub fn from<T: BvhObject>(objects: &[T]) -> Bvh {
+        Bvh::from_with_strategy(objects, BvhBuildStrategy::default())
+    }
+
     // BVH algorithm adapted
     // from https://jacco.ompf2.com/2022/04/13/how-to-build-a-bvh-part-1-basics/
-    pub fn from<T: BvhObject>(objects: &[T]) -> Bvh {
+    pub fn from_with_strategy<T: BvhObject>(objects: &[T], strategy: BvhBuildStrategy) -> Bvh {
+        if objects.len() > MAX_OBJECTS {
+            panic!("Bvh::from_with_strategy: at most {} objects are supported", MAX_OBJECTS);
+        }
+
         // Precalculate the object bounds map
         let object_bounds = objects.iter()
             .enumerate()
@@ -79,22 +226,129 @@ impl Bvh {
             })
             .collect();
 
-        build(object_bounds)
+        let thread_count = build_thread_count();
+        let mut bvh = build(object_bounds, strategy, thread_count);
+        let stats = bvh.stats(strategy);
+        bvh.built_sah_cost = stats.sah_cost;
+        let strategy_name = match strategy {
+            BvhBuildStrategy::MeanSplit => "mean split",
+            BvhBuildStrategy::Sah(_) => "SAH",
+        };
+        info!("Built BVH over {} objects ({}, threads: {}): {}", objects.len(), strategy_name, thread_count, stats);
+        bvh
+    }
+
+    /// Recomputes the bounds of every node after objects have moved, keeping the structure of the tree.
+    /// {objects} must be the objects the tree was built from, in the same order.
+    /// When the objects have moved so far that the refitted tree would be much slower to trace (see REFIT_REBUILD_COST_RATIO),
+    /// the tree is rebuilt instead. Returns true if the tree was rebuilt.
+    pub fn refit<T: BvhObject>(&mut self, objects: &[T]) -> bool {
+        if objects.len() != self.object_indices.len() {
+            panic!("Bvh::refit: expected {} objects, found {}", self.object_indices.len(), objects.len());
+        }
+        let object_aabbs = objects.iter()
+            .map(|object| object.calculate_bounds().aabb)
+            .collect::<Vec<_>>();
+
+        // Children are always stored after their parent, so visiting nodes in reverse updates children first
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[node_index];
+            let aabb = if node.is_leaf() {
+                AABB::from_vertices_iter(
+                    self.object_indices[node.range()].iter()
+                        .map(|&object_index| &object_aabbs[object_index as usize])
+                        .flat_map(|aabb| [aabb.min, aabb.max])
+                )
+            } else {
+                AABB::surrounding(self.nodes[node.left_offset()].aabb(), self.nodes[node.right_offset()].aabb())
+            };
+            self.nodes[node_index].set_aabb(&aabb);
+        }
+
+        let sah_cost = self.stats(self.strategy).sah_cost;
+        if sah_cost > self.built_sah_cost * REFIT_REBUILD_COST_RATIO {
+            info!("Rebuilding BVH over {} objects, as refitting raised its SAH cost from {:.2} to {:.2}", objects.len(), self.built_sah_cost, sah_cost);
+            *self = Bvh::from_with_strategy(objects, self.strategy);
+            return true;
+        }
+        false
     }
 
     pub fn aabb(&self) -> AABB {
-        self.nodes[0].aabb.clone()
+        self.nodes[0].aabb()
+    }
+
+    /// The number of objects the tree was built from
+    pub fn object_count(&self) -> usize {
+        self.object_indices.len()
+    }
+
+    /// Finds the closest object hit by the ray.
+    /// Nodes are visited front-to-back and any node further away than the closest hit found so far is skipped.
+    /// {hit_object} is called with the index of each candidate object and the current maximum {t}.
+    pub fn closest_hit<H: BvhHit>(&self, ray: Ray, t_min: f32, t_max: f32, mut hit_object: impl FnMut(usize, f32) -> Option<H>) -> Option<H> {
+        let ray = BvhRay::new(ray);
+        let mut closest: Option<H> = None;
+        let mut t_max = t_max;
+        let mut stack = TraversalStack::new();
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.hit(&ray, t_min, t_max).is_none() {
+                continue;
+            }
+            if node.is_leaf() {
+                for &object_index in &self.object_indices[node.range()] {
+                    if let Some(hit) = hit_object(object_index as usize, t_max) {
+                        t_max = hit.t();
+                        closest = Some(hit);
+                    }
+                }
+            } else if ray.negative[node.axis() as usize] {
+                // Push the far child first, so the near child is visited first.
+                // The ray reaches the left child first when travelling in the positive direction on the split axis
+                stack.push(node.left_offset());
+                stack.push(node.right_offset());
+            } else {
+                stack.push(node.right_offset());
+                stack.push(node.left_offset());
+            }
+        }
+        closest
+    }
+
+    /// Tests if the ray hits any object, as decided by {hit_object}.
+    /// Returns as soon as {hit_object} returns true for any candidate object, without ordering the search.
+    pub fn any_hit(&self, ray: Ray, t_min: f32, t_max: f32, mut hit_object: impl FnMut(usize) -> bool) -> bool {
+        let ray = BvhRay::new(ray);
+        let mut stack = TraversalStack::new();
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.hit(&ray, t_min, t_max).is_none() {
+                continue;
+            }
+            if node.is_leaf() {
+                if self.object_indices[node.range()].iter().any(|&object_index| hit_object(object_index as usize)) {
+                    return true;
+                }
+            } else {
+                stack.push(node.right_offset());
+                stack.push(node.left_offset());
+            }
+        }
+        false
     }
 
     pub fn hit_candidates<'a>(&'a self, ray: Ray, t_min: f32, t_max: f32) -> BvhHitCandidateIter<'a> {
-        let mut stack = ArrayVec::new();
+        let mut stack = TraversalStack::new();
         stack.push(State { node_index: 0, offset: 0 });
-        BvhHitCandidateIter { bvh: self, stack, ray, t_min, t_max }
+        BvhHitCandidateIter { bvh: self, stack, ray: BvhRay::new(ray), t_min, t_max }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
-enum Axis { X, Y, Z }
+enum Axis { X = 0, Y = 1, Z = 2 }
 impl Axis {
     fn value(&self, v3: &V3) -> f32 {
         match self {
@@ -107,12 +361,10 @@ impl Axis {
 
 /// Create a leaf node representing the given object_bounds.
 fn create_leaf(offset: usize, object_bounds: &[BvhObjectBounds]) -> BvhNode {
-    BvhNode {
-        aabb: AABB::from_vertices_iter(
-            object_bounds.iter().flat_map(|b| [b.bounds.aabb.min, b.bounds.aabb.max])
-        ),
-        data: BvhNodeData::Leaf(BvhLeaf { offset, length: object_bounds.len() })
-    }
+    let aabb = AABB::from_vertices_iter(
+        object_bounds.iter().flat_map(|b| [b.bounds.aabb.min, b.bounds.aabb.max])
+    );
+    BvhNode::leaf(&aabb, offset, object_bounds.len())
 }
 
 fn select_longest_axis(extent: &V3) -> Axis {
@@ -126,33 +378,183 @@ fn select_longest_axis(extent: &V3) -> Axis {
     axis
 }
 
-fn build(mut object_bounds: Vec<BvhObjectBounds>) -> Bvh {
-    let mut nodes = Vec::with_capacity(object_bounds.len() * 2);
+/// Nodes with at least this many objects may have their two subtrees built on separate threads
+const PARALLEL_BUILD_MIN_OBJECTS: usize = 4096;
+
+/// Builds with at least this many objects report their progress to the log
+const BUILD_PROGRESS_MIN_OBJECTS: usize = 1_000_000;
+
+/// The number of threads used to build each BVH, or 0 to use every available CPU
+static BUILD_THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the number of threads used to build each BVH.
+/// Large trees build their subtrees in parallel, and are identical to trees built on a single thread.
+pub fn set_build_thread_count(thread_count: usize) {
+    BUILD_THREAD_COUNT.store(thread_count, Ordering::Relaxed);
+}
+
+fn build_thread_count() -> usize {
+    match BUILD_THREAD_COUNT.load(Ordering::Relaxed) {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    }
+}
+
+/// State shared by every thread of a build
+struct BuildContext {
+    strategy: BvhBuildStrategy,
+    object_count: usize,
+    // The number of objects placed into finished leaves
+    objects_placed: AtomicUsize,
+}
+
+impl BuildContext {
+    /// Records objects placed into a finished leaf, logging each 10% of the build
+    fn place_objects(&self, count: usize) {
+        if self.object_count < BUILD_PROGRESS_MIN_OBJECTS {
+            return;
+        }
+        let placed = self.objects_placed.fetch_add(count, Ordering::Relaxed);
+        let (before, after) = (placed * 10 / self.object_count, (placed + count) * 10 / self.object_count);
+        if after > before && after < 10 {
+            info!("Building BVH over {} objects: {}%", self.object_count, after * 10);
+        }
+    }
+}
+
+fn build(mut object_bounds: Vec<BvhObjectBounds>, strategy: BvhBuildStrategy, thread_count: usize) -> Bvh {
+    let context = BuildContext { strategy, object_count: object_bounds.len(), objects_placed: AtomicUsize::new(0) };
 
     // Prepare the root node
-    nodes.push(create_leaf(0, &object_bounds));
+    let root = create_leaf(0, &object_bounds);
 
-    subdivide(0, &mut nodes, &mut object_bounds);
+    let mut nodes = build_subtree(root, 1, &mut object_bounds, &context, thread_count);
     nodes.shrink_to_fit();
 
     Bvh {
-        object_bounds,
-        nodes
+        object_indices: object_bounds.iter().map(|o| o.object_index as u32).collect(),
+        nodes,
+        strategy,
+        built_sah_cost: 0.0,
     }
 }
 
-fn subdivide(offset: usize, nodes: &mut Vec<BvhNode>, object_bounds: &mut [BvhObjectBounds]) {
+/// Builds the subtree below the leaf {root}, which includes every object in {object_bounds}.
+/// Returns the nodes of the subtree starting with {root}, with branches referencing nodes relative to the start of the subtree.
+fn build_subtree(root: BvhNode, depth: usize, object_bounds: &mut [BvhObjectBounds], context: &BuildContext, thread_count: usize) -> Vec<BvhNode> {
+    // NOTE: Subtrees built on other threads are copied in once complete, so only reserve space for sequential builds
+    let capacity = if thread_count > 1 { 1 } else { object_bounds.len() * 2 };
+    let mut nodes = Vec::with_capacity(capacity);
+    nodes.push(root);
+    subdivide(0, depth, &mut nodes, object_bounds, context, thread_count);
+    nodes
+}
+
+/// Splits the leaf at {offset}, which includes every object in {object_bounds}, and recursively splits its children.
+fn subdivide(offset: usize, depth: usize, nodes: &mut Vec<BvhNode>, object_bounds: &mut [BvhObjectBounds], context: &BuildContext, thread_count: usize) {
     let node = &nodes[offset];
-    let leaf = node.leaf_data();
+    let (node_offset, aabb) = (node.range().start, node.aabb());
+    let strategy = context.strategy;
 
-    // Stop subdividing nodes when we get to a minimum size
-    if leaf.length <= 2 {
+    // Stop subdividing at the maximum depth
+    if depth >= MAX_DEPTH {
+        context.place_objects(object_bounds.len());
         return;
     }
 
+    let max_leaf_size = match strategy {
+        BvhBuildStrategy::MeanSplit => 2,
+        BvhBuildStrategy::Sah(options) => options.max_leaf_size.max(1),
+    };
+
+    // Partition objects in place
+    let objects = object_bounds;
+    let split = match strategy {
+        _ if depth >= MEDIAN_SPLIT_DEPTH => None,
+        BvhBuildStrategy::MeanSplit => partition_mean_split(objects),
+        // NOTE: The surface area of infinite (or flattened) bounds is meaningless, so fall back to the mean split
+        BvhBuildStrategy::Sah(_) if !(surface_area(&aabb) > 0.0 && surface_area(&aabb).is_finite()) => partition_mean_split(objects),
+        BvhBuildStrategy::Sah(options) => partition_sah(objects, &aabb, &options),
+    };
+    // Fall back to splitting at the median object when the strategy can't split a large node
+    // (e.g. when many objects share the same centroid), or when the tree is getting too deep.
+    let split = match split {
+        None if objects.len() > max_leaf_size => Some(partition_median(objects)),
+        split => split,
+    };
+    let Some((left_len, axis)) = split else {
+        context.place_objects(objects.len());
+        return;
+    };
+    let parallel = thread_count > 1 && objects.len() >= PARALLEL_BUILD_MIN_OBJECTS;
+    let (left, right) = objects.split_at_mut(left_len);
+
+    // Create child nodes
+    let l1 = create_leaf(node_offset, left);
+    let l2 = create_leaf(node_offset + left.len(), right);
+
+    let left_offset = nodes.len();
+
+    // Convert current node into a branch
+    nodes[offset].set_branch(left_offset, axis);
+
+    if parallel {
+        // Build the right subtree on a new thread, sharing the remaining threads between both subtrees
+        let right_thread_count = thread_count / 2;
+        let (left_nodes, right_nodes) = thread::scope(|scope| {
+            let right_build = scope.spawn(|| build_subtree(l2, depth + 1, right, context, right_thread_count));
+            let left_nodes = build_subtree(l1, depth + 1, left, context, thread_count - right_thread_count);
+            (left_nodes, right_build.join().expect("BVH build thread panicked"))
+        });
+
+        // Splice the subtrees in the order a sequential build creates them:
+        // both children, then the descendants of the left child, then the descendants of the right child
+        let relocate = |node: &BvhNode, shift: usize| {
+            let mut node = node.clone();
+            if !node.is_leaf() {
+                node.set_branch(node.left_offset() + shift, node.axis());
+            }
+            node
+        };
+        let (left_shift, right_shift) = (left_offset + 1, left_offset + left_nodes.len());
+        nodes.reserve(left_nodes.len() + right_nodes.len());
+        nodes.push(relocate(&left_nodes[0], left_shift));
+        nodes.push(relocate(&right_nodes[0], right_shift));
+        nodes.extend(left_nodes[1..].iter().map(|node| relocate(node, left_shift)));
+        nodes.extend(right_nodes[1..].iter().map(|node| relocate(node, right_shift)));
+        return;
+    }
+
+    nodes.push(l1);
+    let right_offset = nodes.len();
+    nodes.push(l2);
+
+    // Recurse
+    subdivide(left_offset, depth + 1, nodes, left, context, thread_count);
+    subdivide(right_offset, depth + 1, nodes, right, context, thread_count);
+}
+
+/// Partitions objects into two equal halves, ordered by their centroids on the longest axis.
+/// Returns the size of the left partition and the split axis.
+fn partition_median(objects: &mut [BvhObjectBounds]) -> (usize, Axis) {
+    let bounds = AABB::from_vertices_iter(objects.iter().map(|o| o.bounds.centroid));
+    let axis = select_longest_axis(&(bounds.max - bounds.min));
+    let mid = objects.len() / 2;
+    objects.select_nth_unstable_by(mid, |a, b| axis.value(&a.bounds.centroid).total_cmp(&axis.value(&b.bounds.centroid)));
+    (mid, axis)
+}
+
+/// Partitions objects about the mean centroid on the longest axis.
+/// Returns the size of the left partition and the split axis, or None if the node should remain a leaf.
+fn partition_mean_split(objects: &mut [BvhObjectBounds]) -> Option<(usize, Axis)> {
+    // Stop subdividing nodes when we get to a minimum size
+    if objects.len() <= 2 {
+        return None;
+    }
+
     // See: https://fileadmin.cs.lth.se/cs/Education/EDAN35/projects/2022/Sanden-BVH.pdf
     // This is rough implementation of Select Longest Axis & Mean Partitioning
-    let centroids = object_bounds[leaf.range()].iter().map(|o| o.bounds.centroid);
+    let centroids = objects.iter().map(|o| o.bounds.centroid);
 
     // Select the longest axis to subdivide on
     let bounds = AABB::from_vertices_iter(centroids.clone());
@@ -160,37 +562,506 @@ fn subdivide(offset: usize, nodes: &mut Vec<BvhNode>, object_bounds: &mut [BvhOb
     let axis = select_longest_axis(&extent);
 
     // Find a split point (find the mean position on this axis)
-    let split_on = centroids.map(|c| axis.value(&c)).sum::<f32>() / leaf.length as f32;
+    let split_on = centroids.map(|c| axis.value(&c)).sum::<f32>() / objects.len() as f32;
 
-    // Partition objects in place
-    let (left, right) = partition_by_key(&mut object_bounds[leaf.range()], split_on, |o| axis.value(&o.bounds.centroid));
+    let (left, right) = partition_by_key(objects, split_on, |o| axis.value(&o.bounds.centroid));
 
     // Stop subdividing if one of the partitions is empty
-    if left.len() == 0 || right.len() == 0 {
-        return;
+    if left.is_empty() || right.is_empty() {
+        return None;
     }
+    Some((left.len(), axis))
+}
 
-    // Create child nodes
-    let l1 = create_leaf(leaf.offset, left);
-    let l2 = create_leaf(leaf.offset + left.len(), right);
+#[derive(Clone, Default)]
+struct SahBin {
+    aabb: Option<AABB>,
+    count: usize,
+}
 
-    let left_offset = nodes.len();
-    nodes.push(l1);
-    let right_offset = nodes.len();
-    nodes.push(l2);
+/// Partitions objects at the cheapest split found by the binned Surface Area Heuristic.
+/// Returns the size of the left partition and the split axis, or None if the node is cheaper to keep as a leaf.
+/// See: "On fast Construction of SAH-based Bounding Volume Hierarchies", Ingo Wald (2007)
+/// and https://jacco.ompf2.com/2022/04/21/how-to-build-a-bvh-part-3-quick-builds/
+fn partition_sah(objects: &mut [BvhObjectBounds], aabb: &AABB, options: &SahOptions) -> Option<(usize, Axis)> {
+    if objects.len() <= 1 {
+        return None;
+    }
+    let bin_count = options.bin_count.max(2);
+    let centroid_bounds = AABB::from_vertices_iter(objects.iter().map(|o| o.bounds.centroid));
 
-    // Convert current node into a branch
-    nodes[offset].data = BvhNodeData::Branch(BvhBranch { left_offset, right_offset });
+    // Find the cheapest split between any two bins on any axis
+    // NOTE: Costs are relative to the surface area of the node being split
+    let parent_area = surface_area(aabb);
+    let mut best: Option<(Axis, usize, f32)> = None;
+    for axis in [Axis::X, Axis::Y, Axis::Z] {
+        let (min, max) = (axis.value(&centroid_bounds.min), axis.value(&centroid_bounds.max));
+        if max <= min {
+            // All centroids lie on the same plane on this axis
+            continue;
+        }
+        let scale = bin_count as f32 / (max - min);
+        let bin_index = |o: &BvhObjectBounds| (((axis.value(&o.bounds.centroid) - min) * scale) as usize).min(bin_count - 1);
 
-    // Recurse
-    subdivide(left_offset, nodes, object_bounds);
-    subdivide(right_offset, nodes, object_bounds);
+        let mut bins = vec![SahBin::default(); bin_count];
+        for o in objects.iter() {
+            let bin = &mut bins[bin_index(o)];
+            bin.count += 1;
+            bin.aabb = Some(match bin.aabb.take() {
+                Some(b) => AABB::surrounding(b, o.bounds.aabb.clone()),
+                None => o.bounds.aabb.clone(),
+            });
+        }
+
+        // Sweep from each side to find the area and object count to the left/right of each split
+        let sweep = |bins: &mut dyn Iterator<Item = &SahBin>| {
+            let mut acc = SahBin::default();
+            bins.map(|bin| {
+                acc.count += bin.count;
+                acc.aabb = match (acc.aabb.take(), bin.aabb.clone()) {
+                    (Some(a), Some(b)) => Some(AABB::surrounding(a, b)),
+                    (a, b) => a.or(b),
+                };
+                (acc.aabb.as_ref().map(surface_area).unwrap_or(0.0), acc.count)
+            })
+            .collect::<Vec<_>>()
+        };
+        let left = sweep(&mut bins.iter());
+        let mut right = sweep(&mut bins.iter().rev());
+        right.reverse();
+
+        // Split {i} places bins 0..=i on the left
+        for i in 0..(bin_count - 1) {
+            let ((left_area, left_count), (right_area, right_count)) = (left[i], right[i + 1]);
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = options.traversal_cost
+                + options.intersection_cost * (left_area * left_count as f32 + right_area * right_count as f32) / parent_area;
+            if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, i, cost));
+            }
+        }
+    }
+
+    let (axis, split_bin, cost) = best?;
+
+    // Only split when it is cheaper than testing every object in this node, unless the node is too large
+    let leaf_cost = options.intersection_cost * objects.len() as f32;
+    if cost >= leaf_cost && objects.len() <= options.max_leaf_size {
+        return None;
+    }
+
+    let (min, max) = (axis.value(&centroid_bounds.min), axis.value(&centroid_bounds.max));
+    let scale = bin_count as f32 / (max - min);
+    let (left, _) = partition_by_key(objects, split_bin, |o| (((axis.value(&o.bounds.centroid) - min) * scale) as usize).min(bin_count - 1));
+    Some((left.len(), axis))
+}
+
+fn surface_area(aabb: &AABB) -> f32 {
+    let extent = aabb.max - aabb.min;
+    2.0 * (extent.0 * extent.1 + extent.1 * extent.2 + extent.2 * extent.0)
+}
+
+// Statistics
+
+/// Describes the shape of a built BVH
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    /// Estimated cost of tracing a ray through the tree, relative to the surface area of the root node
+    pub sah_cost: f32,
+    /// The number of leaves containing each number of objects (indexed by object count)
+    pub leaf_size_histogram: Vec<usize>,
+}
+
+impl Bvh {
+    /// Collects statistics about the tree, costed using the given strategy
+    pub fn stats(&self, strategy: BvhBuildStrategy) -> BvhStats {
+        let options = match strategy {
+            BvhBuildStrategy::Sah(options) => options,
+            BvhBuildStrategy::MeanSplit => SahOptions::default(),
+        };
+        let root_area = surface_area(&self.nodes[0].aabb());
+        let mut stats = BvhStats { node_count: self.nodes.len(), leaf_count: 0, max_depth: 0, sah_cost: 0.0, leaf_size_histogram: vec![] };
+        let mut stack = vec![(0, 1)];
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = &self.nodes[node_index];
+            let area = surface_area(&node.aabb()) / root_area;
+            stats.max_depth = stats.max_depth.max(depth);
+            if node.is_leaf() {
+                let length = node.length();
+                stats.sah_cost += area * options.intersection_cost * length as f32;
+                stats.leaf_count += 1;
+                if stats.leaf_size_histogram.len() <= length {
+                    stats.leaf_size_histogram.resize(length + 1, 0);
+                }
+                stats.leaf_size_histogram[length] += 1;
+            } else {
+                stats.sah_cost += area * options.traversal_cost;
+                stack.push((node.left_offset(), depth + 1));
+                stack.push((node.right_offset(), depth + 1));
+            }
+        }
+        stats
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} nodes, {} leaves, depth {}, SAH cost {:.2}, leaf sizes [", self.node_count, self.leaf_count, self.max_depth, self.sah_cost)?;
+        let sizes = self.leaf_size_histogram.iter().enumerate().filter(|(_, count)| **count > 0);
+        for (i, (size, count)) in sizes.enumerate() {
+            write!(f, "{}{}: {}", if i > 0 { ", " } else { "" }, size, count)?;
+        }
+        write!(f, "]")
+    }
+}
+
+// Serialisation
+
+/// Identifies the binary layout written by Bvh::write. Increment when the layout changes.
+const FORMAT_VERSION: u32 = 1;
+
+impl Bvh {
+    /// Writes the tree in a compact binary format, which Bvh::read loads without rebuilding the tree
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let write_u32 = |writer: &mut dyn Write, value: u32| writer.write_all(&value.to_le_bytes());
+        let write_f32 = |writer: &mut dyn Write, value: f32| writer.write_all(&value.to_le_bytes());
+
+        write_u32(writer, FORMAT_VERSION)?;
+        match self.strategy {
+            BvhBuildStrategy::MeanSplit => write_u32(writer, 0)?,
+            BvhBuildStrategy::Sah(options) => {
+                write_u32(writer, 1)?;
+                write_u32(writer, options.bin_count as u32)?;
+                write_f32(writer, options.traversal_cost)?;
+                write_f32(writer, options.intersection_cost)?;
+                write_u32(writer, options.max_leaf_size as u32)?;
+            },
+        }
+        write_f32(writer, self.built_sah_cost)?;
+
+        write_u32(writer, self.object_indices.len() as u32)?;
+        for &object_index in &self.object_indices {
+            write_u32(writer, object_index)?;
+        }
+        write_u32(writer, self.nodes.len() as u32)?;
+        for node in &self.nodes {
+            for value in node.min {
+                write_f32(writer, value)?;
+            }
+            write_u32(writer, node.offset)?;
+            for value in node.max {
+                write_f32(writer, value)?;
+            }
+            write_u32(writer, node.data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a tree written by Bvh::write.
+    /// The tree is checked to be well formed, so traversal can't fail, but is trusted to match the objects it is used with.
+    pub fn read(reader: &mut dyn Read) -> io::Result<Bvh> {
+        let read_u32 = |reader: &mut dyn Read| -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let read_f32 = |reader: &mut dyn Read| read_u32(reader).map(f32::from_bits);
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let version = read_u32(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!("Bvh::read: unsupported format version {}", version)));
+        }
+        let strategy = match read_u32(reader)? {
+            0 => BvhBuildStrategy::MeanSplit,
+            1 => BvhBuildStrategy::Sah(SahOptions {
+                bin_count: read_u32(reader)? as usize,
+                traversal_cost: read_f32(reader)?,
+                intersection_cost: read_f32(reader)?,
+                max_leaf_size: read_u32(reader)? as usize,
+            }),
+            tag => return Err(invalid(format!("Bvh::read: unknown build strategy {}", tag))),
+        };
+        let built_sah_cost = read_f32(reader)?;
+
+        let object_count = read_u32(reader)? as usize;
+        if object_count > MAX_OBJECTS {
+            return Err(invalid(format!("Bvh::read: too many objects ({})", object_count)));
+        }
+        let object_indices = (0..object_count)
+            .map(|_| read_u32(reader))
+            .collect::<io::Result<Vec<_>>>()?;
+        let node_count = read_u32(reader)? as usize;
+        if node_count == 0 || node_count > object_count.max(1) * 2 {
+            return Err(invalid(format!("Bvh::read: unexpected node count {} for {} objects", node_count, object_count)));
+        }
+        let nodes = (0..node_count)
+            .map(|_| Ok(BvhNode {
+                min: [read_f32(reader)?, read_f32(reader)?, read_f32(reader)?],
+                offset: read_u32(reader)?,
+                max: [read_f32(reader)?, read_f32(reader)?, read_f32(reader)?],
+                data: read_u32(reader)?,
+            }))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Check every reference is in range, and the tree is no deeper than traversal stacks allow.
+        // NOTE: Children are always stored after their parent, which also rules out cycles.
+        if object_indices.iter().any(|&object_index| object_index as usize >= object_count) {
+            return Err(invalid("Bvh::read: object index out of range".into()));
+        }
+        let mut depths = vec![1; node_count];
+        for (node_index, node) in nodes.iter().enumerate() {
+            if depths[node_index] > MAX_DEPTH {
+                return Err(invalid(format!("Bvh::read: tree is deeper than {}", MAX_DEPTH)));
+            }
+            if node.is_leaf() {
+                if node.range().end > object_count {
+                    return Err(invalid("Bvh::read: leaf objects out of range".into()));
+                }
+            } else if node.data > Axis::Z as u32 || node.left_offset() <= node_index || node.right_offset() >= node_count {
+                return Err(invalid("Bvh::read: malformed branch node".into()));
+            } else {
+                depths[node.left_offset()] = depths[node_index] + 1;
+                depths[node.right_offset()] = depths[node_index] + 1;
+            }
+        }
+
+        Ok(Bvh { object_indices, nodes, strategy, built_sah_cost })
+    }
+}
+
+// Wide BVH
+
+/// A BVH with four children per node, collapsed from a binary Bvh.
+/// Each node tests the ray against all four child boxes at once using SIMD,
+/// and the tree is half as deep, so far fewer nodes are visited per ray.
+/// See: "Shallow Bounding Volume Hierarchies for Fast SIMD Ray Tracing of Incoherent Rays", Dammertz et al. (2008)
+pub struct Bvh4 {
+    object_indices: Vec<u32>,
+    nodes: Vec<Bvh4Node>,
+    aabb: AABB,
+}
+
+/// A node of the wide BVH, holding the bounds of its four children in SIMD lanes
+#[derive(Clone)]
+#[repr(C, align(64))]
+struct Bvh4Node {
+    // Bounds of each child, indexed by [min/max][axis] with one lane per child.
+    // Unused lanes hold inverted bounds, which no ray can hit.
+    bounds: [[f32x4; 3]; 2],
+    // Branch children: index of the child node in Node collection
+    // Leaf children: index of the first object in Object index collection
+    child: [u32; 4],
+    // Leaf children: the number of objects. Branch children: 0
+    count: [u32; 4],
+}
+
+impl Bvh4Node {
+    fn empty() -> Bvh4Node {
+        Bvh4Node {
+            bounds: [[f32x4::splat(f32::INFINITY); 3], [f32x4::splat(f32::NEG_INFINITY); 3]],
+            child: [0; 4],
+            count: [0; 4],
+        }
+    }
+
+    /// Returns a mask of the children hit by the ray within {t_min..t_max} (bit {i} for child {i}),
+    /// and the {t} at which the ray enters each child
+    fn hit(&self, ray: &Bvh4Ray, t_min: f32, t_max: f32) -> (i32, [f32; 4]) {
+        let mut t_min = f32x4::splat(t_min);
+        let mut t_max = f32x4::splat(t_max);
+        for axis in 0..3 {
+            let near = self.bounds[ray.near[axis]][axis];
+            let far = self.bounds[1 - ray.near[axis]][axis];
+            // NOTE: f32x4::max/min ignore NaN in either argument, as BvhNode::hit relies on
+            t_min = t_min.max((near - ray.origin[axis]) * ray.inv_direction[axis]);
+            t_max = t_max.min((far - ray.origin[axis]) * ray.inv_direction[axis]);
+        }
+        (t_min.cmp_le(t_max).move_mask(), t_min.to_array())
+    }
+}
+
+/// A BvhRay with each component splatted across the SIMD lanes
+struct Bvh4Ray {
+    origin: [f32x4; 3],
+    inv_direction: [f32x4; 3],
+    // Index of the bounds (0 = min, 1 = max) the ray enters boxes through on each axis
+    near: [usize; 3],
+}
+
+impl Bvh4Ray {
+    fn new(ray: Ray) -> Bvh4Ray {
+        let ray = BvhRay::new(ray);
+        Bvh4Ray {
+            origin: ray.origin.map(f32x4::splat),
+            inv_direction: ray.inv_direction.map(f32x4::splat),
+            near: ray.negative.map(|negative| negative as usize),
+        }
+    }
+}
+
+/// A child waiting to be visited during traversal of a Bvh4
+#[derive(Clone, Copy)]
+struct Bvh4Entry {
+    child: u32,
+    count: u32,
+    // The {t} at which the ray enters the child
+    t: f32,
+}
+
+impl Bvh4Entry {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.child as usize..(self.child + self.count) as usize
+    }
+}
+
+/// A stack of children waiting to be visited during traversal of a Bvh4.
+/// NOTE: Each node visited replaces itself with up to four children, and the tree is no deeper than the binary BVH.
+type Bvh4TraversalStack = ArrayVec<Bvh4Entry, { 3 * MAX_DEPTH + 1 }>;
+
+impl Bvh4 {
+    pub fn from<T: BvhObject>(objects: &[T]) -> Bvh4 {
+        Bvh4::from_bvh(Bvh::from(objects))
+    }
+
+    pub fn from_with_strategy<T: BvhObject>(objects: &[T], strategy: BvhBuildStrategy) -> Bvh4 {
+        Bvh4::from_bvh(Bvh::from_with_strategy(objects, strategy))
+    }
+
+    /// Collapses a binary BVH into a wide one.
+    /// Object indices are unchanged.
+    pub fn from_bvh(bvh: Bvh) -> Bvh4 {
+        let root = &bvh.nodes[0];
+        let children = match root.is_leaf() {
+            true => [0].into_iter().collect(),
+            false => [root.left_offset(), root.right_offset()].into_iter().collect(),
+        };
+        let mut nodes = Vec::with_capacity(bvh.nodes.len() / 3 + 1);
+        collapse(&bvh, children, &mut nodes);
+        nodes.shrink_to_fit();
+
+        Bvh4 {
+            aabb: bvh.aabb(),
+            object_indices: bvh.object_indices,
+            nodes,
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        self.aabb.clone()
+    }
+
+    /// Finds the closest object hit by the ray, as Bvh::closest_hit does
+    pub fn closest_hit<H: BvhHit>(&self, ray: Ray, t_min: f32, t_max: f32, mut hit_object: impl FnMut(usize, f32) -> Option<H>) -> Option<H> {
+        let ray = Bvh4Ray::new(ray);
+        let mut closest: Option<H> = None;
+        let mut t_max = t_max;
+        let mut stack = Bvh4TraversalStack::new();
+        stack.push(Bvh4Entry { child: 0, count: 0, t: t_min });
+        while let Some(entry) = stack.pop() {
+            // Skip children entered beyond the closest hit found since they were pushed
+            if entry.t > t_max {
+                continue;
+            }
+            if entry.is_leaf() {
+                for &object_index in &self.object_indices[entry.range()] {
+                    if let Some(hit) = hit_object(object_index as usize, t_max) {
+                        t_max = hit.t();
+                        closest = Some(hit);
+                    }
+                }
+                continue;
+            }
+
+            // Push the children hit from far to near, so the nearest child is visited first
+            let node = &self.nodes[entry.child as usize];
+            let (mask, t) = node.hit(&ray, t_min, t_max);
+            let first = stack.len();
+            for lane in (0..4).filter(|lane| mask & (1 << lane) != 0) {
+                stack.push(Bvh4Entry { child: node.child[lane], count: node.count[lane], t: t[lane] });
+                // Insertion sort, as there are at most four children
+                let mut i = stack.len() - 1;
+                while i > first && stack[i - 1].t < stack[i].t {
+                    stack.swap(i - 1, i);
+                    i -= 1;
+                }
+            }
+        }
+        closest
+    }
+
+    /// Tests if the ray hits any object, as Bvh::any_hit does
+    pub fn any_hit(&self, ray: Ray, t_min: f32, t_max: f32, mut hit_object: impl FnMut(usize) -> bool) -> bool {
+        let ray = Bvh4Ray::new(ray);
+        let mut stack = Bvh4TraversalStack::new();
+        stack.push(Bvh4Entry { child: 0, count: 0, t: t_min });
+        while let Some(entry) = stack.pop() {
+            if entry.is_leaf() {
+                if self.object_indices[entry.range()].iter().any(|&object_index| hit_object(object_index as usize)) {
+                    return true;
+                }
+                continue;
+            }
+
+            let node = &self.nodes[entry.child as usize];
+            let (mask, _) = node.hit(&ray, t_min, t_max);
+            // Push in reverse, so children are visited in the same order as the binary tree
+            for lane in (0..4).rev().filter(|lane| mask & (1 << lane) != 0) {
+                stack.push(Bvh4Entry { child: node.child[lane], count: node.count[lane], t: t_min });
+            }
+        }
+        false
+    }
+}
+
+/// Creates a wide node from up to four nodes of the binary tree, recursively collapsing any branches among them.
+/// Returns the index of the new node.
+fn collapse(bvh: &Bvh, mut children: ArrayVec<usize, 4>, nodes: &mut Vec<Bvh4Node>) -> u32 {
+    // Pull grandchildren up into this node, opening the largest branch first
+    while children.len() < 4 {
+        let largest = (0..children.len())
+            .filter(|&i| !bvh.nodes[children[i]].is_leaf())
+            .max_by(|&a, &b| surface_area(&bvh.nodes[children[a]].aabb()).total_cmp(&surface_area(&bvh.nodes[children[b]].aabb())));
+        let Some(i) = largest else { break };
+        let branch = &bvh.nodes[children[i]];
+        children[i] = branch.left_offset();
+        children.insert(i + 1, branch.right_offset());
+    }
+
+    // Reserve this node before its children, so the root is always at index 0
+    let index = nodes.len();
+    nodes.push(Bvh4Node::empty());
+    let mut node = Bvh4Node::empty();
+    for (lane, &child) in children.iter().enumerate() {
+        let child = &bvh.nodes[child];
+        for axis in 0..3 {
+            node.bounds[0][axis].as_array_mut()[lane] = child.min[axis];
+            node.bounds[1][axis].as_array_mut()[lane] = child.max[axis];
+        }
+        if child.is_leaf() {
+            node.child[lane] = child.offset;
+            node.count[lane] = child.length() as u32;
+        } else {
+            let grandchildren = [child.left_offset(), child.right_offset()].into_iter().collect();
+            node.child[lane] = collapse(bvh, grandchildren, nodes);
+        }
+    }
+    nodes[index] = node;
+    index as u32
 }
 
 pub struct BvhHitCandidateIter<'a> {
     bvh: &'a Bvh,
-    stack: ArrayVec<State, 30>,
-    ray: Ray,
+    stack: TraversalStack<State>,
+    ray: BvhRay,
     t_min: f32,
     t_max: f32
 }
@@ -214,24 +1085,243 @@ impl<'a> Iterator for BvhHitCandidateIter<'a> {
         loop {
             let State { node_index, offset } = self.stack.pop()?;
             let node = &self.bvh.nodes[node_index];
-            if !node.aabb.hit_aabb(self.ray, self.t_min, self.t_max) {
+            if node.hit(&self.ray, self.t_min, self.t_max).is_none() {
                 continue;
             }
-            match node.data {
-                BvhNodeData::Branch(ref branch) => {
-                    self.stack.push(State { node_index: branch.left_offset, offset: 0 });
-                    self.stack.push(State { node_index: branch.right_offset, offset: 0 });
-                },
-                BvhNodeData::Leaf(ref leaf) => {
-                    if offset < leaf.length - 1 {
-                        // Push the next object to be emitted to the stack
-                        self.stack.push(State { node_index, offset: offset + 1 });
-                    }
-                    // Emit the current object
-                    let object_index = self.bvh.object_bounds[leaf.offset + offset].object_index;
-                    return Some(BvhHitCandidate { object_index });
+            if node.is_leaf() {
+                let range = node.range();
+                if offset < range.len() - 1 {
+                    // Push the next object to be emitted to the stack
+                    self.stack.push(State { node_index, offset: offset + 1 });
                 }
+                // Emit the current object
+                let object_index = self.bvh.object_indices[range.start + offset] as usize;
+                return Some(BvhHitCandidate { object_index });
+            } else {
+                self.stack.push(State { node_index: node.left_offset(), offset: 0 });
+                self.stack.push(State { node_index: node.right_offset(), offset: 0 });
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rand::{ Rng, SeedableRng };
+    use rand::rngs::StdRng;
+
+    use crate::implementation::AABB;
+    use crate::types::{ Ray, V3 };
+    use super::{ build, Bvh, Bvh4, BvhBounds, BvhBuildStrategy, BvhHit, BvhObject, BvhObjectBounds, SahOptions, MAX_DEPTH };
+
+    struct TestBox(AABB);
+
+    impl BvhObject for TestBox {
+        fn calculate_bounds(&self) -> BvhBounds {
+            BvhBounds { centroid: self.0.min * 0.5 + self.0.max * 0.5, aabb: self.0.clone() }
+        }
+    }
+
+    struct TestHit(f32);
+
+    impl BvhHit for TestHit {
+        fn t(&self) -> f32 {
+            self.0
+        }
+    }
+
+    fn strategies() -> [BvhBuildStrategy; 3] {
+        [
+            BvhBuildStrategy::MeanSplit,
+            BvhBuildStrategy::default(),
+            BvhBuildStrategy::Sah(SahOptions { bin_count: 4, max_leaf_size: 1, ..Default::default() }),
+        ]
+    }
+
+    fn random_v3(rng: &mut impl Rng, scale: f32) -> V3 {
+        V3(rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5) * scale
+    }
+
+    /// Compares closest and any hit queries against testing every box, for many random rays
+    fn assert_matches_brute_force(boxes: &[TestBox], rng: &mut impl Rng) {
+        for strategy in strategies() {
+            let bvh = Bvh::from_with_strategy(boxes, strategy);
+            assert!(bvh.stats(strategy).max_depth <= MAX_DEPTH);
+            let bvh4 = Bvh4::from_with_strategy(boxes, strategy);
+            assert_trees_match_brute_force(&bvh, Some(&bvh4), boxes, rng, &format!("{:?}", strategy));
+        }
+    }
+
+    fn assert_trees_match_brute_force(bvh: &Bvh, bvh4: Option<&Bvh4>, boxes: &[TestBox], rng: &mut impl Rng, label: &str) {
+        for i in 0..500 {
+            // Aim half of the rays at an object, so that degenerate objects are hit too
+            let origin = random_v3(rng, 40.0);
+            let target = match i % 2 {
+                0 => random_v3(rng, 10.0),
+                _ => boxes[rng.random_range(0..boxes.len())].calculate_bounds().centroid,
+            };
+            let ray = Ray::new(origin, target - origin);
+            let (t_min, t_max) = (0.0, rng.random::<f32>() * 2.0);
+
+            let hit_box = |i: usize, t_max: f32| boxes[i].0.hit_aabb_range(ray, t_min, t_max).map(|(t, _)| TestHit(t));
+            let expected = (0..boxes.len())
+                .filter_map(|i| hit_box(i, t_max))
+                .map(|hit| hit.0)
+                .reduce(f32::min);
+
+            let closest = bvh.closest_hit(ray, t_min, t_max, hit_box).map(|hit| hit.0);
+            assert_eq!(closest, expected, "{}", label);
+            let any = bvh.any_hit(ray, t_min, t_max, |i| hit_box(i, t_max).is_some());
+            assert_eq!(any, expected.is_some(), "{}", label);
+
+            if let Some(bvh4) = bvh4 {
+                let closest = bvh4.closest_hit(ray, t_min, t_max, hit_box).map(|hit| hit.0);
+                assert_eq!(closest, expected, "{} (wide)", label);
+                let any = bvh4.any_hit(ray, t_min, t_max, |i| hit_box(i, t_max).is_some());
+                assert_eq!(any, expected.is_some(), "{} (wide)", label);
+            }
+        }
+    }
+
+    #[test]
+    fn random_boxes_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // Include trees small enough to be a single leaf, or a single wide node
+        for count in [1, 5, 2000] {
+            let boxes = (0..count)
+                .map(|_| {
+                    let min = random_v3(&mut rng, 20.0);
+                    // Mix of tiny and large boxes
+                    let size = (random_v3(&mut rng, 1.0) + V3::ONE * 0.5) * rng.random::<f32>().powi(4) * 10.0;
+                    TestBox(AABB::from_min_max(min, min + size))
+                })
+                .collect::<Vec<_>>();
+            assert_matches_brute_force(&boxes, &mut rng);
+        }
+    }
+
+    #[test]
+    fn degenerate_boxes_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(2);
+
+        // Many boxes sharing exactly the same centroid
+        let coincident = (0..5000)
+            .map(|i| TestBox(AABB::from_min_max(V3::ONE * -(i as f32 * 0.001), V3::ONE * (i as f32 * 0.001))))
+            .collect::<Vec<_>>();
+        assert_matches_brute_force(&coincident, &mut rng);
+
+        // Identical, flat boxes
+        let identical = (0..5000)
+            .map(|_| TestBox(AABB::from_min_max(V3(-1.0, 0.0, -1.0), V3(1.0, 0.0, 1.0))))
+            .collect::<Vec<_>>();
+        assert_matches_brute_force(&identical, &mut rng);
+
+        // Points along a line, with exponentially growing gaps
+        let line = (0..2000)
+            .map(|i| {
+                let p = V3::POS_X * (1.01_f32.powi(i) - 1.0);
+                TestBox(AABB::from_min_max(p, p))
+            })
+            .collect::<Vec<_>>();
+        assert_matches_brute_force(&line, &mut rng);
+    }
+
+    #[test]
+    fn refit_moved_boxes() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let boxes = (0..2000)
+            .map(|_| {
+                let min = random_v3(&mut rng, 20.0);
+                TestBox(AABB::from_min_max(min, min + V3::ONE * 0.5))
+            })
+            .collect::<Vec<_>>();
+        let move_boxes = |boxes: &[TestBox], rng: &mut StdRng, distance: f32| boxes.iter()
+            .map(|b| {
+                let offset = random_v3(rng, distance);
+                TestBox(AABB::from_min_max(b.0.min + offset, b.0.max + offset))
+            })
+            .collect::<Vec<_>>();
+
+        for strategy in strategies() {
+            let mut bvh = Bvh::from_with_strategy(&boxes, strategy);
+
+            // Small movements are refitted
+            let nudged = move_boxes(&boxes, &mut rng, 0.2);
+            assert!(!bvh.refit(&nudged), "{:?}", strategy);
+            assert_trees_match_brute_force(&bvh, None, &nudged, &mut rng, &format!("{:?} refit", strategy));
+
+            // Scattering the objects degrades the tree too far, so it is rebuilt
+            let scattered = move_boxes(&nudged, &mut rng, 20.0);
+            assert!(bvh.refit(&scattered), "{:?}", strategy);
+            assert_trees_match_brute_force(&bvh, None, &scattered, &mut rng, &format!("{:?} rebuilt", strategy));
+        }
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let boxes = (0..1000)
+            .map(|_| {
+                let min = random_v3(&mut rng, 20.0);
+                TestBox(AABB::from_min_max(min, min + V3::ONE))
+            })
+            .collect::<Vec<_>>();
+        for strategy in strategies() {
+            let bvh = Bvh::from_with_strategy(&boxes, strategy);
+            let mut bytes = vec![];
+            bvh.write(&mut bytes).unwrap();
+            let read = Bvh::read(&mut bytes.as_slice()).unwrap();
+            assert!(read.nodes == bvh.nodes && read.object_indices == bvh.object_indices, "{:?}", strategy);
+            assert_trees_match_brute_force(&read, None, &boxes, &mut rng, &format!("{:?} read", strategy));
+
+            // Truncated or corrupted data is rejected
+            assert!(Bvh::read(&mut &bytes[..bytes.len() - 1]).is_err());
+            let mut corrupt = bytes.clone();
+            let last_branch = bvh.nodes.iter().rposition(|node| !node.is_leaf()).unwrap();
+            let offset = bytes.len() - (bvh.nodes.len() - last_branch) * 32 + 12;
+            corrupt[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+            assert!(Bvh::read(&mut corrupt.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn parallel_build_matches_sequential() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let boxes = (0..50_000)
+            .map(|_| {
+                let min = random_v3(&mut rng, 100.0);
+                TestBox(AABB::from_min_max(min, min + V3::ONE))
+            })
+            .collect::<Vec<_>>();
+        let object_bounds = || boxes.iter()
+            .enumerate()
+            .map(|(object_index, object)| BvhObjectBounds { object_index, bounds: object.calculate_bounds() })
+            .collect::<Vec<_>>();
+
+        for strategy in strategies() {
+            let sequential = build(object_bounds(), strategy, 1);
+            for thread_count in [2, 3, 8] {
+                let parallel = build(object_bounds(), strategy, thread_count);
+                assert!(parallel.nodes == sequential.nodes, "{:?} with {} threads", strategy, thread_count);
+                assert!(parallel.object_indices == sequential.object_indices, "{:?} with {} threads", strategy, thread_count);
+            }
+        }
+    }
+
+    #[test]
+    fn depth_is_bounded() {
+        // Exponentially spaced centroids fall into the same few bins,
+        // which degrades binned splits into a list peeling off a few objects per level
+        let boxes = (0..120)
+            .map(|i| {
+                let p = V3::POS_X * 2.0_f32.powi(i);
+                TestBox(AABB::from_min_max(p, p + V3::ONE))
+            })
+            .collect::<Vec<_>>();
+        let two_bins = BvhBuildStrategy::Sah(SahOptions { bin_count: 2, max_leaf_size: 1, ..Default::default() });
+        for strategy in strategies().into_iter().chain([two_bins]) {
+            let stats = Bvh::from_with_strategy(&boxes, strategy).stats(strategy);
+            assert!(stats.max_depth <= MAX_DEPTH, "{:?} depth {}", strategy, stats.max_depth);
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use std::sync::Arc;
 
-use crate::bvh::{ Bvh, BvhBounds, BvhObject };
+use crate::bvh::{ Bvh, BvhBounds, BvhHit, BvhObject };
+use crate::medium::{ henyey_greenstein, sample_henyey_greenstein, Media, Medium, MediumEvent, Volume, VolumeEvent };
+use crate::spectrum::{ max_wavelength_weight, Wavelength };
 use crate::types::{ IntoArc, Ray, V2, V3 };
 
+use log::warn;
 use rand::{ RngCore, Rng };
 
 // Util
@@ -89,9 +92,19 @@ impl AABB {
         self.min == -V3::INFINITY && self.max == V3::INFINITY
     }
 
-    pub fn hit_aabb(&self, ray: Ray, mut t_min: f32, mut t_max: f32) -> bool {
+    /// Returns true if every component of this bounding box is finite
+    pub fn is_finite(&self) -> bool {
+        self.min.xyz().iter().chain(self.max.xyz().iter()).all(|v| v.is_finite())
+    }
+
+    pub fn hit_aabb(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit_aabb_range(ray, t_min, t_max).is_some()
+    }
+
+    /// Finds the range of {t} values over which the ray is inside this AABB, clipped to {t_min..t_max}
+    pub fn hit_aabb_range(&self, ray: Ray, mut t_min: f32, mut t_max: f32) -> Option<(f32, f32)> {
         if self.is_infinite() {
-            return true;
+            return Some((t_min, t_max));
         }
         // Algorithm from "Ray Tracing - The Next Weekend"
         // Attempt to determine if this ray intersects with this AABB in all three dimensions
@@ -110,11 +123,11 @@ impl AABB {
             t_max = if t1 < t_max { t1 } else { t_max };
             if t_max < t_min {
                 // No intersection on this dimension
-                return false;
+                return None;
             }
         }
 
-        true
+        Some((t_min, t_max))
     }
 
     pub fn corners(&self) -> [V3; 8] {
@@ -136,11 +149,15 @@ impl AABB {
 pub struct Reflect {
     pub ray: Ray,
     pub intensity: f32,
+    // Tints the reflected light, for materials whose reflectance varies with wavelength (e.g. metals)
+    pub color: V3,
 }
 
 pub struct Refract {
     pub ray: Ray,
     pub intensity: f32,
+    // Tints the refracted light, e.g. for light absorbed inside tinted glass
+    pub color: V3,
 }
 
 pub struct MatRecord {
@@ -150,6 +167,54 @@ pub struct MatRecord {
 
 pub trait Material: Send + Sync {
     fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord;
+
+    /// Returns true if no light can pass through this material.
+    /// Shadow rays stop at the first opaque surface they find.
+    fn is_opaque(&self) -> bool {
+        true
+    }
+
+    /// The fraction of light which passes straight through a non-opaque material, used to tint shadows.
+    fn transmission(&self, _ray: Ray, _hit_record: &HitRecord) -> f32 {
+        0.0
+    }
+
+    /// The fraction of light from a lamp in {light_direction} (pointing from the surface towards the lamp)
+    /// which is reflected back along the ray, including the cosine term.
+    /// Returns None for diffuse (Lambertian) lighting, scaled down for reflective and refractive materials.
+    fn light_response(&self, _ray: Ray, _hit_record: &HitRecord, _light_direction: V3) -> Option<V3> {
+        None
+    }
+
+    /// Returns true if the material may split light into single wavelengths (see spectrum::Wavelength).
+    /// Scenes with dispersive materials spread wavelengths over the samples of each pixel.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the surface is cut away at the hit point (e.g. by an alpha mask),
+    /// so that both camera and shadow rays pass straight through it.
+    /// Materials which cut away parts of their surface must not be opaque.
+    fn is_cut_out(&self, _hit_record: &HitRecord) -> bool {
+        false
+    }
+
+    /// The normal used to shade the hit, which may be perturbed from the surface normal (e.g. by a normal or bump map).
+    /// Scattering and lighting see the shading normal as the normal of the hit record.
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        hit_record.normal
+    }
+
+    /// The medium filling the interior of closed shapes with this material, if any.
+    /// Rays scattered into the surface take a random walk through the medium until they reach the surface again.
+    fn interior_medium(&self, _hit_record: &HitRecord) -> Option<&Medium> {
+        None
+    }
+
+    /// Returns true if the interior of the material may be filled with a medium (see interior_medium)
+    fn has_interior_medium(&self) -> bool {
+        false
+    }
 }
 
 crate::types::derive_into_arc!(trait Material);
@@ -167,19 +232,51 @@ crate::types::derive_into_arc!(trait Texture);
 
 // Hitables
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub entity_id: Option<u32>,
     pub t: f32,
     pub p: V3,
     pub normal: V3,
     pub uv: V2,
+    /// The directions in which the u and v coordinates increase over the surface, used to orient normal and bump maps.
+    /// V3::ZERO for surfaces without uv coordinates.
+    pub tangent: V3,
+    pub bitangent: V3,
     pub mat_id: MatId,
     pub tex_id: TexId,
     pub tex_key: Option<usize>,
 }
 
+impl HitRecord {
+    /// The unit tangent and bitangent at the hit, made perpendicular to the normal.
+    /// Returns None for surfaces without uv coordinates.
+    pub fn tangent_frame(&self) -> Option<(V3, V3)> {
+        let tangent = (self.tangent - self.normal * V3::dot(self.tangent, self.normal)).unit();
+        if tangent == V3::ZERO {
+            return None;
+        }
+        // NOTE: Mirrored uv coordinates flip the bitangent
+        let bitangent = V3::cross(self.normal, tangent);
+        let bitangent = if V3::dot(bitangent, self.bitangent) < 0.0 { -bitangent } else { bitangent };
+        Some((tangent, bitangent))
+    }
+}
+
+impl BvhHit for HitRecord {
+    fn t(&self) -> f32 {
+        self.t
+    }
+}
+
 pub trait Hitable: Send + Sync {
     fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    /// Tests if the ray hits anything opaque (as decided by {is_opaque}) between {t_min} and {t_max}.
+    /// Implementations may return on the first opaque hit found, rather than searching for the closest hit.
+    /// NOTE: The default implementation only tests the closest hit, which is correct for objects with a single material.
+    fn occluded(&self, ray: Ray, t_min: f32, t_max: f32, is_opaque: &dyn Fn(&HitRecord) -> bool) -> bool {
+        self.hit(ray, t_min, t_max).is_some_and(|hit| is_opaque(&hit))
+    }
     /// Returns the AABB bounding box of this hitable in worldspace coordinates.
     /// The worldspace origin is assumed to be 0,0,0
     fn aabb(&self) -> AABB;
@@ -255,7 +352,14 @@ impl Entity {
         self
     }
 
-    fn calculate_origin_aabb(&self) -> (V3, AABB) {
+    /// Removes every translation and rotation, so the entity can be placed again (e.g. for the next frame of an animation)
+    pub fn clear_transforms(mut self) -> Self {
+        self.translations.clear();
+        self.rotations.clear();
+        self
+    }
+
+    fn calculate_aabb(&self) -> AABB {
         // HACK: rotate the bounding box directly and find the new min/max.
         // NOTE: this may leave an AABB with lots of extra empty space
         let mut aabb = self.hitable.aabb();
@@ -270,16 +374,17 @@ impl Entity {
             }
         }
 
-        let mut origin = V3::ZERO;
         for t in self.translations.iter() {
             for c in corners.iter_mut() {
                 *c = *c + t.offset;
             }
-            origin = origin + t.offset;
         }
 
         aabb = AABB::from_vertices(&corners);
-        (origin, aabb)
+        if !aabb.is_finite() && !aabb.is_infinite() {
+            warn!("Entity {:?} has partially infinite bounds {:?}, which will not be culled by the scene BVH", self.id, aabb);
+        }
+        aabb
     }
 
     fn hit(&self, mut ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
@@ -300,6 +405,8 @@ impl Entity {
         for t in self.rotations.iter().rev() {
             hit.p = hit.p.rotate_about_axis(t.axis, t.theta);
             hit.normal = hit.normal.rotate_about_axis(t.axis, t.theta);
+            hit.tangent = hit.tangent.rotate_about_axis(t.axis, t.theta);
+            hit.bitangent = hit.bitangent.rotate_about_axis(t.axis, t.theta);
         }
         for t in self.translations.iter().rev() {
             hit.p = hit.p + t.offset;
@@ -308,6 +415,18 @@ impl Entity {
         hit.entity_id = self.id;
         Some(hit)
     }
+
+    fn occluded(&self, mut ray: Ray, t_min: f32, t_max: f32, is_opaque: &dyn Fn(&HitRecord) -> bool) -> bool {
+        // Transform the ray into entity frame of reference (see hit)
+        for t in self.translations.iter() {
+            ray.origin = ray.origin - t.offset;
+        }
+        for t in self.rotations.iter() {
+            ray.origin = ray.origin.rotate_about_axis(t.axis, -t.theta);
+            ray.direction = ray.direction.rotate_about_axis(t.axis, -t.theta);
+        }
+        self.hitable.occluded(ray, t_min, t_max, is_opaque)
+    }
 }
 
 crate::types::derive_into_arc!(struct Entity);
@@ -319,14 +438,19 @@ pub struct Scene {
     lights: Vec<Arc<dyn LightSource>>,
     materials: Vec<Arc<dyn Material>>,
     textures: Vec<Arc<dyn Texture>>,
+    media: Media,
     // Constructed from scene entities before raytracing begins (see build_bvh)
     bvh_root: Option<EntityBvhRoot>,
+    // Set before raytracing begins (see build_bvh)
+    has_transparent_materials: bool,
+    has_dispersive_materials: bool,
+    has_media: bool,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct MatId(usize);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct TexId(usize);
 
 pub struct RenderSettings {
@@ -345,7 +469,11 @@ impl Scene {
             lights: vec![],
             materials: vec![],
             textures: vec![],
+            media: Media::default(),
             bvh_root: None,
+            has_transparent_materials: false,
+            has_dispersive_materials: false,
+            has_media: false,
         }
     }
 
@@ -369,17 +497,68 @@ impl Scene {
         self.lights.push(light.into_arc());
     }
 
+    /// Fills the space between the shapes of the scene with a homogeneous {fog} (see Media::set_fog)
+    pub fn set_fog(&mut self, fog: Medium) {
+        self.media.set_fog(fog);
+    }
+
+    /// Adds a bounded {volume} of smoke, cloud or other media to the space between the shapes of the scene
+    pub fn add_volume(&mut self, volume: Volume) {
+        self.media.add_volume(volume);
+    }
+
     fn hit_closest(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let root = self.bvh_root.as_ref().expect("Scene bounding volume hierachy not constructed");
         root.try_hit(ray, t_min, t_max)
     }
 
+    /// Finds the closest hit on the ray, skipping past any surfaces which are cut out at the hit point
+    fn hit_closest_surface(&self, ray: Ray, mut t_min: f32, t_max: f32) -> Option<HitRecord> {
+        loop {
+            let hit = self.hit_closest(ray, t_min, t_max)?;
+            if !self.get_mat(hit.mat_id).is_cut_out(&hit) {
+                return Some(hit);
+            }
+            t_min = hit.t + BIAS;
+        }
+    }
+
+    /// Tests if any opaque object lies on the ray between {t_min} and {t_max}
+    fn occluded(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        let root = self.bvh_root.as_ref().expect("Scene bounding volume hierachy not constructed");
+        root.occluded(ray, t_min, t_max, &|hit| self.get_mat(hit.mat_id).is_opaque())
+    }
+
     pub fn build_bvh(&mut self) {
         let bvh_entities = self.entities.iter()
             .map(|e| EntityBvh(e.clone()))
             .collect();
 
         self.bvh_root = Some(EntityBvhRoot::new(bvh_entities));
+        self.has_transparent_materials = self.materials.iter().any(|m| !m.is_opaque());
+        self.has_dispersive_materials = self.materials.iter().any(|m| m.is_dispersive());
+        self.has_media = !self.media.is_empty() || self.materials.iter().any(|m| m.has_interior_medium());
+    }
+
+    /// Replaces each entity with the result of {update}, given the index of the entity in the order it was added.
+    /// Use refit_bvh to update the BVH after entities have moved.
+    pub fn update_entities(&mut self, mut update: impl FnMut(usize, Entity) -> Entity) {
+        for (index, entity) in self.entities.iter_mut().enumerate() {
+            *entity = update(index, entity.as_ref().clone()).into_arc();
+        }
+    }
+
+    /// Updates the BVH after entities have moved (see update_entities), keeping the structure of the existing tree.
+    /// Much faster than build_bvh when only entity transforms have changed between frames.
+    pub fn refit_bvh(&mut self) {
+        let bvh_entities = self.entities.iter()
+            .map(|e| EntityBvh(e.clone()))
+            .collect();
+
+        match self.bvh_root {
+            Some(ref mut root) => root.refit(bvh_entities),
+            None => self.build_bvh(),
+        }
     }
 
     fn get_mat(&self, mat_id: MatId) -> &dyn Material {
@@ -396,23 +575,62 @@ impl Scene {
 //
 
 pub struct EntityBvhRoot {
-    bvh: Bvh,
+    // NOTE: Only entities with finite bounds are placed in the BVH (if there are any).
+    // Unbounded entities (such as planes) would make the bounds of every node containing them infinite,
+    // so they are kept in a separate list and always tested.
+    bvh: Option<Bvh>,
     entities: Vec<EntityBvh>,
+    unbounded: Vec<EntityBvh>,
 }
 
 impl EntityBvhRoot {
     fn new(entities: Vec<EntityBvh>) -> Self {
+        let (entities, unbounded) = Self::partition_unbounded(entities);
         Self {
-            bvh: Bvh::from(&entities),
+            bvh: Self::build(&entities),
             entities,
+            unbounded,
+        }
+    }
+
+    /// Recomputes the BVH bounds of entities which have moved.
+    /// {entities} must be the entities the root was created from, in the same order.
+    /// The BVH is rebuilt if entities have gained or lost finite bounds, or refitting degrades it too far (see Bvh::refit).
+    fn refit(&mut self, entities: Vec<EntityBvh>) {
+        let (entities, unbounded) = Self::partition_unbounded(entities);
+        match self.bvh {
+            // NOTE: Refitted bounds are always recomputed from the entities given,
+            // so the tree stays correct even if entities swapped between the BVH and the unbounded list.
+            Some(ref mut bvh) if entities.len() == self.entities.len() => {
+                bvh.refit(&entities);
+            },
+            _ => self.bvh = Self::build(&entities),
         }
+        self.entities = entities;
+        self.unbounded = unbounded;
+    }
+
+    fn partition_unbounded(entities: Vec<EntityBvh>) -> (Vec<EntityBvh>, Vec<EntityBvh>) {
+        entities.into_iter().partition(|e| e.0.calculate_aabb().is_finite())
+    }
+
+    fn build(entities: &[EntityBvh]) -> Option<Bvh> {
+        if entities.is_empty() { None } else { Some(Bvh::from(entities)) }
     }
 
     pub fn try_hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        self.bvh
-            .hit_candidates(ray, t_min, t_max)
-            .filter_map(|candidate| self.entities[candidate.object_index].0.hit(ray, t_min, t_max))
-            .reduce(|closest, next| if next.t < closest.t { next } else { closest })
+        // Test unbounded entities first, so that any hit can shorten the search of the BVH
+        let mut closest: Option<HitRecord> = None;
+        for entity in self.unbounded.iter() {
+            let limit = closest.as_ref().map(|h| h.t).unwrap_or(t_max);
+            if let Some(hit) = entity.0.hit(ray, t_min, limit) {
+                closest = Some(hit);
+            }
+        }
+        let limit = closest.as_ref().map(|h| h.t).unwrap_or(t_max);
+        self.bvh.as_ref()
+            .and_then(|bvh| bvh.closest_hit(ray, t_min, limit, |i, t_max| self.entities[i].0.hit(ray, t_min, t_max)))
+            .or(closest)
     }
 }
 
@@ -421,8 +639,17 @@ impl Hitable for EntityBvhRoot {
         self.try_hit(ray, t_min, t_max)
     }
 
+    fn occluded(&self, ray: Ray, t_min: f32, t_max: f32, is_opaque: &dyn Fn(&HitRecord) -> bool) -> bool {
+        self.unbounded.iter().any(|e| e.0.occluded(ray, t_min, t_max, is_opaque))
+            || self.bvh.as_ref().is_some_and(|bvh| bvh.any_hit(ray, t_min, t_max, |i| self.entities[i].0.occluded(ray, t_min, t_max, is_opaque)))
+    }
+
     fn aabb(&self) -> AABB {
-        self.bvh.aabb()
+        match self.bvh {
+            _ if !self.unbounded.is_empty() => AABB::infinite(),
+            Some(ref bvh) => bvh.aabb(),
+            None => AABB::default(),
+        }
     }
 }
 
@@ -433,8 +660,8 @@ struct EntityBvh(Arc<Entity>);
 impl BvhObject for EntityBvh {
     fn calculate_bounds(&self) -> BvhBounds {
         // HACK: Use the middle of the AABB as the centroid
-        let (origin, aabb) = self.0.calculate_origin_aabb();
-        let centroid = if aabb.is_infinite() { origin } else { aabb.min * 0.5 + aabb.max * 0.5 };
+        let aabb = self.0.calculate_aabb();
+        let centroid = aabb.min * 0.5 + aabb.max * 0.5;
         BvhBounds { centroid, aabb }
     }
 }
@@ -520,7 +747,7 @@ fn color_sky(ray: Ray, scene: &Scene) -> V3 {
 
 // Lights and shadows
 
-/// Casts a ray *back* towards a lamp, testing for possibly shadowing objects
+/// Casts a ray *back* towards a lamp, testing for possibly shadowing objects and media
 fn cast_light_ray_to_lamp(hit_point: V3, light_record: &LightRecord, scene: &Scene, rng: &mut dyn RngCore) -> V3 {
 
     // Test to see if there is any shape blocking light from this lamp by casting a ray from the shadow back to the light source
@@ -528,30 +755,47 @@ fn cast_light_ray_to_lamp(hit_point: V3, light_record: &LightRecord, scene: &Sce
     // Ignore any hits from behind this light source
     let t_max = light_record.t;
 
+    // Hit opaque object (in shadow)
+    if scene.occluded(light_ray, BIAS, t_max) {
+        return V3::ZERO;
+    }
+
     let mut light_color = light_record.color * light_record.intensity;
-    let mut closest_so_far = BIAS;
+    if !scene.media.is_empty() {
+        // Attenuate light passing through fog and volumes
+        light_color = light_color * scene.media.transmittance(light_ray, BIAS, t_max, rng);
+    }
+    if !scene.has_transparent_materials {
+        return light_color;
+    }
 
-    // Perform hit tests until we escape
+    // Perform hit tests against any transparent objects until we escape
+    let mut closest_so_far = BIAS;
     while let Some(shadow_hit) = scene.hit_closest(light_ray, closest_so_far, t_max) {
-
-        let shadow_mat = scene.get_mat(shadow_hit.mat_id).scatter(light_ray, &shadow_hit, rng);
-        if let Some(shadow_refraction) = shadow_mat.refraction {
-            // Hit transparent object
-            // Hack: simulate colored shadows by taking the albedo of transparent materials.
-            let albedo = scene.get_tex(shadow_hit.tex_id).value(&shadow_hit);
-            light_color = light_color * (albedo * shadow_refraction.intensity);
+        let shadow_mat = scene.get_mat(shadow_hit.mat_id);
+        if shadow_mat.is_cut_out(&shadow_hit) {
             closest_so_far = shadow_hit.t + BIAS;
             continue;
         }
-
-        // Hit opaque object (in shadow)
-        return V3::ZERO;
+        if shadow_mat.is_opaque() {
+            return V3::ZERO;
+        }
+        // Hack: simulate colored shadows by taking the albedo of transparent materials.
+        let albedo = scene.get_tex(shadow_hit.tex_id).value(&shadow_hit);
+        light_color = light_color * (albedo * shadow_mat.transmission(light_ray, &shadow_hit));
+        closest_so_far = shadow_hit.t + BIAS;
     }
 
     // Escaped.
     return light_color;
 }
 
+/// The most times a ray may be scattered inside a medium before it is given up on
+const MAX_MEDIUM_EVENTS: u32 = 1024;
+
+/// The brightest a single sample through a medium may be, before the samples of a pixel are averaged
+const MAX_MEDIUM_SAMPLE: f32 = 16.0;
+
 /// Determines the color which the given ray resolves to.
 fn cast_ray(ray: Ray, scene: &Scene, rng: &mut dyn RngCore, max_reflections: u32) -> V3 {
 
@@ -563,14 +807,55 @@ fn cast_ray(ray: Ray, scene: &Scene, rng: &mut dyn RngCore, max_reflections: u32
             return color_sky(ray, scene);
         }
 
+        let hit = scene.hit_closest_surface(ray, BIAS, std::f32::MAX);
+
+        // Rays may be absorbed or scattered by the media between the shapes of the scene before they reach a surface
+        let (weight, emission) = if scene.media.is_empty() {
+            (V3::ONE, V3::ZERO)
+        } else {
+            let t_max = hit.as_ref().map_or(f32::MAX, |hit| hit.t);
+            match scene.media.track(ray, BIAS, t_max, rng) {
+                (VolumeEvent::Pass { weight }, emission) => (weight, emission),
+                (VolumeEvent::Absorb, emission) => return emission,
+                (VolumeEvent::Scatter { t, weight, g }, emission) => {
+                    return emission + cast_ray_scattered_by_media(ray, t, g, scene, rng, recurse_limit) * weight;
+                },
+            }
+        };
+
         // Hit anything in the scene?
-        match scene.hit_closest(ray, BIAS, std::f32::MAX) {
+        let color = match hit {
             // Hit the sky instead
             None => color_sky(ray, scene),
             // Hit an object
-            Some(hit_record) => {
+            Some(mut hit_record) => {
 
-                let mat_record = scene.get_mat(hit_record.mat_id).scatter(ray, &hit_record, rng);
+                // Shade the hit with the normal given by the material, keeping the surface normal to offset new rays
+                let material = scene.get_mat(hit_record.mat_id);
+                let surface_normal = hit_record.normal;
+                hit_record.normal = material.shading_normal(&hit_record);
+
+                let mut mat_record = material.scatter(ray, &hit_record, rng);
+
+                // Scattered rays carry the same light as the incoming ray, unless the material split it into a single wavelength
+                let inherit_wavelength = |scattered: &mut Ray| if let Wavelength::All(_) = scattered.wavelength {
+                    scattered.wavelength = ray.wavelength;
+                };
+                if let Some(ref mut reflect) = mat_record.reflection {
+                    inherit_wavelength(&mut reflect.ray);
+                }
+                if let Some(ref mut refract) = mat_record.refraction {
+                    inherit_wavelength(&mut refract.ray);
+                }
+
+                // Rays scattered into the interior of a material with a medium walk through the medium until they leave it
+                let medium = material.interior_medium(&hit_record);
+                let trace = |ray: Ray, recurse_limit: u32, rng: &mut dyn RngCore| match medium {
+                    Some(medium) if V3::dot(ray.direction, surface_normal) < 0.0 => {
+                        cast_ray_through_medium(ray, medium, scene, rng, recurse_limit)
+                    },
+                    _ => cast_ray_recursive(ray, scene, rng, recurse_limit),
+                };
 
                 // We may need to recurse more than once, depending on the material we hit.
                 // In this case, split the recursion limit to avoid doubling our work.
@@ -591,7 +876,7 @@ fn cast_ray(ray: Ray, scene: &Scene, rng: &mut dyn RngCore, max_reflections: u32
                 // Determine color from material reflection.
                 let (color_from_reflection, reflection_intensity) = match mat_record.reflection {
                     Some(ref reflect) if reflect.intensity > 0.0 => {
-                        (cast_ray_recursive(reflect.ray, scene, rng, reflect_limit), reflect.intensity)
+                        (trace(reflect.ray, reflect_limit, rng) * reflect.color, reflect.intensity)
                     },
                     _ => Default::default(),
                 };
@@ -599,46 +884,116 @@ fn cast_ray(ray: Ray, scene: &Scene, rng: &mut dyn RngCore, max_reflections: u32
                 // Determine color from material refraction.
                 let (color_from_refraction, refraction_intensity) = match mat_record.refraction {
                     Some(ref refract) if refract.intensity > 0.0 => {
-                        (cast_ray_recursive(refract.ray, scene, rng, refract_limit), refract.intensity)
+                        (trace(refract.ray, refract_limit, rng) * refract.color, refract.intensity)
                     },
                     _ => Default::default(),
                 };
 
                 // NOTE: Move hit point slightly above p along surface normal to avoid "shadow acne"
-                let hit_point = hit_record.p + (hit_record.normal * BIAS);
+                let hit_point = hit_record.p + (surface_normal * BIAS);
+
+                // HACK: Scale the light intensity further for highly reflective or refractive objects
+                // This makes sure that color from lights doesn't overwhelm reflective or refractive materials
+                let lights_intensity = f32::max(0.0, 1.0 - (reflection_intensity + refraction_intensity));
 
                 // Determine color from lights in the scene.
                 let mut color_from_lights = V3::ZERO;
                 for light in scene.lights.iter() {
                     if let Some(light_record) = light.get_direction_and_intensity(hit_point) {
-                        let light_color =
-                            cast_light_ray_to_lamp(hit_point, &light_record, scene, rng) *
+                        let response = material.light_response(ray, &hit_record, -light_record.direction)
                             // Adjust intensity as reflection normal changes
-                            f32::max(0.0, V3::dot(hit_record.normal, -light_record.direction));
-
-                        color_from_lights = color_from_lights + light_color;
+                            .unwrap_or_else(|| V3::ONE * f32::max(0.0, V3::dot(hit_record.normal, -light_record.direction)) * lights_intensity);
+                        if response == V3::ZERO {
+                            continue;
+                        }
+                        color_from_lights = color_from_lights + cast_light_ray_to_lamp(hit_point, &light_record, scene, rng) * response;
                     }
                 }
 
-                // HACK: Scale the light intensity further for highly reflective or refractive objects
-                // This makes sure that color from lights doesn't overwhelm reflective or refractive materials
-                let lights_intensity = f32::max(0.0, 1.0 - (reflection_intensity + refraction_intensity));
                 let albedo = scene.get_tex(hit_record.tex_id).value(&hit_record);
 
                 ((color_from_reflection * reflection_intensity) +
                  (color_from_refraction * refraction_intensity) +
-                 (color_from_lights * lights_intensity)) * albedo
+                 color_from_lights) * albedo
+            }
+        };
+        emission + color * weight
+    }
+
+    /// Shades the point {t} along {ray} where the ray is scattered by a particle of the media of the scene, with the asymmetry {g}.
+    /// The point is lit by the lamps of the scene following the phase function, and by the light scattered towards it.
+    fn cast_ray_scattered_by_media(ray: Ray, t: f32, g: f32, scene: &Scene, rng: &mut dyn RngCore, recurse_limit: u32) -> V3 {
+        let p = ray.point_at_parameter(t);
+        let direction = ray.direction.unit();
+
+        let mut color_from_lights = V3::ZERO;
+        for light in scene.lights.iter() {
+            if let Some(light_record) = light.get_direction_and_intensity(p) {
+                // NOTE: Lamp intensities are scaled so that a white Lambertian surface (reflecting 1/pi of the light per unit solid angle)
+                // reflects all the light falling on it, so the phase function is scaled by the same amount (see materials::LIGHT_SCALE)
+                let response = henyey_greenstein(V3::dot(direction, -light_record.direction), g) * PI;
+                color_from_lights = color_from_lights + cast_light_ray_to_lamp(p, &light_record, scene, rng) * response;
+            }
+        }
+
+        let scattered = Ray::new(p, sample_henyey_greenstein(direction, g, rng)).with_wavelength(ray.wavelength);
+        color_from_lights + cast_ray_recursive(scattered, scene, rng, recurse_limit - 1)
+    }
+
+    /// Follows a ray through the interior of a closed shape filled with {medium}, on a random walk from particle to particle,
+    /// until it reaches the surface again. There it is shaded from the inside, leaving the shape or reflecting back in.
+    fn cast_ray_through_medium(mut ray: Ray, medium: &Medium, scene: &Scene, rng: &mut dyn RngCore, recurse_limit: u32) -> V3 {
+        let mut weight = V3::ONE;
+        for event in 0..MAX_MEDIUM_EVENTS {
+            ray = Ray::new(ray.origin, ray.direction.unit()).with_wavelength(ray.wavelength);
+            let Some(hit_record) = scene.hit_closest_surface(ray, BIAS, f32::MAX) else {
+                // NOTE: Rays escape from shapes which aren't closed
+                return color_sky(ray, scene) * weight;
+            };
+            match medium.sample_distance(hit_record.t, rng) {
+                MediumEvent::Pass { weight: pass_weight } => {
+                    return cast_ray_recursive(ray, scene, rng, recurse_limit) * weight * pass_weight;
+                },
+                MediumEvent::Scatter { t, weight: scatter_weight } => {
+                    weight = weight * scatter_weight;
+                    let direction = medium.sample_scattering(ray.direction, rng);
+                    ray = Ray::new(ray.point_at_parameter(t), direction).with_wavelength(ray.wavelength);
+                },
+            }
+            // Russian roulette: end walks carrying little light at random, making up for them with the walks which carry on
+            if event >= 8 {
+                let survive = weight.0.max(weight.1).max(weight.2).min(1.0);
+                if rng.random::<f32>() >= survive {
+                    return V3::ZERO;
+                }
+                weight = weight / survive;
             }
         }
+        V3::ZERO
     }
 
-    cast_ray_recursive(ray, scene, rng, max_reflections).clamp()
+    let color = cast_ray_recursive(ray, scene, rng, max_reflections);
+    if scene.has_dispersive_materials || scene.has_media {
+        // NOTE: Single wavelength samples are weighted to be much brighter than RGB samples (see spectrum::wavelength_weight),
+        // and walks through media carry more of the channels which travel furthest (see medium::Medium::sample_distance),
+        // so only clamp them to that range here, and clamp the average of the samples instead
+        let mut limit = 1.0;
+        if scene.has_dispersive_materials {
+            limit *= max_wavelength_weight();
+        }
+        if scene.has_media {
+            limit *= MAX_MEDIUM_SAMPLE;
+        }
+        V3(color.0.clamp(-limit, limit), color.1.clamp(-limit, limit), color.2.clamp(-limit, limit))
+    } else {
+        color.clamp()
+    }
 }
 
 pub fn cast_rays_into_scene(scene: &Scene, settings: &RenderSettings, [x, y]: [usize; 2], rng: &mut dyn RngCore) -> V3 {
     let mut col = V3(0.0, 0.0, 0.0);
     // Implement anti-aliasing by taking the average color of ofsett rays cast around these x, y coordinates.
-    for _ in 0..settings.samples_per_pixel {
+    for sample in 0..settings.samples_per_pixel {
         // NOTE:
         // View coordinates are from upper left corner, but World coordinates are from lower left corner.
         // Need to convert coordinate systems with (height - y)
@@ -652,10 +1007,82 @@ pub fn cast_rays_into_scene(scene: &Scene, settings: &RenderSettings, [x, y]: [u
             V2::ZERO
         };
         // Cast a ray, and determine the color
-        let ray = scene.camera.get_ray(u, v, lens_deflection);
+        let mut ray = scene.camera.get_ray(u, v, lens_deflection);
+        if scene.has_dispersive_materials {
+            // Stratify the wavelengths picked by dispersive materials over the samples, so colors converge with fewer samples
+            let wavelength_sample = (sample as f32 + rng.random::<f32>()) / settings.samples_per_pixel as f32;
+            ray = ray.with_wavelength(Wavelength::All(wavelength_sample));
+        }
         col = col + cast_ray(ray, scene, rng, settings.max_reflections);
     }
     // Find the average
     col = col / settings.samples_per_pixel as f32;
-    col // RGB color in the range 0.0 - 1.0
+    col.clamp() // RGB color in the range 0.0 - 1.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::shapes::{ Plane, Sphere };
+    use crate::types::{ Ray, V3 };
+    use super::{ Camera, Entity, MatId, Scene, SceneSky, TexId };
+
+    fn sphere_origin(index: usize, frame: usize) -> V3 {
+        // Spheres orbit the y axis, drifting further apart each frame
+        let angle = index as f32 * 0.7 + frame as f32 * 0.5;
+        let radius = 2.0 + index as f32 + frame as f32 * 3.0;
+        V3(angle.cos() * radius, 1.0 + index as f32 * 0.5, angle.sin() * radius)
+    }
+
+    fn place(entity: Entity, index: usize, frame: usize) -> Entity {
+        entity.rotate(V3::POS_Y, frame as f32).translate(sphere_origin(index, frame))
+    }
+
+    fn scene_at_frame(frame: usize) -> Scene {
+        let camera = Camera::new(V3(0.0, 5.0, 20.0), V3::ZERO, 45.0, 1.0, 0.0, 20.0);
+        let mut scene = Scene::new(camera, SceneSky::Black);
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, MatId::default(), TexId::default())).id(0));
+        for index in 1..16 {
+            let sphere = Sphere::new(1.0, MatId::default(), TexId::default());
+            scene.add_entity(place(Entity::new(sphere).id(index as u32), index, frame));
+        }
+        scene.build_bvh();
+        scene
+    }
+
+    #[test]
+    fn refit_bvh_hits_match_rebuilt_bvh() {
+        let mut refitted = scene_at_frame(0);
+        for frame in 1..4 {
+            refitted.update_entities(|index, entity| match index {
+                0 => entity,
+                _ => place(entity.clear_transforms(), index, frame),
+            });
+            refitted.refit_bvh();
+            let rebuilt = scene_at_frame(frame);
+
+            // Fire rays down onto the floor and across it, on a grid covering every sphere
+            let mut hit_count = 0;
+            for x in -40..=40 {
+                for z in -40..=40 {
+                    let (x, z) = (x as f32, z as f32);
+                    let rays = [
+                        Ray::new(V3(x, 20.0, z), V3(0.01, -1.0, 0.02)),
+                        Ray::new(V3(x, 1.5, z), V3(1.0, 0.0, 0.3)),
+                    ];
+                    for ray in rays {
+                        let expected = rebuilt.hit_closest(ray, 0.0, f32::MAX);
+                        let actual = refitted.hit_closest(ray, 0.0, f32::MAX);
+                        assert_eq!(actual.as_ref().map(|hit| hit.entity_id), expected.as_ref().map(|hit| hit.entity_id));
+                        if let (Some(actual), Some(expected)) = (actual, expected) {
+                            assert!((actual.t - expected.t).abs() < 0.001);
+                            if actual.entity_id != Some(0) {
+                                hit_count += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            assert!(hit_count > 0, "expected some rays to hit spheres in frame {frame}");
+        }
+    }
 }
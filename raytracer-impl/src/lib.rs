@@ -10,3 +10,6 @@ pub mod implementation;
 pub mod viewport;
 pub mod bvh;
 pub mod util;
+pub mod noise;
+pub mod spectrum;
+pub mod medium;
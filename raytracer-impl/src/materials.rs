@@ -1,14 +1,16 @@
 #![allow(unused)]
 
-use std::f32::consts::FRAC_PI_2;
+use std::f32::consts::{ FRAC_PI_2, PI };
 use std::mem::{ swap };
 use std::sync::Arc;
 
-use crate::types::{ V3, Ray, IntoArc };
+use crate::types::{ V2, V3, Ray, IntoArc };
 use crate::implementation::{ Material, MatRecord, Reflect, Refract, HitRecord, Texture };
 use crate::implementation::{ random_normal_reflection_angle };
+use crate::spectrum::{ sample_wavelength, wavelength_weight, Dispersion, Wavelength, SODIUM_D_WAVELENGTH };
+use crate::medium::Medium;
 
-use rand::{ RngCore };
+use rand::{ Rng, RngCore };
 
 macro_rules! assert_in_range {
     ($v:ident) => {
@@ -46,7 +48,7 @@ impl Material for MatLambertian {
         let direction = random_normal_reflection_angle(hit_record.normal, rng);
         let ray = Ray::new(hit_record.p.clone(), direction);
         MatRecord {
-            reflection: Some(Reflect { ray, intensity: self.reflectivity }),
+            reflection: Some(Reflect { ray, intensity: self.reflectivity, color: V3::ONE }),
             refraction: None,
         }
     }
@@ -99,7 +101,7 @@ impl Material for MatSpecular {
         let reflection =
             if V3::dot(scattered, hit_record.normal) > 0.0 {
                 let ray = Ray::new(hit_record.p, scattered);
-                Some(Reflect { ray: ray, intensity: self.reflectiveness })
+                Some(Reflect { ray: ray, intensity: self.reflectiveness, color: V3::ONE })
             } else {
                 None
             };
@@ -111,11 +113,20 @@ impl Material for MatSpecular {
     }
 }
 
+/// Glass, water and other transparent materials, which both reflect and refract light.
+/// Rough surfaces (frosted glass) use the same GGX microfacet model as MatConductor.
 #[derive(Clone)]
 pub struct MatDielectric {
     reflectivity: f32,
     opacity: f32,
     ref_index: f32,
+    roughness: f32,
+    thin_walled: bool,
+    // Beer-Lambert absorption coefficient of the interior, per unit distance
+    absorption: V3,
+    dispersion: Option<Dispersion>,
+    // Scatters light travelling through the interior
+    medium: Option<Medium>,
 }
 
 impl Default for MatDielectric {
@@ -124,6 +135,11 @@ impl Default for MatDielectric {
             reflectivity: 1.0,
             opacity: 0.0,
             ref_index: 1.5,
+            roughness: 0.0,
+            thin_walled: false,
+            absorption: V3::ZERO,
+            dispersion: None,
+            medium: None,
         }
     }
 }
@@ -145,60 +161,1612 @@ impl MatDielectric {
         self.ref_index = ref_index;
         self
     }
+
+    /// Sets the perceived roughness of the surface, from 0.0 (smooth glass) to 1.0 (heavily frosted)
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        assert_in_range!(roughness);
+        self.roughness = roughness;
+        self
+    }
+
+    /// Treats the surface as an infinitely thin sheet of glass (e.g. a window modelled as a single plane),
+    /// which reflects from both of its sides and lets light through without bending it.
+    pub fn with_thin_walled(mut self, thin_walled: bool) -> Self {
+        self.thin_walled = thin_walled;
+        self
+    }
+
+    /// Tints light travelling through the interior, so that after {distance} only {color} remains (Beer-Lambert law).
+    /// Thick glass becomes more strongly colored than thin glass. Use a white texture so the tint comes only from absorption.
+    /// NOTE: Requires closed shapes with outward facing normals, and has no effect on thin walled surfaces.
+    pub fn with_absorption(mut self, color: V3, distance: f32) -> Self {
+        assert!(distance > 0.0, "absorption distance must be greater than 0.0");
+        let coefficient = |c: f32| -c.clamp(1.0e-6, 1.0).ln() / distance;
+        self.absorption = V3(coefficient(color.0), coefficient(color.1), coefficient(color.2));
+        self
+    }
+
+    /// Varies the refractive index with wavelength, splitting white light into its colors (e.g. a prism).
+    /// Replaces the refractive index with the index of the {dispersion} at the sodium D line.
+    /// NOTE: Each sample follows a single wavelength through the material, so more samples per pixel are needed for smooth colors.
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+        self.ref_index = dispersion.ref_index(SODIUM_D_WAVELENGTH);
+        self.dispersion = Some(dispersion);
+        self
+    }
+
+    /// Fills the interior with a {medium} which scatters the light travelling through it, e.g. murky water or jade.
+    /// NOTE: Requires closed shapes with outward facing normals, and has no effect on thin walled surfaces.
+    /// The medium absorbs light by itself, so use it instead of with_absorption.
+    pub fn with_medium(mut self, medium: Medium) -> Self {
+        self.medium = Some(medium);
+        self
+    }
+
+    fn alpha(&self) -> f32 {
+        self.roughness * self.roughness
+    }
+
+    /// The refractive index for the light carried by {ray}, along with the light carried by the scattered rays,
+    /// and their weight when the material picks a single wavelength from white light
+    fn ref_index_for(&self, ray: Ray) -> (f32, Wavelength, V3) {
+        match (self.dispersion, ray.wavelength) {
+            // NOTE: Thin walls don't bend light, so don't split it either
+            (Some(_), wavelength) if self.thin_walled => (self.ref_index, wavelength, V3::ONE),
+            (Some(dispersion), Wavelength::All(u)) => {
+                let wavelength = sample_wavelength(u);
+                (dispersion.ref_index(wavelength), Wavelength::Single(wavelength), wavelength_weight(wavelength))
+            },
+            (Some(dispersion), Wavelength::Single(wavelength)) => {
+                (dispersion.ref_index(wavelength), Wavelength::Single(wavelength), V3::ONE)
+            },
+            (None, wavelength) => (self.ref_index, wavelength, V3::ONE),
+        }
+    }
 }
 
-fn refract (v: V3, n: V3, ni_over_nt: f32) -> V3 {
-    let uv = v.unit();
-    let dt = V3::dot(uv, n);
-    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
-    if discriminant <= 0.0 {
-        V3::ZERO
-    } else {
-        (uv - (n * dt)) * ni_over_nt - (n * discriminant.sqrt())
+/// The Fresnel reflectance of a dielectric, for light arriving at {cos_theta} to the surface normal,
+/// where {eta} is the ratio of the refractive index on the far side of the surface to the near side
+fn fresnel_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_theta * cos_theta).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
     }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_theta - eta * cos_t) / (cos_theta + eta * cos_t);
+    let rp = (eta * cos_theta - cos_t) / (eta * cos_theta + cos_t);
+    0.5 * (rs * rs + rp * rp)
 }
 
-fn schlick_reflect_prob (cosine: f32, ref_idx: f32) -> f32 {
-    let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-    let r0 = r0 * r0;
-    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
+/// Refracts {wo} (pointing away from the surface) through the microfacet {h}, with {eta} as in fresnel_dielectric
+fn refract(wo: V3, h: V3, eta: f32) -> Option<V3> {
+    let cos_i = V3::dot(wo, h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + h * (cos_i / eta - cos_t))
+}
+
+impl MatDielectric {
+    /// Finds the basis around the surface normal facing the ray, the ratio of refractive indices across the surface
+    /// (given the material's {ref_index}), and the color remaining after absorption along the ray when it is leaving the interior
+    fn surface(&self, ray: Ray, hit_record: &HitRecord, ref_index: f32) -> ((V3, V3, V3), f32, V3) {
+        let inside = V3::dot(ray.direction, hit_record.normal) > 0.0;
+        let n = if inside { -hit_record.normal } else { hit_record.normal };
+        let (t, b) = orthonormal_basis(n);
+        match inside && !self.thin_walled {
+            true => {
+                let distance = hit_record.t * ray.direction.length();
+                let absorption = self.absorption * -distance;
+                ((t, b, n), 1.0 / ref_index, V3(absorption.0.exp(), absorption.1.exp(), absorption.2.exp()))
+            },
+            false => ((t, b, n), ref_index, V3::ONE),
+        }
+    }
+
+    /// The reflectance of the surface at {cos_theta}.
+    /// Light bounces back and forth inside a thin wall, reflecting from both of its sides.
+    fn reflectance(&self, cos_theta: f32, eta: f32) -> f32 {
+        let r = fresnel_dielectric(cos_theta, eta);
+        if self.thin_walled { 2.0 * r / (1.0 + r) } else { r }
+    }
 }
 
 impl Material for MatDielectric {
     fn scatter (&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
-        let dot = V3::dot(ray.direction, hit_record.normal);
-        let (outward_normal, ni_over_nt, cosine) =
-            if dot > 0.0 {
-                (-hit_record.normal, self.ref_index, self.ref_index * dot / ray.direction.length())
-            } else {
-                (hit_record.normal, 1.0 / self.ref_index, -dot / ray.direction.length())
-            };
+        let (ref_index, wavelength, weight) = self.ref_index_for(ray);
+        let (basis, eta, absorbed) = self.surface(ray, hit_record, ref_index);
+        let color = absorbed * weight;
+        let wo = to_local(-ray.direction.unit(), basis);
+        let alpha = self.alpha();
 
-        let kr = schlick_reflect_prob(cosine, self.ref_index);
-
-        // compute refraction if it is not a case of total internal reflection
-        let refraction = match kr {
-            kr if kr >= 1.0 => None, // Total internal reflection
-            _ => {
-                let refraction_direction = refract(ray.direction, outward_normal, ni_over_nt).unit();
-                let refraction = Refract {
-                    ray: Ray::new(hit_record.p.clone(), refraction_direction),
-                    intensity: (1.0 - kr) * (1.0 - self.opacity)
-                };
-                Some(refraction)
-            }
-        };
+        // Both reflect and refract through the same sampled microfacet, weighted as in MatConductor
+        let h = ggx_sample_visible_normal(wo, alpha, rng);
+        let kr = self.reflectance(V3::dot(wo, h), eta);
+        let lambda_o = ggx_lambda(wo, alpha);
+        let masking = |wi: V3| (1.0 + lambda_o) / (1.0 + lambda_o + ggx_lambda(wi, alpha));
 
-        let reflection_direction = reflect(ray.direction, hit_record.normal).unit();
-        let reflection = Reflect {
-            ray: Ray::new(hit_record.p.clone(), reflection_direction),
-            intensity: kr * self.reflectivity
+        let reflected = h * (2.0 * V3::dot(wo, h)) - wo;
+        let reflection = (wo.2 > 0.0 && reflected.2 > 0.0).then(|| Reflect {
+            ray: Ray::new(hit_record.p, from_local(reflected, basis)).with_wavelength(wavelength),
+            intensity: kr * self.reflectivity * masking(reflected),
+            color,
+        });
+
+        // Thin walls let light straight through, or mirror the reflection through the wall when rough
+        let refracted = match self.thin_walled {
+            true => Some(V3(reflected.0, reflected.1, -reflected.2)),
+            false => refract(wo, h, eta),
         };
-        let reflection = Some(reflection);
+        let refraction = refracted
+            .filter(|refracted| kr < 1.0 && wo.2 > 0.0 && refracted.2 < 0.0)
+            .map(|refracted| Refract {
+                ray: Ray::new(hit_record.p, from_local(refracted, basis)).with_wavelength(wavelength),
+                intensity: (1.0 - kr) * (1.0 - self.opacity) * masking(refracted),
+                color,
+            });
 
         MatRecord {
             refraction: refraction,
             reflection: reflection,
         }
     }
+
+    fn is_opaque(&self) -> bool {
+        self.opacity >= 1.0
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        let (basis, eta, _) = self.surface(ray, hit_record, self.ref_index);
+        let cos_theta = V3::dot(-ray.direction.unit(), basis.2);
+        (1.0 - self.reflectance(cos_theta, eta)) * (1.0 - self.opacity)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some() && !self.thin_walled
+    }
+
+    fn interior_medium(&self, _hit_record: &HitRecord) -> Option<&Medium> {
+        self.medium.as_ref().filter(|_| !self.thin_walled)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.medium.is_some() && !self.thin_walled
+    }
+}
+
+/// Translucent materials such as wax, skin, marble or milk, where light scatters beneath the surface before leaving it,
+/// softening the lighting and bleeding color into the shadows.
+/// Light is refracted into the surface through a rough dielectric boundary, and takes a random walk through the medium
+/// inside (see medium::Medium) until it leaves the surface again somewhere else, picking up the light of lamps there.
+/// NOTE: Requires closed shapes with outward facing normals. The material provides its own color, use a white texture.
+#[derive(Clone)]
+pub struct MatSubsurface {
+    medium: Medium,
+    ior: f32,
+    roughness: f32,
+}
+
+impl MatSubsurface {
+    /// A material which appears {albedo} colored, where light travels {mean_free_path} (for each RGB channel)
+    /// between particles beneath the surface (see Medium::from_albedo)
+    pub fn new(albedo: V3, mean_free_path: V3) -> Self {
+        Self::with_medium(Medium::from_albedo(albedo, mean_free_path))
+    }
+
+    /// A material filled with {medium}
+    pub fn with_medium(medium: Medium) -> Self {
+        Self { medium, ior: 1.4, roughness: 0.3 }
+    }
+
+    /// Sets the asymmetry of scattering beneath the surface (see Medium::with_anisotropy)
+    pub fn with_anisotropy(mut self, g: f32) -> Self {
+        self.medium = self.medium.with_anisotropy(g);
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    /// Sets the perceived roughness of the boundary, from 0.0 (polished) to 1.0 (matte)
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        assert_in_range!(roughness);
+        self.roughness = roughness;
+        self
+    }
+
+    /// The surface seen from outside, which reflects some light and refracts the rest into the medium
+    fn boundary(&self) -> MatDielectric {
+        MatDielectric::default().with_ref_index(self.ior).with_roughness(self.roughness)
+    }
+
+    /// The chance of light leaving the medium through the surface being reflected back inside,
+    /// for a ray arriving from inside along {ray}
+    fn internal_reflectance(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        let cos_theta = V3::dot(-ray.direction.unit(), facing_basis(ray, hit_record).2);
+        fresnel_dielectric(cos_theta, 1.0 / self.ior)
+    }
+}
+
+impl Material for MatSubsurface {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        let inside = V3::dot(ray.direction, hit_record.normal) > 0.0;
+        if !inside {
+            return self.boundary().scatter(ray, hit_record, rng);
+        }
+
+        // Leaving the medium, light is either reflected back inside or spreads out from beneath the surface.
+        // NOTE: Light leaves in a cosine weighted direction, as from a diffuse surface,
+        // which matches the response to lamps outside (see light_response)
+        let n = facing_basis(ray, hit_record).2;
+        if rng.random::<f32>() < self.internal_reflectance(ray, hit_record) {
+            let ray = Ray::new(hit_record.p, reflect(ray.direction, n));
+            return MatRecord { reflection: Some(Reflect { ray, intensity: 1.0, color: V3::ONE }), refraction: None };
+        }
+        let ray = Ray::new(hit_record.p, random_normal_reflection_angle(-n, rng));
+        MatRecord { reflection: None, refraction: Some(Refract { ray, intensity: 1.0, color: V3::ONE }) }
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        let basis = facing_basis(ray, hit_record);
+        let inside = V3::dot(ray.direction, hit_record.normal) > 0.0;
+        if inside {
+            // Light from lamps outside passes through the surface into the medium
+            let cos_i = V3::dot(light_direction.unit(), -basis.2).max(0.0);
+            return Some(V3::ONE * (cos_i * (1.0 - self.internal_reflectance(ray, hit_record))));
+        }
+        // Light entering the surface reaches the eye after its walk through the medium, so only the specular reflection is seen here
+        let wo = to_local(-ray.direction.unit(), basis);
+        let wi = to_local(light_direction.unit(), basis);
+        let response = match ggx_reflection(wo, wi, self.roughness * self.roughness) {
+            Some((h, reflection)) => V3::ONE * (fresnel_dielectric(V3::dot(wi, h), self.ior) * reflection * LIGHT_SCALE),
+            None => V3::ZERO,
+        };
+        Some(response)
+    }
+
+    fn interior_medium(&self, _hit_record: &HitRecord) -> Option<&Medium> {
+        Some(&self.medium)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        true
+    }
+}
+
+/// A complex index of refraction (eta + ik) for each RGB channel, describing how a conductor reflects light
+#[derive(Clone, Copy, Debug)]
+pub struct ComplexIor {
+    pub eta: V3,
+    pub k: V3,
+}
+
+impl ComplexIor {
+    // Measured values, averaged over the red, green and blue parts of the spectrum
+    pub const GOLD: ComplexIor = ComplexIor { eta: V3(0.143, 0.375, 1.442), k: V3(3.983, 2.386, 1.603) };
+    pub const COPPER: ComplexIor = ComplexIor { eta: V3(0.200, 0.924, 1.102), k: V3(3.913, 2.453, 2.142) };
+    pub const ALUMINIUM: ComplexIor = ComplexIor { eta: V3(1.657, 0.880, 0.521), k: V3(9.224, 6.270, 4.837) };
+    pub const CHROME: ComplexIor = ComplexIor { eta: V3(4.370, 2.917, 1.655), k: V3(5.206, 4.231, 3.755) };
+    pub const TITANIUM: ComplexIor = ComplexIor { eta: V3(2.741, 2.542, 2.267), k: V3(3.814, 3.435, 3.039) };
+}
+
+/// A metal, reflecting light off a rough surface of microscopic mirrors (microfacets).
+/// Microfacet normals follow the GGX (Trowbridge-Reitz) distribution, with Smith masking and shadowing,
+/// and reflect light with the Fresnel equations for a conductor.
+/// NOTE: The color of the metal comes from its index of refraction, use a white texture to avoid tinting it further.
+#[derive(Clone)]
+pub struct MatConductor {
+    ior: ComplexIor,
+    roughness: f32,
+}
+
+impl Default for MatConductor {
+    fn default() -> Self {
+        Self {
+            ior: ComplexIor::ALUMINIUM,
+            roughness: 0.0,
+        }
+    }
+}
+
+impl MatConductor {
+    pub fn gold() -> Self {
+        Self::default().with_ior(ComplexIor::GOLD)
+    }
+
+    pub fn copper() -> Self {
+        Self::default().with_ior(ComplexIor::COPPER)
+    }
+
+    pub fn aluminium() -> Self {
+        Self::default().with_ior(ComplexIor::ALUMINIUM)
+    }
+
+    pub fn chrome() -> Self {
+        Self::default().with_ior(ComplexIor::CHROME)
+    }
+
+    pub fn titanium() -> Self {
+        Self::default().with_ior(ComplexIor::TITANIUM)
+    }
+
+    pub fn with_ior(mut self, ior: ComplexIor) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    /// Sets the perceived roughness of the surface, from 0.0 (a perfect mirror) to 1.0 (very rough)
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        assert_in_range!(roughness);
+        self.roughness = roughness;
+        self
+    }
+
+    /// The GGX {alpha} parameter, which is the square of the perceived roughness
+    fn alpha(&self) -> f32 {
+        self.roughness * self.roughness
+    }
+
+    fn fresnel(&self, cos_theta: f32) -> V3 {
+        let ComplexIor { eta, k } = self.ior;
+        V3(
+            fresnel_conductor(cos_theta, eta.0, k.0),
+            fresnel_conductor(cos_theta, eta.1, k.1),
+            fresnel_conductor(cos_theta, eta.2, k.2),
+        )
+    }
+}
+
+/// The Fresnel reflectance of a conductor with complex index of refraction {eta} + i{k},
+/// for light arriving at {cos_theta} to the surface normal (averaged over both polarisations)
+fn fresnel_conductor(cos_theta: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_theta * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+/// A right handed orthonormal basis (tangent, bitangent, normal) around the unit vector {n}
+pub(crate) fn orthonormal_basis(n: V3) -> (V3, V3) {
+    // NOTE: Duff et al. 2017, "Building an Orthonormal Basis, Revisited"
+    let sign = 1.0f32.copysign(n.2);
+    let a = -1.0 / (sign + n.2);
+    let b = n.0 * n.1 * a;
+    (V3(1.0 + sign * n.0 * n.0 * a, sign * b, -sign * n.0), V3(b, sign + n.1 * n.1 * a, -n.1))
+}
+
+/// Expresses {v} in the basis (t, b, n), so the normal is +Z
+fn to_local(v: V3, (t, b, n): (V3, V3, V3)) -> V3 {
+    V3(V3::dot(v, t), V3::dot(v, b), V3::dot(v, n))
+}
+
+fn from_local(v: V3, (t, b, n): (V3, V3, V3)) -> V3 {
+    t * v.0 + b * v.1 + n * v.2
+}
+
+/// The GGX distribution of microfacet normals {h} (in the local frame, normal +Z)
+fn ggx_d(h: V3, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = h.2 * h.2 * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+/// The Smith Lambda function for GGX, which measures how much of the surface is masked from direction {v}
+fn ggx_lambda(v: V3, alpha: f32) -> f32 {
+    let cos2 = v.2 * v.2;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) * 0.5
+}
+
+/// Samples a microfacet normal visible from {v} (in the local frame, normal +Z), in proportion to its projected area.
+/// NOTE: Heitz 2018, "Sampling the GGX Distribution of Visible Normals"
+fn ggx_sample_visible_normal(v: V3, alpha: f32, rng: &mut dyn RngCore) -> V3 {
+    // Stretch the view direction, to sample a hemisphere rather than the ellipsoid of microfacets
+    let vh = V3(alpha * v.0, alpha * v.1, v.2).unit();
+    let len2 = vh.0 * vh.0 + vh.1 * vh.1;
+    let t1 = if len2 > 0.0 { V3(-vh.1, vh.0, 0.0) / len2.sqrt() } else { V3::POS_X };
+    let t2 = V3::cross(vh, t1);
+
+    // Sample the projected hemisphere as a disk, squashed towards the visible half
+    let r = rng.random::<f32>().sqrt();
+    let phi = 2.0 * PI * rng.random::<f32>();
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.2);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+    // Unstretch back to the ellipsoid
+    V3(alpha * nh.0, alpha * nh.1, nh.2.max(0.0)).unit()
+}
+
+/// Reflects {wo} off a microfacet visible from it, sampled in proportion to its projected area.
+/// Returns the reflected direction, the microfacet normal and the masking weight G2 / G1,
+/// which together with the Fresnel term is the whole weight of the sample.
+/// Returns None if the reflection is masked (points into the surface).
+fn ggx_sample_reflection(wo: V3, alpha: f32, rng: &mut dyn RngCore) -> Option<(V3, V3, f32)> {
+    let h = ggx_sample_visible_normal(wo, alpha, rng);
+    let wi = h * (2.0 * V3::dot(wo, h)) - wo;
+    if wi.2 <= 0.0 || wo.2 <= 0.0 {
+        return None;
+    }
+    let lambda_o = ggx_lambda(wo, alpha);
+    Some((wi, h, (1.0 + lambda_o) / (1.0 + lambda_o + ggx_lambda(wi, alpha))))
+}
+
+/// The GGX reflection from {wi} to {wo} without the Fresnel term, D * G2 / (4 cos_o cos_i), multiplied by cos_i.
+/// Returns the microfacet normal and the reflection, or None if either direction is below the surface.
+// NOTE: A perfect mirror only reflects in a single direction, which lamps are never found in
+fn ggx_reflection(wo: V3, wi: V3, alpha: f32) -> Option<(V3, f32)> {
+    if alpha == 0.0 || wi.2 <= 0.0 || wo.2 <= 0.0 {
+        return None;
+    }
+    let h = (wo + wi).unit();
+    let g2 = 1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+    Some((h, ggx_d(h, alpha) * g2 / (4.0 * wo.2)))
+}
+
+/// Lamp intensities are scaled so that a white Lambertian surface reflects all the light falling on it,
+/// so BRDFs are scaled by the same amount in Material::light_response
+const LIGHT_SCALE: f32 = PI;
+
+/// Finds the basis around the surface normal, facing the side of the surface the ray arrived from
+fn facing_basis(ray: Ray, hit_record: &HitRecord) -> (V3, V3, V3) {
+    let n = if V3::dot(ray.direction, hit_record.normal) > 0.0 { -hit_record.normal } else { hit_record.normal };
+    let (t, b) = orthonormal_basis(n);
+    (t, b, n)
+}
+
+impl Material for MatConductor {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        let basis = facing_basis(ray, hit_record);
+        let wo = to_local(-ray.direction.unit(), basis);
+
+        // Reflect off a sampled microfacet, unless the reflection is masked by the surface
+        let reflection = ggx_sample_reflection(wo, self.alpha(), rng).map(|(wi, h, masking)| Reflect {
+            ray: Ray::new(hit_record.p, from_local(wi, basis)),
+            intensity: 1.0,
+            color: self.fresnel(V3::dot(wo, h)) * masking,
+        });
+
+        MatRecord {
+            reflection,
+            refraction: None,
+        }
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        let basis = facing_basis(ray, hit_record);
+        let wo = to_local(-ray.direction.unit(), basis);
+        let wi = to_local(light_direction.unit(), basis);
+        let response = match ggx_reflection(wo, wi, self.alpha()) {
+            Some((h, reflection)) => self.fresnel(V3::dot(wi, h)) * (reflection * LIGHT_SCALE),
+            None => V3::ZERO,
+        };
+        Some(response)
+    }
+}
+
+/// A material parameter which is either constant, or varies over the surface following a texture.
+/// Textured parameters are the texture value multiplied by a constant factor, as in MTL and glTF materials.
+#[derive(Clone)]
+pub struct MatParam {
+    factor: V3,
+    texture: Option<Arc<dyn Texture>>,
+    channel: usize,
+}
+
+impl MatParam {
+    pub fn constant(value: f32) -> Self {
+        Self::color(V3::ONE * value)
+    }
+
+    pub fn color(color: V3) -> Self {
+        Self { factor: color, texture: None, channel: 0 }
+    }
+
+    pub fn texture(texture: impl IntoArc<dyn Texture>) -> Self {
+        Self { factor: V3::ONE, texture: Some(texture.into_arc()), channel: 0 }
+    }
+
+    /// Multiplies the texture by {factor}
+    pub fn with_factor(mut self, factor: V3) -> Self {
+        self.factor = factor;
+        self
+    }
+
+    /// Reads scalar parameters from a single channel of the texture (0: red, 1: green, 2: blue).
+    /// Defaults to the red channel, which suits greyscale textures.
+    pub fn with_channel(mut self, channel: usize) -> Self {
+        assert!(channel < 3, "channel must be 0 (red), 1 (green) or 2 (blue)");
+        self.channel = channel;
+        self
+    }
+
+    fn color_at(&self, hit_record: &HitRecord) -> V3 {
+        match self.texture {
+            Some(ref texture) => texture.value(hit_record) * self.factor,
+            None => self.factor,
+        }
+    }
+
+    fn scalar_at(&self, hit_record: &HitRecord) -> f32 {
+        self.color_at(hit_record).xyz()[self.channel]
+    }
+
+    /// True if the parameter is zero over the whole surface
+    fn is_zero(&self) -> bool {
+        self.factor == V3::ZERO
+    }
+}
+
+impl From<f32> for MatParam {
+    fn from(value: f32) -> Self {
+        MatParam::constant(value)
+    }
+}
+
+impl From<V3> for MatParam {
+    fn from(color: V3) -> Self {
+        MatParam::color(color)
+    }
+}
+
+/// A principled (Disney style) material, described by the parameters artists use in other tools rather than
+/// reflectivity numbers. Parameters range from 0.0 to 1.0, and any of them may follow a texture.
+/// It combines a diffuse base with sheen, a GGX specular layer which becomes a metal as {metallic} increases,
+/// a clearcoat layer on top, and GGX transmission for glass-like materials.
+/// NOTE: The material provides its own color, use a white texture to avoid tinting it further.
+#[derive(Clone)]
+pub struct MatPrincipled {
+    base_color: MatParam,
+    metallic: MatParam,
+    roughness: MatParam,
+    // Reflectance of non-metals, where the default 0.5 is 4% (the reflectance of most non-metals)
+    specular: MatParam,
+    sheen: MatParam,
+    // How much the sheen takes on the base color, rather than being white
+    sheen_tint: MatParam,
+    // Multiplies the color of the sheen, for sheens with their own color (e.g. glTF sheen)
+    sheen_color: MatParam,
+    clearcoat: MatParam,
+    clearcoat_roughness: MatParam,
+    transmission: MatParam,
+    ior: f32,
+}
+
+impl Default for MatPrincipled {
+    fn default() -> Self {
+        Self {
+            base_color: MatParam::constant(0.8),
+            metallic: MatParam::constant(0.0),
+            roughness: MatParam::constant(0.5),
+            specular: MatParam::constant(0.5),
+            sheen: MatParam::constant(0.0),
+            sheen_tint: MatParam::constant(0.5),
+            sheen_color: MatParam::constant(1.0),
+            clearcoat: MatParam::constant(0.0),
+            clearcoat_roughness: MatParam::constant(0.03),
+            transmission: MatParam::constant(0.0),
+            ior: 1.5,
+        }
+    }
+}
+
+impl MatPrincipled {
+    pub fn with_base_color(mut self, base_color: impl Into<MatParam>) -> Self {
+        self.base_color = base_color.into();
+        self
+    }
+
+    pub fn with_metallic(mut self, metallic: impl Into<MatParam>) -> Self {
+        self.metallic = metallic.into();
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: impl Into<MatParam>) -> Self {
+        self.roughness = roughness.into();
+        self
+    }
+
+    pub fn with_specular(mut self, specular: impl Into<MatParam>) -> Self {
+        self.specular = specular.into();
+        self
+    }
+
+    pub fn with_sheen(mut self, sheen: impl Into<MatParam>) -> Self {
+        self.sheen = sheen.into();
+        self
+    }
+
+    pub fn with_sheen_tint(mut self, sheen_tint: impl Into<MatParam>) -> Self {
+        self.sheen_tint = sheen_tint.into();
+        self
+    }
+
+    pub fn with_sheen_color(mut self, sheen_color: impl Into<MatParam>) -> Self {
+        self.sheen_color = sheen_color.into();
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: impl Into<MatParam>) -> Self {
+        self.clearcoat = clearcoat.into();
+        self
+    }
+
+    pub fn with_clearcoat_roughness(mut self, clearcoat_roughness: impl Into<MatParam>) -> Self {
+        self.clearcoat_roughness = clearcoat_roughness.into();
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: impl Into<MatParam>) -> Self {
+        self.transmission = transmission.into();
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f32) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    /// Looks up the parameters at the hit point
+    fn surface(&self, hit_record: &HitRecord) -> PrincipledSurface {
+        let unit = |param: &MatParam| param.scalar_at(hit_record).clamp(0.0, 1.0);
+        let unit_color = |param: &MatParam| {
+            let color = param.color_at(hit_record);
+            V3(color.0.clamp(0.0, 1.0), color.1.clamp(0.0, 1.0), color.2.clamp(0.0, 1.0))
+        };
+        let base_color = unit_color(&self.base_color);
+        let metallic = unit(&self.metallic);
+        let roughness = unit(&self.roughness);
+        let clearcoat_roughness = unit(&self.clearcoat_roughness);
+
+        let tint = match luminance(base_color) {
+            l if l > 0.0 => base_color / l,
+            _ => V3::ONE,
+        };
+        let sheen_tint = unit(&self.sheen_tint);
+        let specular = 0.08 * unit(&self.specular);
+        PrincipledSurface {
+            base_color,
+            metallic,
+            roughness,
+            alpha: roughness * roughness,
+            dielectric_f0: specular,
+            f0: V3::ONE * (specular * (1.0 - metallic)) + base_color * metallic,
+            sheen: (V3::ONE * (1.0 - sheen_tint) + tint * sheen_tint) * unit_color(&self.sheen_color) * (unit(&self.sheen) * (1.0 - metallic)),
+            clearcoat: 0.25 * unit(&self.clearcoat),
+            clearcoat_alpha: clearcoat_roughness * clearcoat_roughness,
+            diffuse: (1.0 - metallic) * (1.0 - unit(&self.transmission)),
+            transmission: (1.0 - metallic) * unit(&self.transmission),
+        }
+    }
+}
+
+/// The parameters of a MatPrincipled at a point on its surface
+struct PrincipledSurface {
+    base_color: V3,
+    metallic: f32,
+    roughness: f32,
+    alpha: f32,
+    // Specular reflectance at normal incidence, of the non-metallic part and overall
+    dielectric_f0: f32,
+    f0: V3,
+    sheen: V3,
+    clearcoat: f32,
+    clearcoat_alpha: f32,
+    // Weights of the diffuse and transmitted light
+    diffuse: f32,
+    transmission: f32,
+}
+
+/// Clearcoat is a varnish, with a fixed reflectance of 4%
+const CLEARCOAT_F0: V3 = V3(0.04, 0.04, 0.04);
+
+fn luminance(color: V3) -> f32 {
+    0.2126 * color.0 + 0.7152 * color.1 + 0.0722 * color.2
+}
+
+/// Schlick's approximation of Fresnel reflectance, given the reflectance {f0} at normal incidence
+fn schlick_fresnel(f0: V3, cos_theta: f32) -> V3 {
+    f0 + (V3::ONE - f0) * (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+impl PrincipledSurface {
+    /// The diffuse and sheen reflection from {wi} to {wo}, multiplied by PI and cos_i (the weight of a cosine weighted sample)
+    fn diffuse_and_sheen(&self, wo: V3, wi: V3) -> V3 {
+        let cos_d = V3::dot(wi, (wo + wi).unit());
+        // Burley's diffuse, which darkens smooth surfaces and brightens rough surfaces at grazing angles
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let retro = |cos: f32| 1.0 + (fd90 - 1.0) * (1.0 - cos).powi(5);
+        // NOTE: Light reflected by the specular layer never reaches the diffuse base
+        let specular = schlick_fresnel(V3::ONE * self.dielectric_f0, wo.2).0;
+        let diffuse = self.base_color * (self.diffuse * (1.0 - specular) * retro(wo.2) * retro(wi.2));
+        diffuse + self.sheen * ((1.0 - cos_d).powi(5) * PI)
+    }
+}
+
+impl Material for MatPrincipled {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        let surface = self.surface(hit_record);
+        let inside = V3::dot(ray.direction, hit_record.normal) > 0.0;
+        let basis = facing_basis(ray, hit_record);
+        let wo = to_local(-ray.direction.unit(), basis);
+
+        // Reflect off one layer, picked in proportion to how much light it reflects.
+        // NOTE: The weight of the sample is divided by the chance of picking the layer,
+        // so layers which reflect nothing are never picked, and a surface which reflects nothing at all
+        // (e.g. black, with no specular or clearcoat) doesn't reflect.
+        let diffuse_weight = (luminance(surface.base_color) * surface.diffuse + luminance(surface.sheen)).max(0.0);
+        let specular_weight = luminance(schlick_fresnel(surface.f0, wo.2));
+        let clearcoat_weight = surface.clearcoat * luminance(schlick_fresnel(CLEARCOAT_F0, wo.2));
+        let total_weight = diffuse_weight + specular_weight + clearcoat_weight;
+        let pick = rng.random::<f32>() * total_weight;
+        let reflected = if total_weight <= 0.0 {
+            None
+        } else if pick < diffuse_weight || specular_weight + clearcoat_weight <= 0.0 {
+            // Cosine weighted hemisphere sample
+            let r = rng.random::<f32>().sqrt();
+            let phi = 2.0 * PI * rng.random::<f32>();
+            let wi = V3(r * phi.cos(), r * phi.sin(), (1.0 - r * r).max(0.0).sqrt());
+            (wo.2 > 0.0).then(|| (wi, surface.diffuse_and_sheen(wo, wi) * (total_weight / diffuse_weight)))
+        } else if (pick < diffuse_weight + specular_weight && specular_weight > 0.0) || clearcoat_weight <= 0.0 {
+            ggx_sample_reflection(wo, surface.alpha, rng).map(|(wi, h, masking)| {
+                (wi, schlick_fresnel(surface.f0, V3::dot(wo, h)) * (masking * total_weight / specular_weight))
+            })
+        } else {
+            ggx_sample_reflection(wo, surface.clearcoat_alpha, rng).map(|(wi, h, masking)| {
+                (wi, schlick_fresnel(CLEARCOAT_F0, V3::dot(wo, h)) * (surface.clearcoat * masking * total_weight / clearcoat_weight))
+            })
+        };
+        let reflection = reflected.map(|(wi, color)| Reflect {
+            ray: Ray::new(hit_record.p, from_local(wi, basis)),
+            intensity: 1.0,
+            color,
+        });
+
+        // Transmit through a sampled microfacet, as in MatDielectric, tinted by the base color
+        let refraction = match surface.transmission > 0.0 && wo.2 > 0.0 {
+            false => None,
+            true => {
+                let eta = if inside { 1.0 / self.ior } else { self.ior };
+                let h = ggx_sample_visible_normal(wo, surface.alpha, rng);
+                let kt = 1.0 - fresnel_dielectric(V3::dot(wo, h), eta);
+                refract(wo, h, eta)
+                    .filter(|wt| wt.2 < 0.0 && kt > 0.0)
+                    .map(|wt| {
+                        let lambda_o = ggx_lambda(wo, surface.alpha);
+                        let masking = (1.0 + lambda_o) / (1.0 + lambda_o + ggx_lambda(wt, surface.alpha));
+                        Refract {
+                            ray: Ray::new(hit_record.p, from_local(wt, basis)),
+                            intensity: surface.transmission * kt * masking,
+                            color: surface.base_color,
+                        }
+                    })
+            },
+        };
+
+        MatRecord {
+            reflection,
+            refraction,
+        }
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.transmission.is_zero()
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        let surface = self.surface(hit_record);
+        let inside = V3::dot(ray.direction, hit_record.normal) > 0.0;
+        let cos_theta = V3::dot(-ray.direction.unit(), facing_basis(ray, hit_record).2);
+        let eta = if inside { 1.0 / self.ior } else { self.ior };
+        surface.transmission * (1.0 - fresnel_dielectric(cos_theta, eta))
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        let surface = self.surface(hit_record);
+        let basis = facing_basis(ray, hit_record);
+        let wo = to_local(-ray.direction.unit(), basis);
+        let wi = to_local(light_direction.unit(), basis);
+        if wo.2 <= 0.0 || wi.2 <= 0.0 {
+            return Some(V3::ZERO);
+        }
+        // NOTE: diffuse_and_sheen is already scaled by PI (LIGHT_SCALE), so only needs multiplying by cos_i / PI
+        let mut response = surface.diffuse_and_sheen(wo, wi) * (wi.2 / PI * LIGHT_SCALE);
+        if let Some((h, reflection)) = ggx_reflection(wo, wi, surface.alpha) {
+            response = response + schlick_fresnel(surface.f0, V3::dot(wi, h)) * (reflection * LIGHT_SCALE);
+        }
+        if let Some((h, reflection)) = ggx_reflection(wo, wi, surface.clearcoat_alpha) {
+            response = response + schlick_fresnel(CLEARCOAT_F0, V3::dot(wi, h)) * (surface.clearcoat * reflection * LIGHT_SCALE);
+        }
+        Some(response)
+    }
+}
+
+/// The glTF 2.0 metallic-roughness material model, including the transmission, ior, clearcoat and sheen extensions.
+/// Converts into an equivalent MatPrincipled.
+pub struct GltfMetallicRoughness {
+    pub base_color_factor: V3,
+    pub base_color_texture: Option<Arc<dyn Texture>>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    // Roughness is read from the green channel, and metallic from the blue channel
+    pub metallic_roughness_texture: Option<Arc<dyn Texture>>,
+    pub transmission_factor: f32,
+    pub ior: f32,
+    pub clearcoat_factor: f32,
+    pub clearcoat_roughness_factor: f32,
+    pub sheen_color_factor: V3,
+}
+
+impl Default for GltfMetallicRoughness {
+    fn default() -> Self {
+        // NOTE: Defaults from the glTF 2.0 specification
+        Self {
+            base_color_factor: V3::ONE,
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            transmission_factor: 0.0,
+            ior: 1.5,
+            clearcoat_factor: 0.0,
+            clearcoat_roughness_factor: 0.0,
+            sheen_color_factor: V3::ZERO,
+        }
+    }
+}
+
+impl From<GltfMetallicRoughness> for MatPrincipled {
+    fn from(gltf: GltfMetallicRoughness) -> Self {
+        let textured = |texture: &Option<Arc<dyn Texture>>, factor: V3, channel: usize| match texture {
+            Some(texture) => MatParam { factor, texture: Some(texture.clone()), channel },
+            None => MatParam::color(factor),
+        };
+        // glTF sheen is a color rather than an amount, so split it into an amount and a color of at most 1.0
+        let sheen = gltf.sheen_color_factor.0.max(gltf.sheen_color_factor.1).max(gltf.sheen_color_factor.2);
+        let sheen_color = if sheen > 0.0 { gltf.sheen_color_factor / sheen } else { V3::ONE };
+        MatPrincipled::default()
+            .with_base_color(textured(&gltf.base_color_texture, gltf.base_color_factor, 0))
+            .with_metallic(textured(&gltf.metallic_roughness_texture, V3::ONE * gltf.metallic_factor, 2))
+            .with_roughness(textured(&gltf.metallic_roughness_texture, V3::ONE * gltf.roughness_factor, 1))
+            // glTF derives the specular reflectance of non-metals from the ior, where 1.5 gives 4%
+            .with_specular(((gltf.ior - 1.0) / (gltf.ior + 1.0)).powi(2) / 0.08)
+            .with_transmission(gltf.transmission_factor)
+            .with_ior(gltf.ior)
+            .with_clearcoat(gltf.clearcoat_factor)
+            .with_clearcoat_roughness(gltf.clearcoat_roughness_factor)
+            .with_sheen(sheen)
+            .with_sheen_tint(0.0)
+            .with_sheen_color(sheen_color)
+    }
+}
+
+// A collection of OBJ mtl materials, one for each texture in a MeshTextureSet.
+// Only supported if the HitRecord specifies a {tex_key}
+pub struct MeshMaterialSet {
+    pub materials: Vec<Arc<dyn Material>>,
+}
+
+/// Used for faces without a material
+static MATERIAL_NOT_FOUND: MatLambertian = MatLambertian { reflectivity: 0.0 };
+
+impl MeshMaterialSet {
+    fn get(&self, hit_record: &HitRecord) -> &dyn Material {
+        hit_record.tex_key
+            .and_then(|key| self.materials.get(key))
+            .map_or(&MATERIAL_NOT_FOUND, |material| material.as_ref())
+    }
+}
+
+impl Material for MeshMaterialSet {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        self.get(hit_record).scatter(ray, hit_record, rng)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.materials.iter().all(|material| material.is_opaque())
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.get(hit_record).transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        self.get(hit_record).light_response(ray, hit_record, light_direction)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.materials.iter().any(|material| material.is_dispersive())
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.get(hit_record).is_cut_out(hit_record)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        self.get(hit_record).shading_normal(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.get(hit_record).interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.materials.iter().any(|material| material.has_interior_medium())
+    }
+}
+
+// Material combinators
+//
+// NOTE: The combined materials share the texture of the entity, which tints all of them.
+// Combine materials which take their color from the material itself (e.g. MatPrincipled, MatConductor)
+// and use a white texture, so that each part of the surface keeps its own color.
+
+/// A random number from 0.0 to 1.0 for the hit point, the same each time the point is shaded
+fn hit_point_random(hit_record: &HitRecord) -> f32 {
+    let V3(x, y, z) = hit_record.p;
+    let mut h = [x, y, z].iter().fold(0x9E3779B97F4A7C15u64, |h, v| (h ^ v.to_bits() as u64).wrapping_mul(0x100000001B3));
+    // splitmix64 finalizer
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+    h ^= h >> 31;
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Mixes two materials, following {factor} from 0.0 (only {a}) to 1.0 (only {b}).
+/// A texture factor selects between the materials over the surface, e.g. scratches through paint to the metal beneath.
+/// Each hit point uses one of the materials, picked at random with the probability given by {factor},
+/// so that the average of the samples for a pixel is the blend of the materials.
+#[derive(Clone)]
+pub struct MatMix {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    factor: MatParam,
+}
+
+impl MatMix {
+    pub fn new(a: impl IntoArc<dyn Material>, b: impl IntoArc<dyn Material>, factor: impl Into<MatParam>) -> Self {
+        Self { a: a.into_arc(), b: b.into_arc(), factor: factor.into() }
+    }
+
+    // NOTE: Picks the material from the hit point rather than an rng,
+    // so that scattering, lighting and shadows all see the same material at a point
+    fn get(&self, hit_record: &HitRecord) -> &dyn Material {
+        match hit_point_random(hit_record) < self.factor.scalar_at(hit_record) {
+            true => self.b.as_ref(),
+            false => self.a.as_ref(),
+        }
+    }
+}
+
+impl Material for MatMix {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        self.get(hit_record).scatter(ray, hit_record, rng)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.a.is_opaque() && self.b.is_opaque()
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.get(hit_record).transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        self.get(hit_record).light_response(ray, hit_record, light_direction)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.a.is_dispersive() || self.b.is_dispersive()
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.get(hit_record).is_cut_out(hit_record)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        self.get(hit_record).shading_normal(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.get(hit_record).interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.a.has_interior_medium() || self.b.has_interior_medium()
+    }
+}
+
+/// Layers a transparent {coat} (e.g. a smooth MatDielectric, as lacquer or varnish) over a {base} material.
+/// The coat reflects light as usual, and the light passing through the coat reaches the base.
+/// NOTE: The coat is treated as infinitely thin, so light isn't bent or absorbed on the way to the base,
+/// and isn't reflected back and forth between the layers.
+#[derive(Clone)]
+pub struct MatLayered {
+    coat: Arc<dyn Material>,
+    base: Arc<dyn Material>,
+}
+
+impl MatLayered {
+    pub fn new(coat: impl IntoArc<dyn Material>, base: impl IntoArc<dyn Material>) -> Self {
+        Self { coat: coat.into_arc(), base: base.into_arc() }
+    }
+}
+
+impl Material for MatLayered {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        let coat = self.coat.scatter(ray, hit_record, rng);
+        let base = self.base.scatter(ray, hit_record, rng);
+        let through_coat = self.coat.transmission(ray, hit_record);
+
+        // Both layers reflect light, so follow one of the reflected rays, picked in proportion to the light it carries.
+        // Its weight is scaled up by the chance of picking it, so that the average is the sum of both reflections.
+        let coat_reflection = coat.reflection.filter(|reflect| reflect.intensity > 0.0);
+        let base_reflection = base.reflection
+            .map(|reflect| Reflect { intensity: reflect.intensity * through_coat, ..reflect })
+            .filter(|reflect| reflect.intensity > 0.0);
+        let reflection = match (coat_reflection, base_reflection) {
+            (Some(coat), Some(base)) => {
+                let intensity = coat.intensity + base.intensity;
+                let reflect = if rng.random::<f32>() * intensity < coat.intensity { coat } else { base };
+                Some(Reflect { intensity, ..reflect })
+            },
+            (coat, base) => coat.or(base),
+        };
+
+        // Only light passing through both layers is refracted
+        let refraction = base.refraction
+            .map(|refract| Refract { intensity: refract.intensity * through_coat, ..refract });
+
+        MatRecord { reflection, refraction }
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.base.is_opaque()
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.coat.transmission(ray, hit_record) * self.base.transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        let coat = self.coat.light_response(ray, hit_record, light_direction);
+        let base = self.base.light_response(ray, hit_record, light_direction);
+        if coat.is_none() && base.is_none() {
+            return None;
+        }
+        // NOTE: A base without its own response is lit as a diffuse (Lambertian) surface beneath the coat
+        let through_coat = self.coat.transmission(ray, hit_record);
+        let base = base.unwrap_or_else(|| V3::ONE * f32::max(0.0, V3::dot(hit_record.normal, light_direction)));
+        Some(coat.unwrap_or(V3::ZERO) + base * through_coat)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.coat.is_dispersive() || self.base.is_dispersive()
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.base.is_cut_out(hit_record)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        // NOTE: The coat follows the surface of the base
+        self.base.shading_normal(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.base.interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.base.has_interior_medium()
+    }
+}
+
+/// Cuts away parts of the surface of a {material} where {alpha} is below the threshold (0.5 by default),
+/// letting camera and shadow rays pass straight through, e.g. for leaves, grates or decals drawn on a plane.
+/// Alpha textures are read from their red channel by default (see MatParam::with_channel).
+#[derive(Clone)]
+pub struct MatCutout {
+    material: Arc<dyn Material>,
+    alpha: MatParam,
+    threshold: f32,
+}
+
+impl MatCutout {
+    pub fn new(material: impl IntoArc<dyn Material>, alpha: impl Into<MatParam>) -> Self {
+        Self { material: material.into_arc(), alpha: alpha.into(), threshold: 0.5 }
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        assert_in_range!(threshold);
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl Material for MatCutout {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        self.material.scatter(ray, hit_record, rng)
+    }
+
+    fn is_opaque(&self) -> bool {
+        // NOTE: Shadow rays must be able to find the cut out parts of the surface
+        false
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.material.transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        self.material.light_response(ray, hit_record, light_direction)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.alpha.scalar_at(hit_record) < self.threshold || self.material.is_cut_out(hit_record)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        self.material.shading_normal(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.material.interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.material.has_interior_medium()
+    }
+}
+
+// Normal and bump mapping
+//
+// Both perturb the normal used to shade a surface, adding detail which isn't in the geometry.
+// Maps are oriented by the tangent frame of the hit (see HitRecord::tangent_frame), so they need surfaces
+// with uv coordinates, e.g. meshes and planes. Elsewhere the normal is left unchanged.
+
+/// Perturbs the shading normal of a {material} with a tangent space normal map.
+/// The red, green and blue channels hold the components of the normal along the tangent (u), bitangent (v)
+/// and surface normal, mapped from -1.0..1.0 onto 0.0..1.0 (as in OpenGL style maps, where flat is (0.5, 0.5, 1.0)).
+/// NOTE: Normal maps must be loaded without gamma correction, as color maps are.
+#[derive(Clone)]
+pub struct MatNormalMap {
+    material: Arc<dyn Material>,
+    map: Arc<dyn Texture>,
+    strength: f32,
+}
+
+impl MatNormalMap {
+    pub fn new(material: impl IntoArc<dyn Material>, map: impl IntoArc<dyn Texture>) -> Self {
+        Self { material: material.into_arc(), map: map.into_arc(), strength: 1.0 }
+    }
+
+    /// Scales the tilt of the normals in the map, from 0.0 (flat) through 1.0 (as drawn) and beyond
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength;
+        self
+    }
+}
+
+impl Material for MatNormalMap {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        self.material.scatter(ray, hit_record, rng)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.material.is_opaque()
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.material.transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        self.material.light_response(ray, hit_record, light_direction)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.material.is_cut_out(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.material.interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.material.has_interior_medium()
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        let normal = self.material.shading_normal(hit_record);
+        let hit_record = HitRecord { normal, ..hit_record.clone() };
+        let Some((tangent, bitangent)) = hit_record.tangent_frame() else {
+            return normal;
+        };
+        let V3(x, y, z) = self.map.value(&hit_record) * 2.0 - 1.0;
+        let perturbed = (tangent * x + bitangent * y) * self.strength + normal * z;
+        if perturbed == V3::ZERO { normal } else { perturbed.unit() }
+    }
+}
+
+/// Perturbs the shading normal of a {material} following the slope of a greyscale {height} map,
+/// e.g. for panel lines, rivets or hammered metal.
+/// The slope is the difference in height between points {uv_step} apart (by default, one texel of a 1024 pixel map),
+/// scaled by the strength (1.0 by default).
+/// Height textures are read from their red channel by default (see MatParam::with_channel).
+#[derive(Clone)]
+pub struct MatBumpMap {
+    material: Arc<dyn Material>,
+    height: MatParam,
+    strength: f32,
+    uv_step: f32,
+}
+
+impl MatBumpMap {
+    pub fn new(material: impl IntoArc<dyn Material>, height: impl Into<MatParam>) -> Self {
+        Self { material: material.into_arc(), height: height.into(), strength: 1.0, uv_step: 1.0 / 1024.0 }
+    }
+
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    /// Sets the distance in uv space between the points used to measure the slope, ideally the size of one texel
+    pub fn with_uv_step(mut self, uv_step: f32) -> Self {
+        assert!(uv_step > 0.0, "uv_step must be positive");
+        self.uv_step = uv_step;
+        self
+    }
+}
+
+impl Material for MatBumpMap {
+    fn scatter(&self, ray: Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> MatRecord {
+        self.material.scatter(ray, hit_record, rng)
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.material.is_opaque()
+    }
+
+    fn transmission(&self, ray: Ray, hit_record: &HitRecord) -> f32 {
+        self.material.transmission(ray, hit_record)
+    }
+
+    fn light_response(&self, ray: Ray, hit_record: &HitRecord, light_direction: V3) -> Option<V3> {
+        self.material.light_response(ray, hit_record, light_direction)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+
+    fn is_cut_out(&self, hit_record: &HitRecord) -> bool {
+        self.material.is_cut_out(hit_record)
+    }
+
+    fn interior_medium(&self, hit_record: &HitRecord) -> Option<&Medium> {
+        self.material.interior_medium(hit_record)
+    }
+
+    fn has_interior_medium(&self) -> bool {
+        self.material.has_interior_medium()
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> V3 {
+        let normal = self.material.shading_normal(hit_record);
+        let hit_record = HitRecord { normal, ..hit_record.clone() };
+        let Some((tangent, bitangent)) = hit_record.tangent_frame() else {
+            return normal;
+        };
+        // Central differences either side of the hit
+        let height_at = |offset: V2| self.height.scalar_at(&HitRecord { uv: hit_record.uv + offset, ..hit_record.clone() });
+        let step = self.uv_step;
+        let slope_u = (height_at(V2(step, 0.0)) - height_at(V2(-step, 0.0))) * 0.5;
+        let slope_v = (height_at(V2(0.0, step)) - height_at(V2(0.0, -step))) * 0.5;
+        (normal - (tangent * slope_u + bitangent * slope_v) * self.strength).unit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::texture::ColorTexture;
+
+    /// A hit at the origin, on a surface facing {normal} without uv coordinates
+    fn test_hit_record(normal: V3) -> HitRecord {
+        HitRecord {
+            entity_id: None,
+            t: 1.0,
+            p: V3::ZERO,
+            normal,
+            uv: Default::default(),
+            tangent: V3::ZERO,
+            bitangent: V3::ZERO,
+            mat_id: Default::default(),
+            tex_id: Default::default(),
+            tex_key: None,
+        }
+    }
+
+    #[test]
+    fn conductor_fresnel() {
+        // Normal incidence has a closed form, and reflectance rises to 1.0 at grazing angles
+        for ComplexIor { eta, k } in [ComplexIor::GOLD, ComplexIor::ALUMINIUM, ComplexIor::TITANIUM] {
+            for (eta, k) in [(eta.0, k.0), (eta.1, k.1), (eta.2, k.2)] {
+                let expected = ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+                assert!((fresnel_conductor(1.0, eta, k) - expected).abs() < 1.0e-5);
+                assert!((fresnel_conductor(0.0, eta, k) - 1.0).abs() < 1.0e-5);
+            }
+        }
+        // Gold reflects more red than blue
+        let gold = MatConductor::gold().fresnel(1.0);
+        assert!(gold.0 > gold.1 && gold.1 > gold.2);
+    }
+
+    #[test]
+    fn dielectric_fresnel_and_refraction() {
+        let eta = 1.5;
+        let r0 = ((eta - 1.0) / (eta + 1.0)) * ((eta - 1.0) / (eta + 1.0));
+        assert!((fresnel_dielectric(1.0, eta) - r0).abs() < 1.0e-6);
+        assert!((fresnel_dielectric(0.0, eta) - 1.0).abs() < 1.0e-6);
+        // Total internal reflection beyond the critical angle, leaving the glass
+        let critical = (1.0 / eta).asin();
+        assert_eq!(fresnel_dielectric((critical + 0.01).cos(), 1.0 / eta), 1.0);
+        assert!(refract(V3((critical + 0.01).sin(), 0.0, (critical + 0.01).cos()), V3::POS_Z, 1.0 / eta).is_none());
+
+        // Snell's law: sin_i = eta * sin_t
+        let wo = V3(0.6, 0.0, 0.8);
+        let wt = refract(wo, V3::POS_Z, eta).unwrap();
+        assert!((wt.length() - 1.0).abs() < 1.0e-5 && wt.2 < 0.0);
+        assert!((wo.0 - eta * -wt.0).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn dispersive_dielectric() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let hit_record = test_hit_record(V3::POS_Y);
+        let glass = MatDielectric::default().with_dispersion(Dispersion::DENSE_FLINT);
+        let ray = Ray::new(V3(0.0, 1.0, 0.0), V3(1.0, -1.0, 0.0));
+        let refracted = |ray: Ray, rng: &mut StdRng| glass.scatter(ray, &hit_record, rng).refraction.unwrap();
+
+        // Blue light bends further towards the normal than red light
+        let blue = refracted(ray.with_wavelength(Wavelength::Single(450.0)), &mut rng);
+        let red = refracted(ray.with_wavelength(Wavelength::Single(650.0)), &mut rng);
+        assert!(blue.ray.direction.0 < red.ray.direction.0);
+        assert_eq!((blue.color, blue.ray.wavelength), (V3::ONE, Wavelength::Single(450.0)));
+
+        // White light is split into a single wavelength, picked by the sample on the ray and weighted by its color
+        let split = refracted(ray.with_wavelength(Wavelength::All(0.1)), &mut rng);
+        let wavelength = sample_wavelength(0.1);
+        assert_eq!(split.ray.wavelength, Wavelength::Single(wavelength));
+        assert_eq!(split.color, wavelength_weight(wavelength));
+        assert!(glass.is_dispersive() && !glass.clone().with_thin_walled(true).is_dispersive());
+    }
+
+    #[test]
+    fn mix_layer_and_cut_out() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let hit_at = |p: V3| HitRecord { p, ..test_hit_record(V3::POS_Y) };
+        let points = (0..1000).map(|i| V3(i as f32 * 0.37, 0.0, i as f32 * 0.11)).collect::<Vec<_>>();
+
+        // Mixed materials are picked in proportion to the factor, and consistently at each point
+        let hole = MatCutout::new(MatLambertian::default(), 0.0);
+        let mix = MatMix::new(hole, MatLambertian::default(), 0.25);
+        let cut_out = points.iter().filter(|&&p| mix.is_cut_out(&hit_at(p))).count();
+        assert!((700..800).contains(&cut_out), "{cut_out}");
+        assert!(points.iter().all(|&p| mix.is_cut_out(&hit_at(p)) == mix.is_cut_out(&hit_at(p))));
+        assert!(!mix.is_opaque());
+
+        // Cut outs follow the alpha compared to the threshold
+        let alpha = MatCutout::new(MatLambertian::default(), 0.4);
+        assert!(alpha.is_cut_out(&hit_at(V3::ZERO)));
+        assert!(!alpha.with_threshold(0.3).is_cut_out(&hit_at(V3::ZERO)));
+
+        // A clear coat reflects a little light at normal incidence, and the rest reaches the diffuse base
+        let layered = MatLayered::new(MatDielectric::default(), MatLambertian::default());
+        let hit = hit_at(V3::ZERO);
+        let ray = Ray::new(V3::POS_Y, V3::NEG_Y);
+        let record = layered.scatter(ray, &hit, &mut rng);
+        let reflection = record.reflection.expect("coat reflection");
+        assert!((reflection.intensity - 0.04).abs() < 1.0e-3 && record.refraction.is_none());
+        assert!(layered.is_opaque());
+        // A coat with its own lighting response replaces the default diffuse lighting of the base
+        let metal_coat = MatLayered::new(MatConductor::default().with_roughness(0.5), MatLambertian::default());
+        assert!(metal_coat.light_response(ray, &hit, V3::POS_Y).is_some());
+    }
+
+    #[test]
+    fn subsurface_enters_and_exits() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let hit_record = test_hit_record(V3::POS_Y);
+        let wax = MatSubsurface::new(V3(0.9, 0.8, 0.6), V3::ONE * 0.5);
+        assert!(wax.interior_medium(&hit_record).is_some() && wax.has_interior_medium() && wax.is_opaque());
+
+        // From inside, light either reflects back in or leaves the surface
+        let from_inside = Ray::new(V3::NEG_Y, V3(0.3, 1.0, 0.0));
+        for _ in 0..100 {
+            match wax.scatter(from_inside, &hit_record, &mut rng) {
+                MatRecord { reflection: Some(reflect), refraction: None } => assert!(reflect.ray.direction.1 < 0.0),
+                MatRecord { reflection: None, refraction: Some(refract) } => assert!(refract.ray.direction.1 > 0.0),
+                _ => panic!("expected a single scattered ray"),
+            }
+        }
+        // Lamps outside light the medium through the surface, but not from behind it
+        assert!(wax.light_response(from_inside, &hit_record, V3::POS_Y).unwrap().0 > 0.0);
+        assert_eq!(wax.light_response(from_inside, &hit_record, V3::NEG_Y), Some(V3::ZERO));
+
+        // Glass only holds a medium when it has an interior
+        let murky = MatDielectric::default().with_medium(Medium::new(V3::ONE, V3::ONE));
+        assert!(murky.interior_medium(&hit_record).is_some() && murky.has_interior_medium());
+        let bubble = murky.with_thin_walled(true);
+        assert!(bubble.interior_medium(&hit_record).is_none() && !bubble.has_interior_medium());
+    }
+
+    #[test]
+    fn normal_and_bump_maps() {
+        // A height which rises along u
+        struct RampU;
+        impl Texture for RampU {
+            fn value(&self, hit_record: &HitRecord) -> V3 {
+                V3::ONE * hit_record.uv.0
+            }
+        }
+        let hit_record = |tangent: V3, bitangent: V3| HitRecord { uv: V2(0.5, 0.5), tangent, bitangent, ..test_hit_record(V3::POS_Y) };
+        let hit = hit_record(V3::POS_X, V3::NEG_Z);
+        let close = |a: V3, b: V3| (a - b).length() < 1.0e-4;
+
+        // Flat normal maps leave the normal unchanged, and tilted normals follow the tangent frame
+        let flat = MatNormalMap::new(MatLambertian::default(), ColorTexture(V3(0.5, 0.5, 1.0)));
+        assert!(close(flat.shading_normal(&hit), V3::POS_Y));
+        let tilted = MatNormalMap::new(MatLambertian::default(), ColorTexture(V3(1.0, 0.5, 0.5)));
+        assert!(close(tilted.shading_normal(&hit), V3::POS_X));
+        let tilted = MatNormalMap::new(MatLambertian::default(), ColorTexture(V3(0.5, 1.0, 0.5)));
+        assert!(close(tilted.shading_normal(&hit), V3::NEG_Z));
+        // Surfaces without uv coordinates aren't perturbed
+        assert!(close(tilted.shading_normal(&hit_record(V3::ZERO, V3::ZERO)), V3::POS_Y));
+
+        // Bumps tilt the normal away from rising ground
+        let step = 0.01;
+        let bump = MatBumpMap::new(MatLambertian::default(), MatParam::texture(RampU)).with_uv_step(step).with_strength(1.0 / step);
+        assert!(close(bump.shading_normal(&hit), V3(-1.0, 1.0, 0.0).unit()));
+        // Wrapped maps combine, with the outer map following the normal of the inner one
+        let both = MatNormalMap::new(bump, ColorTexture(V3(0.5, 0.5, 1.0)));
+        assert!(close(both.shading_normal(&hit), V3(-1.0, 1.0, 0.0).unit()));
+    }
+
+    #[test]
+    fn principled_conserves_energy() {
+        // Under uniform white light, the average weight of the scattered rays is the fraction of light reflected,
+        // which should never be much more than 1.0, and close to 1.0 for white materials.
+        // NOTE: Rough metals lose some light, as light reflecting between microfacets more than once is ignored
+        let mut rng = StdRng::seed_from_u64(3);
+        let hit_record = test_hit_record(V3::POS_Y);
+        let white = || MatPrincipled::default().with_base_color(V3::ONE);
+        let materials = [
+            ("diffuse", white().with_roughness(1.0), 0.8),
+            ("plastic", white().with_roughness(0.2).with_clearcoat(1.0), 0.8),
+            ("velvet", white().with_sheen(1.0), 0.8),
+            ("metal", white().with_metallic(1.0).with_roughness(0.0), 0.99),
+            ("rough metal", white().with_metallic(1.0).with_roughness(0.5), 0.8),
+            ("glass", white().with_transmission(1.0).with_roughness(0.0), 0.99),
+        ];
+        for (name, material, min) in materials {
+            for cos_theta in [1.0f32, 0.7, 0.3] {
+                let direction = V3((1.0 - cos_theta * cos_theta).sqrt(), -cos_theta, 0.0);
+                let ray = Ray::new(V3::ZERO - direction, direction);
+                let samples = 20_000;
+                let total = (0..samples)
+                    .map(|_| {
+                        let record = material.scatter(ray, &hit_record, &mut rng);
+                        let reflected = record.reflection.map_or(V3::ZERO, |r| r.color * r.intensity);
+                        let refracted = record.refraction.map_or(V3::ZERO, |r| r.color * r.intensity);
+                        luminance(reflected + refracted)
+                    })
+                    .sum::<f32>() / samples as f32;
+                assert!(total > min && total < 1.05, "{} at cos {}: {}", name, cos_theta, total);
+            }
+        }
+
+        // Surfaces and layers which reflect nothing are never sampled, rather than giving NaN weights
+        let ray = Ray::new(V3::POS_Y, V3::NEG_Y);
+        let black = MatPrincipled::default().with_base_color(V3::ZERO).with_specular(0.0);
+        assert!(black.scatter(ray, &hit_record, &mut rng).reflection.is_none());
+        let black_plastic = black.with_clearcoat(1.0);
+        for _ in 0..100 {
+            let color = black_plastic.scatter(ray, &hit_record, &mut rng).reflection.map_or(V3::ZERO, |r| r.color);
+            assert!(color.0.is_finite() && color.1.is_finite() && color.2.is_finite());
+        }
+    }
+
+    #[test]
+    fn gltf_maps_onto_principled() {
+        let texture: Arc<dyn Texture> = Arc::new(ColorTexture(V3(0.2, 0.6, 0.4)));
+        let principled = MatPrincipled::from(GltfMetallicRoughness {
+            base_color_factor: V3(0.5, 0.5, 1.0),
+            base_color_texture: Some(texture.clone()),
+            metallic_factor: 0.5,
+            roughness_factor: 0.5,
+            metallic_roughness_texture: Some(texture),
+            transmission_factor: 0.25,
+            ior: 1.5,
+            clearcoat_factor: 1.0,
+            clearcoat_roughness_factor: 0.1,
+            sheen_color_factor: V3(0.2, 0.4, 0.1),
+        });
+        let surface = principled.surface(&test_hit_record(V3::POS_Y));
+        let close = |a: f32, b: f32| (a - b).abs() < 1.0e-5;
+        let close_color = |a: V3, b: V3| (a - b).length() < 1.0e-5;
+
+        // Textured parameters are the texture multiplied by the factor, with metallic in blue and roughness in green
+        assert!(close_color(surface.base_color, V3(0.1, 0.3, 0.4)));
+        assert!(close(surface.metallic, 0.2) && close(surface.roughness, 0.3));
+        // An ior of 1.5 reflects 4% at normal incidence
+        assert!(close(surface.dielectric_f0, 0.04) && principled.ior == 1.5);
+        assert!(close(surface.transmission, 0.25 * 0.8) && !principled.is_opaque());
+        assert!(close(surface.clearcoat, 0.25) && close(surface.clearcoat_alpha, 0.01));
+        // The sheen keeps the color of the glTF sheen, rather than taking on the base color
+        assert!(close_color(surface.sheen, V3(0.2, 0.4, 0.1) * 0.8));
+        // Without a sheen color there is no sheen
+        let plain = MatPrincipled::from(GltfMetallicRoughness::default());
+        assert_eq!(plain.surface(&test_hit_record(V3::POS_Y)).sheen, V3::ZERO);
+    }
+
+    #[test]
+    fn ggx_is_normalized() {
+        // The projected area of the microfacets is the area of the surface: integral of D(h) cos(h) dh = 1
+        let mut rng = StdRng::seed_from_u64(1);
+        for alpha in [0.1, 0.5, 1.0] {
+            let samples = 200_000;
+            let sum = (0..samples)
+                .map(|_| {
+                    // Uniformly sample the hemisphere (pdf = 1 / 2pi)
+                    let z = rng.random::<f32>();
+                    let phi = 2.0 * PI * rng.random::<f32>();
+                    let r = (1.0 - z * z).sqrt();
+                    let h = V3(r * phi.cos(), r * phi.sin(), z);
+                    ggx_d(h, alpha) * h.2 * 2.0 * PI
+                })
+                .sum::<f32>() / samples as f32;
+            assert!((sum - 1.0).abs() < 0.05, "alpha {}: {}", alpha, sum);
+        }
+    }
+
+    #[test]
+    fn visible_normals_face_the_viewer() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for alpha in [0.0, 0.2, 1.0] {
+            for v in [V3::POS_Z, V3(0.6, 0.0, 0.8), V3(-0.99, 0.1, 0.1).unit()] {
+                for _ in 0..1000 {
+                    let h = ggx_sample_visible_normal(v, alpha, &mut rng);
+                    assert!((h.length() - 1.0).abs() < 1.0e-4 && h.2 >= 0.0 && V3::dot(v, h) >= -1.0e-6);
+                }
+            }
+        }
+    }
 }
@@ -70,6 +70,22 @@ impl Matrix {
         ])
     }
 
+    /// Rotation about an arbitrary (unit) axis, following the right hand rule
+    pub fn rotate_axis(axis: V3, theta_rads: f32) -> Matrix {
+        // See: https://en.wikipedia.org/wiki/Rotation_matrix#Rotation_matrix_from_axis_and_angle
+        let sin = theta_rads.sin();
+        let cos = theta_rads.cos();
+        let t = 1.0 - cos;
+        let V3(x, y, z) = axis;
+        // NOTE: Transposed, as vectors are multiplied on the left
+        Matrix([
+            [t*x*x + cos,   t*x*y + z*sin, t*x*z - y*sin, 0.0],
+            [t*x*y - z*sin, t*y*y + cos,   t*y*z + x*sin, 0.0],
+            [t*x*z + y*sin, t*y*z - x*sin, t*z*z + cos,   0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
     // pub fn orthographic(width: f32, height: f32, depth: f32) -> Matrix {
     //     let x = 2.0 / width;
     //     let y = -2.0 / height;
@@ -82,6 +98,52 @@ impl Matrix {
     //     ])
     // }
 
+    /// Inverts an affine transformation matrix (any combination of translation, rotation and scale)
+    pub fn inverse_affine(&self) -> Matrix {
+        let m = &self.0;
+        // Invert the upper 3x3 rotation/scale matrix using its cofactors
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+        let c = [
+            [cofactor(1, 2, 1, 2), -cofactor(1, 2, 0, 2), cofactor(1, 2, 0, 1)],
+            [-cofactor(0, 2, 1, 2), cofactor(0, 2, 0, 2), -cofactor(0, 2, 0, 1)],
+            [cofactor(0, 1, 1, 2), -cofactor(0, 1, 0, 2), cofactor(0, 1, 0, 1)],
+        ];
+        let det = m[0][0] * c[0][0] + m[0][1] * c[0][1] + m[0][2] * c[0][2];
+        let mut result = [[0.0; 4]; 4];
+        for i in 0..3 {
+            for j in 0..3 {
+                // The inverse is the transposed cofactor matrix, divided by the determinant
+                result[i][j] = c[j][i] / det;
+            }
+        }
+        // Invert the translation
+        let V3(x, y, z) = V3(m[3][0], m[3][1], m[3][2]) * Matrix(result);
+        result[3] = [-x, -y, -z, 1.0];
+        Matrix(result)
+    }
+
+    /// Transforms a direction vector, ignoring any translation
+    pub fn transform_direction(&self, v: V3) -> V3 {
+        let m = &self.0;
+        V3(
+            m[0][0]*v.0 + m[1][0]*v.1 + m[2][0]*v.2,
+            m[0][1]*v.0 + m[1][1]*v.1 + m[2][1]*v.2,
+            m[0][2]*v.0 + m[1][2]*v.1 + m[2][2]*v.2,
+        )
+    }
+
+    /// Transforms a surface normal by the transpose of this matrix, ignoring any translation.
+    /// Normals must be transformed by the inverse transpose of a transformation to remain perpendicular
+    /// to the surface, so this should be called on the *inverse* of the transformation being applied.
+    pub fn transform_normal_by_inverse(&self, n: V3) -> V3 {
+        let m = &self.0;
+        V3(
+            m[0][0]*n.0 + m[0][1]*n.1 + m[0][2]*n.2,
+            m[1][0]*n.0 + m[1][1]*n.1 + m[1][2]*n.2,
+            m[2][0]*n.0 + m[2][1]*n.1 + m[2][2]*n.2,
+        )
+    }
+
     pub fn multiply(Matrix(m1): &Matrix, Matrix(m2): &Matrix) -> Matrix {
         let mut result = [[0.0; 4]; 4];
         for i in 0..4 {
@@ -140,6 +202,12 @@ impl MatrixBuilder {
         self
     }
 
+    /// Add rotation about an arbitrary (unit) axis
+    pub fn rotate(mut self, axis: V3, theta_rads: f32) -> Self {
+        self.matrix = self.matrix * Matrix::rotate_axis(axis, theta_rads);
+        self
+    }
+
     /// Add rotation on the X axis
     pub fn rotate_x(mut self, theta_rads: f32) -> Self {
         self.matrix = self.matrix * Matrix::rotate_x(theta_rads);
@@ -248,6 +316,34 @@ mod test {
         assert_approx_eq!(p2.2, 0.5);
     }
 
+    #[test]
+    fn inverse_affine() {
+        let matrix = MatrixBuilder::new()
+            .scale(2.0, 0.5, 3.0)
+            .rotate_x(30_f32.to_radians())
+            .rotate_y(45_f32.to_radians())
+            .translate(1.0, -2.0, 3.0)
+            .done();
+
+        let p1 = V3(1.0, 2.0, 3.0);
+        let p2 = (p1 * matrix) * matrix.inverse_affine();
+        assert_approx_eq!(p2.0, p1.0, EPSILON=0.0001);
+        assert_approx_eq!(p2.1, p1.1, EPSILON=0.0001);
+        assert_approx_eq!(p2.2, p1.2, EPSILON=0.0001);
+    }
+
+    #[test]
+    fn rotate_axis_matches_rodrigues() {
+        let axis = V3(1.0, 2.0, -0.5).unit();
+        let theta = 70_f32.to_radians();
+        let p = V3(0.3, -1.0, 2.0);
+        let p1 = p * Matrix::rotate_axis(axis, theta);
+        let p2 = p.rotate_about_axis(axis, theta);
+        assert_approx_eq!(p1.0, p2.0, EPSILON=0.0001);
+        assert_approx_eq!(p1.1, p2.1, EPSILON=0.0001);
+        assert_approx_eq!(p1.2, p2.2, EPSILON=0.0001);
+    }
+
     #[test]
     fn transform_composition() {
         let matrix = MatrixBuilder::new()
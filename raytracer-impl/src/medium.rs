@@ -0,0 +1,676 @@
+use std::f32::consts::PI;
+use std::io::{ self, Read, Write };
+use std::sync::Arc;
+
+use rand::{ Rng, RngCore };
+
+use crate::implementation::AABB;
+use crate::materials::orthonormal_basis;
+use crate::noise::Perlin;
+use crate::types::{ IntoArc, Ray, V3 };
+
+// Participating media
+//
+// Light travelling through a medium (e.g. wax, skin, milk or murky water) is absorbed and scattered by the
+// particles it meets along the way. Rays inside a medium take a random walk: the distance to the next particle
+// is sampled from the density of the medium, and each particle scatters the ray in a new direction picked by
+// the phase function, until the ray finds its way out of the medium again.
+// The coefficients differ for each RGB channel, so distances are sampled for one channel picked at random,
+// and the weight of the sample corrects for the other channels (the "hero" channel is picked with equal chance).
+//
+// See: https://pbr-book.org/4ed/Volume_Scattering
+// See: "Practical and Controllable Subsurface Scattering for Production Path Tracing", Chiang, Kutz & Burley (2016)
+
+/// A homogeneous medium, with the same density throughout
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Medium {
+    // Absorption and scattering coefficients of each RGB channel, per unit distance
+    sigma_a: V3,
+    sigma_s: V3,
+    // Henyey-Greenstein asymmetry parameter (see with_anisotropy)
+    g: f32,
+}
+
+/// The outcome of sampling the distance travelled through a medium (see Medium::sample_distance)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediumEvent {
+    /// The ray is scattered by a particle {t} along the ray, and carries {weight} of its light on
+    Scatter { t: f32, weight: V3 },
+    /// The ray passes through the medium, and carries {weight} of its light on
+    Pass { weight: V3 },
+}
+
+impl Medium {
+    /// A medium which absorbs {sigma_a} and scatters {sigma_s} of the light passing through it, per unit distance
+    pub fn new(sigma_a: V3, sigma_s: V3) -> Self {
+        assert!(sigma_a.xyz().iter().chain(sigma_s.xyz().iter()).all(|&c| c >= 0.0), "medium coefficients must not be negative");
+        Self { sigma_a, sigma_s, g: 0.0 }
+    }
+
+    /// A medium which appears {albedo} colored overall, after light has scattered many times inside it,
+    /// where light travels {mean_free_path} (for each RGB channel) between particles on average.
+    /// Longer paths let light travel further beneath the surface, making the medium more translucent.
+    pub fn from_albedo(albedo: V3, mean_free_path: V3) -> Self {
+        // NOTE: Chiang et al. 2016 fit the single scattering albedo which gives the multiple scattering {albedo}
+        let single_scattering_albedo = |a: f32| {
+            let a = a.clamp(0.0, 1.0);
+            let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            (1.0 - s * s).clamp(0.0, 1.0)
+        };
+        let sigma_t = |d: f32| {
+            assert!(d > 0.0, "mean free path must be greater than 0.0");
+            1.0 / d
+        };
+        let sigma_t = V3(sigma_t(mean_free_path.0), sigma_t(mean_free_path.1), sigma_t(mean_free_path.2));
+        let rho = V3(single_scattering_albedo(albedo.0), single_scattering_albedo(albedo.1), single_scattering_albedo(albedo.2));
+        Self::new(sigma_t * (V3::ONE - rho), sigma_t * rho)
+    }
+
+    /// Sets the asymmetry of scattering, from -1.0 (light bounces back) through 0.0 (every direction equally) to 1.0 (light carries on forwards)
+    pub fn with_anisotropy(mut self, g: f32) -> Self {
+        assert!(g > -1.0 && g < 1.0, "anisotropy must be between -1.0 and 1.0");
+        self.g = g;
+        self
+    }
+
+    /// The extinction coefficient: the fraction of light absorbed or scattered out of a ray per unit distance
+    pub fn sigma_t(&self) -> V3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    /// The fraction of light which passes straight through {distance} of the medium (Beer-Lambert law)
+    pub fn transmittance(&self, distance: f32) -> V3 {
+        // NOTE: Channels which pass all light stay clear over infinite distances
+        let channel = |sigma_t: f32| if sigma_t == 0.0 { 1.0 } else { (-sigma_t * distance).exp() };
+        let V3(r, g, b) = self.sigma_t();
+        V3(channel(r), channel(g), channel(b))
+    }
+
+    /// Samples how far a ray travels through the medium before it is scattered, where the medium ends {t_max} along the ray.
+    /// NOTE: Distances are measured in units of the ray direction, which should be a unit vector.
+    pub fn sample_distance(&self, t_max: f32, rng: &mut dyn RngCore) -> MediumEvent {
+        let sigma_t = self.sigma_t().xyz();
+        let channel = rng.random_range(0..3);
+        let t = -(1.0 - rng.random::<f32>()).ln() / sigma_t[channel];
+        let transmittance = self.transmittance(t.min(t_max));
+        let V3(r, g, b) = transmittance;
+        if t < t_max {
+            // The chance of scattering at t, averaged over the channels which could have been picked
+            let pdf = (sigma_t[0] * r + sigma_t[1] * g + sigma_t[2] * b) / 3.0;
+            MediumEvent::Scatter { t, weight: self.sigma_s * transmittance / pdf }
+        } else {
+            // The chance of passing through, averaged over the channels
+            let pdf = (r + g + b) / 3.0;
+            MediumEvent::Pass { weight: transmittance / pdf }
+        }
+    }
+
+    /// Picks the direction a ray travelling in {direction} is scattered in by a particle of the medium.
+    /// Directions are picked in proportion to the phase function, so the weight of the sample doesn't change.
+    pub fn sample_scattering(&self, direction: V3, rng: &mut dyn RngCore) -> V3 {
+        sample_henyey_greenstein(direction.unit(), self.g, rng)
+    }
+}
+
+/// The Henyey-Greenstein phase function: the share of scattered light which is deflected by an angle with {cos_theta},
+/// per unit solid angle, for the asymmetry {g}
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.max(0.0).sqrt())
+}
+
+/// Picks a direction scattered from the unit vector {direction}, following the Henyey-Greenstein phase function
+pub fn sample_henyey_greenstein(direction: V3, g: f32, rng: &mut dyn RngCore) -> V3 {
+    let (u1, u2) = (rng.random::<f32>(), rng.random::<f32>());
+    let cos_theta = if g.abs() < 1.0e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    let (t, b) = orthonormal_basis(direction);
+    t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + direction * cos_theta
+}
+
+// Volumes
+//
+// Media which fill regions of the scene rather than the interior of shapes: a homogeneous fog throughout the scene,
+// and bounded heterogeneous volumes (e.g. smoke or clouds) whose density varies from point to point.
+// Rays are followed through the media with delta tracking: collisions are sampled against a majorant, the highest
+// extinction along the ray, and each collision is real (absorbing or scattering the ray) or null (the ray carries on)
+// in proportion to the extinction at that point. Shadow rays estimate the transmittance with ratio tracking instead,
+// which weights the ray by the chance of a null collision at each point rather than picking one.
+// The majorant differs for each RGB channel, so the highest channel is used, and the weight of each sample corrects
+// for the channels with lower extinction.
+//
+// See: https://pbr-book.org/4ed/Light_Transport_II_Volume_Rendering/Volume_Scattering_Integrators
+// See: "Monte Carlo Methods for Volumetric Light Transport Simulation", Novák et al. (2018)
+
+/// The density of a volume at each point, e.g. a noise field or a voxel grid
+pub trait Density: Send + Sync {
+    /// Returns the density at point {p}, where the volume spans the unit cube from (0, 0, 0) to (1, 1, 1)
+    fn density(&self, p: V3) -> f32;
+    /// Returns the highest density anywhere in the volume
+    fn max_density(&self) -> f32;
+}
+
+crate::types::derive_into_arc!(trait Density);
+
+/// The same density throughout the volume
+pub struct UniformDensity(pub f32);
+
+impl Density for UniformDensity {
+    fn density(&self, _p: V3) -> f32 {
+        self.0
+    }
+
+    fn max_density(&self) -> f32 {
+        self.0
+    }
+}
+
+/// Billowing density from 0.0 to 1.0 following a fractal noise field, e.g. smoke or clouds.
+/// Wispy edges fade out towards the sides of the volume, so the volume doesn't look boxed in.
+#[derive(Clone)]
+pub struct NoiseDensity {
+    noise: Perlin,
+    frequency: f32,
+    octaves: u32,
+    coverage: f32,
+}
+
+impl NoiseDensity {
+    pub fn new(seed: u64) -> Self {
+        Self { noise: Perlin::new(seed), frequency: 4.0, octaves: 4, coverage: 0.5 }
+    }
+
+    /// Sets the number of billows across the volume
+    pub fn with_frequency(mut self, frequency: f32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    /// Sets how much of the volume is filled, from 0.0 (empty) to 1.0 (full)
+    pub fn with_coverage(mut self, coverage: f32) -> Self {
+        assert!((0.0..=1.0).contains(&coverage), "coverage must be between 0.0 and 1.0");
+        self.coverage = coverage;
+        self
+    }
+}
+
+impl Density for NoiseDensity {
+    fn density(&self, p: V3) -> f32 {
+        // Fade out over the outer tenth of the volume on each side
+        let edge = p.xyz().iter().map(|&x| x.min(1.0 - x)).fold(f32::MAX, f32::min);
+        let falloff = (edge * 10.0).clamp(0.0, 1.0);
+        let n = self.noise.fbm(p * self.frequency, self.octaves);
+        ((n + self.coverage - 0.5) * 2.0 * falloff).clamp(0.0, 1.0)
+    }
+
+    fn max_density(&self) -> f32 {
+        1.0
+    }
+}
+
+/// Density sampled from a grid of voxels spanning the volume, interpolated between the centres of the voxels.
+/// Grids are stored as binary files by VoxelGrid::write, e.g. exported from a fluid simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelGrid {
+    size: [usize; 3],
+    // Densities ordered by x, then y, then z
+    values: Vec<f32>,
+    max_density: f32,
+}
+
+/// Identifies the binary layout written by VoxelGrid::write. Increment when the layout changes.
+const VOXEL_GRID_FORMAT_VERSION: u32 = 1;
+
+/// The most voxels read from a file, so that malformed files can't exhaust memory
+const MAX_VOXELS: usize = 1 << 28;
+
+impl VoxelGrid {
+    /// A grid of {size} voxels, with {values} ordered by x, then y, then z
+    pub fn new(size: [usize; 3], values: Vec<f32>) -> Self {
+        assert!(size.iter().all(|&n| n > 0), "voxel grid must not be empty");
+        assert_eq!(values.len(), size[0] * size[1] * size[2], "voxel grid has the wrong number of values");
+        assert!(values.iter().all(|&v| v >= 0.0), "voxel densities must not be negative");
+        let max_density = values.iter().cloned().fold(0.0, f32::max);
+        Self { size, values, max_density }
+    }
+
+    /// A grid of {size} voxels, with the density at the centre of each voxel given by {density}
+    pub fn from_fn(size: [usize; 3], density: impl Fn(V3) -> f32) -> Self {
+        let [nx, ny, nz] = size;
+        let values = (0..nz)
+            .flat_map(|z| (0..ny).flat_map(move |y| (0..nx).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| density(V3((x as f32 + 0.5) / nx as f32, (y as f32 + 0.5) / ny as f32, (z as f32 + 0.5) / nz as f32)))
+            .collect();
+        Self::new(size, values)
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    fn value(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[x + self.size[0] * (y + self.size[1] * z)]
+    }
+
+    /// Writes the grid in a compact binary format, which VoxelGrid::read loads
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&VOXEL_GRID_FORMAT_VERSION.to_le_bytes())?;
+        for n in self.size {
+            writer.write_all(&(n as u32).to_le_bytes())?;
+        }
+        for value in &self.values {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads a grid written by VoxelGrid::write
+    pub fn read(reader: &mut dyn Read) -> io::Result<VoxelGrid> {
+        let read_u32 = |reader: &mut dyn Read| -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let version = read_u32(reader)?;
+        if version != VOXEL_GRID_FORMAT_VERSION {
+            return Err(invalid(format!("VoxelGrid::read: unsupported format version {}", version)));
+        }
+        let size = [read_u32(reader)? as usize, read_u32(reader)? as usize, read_u32(reader)? as usize];
+        let count = size.iter().try_fold(1usize, |count, &n| count.checked_mul(n)).unwrap_or(usize::MAX);
+        if count == 0 || count > MAX_VOXELS {
+            return Err(invalid(format!("VoxelGrid::read: unexpected size {:?}", size)));
+        }
+        let values = (0..count)
+            .map(|_| read_u32(reader).map(f32::from_bits))
+            .collect::<io::Result<Vec<_>>>()?;
+        if values.iter().any(|v| !(v.is_finite() && *v >= 0.0)) {
+            return Err(invalid("VoxelGrid::read: densities must be finite and not negative".into()));
+        }
+        Ok(VoxelGrid::new(size, values))
+    }
+}
+
+impl Density for VoxelGrid {
+    fn density(&self, p: V3) -> f32 {
+        // Trilinear interpolation between the centres of the 8 nearest voxels, clamped to the edges of the grid
+        let [nx, ny, nz] = self.size;
+        let cell = |x: f32, n: usize| {
+            let x = (x * n as f32 - 0.5).clamp(0.0, (n - 1) as f32);
+            let i = (x as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), x - i as f32)
+        };
+        let (x0, x1, fx) = cell(p.0, nx);
+        let (y0, y1, fy) = cell(p.1, ny);
+        let (z0, z1, fz) = cell(p.2, nz);
+        let lerp = |a: f32, b: f32, f: f32| a + (b - a) * f;
+        let plane = |z: usize| lerp(
+            lerp(self.value(x0, y0, z), self.value(x1, y0, z), fx),
+            lerp(self.value(x0, y1, z), self.value(x1, y1, z), fx),
+            fy,
+        );
+        lerp(plane(z0), plane(z1), fz)
+    }
+
+    fn max_density(&self) -> f32 {
+        self.max_density
+    }
+}
+
+/// A heterogeneous medium filling the box {bounds}, e.g. a plume of smoke or a cloud.
+/// The coefficients of the {medium} are scaled by the {density} at each point, so a density of 1.0 matches the medium.
+#[derive(Clone)]
+pub struct Volume {
+    bounds: AABB,
+    density: Arc<dyn Density>,
+    medium: Medium,
+    emission: V3,
+}
+
+impl Volume {
+    pub fn new(bounds: AABB, density: impl IntoArc<dyn Density>, medium: Medium) -> Self {
+        assert!(bounds.is_finite(), "volume bounds must be finite");
+        // NOTE: Densities are looked up relative to the extent of the bounds, so a flat or inverted box would give NaN densities
+        let extent = bounds.max - bounds.min;
+        assert!(extent.0 > 0.0 && extent.1 > 0.0 && extent.2 > 0.0, "volume bounds must have a positive extent on every axis");
+        Self { bounds, density: density.into_arc(), medium, emission: V3::ZERO }
+    }
+
+    /// Sets the light emitted per unit distance where the density is 1.0, e.g. for fire or glowing gas
+    pub fn with_emission(mut self, emission: V3) -> Self {
+        self.emission = emission;
+        self
+    }
+
+    fn density_at(&self, p: V3) -> f32 {
+        let local = (p - self.bounds.min) / (self.bounds.max - self.bounds.min);
+        self.density.density(local).clamp(0.0, self.density.max_density())
+    }
+
+    /// The highest extinction of any channel anywhere in the volume
+    fn majorant(&self) -> f32 {
+        max_channel(self.medium.sigma_t()) * self.density.max_density()
+    }
+}
+
+/// The outcome of tracking a ray through the media of a scene (see Media::track)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeEvent {
+    /// The ray is scattered by a particle {t} along the ray, with the asymmetry {g}, and carries {weight} of its light on
+    Scatter { t: f32, weight: V3, g: f32 },
+    /// The ray passes through the media, and carries {weight} of its light on
+    Pass { weight: V3 },
+    /// The ray is absorbed, and carries none of its light on
+    Absorb,
+}
+
+/// The media filling the space between the shapes of a scene: a global fog, and any number of bounded volumes
+#[derive(Clone, Default)]
+pub struct Media {
+    fog: Option<Medium>,
+    volumes: Vec<Volume>,
+}
+
+/// The coefficients of the media at a point
+#[derive(Default)]
+struct MediaPoint {
+    sigma_a: V3,
+    sigma_s: V3,
+    emission: V3,
+    // Asymmetry of the medium which scatters the most light at the point
+    g: f32,
+}
+
+fn max_channel(v: V3) -> f32 {
+    v.0.max(v.1).max(v.2)
+}
+
+fn mean_channel(v: V3) -> f32 {
+    (v.0 + v.1 + v.2) / 3.0
+}
+
+impl Media {
+    /// Fills the whole scene with a homogeneous {fog}.
+    /// NOTE: The fog has no end, so the sky and directional lights fade out beyond a few mean free paths.
+    /// Use it with point and lamp lights, e.g. to show the beams of lamps.
+    pub fn set_fog(&mut self, fog: Medium) {
+        self.fog = Some(fog);
+    }
+
+    pub fn add_volume(&mut self, volume: Volume) {
+        self.volumes.push(volume);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fog.is_none() && self.volumes.is_empty()
+    }
+
+    /// The coefficients at point {p}, inside {volumes}, including the fog if {with_fog}
+    fn at(&self, p: V3, volumes: &[&Volume], with_fog: bool) -> MediaPoint {
+        let fog = self.fog.filter(|_| with_fog);
+        let mut point = fog.map_or_else(Default::default, |fog| MediaPoint {
+            sigma_a: fog.sigma_a,
+            sigma_s: fog.sigma_s,
+            emission: V3::ZERO,
+            g: fog.g,
+        });
+        let mut strongest = mean_channel(point.sigma_s);
+        for volume in volumes {
+            let density = volume.density_at(p);
+            if density <= 0.0 {
+                continue;
+            }
+            let sigma_s = volume.medium.sigma_s * density;
+            point.sigma_a = point.sigma_a + volume.medium.sigma_a * density;
+            point.sigma_s = point.sigma_s + sigma_s;
+            point.emission = point.emission + volume.emission * density;
+            if mean_channel(sigma_s) > strongest {
+                strongest = mean_channel(sigma_s);
+                point.g = volume.medium.g;
+            }
+        }
+        point
+    }
+
+    /// Splits the unit length ray {ray} from {t_min} to {t_max} into the spans where the same volumes overlap,
+    /// calling {track_span} with the range, the majorant and the volumes of each span until it returns false.
+    /// The majorant includes the fog if {with_fog}.
+    fn for_each_span(&self, ray: Ray, t_min: f32, t_max: f32, with_fog: bool, mut track_span: impl FnMut(f32, f32, f32, &[&Volume]) -> bool) {
+        let fog_majorant = self.fog.filter(|_| with_fog).map_or(0.0, |fog| max_channel(fog.sigma_t()));
+        let ranges = self.volumes.iter()
+            .filter_map(|volume| volume.bounds.hit_aabb_range(ray, t_min, t_max).map(|range| (volume, range)))
+            .collect::<Vec<_>>();
+        let mut boundaries = ranges.iter()
+            .flat_map(|&(_, (t0, t1))| [t0, t1])
+            .chain([t_min, t_max])
+            .collect::<Vec<_>>();
+        boundaries.sort_by(f32::total_cmp);
+        boundaries.dedup();
+        for span in boundaries.windows(2) {
+            let (t0, t1) = (span[0], span[1]);
+            let mid = (t0 + t1) * 0.5;
+            let volumes = ranges.iter()
+                .filter(|&&(_, (v0, v1))| v0 <= mid && mid <= v1)
+                .map(|&(volume, _)| volume)
+                .collect::<Vec<_>>();
+            let majorant = fog_majorant + volumes.iter().map(|volume| volume.majorant()).sum::<f32>();
+            if majorant > 0.0 && !track_span(t0, t1, majorant, &volumes) {
+                return;
+            }
+        }
+    }
+
+    /// Follows {ray} through the media from {t_min} to where it reaches a surface {t_max} along the ray,
+    /// using delta tracking to find where it is scattered or absorbed, if anywhere.
+    /// Returns what happened to the ray, and the light emitted by the media along the way.
+    pub fn track(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> (VolumeEvent, V3) {
+        // Track in units of distance, along a unit length ray
+        let length = ray.direction.length();
+        let unit_ray = Ray::new(ray.origin, ray.direction / length);
+        let mut weight = V3::ONE;
+        let mut emission = V3::ZERO;
+        let mut event = None;
+        self.for_each_span(unit_ray, t_min * length, t_max * length, true, |t0, t1, majorant, volumes| {
+            let mut t = t0;
+            loop {
+                t -= (1.0 - rng.random::<f32>()).ln() / majorant;
+                if t >= t1 {
+                    return true;
+                }
+                let point = self.at(unit_ray.point_at_parameter(t), volumes, true);
+                emission = emission + weight * point.emission / majorant;
+                // Pick a real or null collision in proportion to the extinction averaged over the channels
+                let sigma_n = V3::ONE * majorant - point.sigma_a - point.sigma_s;
+                let (p_absorb, p_scatter) = (mean_channel(point.sigma_a) / majorant, mean_channel(point.sigma_s) / majorant);
+                let u = rng.random::<f32>();
+                if u < p_absorb {
+                    event = Some(VolumeEvent::Absorb);
+                    return false;
+                }
+                if u < p_absorb + p_scatter {
+                    weight = weight * point.sigma_s / (majorant * p_scatter);
+                    event = Some(VolumeEvent::Scatter { t: t / length, weight, g: point.g });
+                    return false;
+                }
+                weight = weight * sigma_n / (majorant * (1.0 - p_absorb - p_scatter));
+            }
+        });
+        (event.unwrap_or(VolumeEvent::Pass { weight }), emission)
+    }
+
+    /// Estimates the fraction of light which passes through the media along {ray} from {t_min} to {t_max},
+    /// with the fog in closed form and ratio tracking through the volumes
+    pub fn transmittance(&self, ray: Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> V3 {
+        let length = ray.direction.length();
+        let unit_ray = Ray::new(ray.origin, ray.direction / length);
+        let (t_min, t_max) = (t_min * length, t_max * length);
+        let mut transmittance = self.fog.map_or(V3::ONE, |fog| fog.transmittance(t_max - t_min));
+        if self.volumes.is_empty() || transmittance == V3::ZERO {
+            return transmittance;
+        }
+        self.for_each_span(unit_ray, t_min, t_max, false, |t0, t1, majorant, volumes| {
+            let mut t = t0;
+            loop {
+                t -= (1.0 - rng.random::<f32>()).ln() / majorant;
+                if t >= t1 {
+                    return true;
+                }
+                let point = self.at(unit_ray.point_at_parameter(t), volumes, false);
+                transmittance = transmittance * (V3::ONE - (point.sigma_a + point.sigma_s) / majorant);
+                // Russian roulette: stop tracking rays which are nearly blocked
+                if max_channel(transmittance) < 0.1 {
+                    let survive = max_channel(transmittance) * 10.0;
+                    if rng.random::<f32>() >= survive {
+                        transmittance = V3::ZERO;
+                        return false;
+                    }
+                    transmittance = transmittance / survive;
+                }
+            }
+        });
+        transmittance
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn henyey_greenstein_sampling() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 20000;
+        for g in [-0.6, 0.0, 0.8] {
+            // The mean cosine of the scattering angle is g
+            let direction = V3(1.0, 2.0, -2.0).unit();
+            let mean_cos = (0..n)
+                .map(|_| V3::dot(sample_henyey_greenstein(direction, g, &mut rng), direction))
+                .sum::<f32>() / n as f32;
+            assert!((mean_cos - g).abs() < 0.02, "{g} {mean_cos}");
+
+            // The phase function integrates to 1.0 over the sphere
+            let steps = 1000;
+            let total = (0..steps)
+                .map(|i| -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32)
+                .map(|cos_theta| henyey_greenstein(cos_theta, g) * 2.0 * PI * 2.0 / steps as f32)
+                .sum::<f32>();
+            assert!((total - 1.0).abs() < 0.01, "{g} {total}");
+        }
+    }
+
+    #[test]
+    fn medium_distance_sampling() {
+        // Averaged over many samples, the weight of passing through is the transmittance,
+        // and scattering is the rest of the light less the absorbed light
+        let medium = Medium::new(V3(0.2, 0.5, 1.0), V3(1.0, 0.5, 0.1));
+        let mut rng = StdRng::seed_from_u64(2);
+        let n = 100000;
+        let (mut passed, mut scattered) = (V3::ZERO, V3::ZERO);
+        for _ in 0..n {
+            match medium.sample_distance(1.0, &mut rng) {
+                MediumEvent::Pass { weight } => passed = passed + weight,
+                MediumEvent::Scatter { t, weight } => {
+                    assert!(t < 1.0);
+                    scattered = scattered + weight;
+                },
+            }
+        }
+        let expected_passed = medium.transmittance(1.0);
+        let expected_scattered = medium.sigma_s / medium.sigma_t() * (V3::ONE - expected_passed);
+        for (actual, expected) in [(passed / n as f32, expected_passed), (scattered / n as f32, expected_scattered)] {
+            for (a, e) in actual.xyz().into_iter().zip(expected.xyz()) {
+                assert!((a - e).abs() < 0.01, "{actual:?} {expected:?}");
+            }
+        }
+
+        // Albedo and mean free path map onto the coefficients
+        let skin = Medium::from_albedo(V3(0.8, 0.5, 0.4), V3(1.0, 0.5, 0.25));
+        let V3(r, g, b) = skin.sigma_t() - V3(1.0, 2.0, 4.0);
+        assert!(r.abs() < 1.0e-5 && g.abs() < 1.0e-5 && b.abs() < 1.0e-5);
+        // White media scatter all of the light, and black media absorb all of it
+        assert!(Medium::from_albedo(V3::ONE, V3::ONE).sigma_a.0 < 1.0e-4);
+        assert!(Medium::from_albedo(V3::ZERO, V3::ONE).sigma_s.0 < 1.0e-4);
+    }
+
+    #[test]
+    fn voxel_grid_file_and_interpolation() {
+        let grid = VoxelGrid::from_fn([4, 3, 2], |p| p.0 + p.1 * 2.0 + p.2 * 4.0);
+        let mut bytes = vec![];
+        grid.write(&mut bytes).unwrap();
+        let read = VoxelGrid::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, grid);
+
+        // Densities match the voxels at their centres, and change linearly between them
+        assert!((grid.density(V3(0.375, 0.5, 0.25)) - (0.375 + 1.0 + 1.0)).abs() < 1.0e-5);
+        assert!((grid.density(V3(0.5, 0.5, 0.5)) - (0.5 + 1.0 + 2.0)).abs() < 1.0e-5);
+        assert!((grid.max_density() - (0.875 + 5.0 / 3.0 + 3.0)).abs() < 1.0e-5);
+
+        // Truncated and unknown files are rejected
+        assert!(VoxelGrid::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        bytes[0] = 9;
+        assert!(VoxelGrid::read(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn volume_tracking() {
+        // A ray through a box of uniform density, 2.0 units across
+        let medium = Medium::new(V3(0.2, 0.4, 0.6), V3(0.5, 0.3, 0.1));
+        let bounds = AABB::from_min_max(V3(-1.0, -1.0, -1.0), V3(1.0, 1.0, 1.0));
+        let mut media = Media::default();
+        media.add_volume(Volume::new(bounds, UniformDensity(0.5), medium).with_emission(V3(0.2, 0.0, 0.1)));
+        // NOTE: Rays don't need to be unit length
+        let ray = Ray::new(V3(0.0, 0.0, -5.0), V3(0.0, 0.0, 2.0));
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 100000;
+        let (mut passed, mut scattered, mut emitted, mut transmittance) = (V3::ZERO, 0, V3::ZERO, V3::ZERO);
+        for _ in 0..n {
+            let (event, emission) = media.track(ray, 0.0, f32::MAX, &mut rng);
+            match event {
+                VolumeEvent::Pass { weight } => passed = passed + weight,
+                VolumeEvent::Scatter { t, .. } => {
+                    assert!((2.0..=3.0).contains(&t));
+                    scattered += 1;
+                },
+                VolumeEvent::Absorb => {},
+            }
+            emitted = emitted + emission;
+            transmittance = transmittance + media.transmittance(ray, 0.0, f32::MAX, &mut rng);
+        }
+        let sigma_t = medium.sigma_t() * 0.5;
+        let expected = Medium::new(sigma_t, V3::ZERO).transmittance(2.0);
+        let expected_emitted = V3(0.1, 0.0, 0.05) * (V3::ONE - expected) / sigma_t;
+        for (actual, expected) in [(passed / n as f32, expected), (transmittance / n as f32, expected), (emitted / n as f32, expected_emitted)] {
+            for (a, e) in actual.xyz().into_iter().zip(expected.xyz()) {
+                assert!((a - e).abs() < 0.01, "{actual:?} {expected:?}");
+            }
+        }
+        assert!(scattered > 0);
+
+        // Rays which miss the volume pass straight through, and fog has no end
+        let miss = Ray::new(V3(0.0, 5.0, -5.0), V3::POS_Z);
+        assert_eq!(media.track(miss, 0.0, f32::MAX, &mut rng), (VolumeEvent::Pass { weight: V3::ONE }, V3::ZERO));
+        media.set_fog(Medium::new(V3(0.1, 0.1, 0.0), V3::ZERO));
+        assert_eq!(media.transmittance(miss, 0.0, f32::MAX, &mut rng), V3(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "volume bounds must have a positive extent on every axis")]
+    fn volume_rejects_flat_bounds() {
+        let flat = AABB::from_min_max(V3(0.0, 1.0, 0.0), V3(1.0, 1.0, 1.0));
+        Volume::new(flat, UniformDensity(1.0), Medium::new(V3::ONE, V3::ONE));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::types::V3;
+
+// Gradient noise
+//
+// Based on Ken Perlin's "Improved Noise" reference implementation
+// See: https://mrl.cs.nyu.edu/~perlin/noise/
+// The permutation table is shuffled from a seed so that scenes can ask for distinct (but repeatable) noise fields.
+
+#[derive(Clone)]
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut table = [0u8; 256];
+        for (i, p) in table.iter_mut().enumerate() {
+            *p = i as u8;
+        }
+
+        // Fisher-Yates shuffle driven by splitmix64
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        };
+        for i in (1..256).rev() {
+            let j = (next() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        let mut perm = [0u8; 512];
+        for (i, p) in perm.iter_mut().enumerate() {
+            *p = table[i & 255];
+        }
+        Perlin { perm }
+    }
+
+    /// Samples the noise field at point {p}.
+    /// Returns a value in the (approximate) range of -1..1
+    pub fn noise(&self, p: V3) -> f32 {
+        let (xf, yf, zf) = (p.0.floor(), p.1.floor(), p.2.floor());
+        // Find the unit cube which contains the point
+        let xi = (xf as i32 & 255) as usize;
+        let yi = (yf as i32 & 255) as usize;
+        let zi = (zf as i32 & 255) as usize;
+        // Find the relative position of the point in the cube
+        let (x, y, z) = (p.0 - xf, p.1 - yf, p.2 - zf);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let perm = &self.perm;
+        let a  = perm[xi] as usize + yi;
+        let aa = perm[a] as usize + zi;
+        let ab = perm[a + 1] as usize + zi;
+        let b  = perm[xi + 1] as usize + yi;
+        let ba = perm[b] as usize + zi;
+        let bb = perm[b + 1] as usize + zi;
+
+        // Blend the gradient contributions from all eight corners of the cube
+        lerp(w,
+            lerp(v,
+                lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1.0, y, z)),
+                lerp(u, grad(perm[ab], x, y - 1.0, z), grad(perm[bb], x - 1.0, y - 1.0, z))),
+            lerp(v,
+                lerp(u, grad(perm[aa + 1], x, y, z - 1.0), grad(perm[ba + 1], x - 1.0, y, z - 1.0)),
+                lerp(u, grad(perm[ab + 1], x, y - 1.0, z - 1.0), grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    /// Fractal Brownian motion: sums {octaves} layers of noise,
+    /// each at double the frequency and half the amplitude of the last.
+    /// Returns a value in the (approximate) range of -1..1
+    pub fn fbm(&self, p: V3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total_amplitude = 0.0;
+        let mut p = p;
+        for _ in 0..octaves {
+            sum += self.noise(p) * amplitude;
+            total_amplitude += amplitude;
+            amplitude *= 0.5;
+            p = p * 2.0;
+        }
+        if total_amplitude > 0.0 { sum / total_amplitude } else { 0.0 }
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    // 6t^5 - 15t^4 + 10t^3
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn grad(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    // Convert the low 4 bits of the hash into one of 12 gradient directions
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
@@ -0,0 +1,279 @@
+use crate::noise::Perlin;
+use crate::texture::ColorMap;
+use crate::types::{ Ray, V2, V3 };
+use crate::implementation::{ Hitable, HitRecord, AABB, MatId, TexId };
+
+// Height maps
+
+/// A 2D grid of height samples in the range of 0..1, stored row by row (X across, Z down)
+pub struct HeightMap {
+    pub width: usize,
+    pub depth: usize,
+    pub values: Vec<f32>,
+}
+
+impl HeightMap {
+    /// Creates a height map from the luminance of each pixel in the color map
+    pub fn from_color_map(map: &ColorMap) -> HeightMap {
+        let values = map.pixels.iter()
+            .map(|c| 0.2126 * c.0 + 0.7152 * c.1 + 0.0722 * c.2)
+            .collect();
+        HeightMap { width: map.width, depth: map.height, values }
+    }
+
+    /// Creates a {width} by {depth} height map by sampling fractal noise across the XZ plane
+    pub fn from_noise(noise: &Perlin, width: usize, depth: usize, frequency: f32, octaves: u32) -> HeightMap {
+        let mut values = Vec::with_capacity(width * depth);
+        for z in 0..depth {
+            for x in 0..width {
+                let p = V3(x as f32 / width as f32, 0.0, z as f32 / depth as f32) * frequency;
+                // Map noise from the -1..1 range into 0..1
+                values.push((noise.fbm(p, octaves) * 0.5 + 0.5).clamp(0.0, 1.0));
+            }
+        }
+        HeightMap { width, depth, values }
+    }
+
+    fn get(&self, x: usize, z: usize) -> f32 {
+        self.values[z * self.width + x]
+    }
+}
+
+// Heightfield
+
+/// Terrain described by a grid of heights.
+/// The field is centered on the origin in the XZ plane and rises from y = 0.
+/// Each grid cell is split into two triangles, which are found by walking the ray across the grid.
+pub struct Heightfield {
+    width: usize,
+    depth: usize,
+    heights: Vec<f32>,
+    normals: Vec<V3>,
+    // The min/max height of the four corners of each cell
+    cell_ranges: Vec<(f32, f32)>,
+    cell_size: V2,
+    aabb: AABB,
+    mat_id: MatId,
+    tex_id: TexId,
+}
+
+impl Heightfield {
+    /// Creates a heightfield from the given height map.
+    /// {size} gives the extent of the field on the X axis, its maximum height, and extent on the Z axis.
+    pub fn new(map: HeightMap, size: V3, mat_id: MatId, tex_id: TexId) -> Self {
+        let (width, depth) = (map.width, map.depth);
+        if width < 2 || depth < 2 {
+            panic!("Heightfield::new: height map must be at least 2x2 samples");
+        }
+        let cell_size = V2(size.0 / (width - 1) as f32, size.2 / (depth - 1) as f32);
+
+        let heights = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| map.get(x, z) * size.1)
+            .collect::<Vec<_>>();
+
+        // Calculate smooth vertex normals from the slope of the neighbouring samples
+        let height_at = |x: usize, z: usize| heights[z * width + x];
+        let mut normals = Vec::with_capacity(width * depth);
+        for z in 0..depth {
+            for x in 0..width {
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                let (z0, z1) = (z.saturating_sub(1), (z + 1).min(depth - 1));
+                let dh_dx = (height_at(x1, z) - height_at(x0, z)) / ((x1 - x0) as f32 * cell_size.0);
+                let dh_dz = (height_at(x, z1) - height_at(x, z0)) / ((z1 - z0) as f32 * cell_size.1);
+                normals.push(V3(-dh_dx, 1.0, -dh_dz).unit());
+            }
+        }
+
+        let mut cell_ranges = Vec::with_capacity((width - 1) * (depth - 1));
+        for z in 0..(depth - 1) {
+            for x in 0..(width - 1) {
+                let corners = [height_at(x, z), height_at(x + 1, z), height_at(x, z + 1), height_at(x + 1, z + 1)];
+                let min = corners.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = corners.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                cell_ranges.push((min, max));
+            }
+        }
+
+        let min_height = cell_ranges.iter().map(|r| r.0).fold(f32::INFINITY, f32::min);
+        let max_height = cell_ranges.iter().map(|r| r.1).fold(f32::NEG_INFINITY, f32::max);
+        let aabb = AABB::from_min_max(
+            V3(-size.0 / 2.0, min_height, -size.2 / 2.0),
+            V3(size.0 / 2.0, max_height, size.2 / 2.0)
+        );
+
+        Heightfield { width, depth, heights, normals, cell_ranges, cell_size, aabb, mat_id, tex_id }
+    }
+
+    fn vertex(&self, x: usize, z: usize) -> V3 {
+        V3(
+            self.aabb.min.0 + x as f32 * self.cell_size.0,
+            self.heights[z * self.width + x],
+            self.aabb.min.2 + z as f32 * self.cell_size.1
+        )
+    }
+
+    fn uv(&self, x: usize, z: usize) -> V2 {
+        // NOTE: Row 0 of the height map corresponds to the top of a texture
+        V2(x as f32 / (self.width - 1) as f32, 1.0 - z as f32 / (self.depth - 1) as f32)
+    }
+
+    /// Tests for a hit against the two triangles of the cell at {x, z}
+    fn hit_cell(&self, ray: Ray, t_min: f32, t_max: f32, x: usize, z: usize) -> Option<HitRecord> {
+        let corners = [(x, z), (x + 1, z), (x + 1, z + 1), (x, z + 1)];
+        let tris = [[corners[0], corners[1], corners[2]], [corners[0], corners[2], corners[3]]];
+        let mut closest: Option<HitRecord> = None;
+        for tri in tris {
+            let [a, b, c] = tri.map(|(x, z)| self.vertex(x, z));
+            let Some((t, u, v)) = intersect_tri(ray, a, b, c) else { continue };
+            let limit = closest.as_ref().map(|h| h.t).unwrap_or(t_max);
+            if t < t_min || t > limit {
+                continue;
+            }
+            let w = 1.0 - u - v;
+            let [na, nb, nc] = tri.map(|(x, z)| self.normals[z * self.width + x]);
+            let [uva, uvb, uvc] = tri.map(|(x, z)| self.uv(x, z));
+            closest = Some(HitRecord {
+                entity_id: None,
+                t,
+                p: ray.point_at_parameter(t),
+                normal: (na * w + nb * u + nc * v).unit(),
+                uv: uva * w + uvb * u + uvc * v,
+                // NOTE: u increases along x, and v decreases along z (see uv)
+                tangent: V3::POS_X,
+                bitangent: V3::NEG_Z,
+                mat_id: self.mat_id,
+                tex_id: self.tex_id,
+                tex_key: None,
+            });
+        }
+        closest
+    }
+}
+
+/// Möller–Trumbore ray/triangle intersection.
+/// Returns {t} and the barycentric weights of vertices {b} and {c}.
+fn intersect_tri(ray: Ray, a: V3, b: V3, c: V3) -> Option<(f32, f32, f32)> {
+    let edge_ab = b - a;
+    let edge_ac = c - a;
+    let p = V3::cross(ray.direction, edge_ac);
+    let det = V3::dot(edge_ab, p);
+    if det.abs() < 1.0e-9 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - a;
+    let u = V3::dot(s, p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = V3::cross(s, edge_ab);
+    let v = V3::dot(ray.direction, q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    Some((V3::dot(edge_ac, q) * inv_det, u, v))
+}
+
+// Ref: "A Fast Voxel Traversal Algorithm for Ray Tracing", Amanatides & Woo
+impl Hitable for Heightfield {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (t_start, t_end) = self.aabb.hit_aabb_range(ray, t_min, t_max)?;
+
+        // Find the cell the ray enters the field in
+        let cells = [self.width - 1, self.depth - 1];
+        let size = [self.cell_size.0, self.cell_size.1];
+        let entry = ray.point_at_parameter(t_start);
+        let entry = [entry.0 - self.aabb.min.0, entry.2 - self.aabb.min.2];
+        let direction = [ray.direction.0, ray.direction.2];
+
+        let mut cell = [0usize; 2];
+        let mut step = [0isize; 2];
+        let mut t_next = [f32::INFINITY; 2];
+        let mut t_delta = [f32::INFINITY; 2];
+        for axis in 0..2 {
+            let c = ((entry[axis] / size[axis]).floor().max(0.0) as usize).min(cells[axis] - 1);
+            cell[axis] = c;
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                t_delta[axis] = size[axis] / direction[axis];
+                t_next[axis] = t_start + ((c + 1) as f32 * size[axis] - entry[axis]) / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                t_delta[axis] = -size[axis] / direction[axis];
+                t_next[axis] = t_start + (c as f32 * size[axis] - entry[axis]) / direction[axis];
+            }
+        }
+
+        let mut t_cell_start = t_start;
+        loop {
+            let t_cell_end = t_next[0].min(t_next[1]).min(t_end);
+
+            // Skip cells where the ray passes entirely above or below the terrain
+            let (min, max) = self.cell_ranges[cell[1] * cells[0] + cell[0]];
+            let y0 = ray.point_at_parameter(t_cell_start).1;
+            let y1 = ray.point_at_parameter(t_cell_end).1;
+            if y0.min(y1) <= max && y0.max(y1) >= min
+                && let Some(hit) = self.hit_cell(ray, t_min, t_max, cell[0], cell[1]) {
+                return Some(hit);
+            }
+
+            // Step into the next cell
+            let axis = if t_next[0] < t_next[1] { 0 } else { 1 };
+            if t_next[axis] > t_end {
+                return None;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= cells[axis] as isize {
+                return None;
+            }
+            cell[axis] = next as usize;
+            t_cell_start = t_next[axis];
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    fn aabb(&self) -> AABB {
+        self.aabb.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::implementation::{ Hitable, MatId, TexId };
+    use crate::types::{ Ray, V3 };
+    use super::{ Heightfield, HeightMap };
+
+    fn make_slope() -> Heightfield {
+        // A ramp rising along the X axis from 0 to 1
+        let (width, depth) = (11, 11);
+        let values = (0..depth).flat_map(|_| (0..width).map(|x| x as f32 / (width - 1) as f32)).collect();
+        let map = HeightMap { width, depth, values };
+        Heightfield::new(map, V3(10.0, 1.0, 10.0), MatId::default(), TexId::default())
+    }
+
+    #[test]
+    fn hit_from_above() {
+        let field = make_slope();
+        let ray = Ray::new(V3(0.0, 10.0, 0.0), V3::NEG_Y);
+        let hit = field.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!((hit.p.1 - 0.5).abs() < 0.001);
+        assert!(hit.normal.1 > 0.9);
+    }
+
+    #[test]
+    fn hit_across_cells() {
+        let field = make_slope();
+        // Travel along the X axis at half height, hitting the ramp halfway along
+        let ray = Ray::new(V3(-20.0, 0.5, 0.3), V3::POS_X);
+        let hit = field.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!(hit.p.0.abs() < 0.001);
+    }
+
+    #[test]
+    fn miss_above() {
+        let field = make_slope();
+        let ray = Ray::new(V3(20.0, 1.5, 0.3), V3::NEG_X);
+        assert!(field.hit(ray, 0.0, f32::MAX).is_none());
+    }
+}
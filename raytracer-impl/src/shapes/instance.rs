@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::bvh::{ Bvh4, BvhBounds, BvhObject };
+use crate::matrix::Matrix;
+use crate::types::{ IntoArc, Ray };
+use crate::implementation::{ Hitable, HitRecord, AABB };
+
+// Object instancing
+//
+// An InstanceSet places many copies of a single shared object into the scene.
+// The object keeps its own acceleration structure (e.g. the mesh BVH of a MeshObject) in object space,
+// while the set builds a second, top-level BVH over the world-space bounds of each instance.
+// Rays are transformed into object space for each candidate instance, so the object is only stored once.
+
+struct Instance {
+    // Transforms world space into object space.
+    // NOTE: Hit points are recovered from the world ray (as {t} is unchanged by the transform)
+    // and normals are transformed using the transpose of this matrix.
+    to_local: Matrix,
+    // Transforms object space into world space, for the tangents of a hit
+    to_world: Matrix,
+}
+
+pub struct InstanceSet {
+    object: Arc<dyn Hitable>,
+    instances: Vec<Instance>,
+    bvh: Bvh4,
+}
+
+impl InstanceSet {
+    /// Creates a set with one instance of {object} for each transform.
+    /// Transforms may be any combination of translation, rotation and scale (see MatrixBuilder).
+    pub fn new(object: impl IntoArc<dyn Hitable>, transforms: impl IntoIterator<Item = Matrix>) -> Self {
+        let object = object.into_arc();
+        let object_aabb = object.aabb();
+        if object_aabb.corners().iter().any(|c| c.xyz().iter().any(|v| !v.is_finite())) {
+            panic!("InstanceSet::new: instanced objects must have finite bounds");
+        }
+
+        let bounds = transforms.into_iter()
+            .map(|to_world| InstanceBounds {
+                to_world,
+                aabb: AABB::from_vertices_iter(object_aabb.corners().into_iter().map(|c| c * to_world)),
+            })
+            .collect::<Vec<_>>();
+        if bounds.is_empty() {
+            panic!("InstanceSet::new: at least one transform is required");
+        }
+
+        let bvh = Bvh4::from(&bounds);
+        let instances = bounds.iter()
+            .map(|b| Instance { to_local: b.to_world.inverse_affine(), to_world: b.to_world })
+            .collect();
+
+        InstanceSet { object, instances, bvh }
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+}
+
+impl Hitable for InstanceSet {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.bvh.closest_hit(ray, t_min, t_max, |i, t_max| {
+            let instance = &self.instances[i];
+            // Shift the ray into object space
+            let local_ray = Ray::new(ray.origin * instance.to_local, instance.to_local.transform_direction(ray.direction));
+            let mut hit = self.object.hit(local_ray, t_min, t_max)?;
+            // Shift the hit back into world space
+            hit.p = ray.point_at_parameter(hit.t);
+            hit.normal = instance.to_local.transform_normal_by_inverse(hit.normal).unit();
+            hit.tangent = instance.to_world.transform_direction(hit.tangent).unit();
+            hit.bitangent = instance.to_world.transform_direction(hit.bitangent).unit();
+            Some(hit)
+        })
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f32, t_max: f32, is_opaque: &dyn Fn(&HitRecord) -> bool) -> bool {
+        self.bvh.any_hit(ray, t_min, t_max, |i| {
+            let instance = &self.instances[i];
+            let local_ray = Ray::new(ray.origin * instance.to_local, instance.to_local.transform_direction(ray.direction));
+            self.object.occluded(local_ray, t_min, t_max, is_opaque)
+        })
+    }
+
+    fn aabb(&self) -> AABB {
+        self.bvh.aabb()
+    }
+}
+
+// Allow instance bounds to be used with the Bvh algorithm
+
+struct InstanceBounds {
+    to_world: Matrix,
+    aabb: AABB,
+}
+
+impl BvhObject for InstanceBounds {
+    fn calculate_bounds(&self) -> BvhBounds {
+        let centroid = self.aabb.min * 0.5 + self.aabb.max * 0.5;
+        BvhBounds { centroid, aabb: self.aabb.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::implementation::{ Hitable, MatId, TexId };
+    use crate::matrix::MatrixBuilder;
+    use crate::shapes::Sphere;
+    use crate::types::{ Ray, V3 };
+    use super::InstanceSet;
+
+    #[test]
+    fn hit_scaled_and_translated_instances() {
+        let set = InstanceSet::new(
+            Sphere::new(1.0, MatId::default(), TexId::default()),
+            (0..10).map(|i| MatrixBuilder::new()
+                .scale(1.0, 2.0, 1.0)
+                .translate(i as f32 * 10.0, 0.0, 0.0)
+                .done())
+        );
+        assert_eq!(set.instance_count(), 10);
+
+        // Fire down onto the stretched sphere at x = 30
+        let ray = Ray::new(V3(30.0, 10.0, 0.0), V3::NEG_Y);
+        let hit = set.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!((hit.p.1 - 2.0).abs() < 0.001);
+        assert!((hit.t - 8.0).abs() < 0.001);
+        assert!(hit.normal.1 > 0.999);
+
+        // Fire along the row, hitting the first instance
+        let ray = Ray::new(V3(-10.0, 0.0, 0.0), V3::POS_X);
+        let hit = set.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!((hit.p.0 + 1.0).abs() < 0.001);
+
+        // Fire between the instances
+        let ray = Ray::new(V3(5.0, 10.0, 0.0), V3::NEG_Y);
+        assert!(set.hit(ray, 0.0, f32::MAX).is_none());
+        assert!(!set.occluded(ray, 0.0, f32::MAX, &|_| true));
+
+        // Occlusion checks stop at {t_max} and respect the opacity test
+        let ray = Ray::new(V3(30.0, 10.0, 0.0), V3::NEG_Y);
+        assert!(set.occluded(ray, 0.0, f32::MAX, &|_| true));
+        assert!(!set.occluded(ray, 0.0, 7.0, &|_| true));
+        assert!(!set.occluded(ray, 0.0, f32::MAX, &|_| false));
+    }
+}
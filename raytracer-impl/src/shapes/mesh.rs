@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use crate::bvh::{ Bvh, BvhBounds, BvhObject };
+use crate::bvh::{ Bvh, Bvh4, BvhBounds, BvhBuildStrategy, BvhHit, BvhObject };
 use crate::types::{ IntoArc, Ray, V2, V3 };
-use crate::implementation::{ Hitable, HitRecord, AABB, MatId, TexId };
+use crate::implementation::{ Hitable, HitRecord, AABB, MatId, TexId, Texture };
 
 // Triangle Mesh BVH
 
@@ -12,97 +13,492 @@ struct MeshTriHit {
     normal: V3,
     t: f32,
     uv: V2,
+    tangent: V3,
+    bitangent: V3,
     tex_key: Option<usize>,
 }
 
-fn try_hit_tri(ray: Ray, t_min: f32, t_max: f32, tri: &MeshTri) -> Option<MeshTriHit> {
+impl BvhHit for MeshTriHit {
+    fn t(&self) -> f32 {
+        self.t
+    }
+}
 
-    // compute normal of and area of the triangle
-    // NOTE: not normalized! Used for area calculations
-    let edge_ab = tri.b - tri.a;
-    let edge_ac = tri.c - tri.a;
-    let normal = V3::cross(edge_ab, edge_ac);
+/// Ray data shared by every triangle test, precomputed once per ray.
+/// See: "Watertight Ray/Triangle Intersection", Woop, Benthin & Wald (2013)
+/// https://jcgt.org/published/0002/01/05/
+struct WatertightRay {
+    ray: Ray,
+    // Axis indices, with {kz} the dimension where the ray direction is largest
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    // Shear constants which transform the ray direction to the +Z axis
+    sx: f32,
+    sy: f32,
+    sz: f32,
+}
 
-    // Step 1: find the intersection {P}
-    let t = super::plane::intersect_plane(ray, tri.a, normal)?;
-    if t < t_min || t > t_max {
-        // Triangle is outside the search range
-        return None;
+impl WatertightRay {
+    fn new(ray: Ray) -> Self {
+        let d = ray.direction.xyz();
+        let kz = if d[0].abs() > d[1].abs() {
+            if d[0].abs() > d[2].abs() { 0 } else { 2 }
+        } else if d[1].abs() > d[2].abs() { 1 } else { 2 };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        // Swap kx and ky to preserve the winding direction of triangles
+        if d[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let sz = 1.0 / d[kz];
+        WatertightRay { ray, kx, ky, kz, sx: d[kx] * sz, sy: d[ky] * sz, sz }
     }
 
-    let p = ray.point_at_parameter(t);
+    /// Returns the {t} of the intersection and the barycentric weights of vertices {a}, {b} and {c}.
+    /// Hits exactly on a shared edge or vertex are reported for every triangle sharing it,
+    /// so rays can never leak between neighbouring triangles.
+    fn intersect(&self, a: V3, b: V3, c: V3) -> Option<(f32, [f32; 3])> {
+        let (kx, ky, kz) = (self.kx, self.ky, self.kz);
 
-    // Step 2: determine if p is inside the triangle
-    // by checking to see if it is to the left of each edge
+        // Translate vertices relative to the ray origin
+        let a = (a - self.ray.origin).xyz();
+        let b = (b - self.ray.origin).xyz();
+        let c = (c - self.ray.origin).xyz();
 
-    // edge ab
-    let normal_abp = V3::cross(edge_ab, p - tri.a);
-    if V3::dot(normal, normal_abp) < 0.0 {
-        // P is to the right of edge ab
-        return None;
-    }
+        // Shear and scale vertices into ray space
+        let (ax, ay) = (a[kx] - self.sx * a[kz], a[ky] - self.sy * a[kz]);
+        let (bx, by) = (b[kx] - self.sx * b[kz], b[ky] - self.sy * b[kz]);
+        let (cx, cy) = (c[kx] - self.sx * c[kz], c[ky] - self.sy * c[kz]);
 
-    // edge bc
-    let normal_bcp = V3::cross(tri.c - tri.b, p - tri.b);
-    if V3::dot(normal, normal_bcp) < 0.0 {
-        // P is to the right of edge bc
-        return None;
+        // Calculate scaled barycentric coordinates
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        // Recalculate in double precision when the ray falls exactly on an edge
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            let (ax, ay, bx, by, cx, cy) = (ax as f64, ay as f64, bx as f64, by as f64, cx as f64, cy as f64);
+            u = (cx * by - cy * bx) as f32;
+            v = (ax * cy - ay * cx) as f32;
+            w = (bx * ay - by * ax) as f32;
+        }
+
+        // Perform edge tests (either winding is accepted)
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+
+        let det = u + v + w;
+        if det == 0.0 {
+            // Ray is parallel to the triangle
+            return None;
+        }
+
+        // Calculate the scaled z-coordinates of the vertices to find the hit distance
+        let (az, bz, cz) = (self.sz * a[kz], self.sz * b[kz], self.sz * c[kz]);
+        let t = (u * az + v * bz + w * cz) / det;
+
+        Some((t, [u / det, v / det, w / det]))
     }
+}
+
+fn try_hit_tri(ray: &WatertightRay, t_min: f32, t_max: f32, mesh: &Mesh, face: &MeshFace, face_normal: V3, [tangent, bitangent]: [V3; 2]) -> Option<MeshTriHit> {
 
-    // edge ca
-    let normal_cap = V3::cross(tri.a - tri.c, p - tri.c);
-    if V3::dot(normal, normal_cap) < 0.0 {
-        // P is to the right of edge ca
+    let [a, b, c] = mesh.face_vertices(face);
+    let (t, [wa, wb, wc]) = ray.intersect(a, b, c)?;
+    if t < t_min || t > t_max {
+        // Triangle is outside the search range
         return None;
     }
 
-    // Calculate uv/barycentric coordinates.
-    // Given a triangle ABC and point P:
-    //                  C
-    //                u P w
-    //               A  v  B
-    // u = {area of CAP} / {area of ABC}
-    // v = {area of ABP} / {area of ABC}
-    // w = {area of BCP} / {area of ABC}
-    // P = wA + uB + vC
-    let area2 = normal.length();
-    let u = normal_cap.length() / area2;
-    let v = normal_abp.length() / area2;
-    let w = 1.0 - u - v;
-    let uv = (tri.a_uv * w) + (tri.b_uv * u) + (tri.c_uv * v);
-    let tex_key = tri.tex_key.clone();
+    let p = ray.ray.point_at_parameter(t);
+
+    // Interpolate uv coordinates using the barycentric weight of each vertex
+    let uv = match face.uvs {
+        Some([a_uv, b_uv, c_uv]) => {
+            let uvs = &mesh.uvs;
+            (uvs[a_uv as usize] * wa) + (uvs[b_uv as usize] * wb) + (uvs[c_uv as usize] * wc)
+        },
+        None => V2::ZERO,
+    };
+    let tex_key = face.tex_key;
 
-    return Some(MeshTriHit { p, normal: normal.unit(), t, uv, tex_key })
+    // Interpolate vertex normals for smooth shading, if present
+    let normal = match face.normals {
+        Some([a_normal, b_normal, c_normal]) => {
+            let normals = &mesh.normals;
+            ((normals[a_normal as usize] * wa) + (normals[b_normal as usize] * wb) + (normals[c_normal as usize] * wc)).unit()
+        },
+        None => face_normal,
+    };
+
+    Some(MeshTriHit { p, normal, t, uv, tangent, bitangent, tex_key })
 }
 
 struct MeshBvhRoot {
-    bvh: Bvh,
+    bvh: Bvh4,
     mesh: Arc<Mesh>,
+    // Precomputed (flat) face normal of each triangle
+    // NOTE: Edge vectors are deliberately not precomputed. The watertight test (see WatertightRay) works from the
+    // shared vertex positions, so triangles either side of an edge compute exactly the same edge function for it.
+    // Edges stored per triangle are measured from a different corner in each neighbour and rounded differently,
+    // which is how rays leaked through shared edges before.
+    face_normals: Vec<V3>,
+    // Precomputed tangent and bitangent of each triangle, or V3::ZERO for triangles without uv coordinates
+    face_tangents: Vec<[V3; 2]>,
 }
 
 impl MeshBvhRoot {
-    fn new(mesh: impl IntoArc<Mesh>) -> MeshBvhRoot {
+    fn new(mesh: impl IntoArc<Mesh>, strategy: BvhBuildStrategy) -> MeshBvhRoot {
         let mesh = mesh.into_arc();
+        let bvh = mesh.build_bvh(strategy);
+        MeshBvhRoot::from_bvh(mesh, bvh)
+    }
+
+    fn from_bvh(mesh: Arc<Mesh>, bvh: Bvh) -> MeshBvhRoot {
+        assert!(bvh.object_count() == mesh.faces.len(), "MeshBvhRoot::from_bvh: BVH was built over {} faces, but the mesh has {}", bvh.object_count(), mesh.faces.len());
         MeshBvhRoot {
-            bvh: Bvh::from(&mesh.tris),
+            bvh: Bvh4::from_bvh(bvh),
+            face_normals: mesh.faces.iter().map(|face| mesh.face_normal(face)).collect(),
+            face_tangents: mesh.faces.iter().map(|face| mesh.face_tangents(face).unwrap_or([V3::ZERO; 2])).collect(),
             mesh,
         }
     }
 
     fn try_hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<MeshTriHit> {
-        let tris = &self.mesh.tris;
-        self.bvh.hit_candidates(ray, t_min, t_max)
-            .filter_map(|candidate| try_hit_tri(ray, t_min, t_max, &tris[candidate.object_index]))
-            .reduce(|closest, next| {
-                if next.t < closest.t { next } else { closest }
-            })
+        let faces = &self.mesh.faces;
+        let watertight_ray = WatertightRay::new(ray);
+        self.bvh.closest_hit(ray, t_min, t_max, |i, t_max| {
+            try_hit_tri(&watertight_ray, t_min, t_max, &self.mesh, &faces[i], self.face_normals[i], self.face_tangents[i])
+        })
+    }
+
+    /// Finds any triangle hit by the ray, not necessarily the closest
+    fn try_hit_any(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<MeshTriHit> {
+        let faces = &self.mesh.faces;
+        let watertight_ray = WatertightRay::new(ray);
+        let mut found = None;
+        self.bvh.any_hit(ray, t_min, t_max, |i| {
+            found = try_hit_tri(&watertight_ray, t_min, t_max, &self.mesh, &faces[i], self.face_normals[i], self.face_tangents[i]);
+            found.is_some()
+        });
+        found
+    }
+}
+
+// Allow mesh faces to be used with the Bvh algorithm
+
+struct MeshBvhFace<'a> {
+    mesh: &'a Mesh,
+    face: &'a MeshFace,
+}
+
+impl BvhObject for MeshBvhFace<'_> {
+    fn calculate_bounds(&self) -> BvhBounds {
+        let [a, b, c] = self.mesh.face_vertices(self.face);
+        let aabb = AABB::from_vertices(&[a, b, c]);
+        let centroid = (a + b + c) * 0.33333;
+        BvhBounds { centroid, aabb }
     }
 }
 
 // Mesh
 
+/// An indexed triangle mesh.
+/// Vertex positions, normals and uv coordinates are stored once and shared between faces by index.
+#[derive(Clone, Default)]
 pub struct Mesh {
-    pub tris: Vec<MeshTri>,
+    pub vertices: Vec<V3>,
+    pub normals: Vec<V3>,
+    pub uvs: Vec<V2>,
+    pub faces: Vec<MeshFace>,
+}
+
+#[derive(Clone, Default)]
+pub struct MeshFace {
+    // Indices of the face vertices (a, b, c) in the mesh vertex collection
+    pub vertices: [u32; 3],
+    // Indices of the vertex normals in the mesh normal collection, for smooth shading
+    pub normals: Option<[u32; 3]>,
+    // Indices of the vertex uv coordinates in the mesh uv collection
+    pub uvs: Option<[u32; 3]>,
+    pub tex_key: Option<usize>,
+}
+
+impl Mesh {
+    /// Creates an indexed mesh from a list of standalone triangles.
+    /// Vertices with exactly the same position are shared between triangles.
+    pub fn from_tris(tris: impl IntoIterator<Item=MeshTri>) -> Mesh {
+        let mut mesh = Mesh::default();
+        let mut vertex_indices: HashMap<[u32; 3], u32> = HashMap::new();
+        for tri in tris {
+            let vertices = [tri.a, tri.b, tri.c].map(|v| {
+                *vertex_indices.entry(position_key(v)).or_insert_with(|| {
+                    mesh.vertices.push(v);
+                    (mesh.vertices.len() - 1) as u32
+                })
+            });
+            let normals = tri.normals.map(|normals| normals.map(|n| {
+                mesh.normals.push(n);
+                (mesh.normals.len() - 1) as u32
+            }));
+            let uvs = [tri.a_uv, tri.b_uv, tri.c_uv].map(|uv| {
+                mesh.uvs.push(uv);
+                (mesh.uvs.len() - 1) as u32
+            });
+            mesh.faces.push(MeshFace { vertices, normals, uvs: Some(uvs), tex_key: tri.tex_key });
+        }
+        mesh
+    }
+
+    /// Builds a BVH over the faces of the mesh, which can be stored and later passed to MeshObject::new_with_bvh
+    pub fn build_bvh(&self, strategy: BvhBuildStrategy) -> Bvh {
+        let bvh_faces = self.faces.iter()
+            .map(|face| MeshBvhFace { mesh: self, face })
+            .collect::<Vec<_>>();
+        Bvh::from_with_strategy(&bvh_faces, strategy)
+    }
+
+    pub fn face_vertices(&self, face: &MeshFace) -> [V3; 3] {
+        face.vertices.map(|i| self.vertices[i as usize])
+    }
+
+    /// The unit normal of the face, following the winding order of the vertices
+    pub fn face_normal(&self, face: &MeshFace) -> V3 {
+        let [a, b, c] = self.face_vertices(face);
+        V3::cross(b - a, c - a).unit()
+    }
+
+    /// The unit tangent and bitangent of the face: the directions in which its u and v coordinates increase.
+    /// Returns None for faces without uv coordinates, or where the uv coordinates don't span an area.
+    // See: http://www.terathon.com/code/tangent.html
+    pub fn face_tangents(&self, face: &MeshFace) -> Option<[V3; 2]> {
+        let [a_uv, b_uv, c_uv] = face.uvs?.map(|i| self.uvs[i as usize]);
+        let [a, b, c] = self.face_vertices(face);
+        let (e1, e2) = (b - a, c - a);
+        let (d1, d2) = (b_uv - a_uv, c_uv - a_uv);
+        let det = d1.0 * d2.1 - d2.0 * d1.1;
+        if det == 0.0 {
+            return None;
+        }
+        let tangent = (e1 * d2.1 - e2 * d1.1) / det;
+        let bitangent = (e2 * d1.0 - e1 * d2.0) / det;
+        Some([tangent.unit(), bitangent.unit()])
+    }
+
+    /// Generates smooth vertex normals for any faces which don't already have them.
+    /// Each vertex normal is the average of the normals of the faces which share that vertex, weighted by
+    /// the angle of each face at that vertex. Faces which meet at an angle greater than {crease_angle} (radians)
+    /// are not smoothed together, so hard edges remain sharp.
+    pub fn generate_normals(&mut self, crease_angle: f32) {
+        let face_normals = self.faces.iter().map(|face| self.face_normal(face)).collect::<Vec<_>>();
+
+        // Collect the angle-weighted normal of each face sharing a vertex
+        let mut vertex_faces: Vec<Vec<(usize, V3)>> = vec![Vec::new(); self.vertices.len()];
+        for (i, face) in self.faces.iter().enumerate() {
+            let [a, b, c] = face.vertices;
+            for (vertex, next, prev) in [(a, b, c), (b, c, a), (c, a, b)] {
+                let [vertex_p, next_p, prev_p] = [vertex, next, prev].map(|i| self.vertices[i as usize]);
+                let angle = V3::theta(next_p - vertex_p, prev_p - vertex_p);
+                let weight = if angle.is_nan() { 0.0 } else { angle };
+                vertex_faces[vertex as usize].push((i, face_normals[i] * weight));
+            }
+        }
+
+        // Share identical generated normals between faces
+        let mut normal_indices: HashMap<[u32; 3], u32> = HashMap::new();
+        let min_cos = crease_angle.cos();
+        let Mesh { faces, normals, .. } = self;
+        for (i, face) in faces.iter_mut().enumerate() {
+            if face.normals.is_some() {
+                continue;
+            }
+            let face_normal = face_normals[i];
+            face.normals = Some(face.vertices.map(|vertex| {
+                let normal = vertex_faces[vertex as usize].iter()
+                    .filter(|(j, _)| V3::dot(face_normals[*j], face_normal) >= min_cos)
+                    .fold(V3::ZERO, |sum, (_, n)| sum + *n)
+                    .unit();
+                let normal = if normal == V3::ZERO { face_normal } else { normal };
+                *normal_indices.entry(position_key(normal)).or_insert_with(|| {
+                    normals.push(normal);
+                    (normals.len() - 1) as u32
+                })
+            }));
+        }
+    }
+}
+
+// Tessellation & displacement
+
+/// A corner of a face: the indices of its vertex, normal and uv coordinates
+#[derive(Clone, Copy)]
+struct FaceCorner {
+    vertex: u32,
+    normal: Option<u32>,
+    uv: Option<u32>,
+}
+
+/// Shares the midpoints of split edges between the faces either side of the edge, so that the mesh has no cracks
+struct EdgeMidpoints<T> {
+    midpoints: HashMap<(u32, u32), u32>,
+    midpoint_of: fn(T, T) -> T,
+}
+
+impl<T: Copy> EdgeMidpoints<T> {
+    fn new(midpoint_of: fn(T, T) -> T) -> Self {
+        Self { midpoints: HashMap::new(), midpoint_of }
+    }
+
+    fn get(&mut self, a: u32, b: u32, values: &mut Vec<T>) -> u32 {
+        let midpoint_of = self.midpoint_of;
+        *self.midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+            values.push(midpoint_of(values[a as usize], values[b as usize]));
+            (values.len() - 1) as u32
+        })
+    }
+}
+
+impl Mesh {
+    /// Splits faces in half along their edges until no edge is longer than {max_edge_length}.
+    /// Whether an edge is split only depends on its length, so the faces either side of an edge always agree
+    /// and the mesh stays closed. Vertex normals and uv coordinates are interpolated onto the new vertices.
+    pub fn tessellate(&mut self, max_edge_length: f32) {
+        assert!(max_edge_length > 0.0, "Mesh::tessellate: max_edge_length must be positive");
+        let mut vertex_midpoints = EdgeMidpoints::new(|a: V3, b: V3| (a + b) * 0.5);
+        let mut normal_midpoints = EdgeMidpoints::new(|a: V3, b: V3| {
+            let n = (a + b).unit();
+            if n == V3::ZERO { a } else { n }
+        });
+        let mut uv_midpoints = EdgeMidpoints::new(|a: V2, b: V2| (a + b) * 0.5);
+
+        let mut faces = std::mem::take(&mut self.faces);
+        loop {
+            let mut split_any = false;
+            let mut next_faces = Vec::with_capacity(faces.len());
+            for face in faces {
+                let corners = [0, 1, 2].map(|i| FaceCorner {
+                    vertex: face.vertices[i],
+                    normal: face.normals.map(|normals| normals[i]),
+                    uv: face.uvs.map(|uvs| uvs[i]),
+                });
+
+                // Walk around the face, adding the midpoint of each edge which is too long
+                let mut polygon = Vec::with_capacity(6);
+                let mut is_midpoint = Vec::with_capacity(6);
+                for i in 0..3 {
+                    let (a, b) = (corners[i], corners[(i + 1) % 3]);
+                    polygon.push(a);
+                    is_midpoint.push(false);
+                    let length = (self.vertices[b.vertex as usize] - self.vertices[a.vertex as usize]).length();
+                    if length > max_edge_length {
+                        polygon.push(FaceCorner {
+                            vertex: vertex_midpoints.get(a.vertex, b.vertex, &mut self.vertices),
+                            normal: a.normal.zip(b.normal).map(|(na, nb)| normal_midpoints.get(na, nb, &mut self.normals)),
+                            uv: a.uv.zip(b.uv).map(|(ua, ub)| uv_midpoints.get(ua, ub, &mut self.uvs)),
+                        });
+                        is_midpoint.push(true);
+                    }
+                }
+                if polygon.len() == 3 {
+                    next_faces.push(face);
+                    continue;
+                }
+                split_any = true;
+
+                // Triangulate the polygon, keeping the winding order of the face
+                let n = polygon.len();
+                let tri = |i: usize, j: usize, k: usize| [polygon[i % n], polygon[j % n], polygon[k % n]];
+                let tris = match n {
+                    // One split edge: join its midpoint to the opposite corner
+                    4 => {
+                        let m = is_midpoint.iter().position(|&m| m).unwrap();
+                        vec![tri(m, m + 1, m + 2), tri(m, m + 2, m + 3)]
+                    },
+                    // Two split edges: cut off the corner between the midpoints, then split the remaining quad
+                    // along its shorter diagonal
+                    5 => {
+                        let c = (0..n).find(|&i| is_midpoint[(i + n - 1) % n] && is_midpoint[(i + 1) % n]).unwrap();
+                        let [m1, m2, q1, q2] = [c + n - 1, c + 1, c + 2, c + 3].map(|i| i % n);
+                        let p = |i: usize| self.vertices[polygon[i].vertex as usize];
+                        if (p(q2) - p(m2)).length() < (p(q1) - p(m1)).length() {
+                            vec![tri(m1, c, m2), tri(m1, m2, q2), tri(m2, q1, q2)]
+                        } else {
+                            vec![tri(m1, c, m2), tri(m1, m2, q1), tri(m1, q1, q2)]
+                        }
+                    },
+                    // Three split edges: four triangles, one in each corner and one in the middle
+                    _ => vec![tri(0, 1, 5), tri(1, 2, 3), tri(3, 4, 5), tri(1, 3, 5)],
+                };
+                for corners in tris {
+                    next_faces.push(MeshFace {
+                        vertices: corners.map(|c| c.vertex),
+                        normals: face.normals.map(|_| corners.map(|c| c.normal.unwrap())),
+                        uvs: face.uvs.map(|_| corners.map(|c| c.uv.unwrap())),
+                        tex_key: face.tex_key,
+                    });
+                }
+            }
+            faces = next_faces;
+            if !split_any {
+                break;
+            }
+        }
+        self.faces = faces;
+    }
+
+    /// Moves each vertex along its normal by the height given by the red channel of {displacement}, times {scale}.
+    /// The texture is sampled at the position, normal and uv coordinates of the vertex.
+    /// Vertices shared by faces with different normals or uv coordinates (e.g. on hard edges or uv seams) move by
+    /// the average of each, so that the mesh stays closed. Normals are then generated for the new surface (see generate_normals).
+    /// NOTE: Only vertices move, so tessellate the mesh first to follow the detail of the texture.
+    /// Faces either side of a hard edge move in different directions, stretching the faces along the edge,
+    /// so displace meshes with smooth normals where possible.
+    pub fn displace(&mut self, displacement: &dyn Texture, scale: f32, crease_angle: f32) {
+        let mut offsets = vec![(V3::ZERO, 0.0, 0); self.vertices.len()];
+        for face in self.faces.iter() {
+            let face_normal = self.face_normal(face);
+            for i in 0..3 {
+                let vertex = face.vertices[i] as usize;
+                let normal = face.normals.map_or(face_normal, |normals| self.normals[normals[i] as usize]);
+                let hit_record = HitRecord {
+                    entity_id: None,
+                    t: 0.0,
+                    p: self.vertices[vertex],
+                    normal,
+                    uv: face.uvs.map_or(V2::ZERO, |uvs| self.uvs[uvs[i] as usize]),
+                    tangent: V3::ZERO,
+                    bitangent: V3::ZERO,
+                    mat_id: MatId::default(),
+                    tex_id: TexId::default(),
+                    tex_key: face.tex_key,
+                };
+                let (normal_sum, height_sum, count) = &mut offsets[vertex];
+                *normal_sum = *normal_sum + normal;
+                *height_sum += displacement.value(&hit_record).0;
+                *count += 1;
+            }
+        }
+        for (vertex, (normal_sum, height_sum, count)) in self.vertices.iter_mut().zip(offsets) {
+            if count > 0 {
+                *vertex = *vertex + normal_sum.unit() * (height_sum / count as f32 * scale);
+            }
+        }
+
+        // The old normals no longer follow the surface
+        self.normals.clear();
+        for face in self.faces.iter_mut() {
+            face.normals = None;
+        }
+        self.generate_normals(crease_angle);
+    }
+}
+
+/// Identifies identical positions
+// NOTE: add 0.0 to treat -0.0 and 0.0 as the same position
+fn position_key(v: V3) -> [u32; 3] {
+    [(v.0 + 0.0).to_bits(), (v.1 + 0.0).to_bits(), (v.2 + 0.0).to_bits()]
 }
 
 impl IntoArc<Mesh> for Mesh {
@@ -111,6 +507,7 @@ impl IntoArc<Mesh> for Mesh {
     }
 }
 
+/// A standalone triangle, for building meshes by hand (see [Mesh::from_tris])
 #[derive(Clone, Default)]
 pub struct MeshTri {
     pub a: V3,
@@ -119,19 +516,11 @@ pub struct MeshTri {
     pub a_uv: V2,
     pub b_uv: V2,
     pub c_uv: V2,
+    // Vertex normals for smooth shading (a, b, c)
+    pub normals: Option<[V3; 3]>,
     pub tex_key: Option<usize>,
 }
 
-// Allow MeshTri to be used with the Bvh algorithm
-
-impl BvhObject for MeshTri {
-    fn calculate_bounds(&self) -> BvhBounds {
-        let aabb = AABB::from_vertices(&[self.a, self.b, self.c]);
-        let centroid = (self.a + self.b + self.c) * 0.33333;
-        BvhBounds { centroid, aabb }
-    }
-}
-
 impl MeshTri {
     pub fn from_abc(a: V3, b: V3, c: V3) -> Self {
         Self { a, b, c, ..Default::default() }
@@ -149,33 +538,203 @@ pub struct MeshObject {
 
 impl MeshObject {
     pub fn new(mesh: impl IntoArc<Mesh>, mat_id: MatId, tex_id: TexId) -> Self {
+        MeshObject::new_with_bvh_strategy(mesh, mat_id, tex_id, BvhBuildStrategy::default())
+    }
+
+    /// Creates a mesh object, building its BVH with the given strategy
+    pub fn new_with_bvh_strategy(mesh: impl IntoArc<Mesh>, mat_id: MatId, tex_id: TexId, strategy: BvhBuildStrategy) -> Self {
+        MeshObject {
+            root: Arc::new(MeshBvhRoot::new(mesh, strategy)),
+            mat_id,
+            tex_id,
+        }
+    }
+
+    /// Creates a mesh object from a BVH previously built with Mesh::build_bvh, skipping the build.
+    /// Panics if the BVH was built over a different number of faces.
+    pub fn new_with_bvh(mesh: impl IntoArc<Mesh>, bvh: Bvh, mat_id: MatId, tex_id: TexId) -> Self {
         MeshObject {
-            root: Arc::new(MeshBvhRoot::new(mesh)),
+            root: Arc::new(MeshBvhRoot::from_bvh(mesh.into_arc(), bvh)),
             mat_id,
             tex_id,
         }
     }
 }
 
-impl Hitable for MeshObject {
-    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        // Shift the ray into mesh space
-        let mesh_ray = Ray::new(ray.origin, ray.direction);
-        let mesh_hit = self.root.try_hit(mesh_ray, t_min, t_max)?;
-        Some(HitRecord {
+impl MeshObject {
+    fn hit_record(&self, mesh_hit: MeshTriHit) -> HitRecord {
+        HitRecord {
             entity_id: None,
             // Shift the hit back into world space
             t: mesh_hit.t,
             p: mesh_hit.p,
             normal: mesh_hit.normal,
             uv: mesh_hit.uv,
+            tangent: mesh_hit.tangent,
+            bitangent: mesh_hit.bitangent,
             mat_id: self.mat_id,
             tex_id: self.tex_id,
             tex_key: mesh_hit.tex_key,
-        })
+        }
+    }
+}
+
+impl Hitable for MeshObject {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        // Shift the ray into mesh space
+        let mesh_ray = Ray::new(ray.origin, ray.direction);
+        let mesh_hit = self.root.try_hit(mesh_ray, t_min, t_max)?;
+        Some(self.hit_record(mesh_hit))
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f32, t_max: f32, is_opaque: &dyn Fn(&HitRecord) -> bool) -> bool {
+        // NOTE: Every triangle shares the same material, so any hit will do
+        self.root.try_hit_any(ray, t_min, t_max).is_some_and(|mesh_hit| is_opaque(&self.hit_record(mesh_hit)))
     }
 
     fn aabb(&self) -> AABB {
         self.root.bvh.aabb()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::texture::ColorTexture;
+    use crate::types::{ Ray, V2, V3 };
+    use super::{ Mesh, MeshTri, WatertightRay, try_hit_tri };
+
+    fn hit_any(ray: Ray, mesh: &Mesh) -> bool {
+        let ray = WatertightRay::new(ray);
+        mesh.faces.iter().any(|face| try_hit_tri(&ray, 0.0, f32::MAX, mesh, face, mesh.face_normal(face), [V3::ZERO; 2]).is_some())
+    }
+
+    #[test]
+    fn no_misses_on_shared_edges() {
+        // A quad split along its diagonal, and a fan of triangles sharing a vertex at the origin
+        let quad = Mesh::from_tris([
+            MeshTri::from_abc(V3(-1.0, 0.0, -1.0), V3(1.0, 0.0, -1.0), V3(1.0, 0.0, 1.0)),
+            MeshTri::from_abc(V3(-1.0, 0.0, -1.0), V3(1.0, 0.0, 1.0), V3(-1.0, 0.0, 1.0)),
+        ]);
+        let fan = Mesh::from_tris((0..7).map(|i| {
+            let theta = |i: i32| i as f32 / 7.0 * std::f32::consts::TAU;
+            let p = |i: i32| V3(theta(i).cos(), 0.3, theta(i).sin());
+            MeshTri::from_abc(V3::ZERO, p(i + 1), p(i))
+        }));
+
+        // NOTE: Skip the ends of the diagonal, which lie on the outside edge of the quad
+        for i in 1..100 {
+            let f = i as f32 / 100.0;
+            // Points exactly on the diagonal, cast from above and below at various angles
+            let on_edge = V3(-1.0 + 2.0 * f, 0.0, -1.0 + 2.0 * f);
+            for direction in [V3::NEG_Y, V3::POS_Y, V3(0.3, -1.0, 0.1), V3(-0.7, 1.0, 0.2)] {
+                let origin = on_edge - direction * 3.0;
+                assert!(hit_any(Ray::new(origin, direction), &quad), "missed edge at {on_edge:?} from {direction:?}");
+            }
+            // Rays through the shared vertex of the fan
+            let direction = V3(f - 0.5, -1.0, 0.5 - f * 0.3);
+            assert!(hit_any(Ray::new(V3::ZERO - direction * 2.0, direction), &fan), "missed vertex from {direction:?}");
+        }
+    }
+
+    #[test]
+    fn barycentric_weights() {
+        let ray = WatertightRay::new(Ray::new(V3(0.25, 1.0, 0.25), V3::NEG_Y));
+        let (t, [wa, wb, wc]) = ray.intersect(V3::ZERO, V3(1.0, 0.0, 0.0), V3(0.0, 0.0, 1.0)).unwrap();
+        assert!((t - 1.0).abs() < 0.00001);
+        assert!((wa - 0.5).abs() < 0.00001);
+        assert!((wb - 0.25).abs() < 0.00001);
+        assert!((wc - 0.25).abs() < 0.00001);
+    }
+
+    #[test]
+    fn from_tris_shares_vertices() {
+        let mesh = Mesh::from_tris([
+            MeshTri::from_abc(V3(0.0, 0.0, 0.0), V3(1.0, 0.0, 0.0), V3(1.0, 0.0, 1.0)),
+            MeshTri::from_abc(V3(-0.0, 0.0, 0.0), V3(1.0, 0.0, 1.0), V3(0.0, 0.0, 1.0)),
+        ]);
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.faces[1].vertices, [0, 2, 3]);
+    }
+
+    #[test]
+    fn face_tangents_follow_uvs() {
+        // A triangle on the floor with u along x and v along -z, and the same triangle with mirrored u
+        let tri = |a_uv, b_uv, c_uv| MeshTri { a_uv, b_uv, c_uv, ..MeshTri::from_abc(V3::ZERO, V3(2.0, 0.0, 0.0), V3(0.0, 0.0, -2.0)) };
+        let mesh = Mesh::from_tris([
+            tri(V2(0.0, 0.0), V2(1.0, 0.0), V2(0.0, 1.0)),
+            tri(V2(1.0, 0.0), V2(0.0, 0.0), V2(1.0, 1.0)),
+        ]);
+        assert_eq!(mesh.face_tangents(&mesh.faces[0]), Some([V3::POS_X, V3::NEG_Z]));
+        assert_eq!(mesh.face_tangents(&mesh.faces[1]), Some([V3::NEG_X, V3::NEG_Z]));
+
+        // Faces without distinct uv coordinates have no tangents
+        let mesh = Mesh::from_tris([MeshTri::from_abc(V3::ZERO, V3::POS_X, V3::POS_Z)]);
+        assert_eq!(mesh.face_tangents(&mesh.faces[0]), None);
+    }
+
+    #[test]
+    fn tessellate_and_displace() {
+        // A unit square split along its diagonal
+        let tri = |a, b, c, a_uv, b_uv, c_uv| MeshTri { a_uv, b_uv, c_uv, ..MeshTri::from_abc(a, b, c) };
+        let [p00, p10, p11, p01] = [V3(0.0, 0.0, 0.0), V3(1.0, 0.0, 0.0), V3(1.0, 0.0, -1.0), V3(0.0, 0.0, -1.0)];
+        let [uv00, uv10, uv11, uv01] = [V2(0.0, 0.0), V2(1.0, 0.0), V2(1.0, 1.0), V2(0.0, 1.0)];
+        let mut mesh = Mesh::from_tris([
+            tri(p00, p10, p11, uv00, uv10, uv11),
+            tri(p00, p11, p01, uv00, uv11, uv01),
+        ]);
+        mesh.generate_normals(0.5);
+        mesh.tessellate(0.3);
+
+        // Every edge is short enough, the faces cover the same area facing the same way, and uvs follow the positions
+        let mut edges = HashMap::new();
+        let mut area = 0.0;
+        for face in mesh.faces.iter() {
+            let [a, b, c] = mesh.face_vertices(face);
+            area += V3::cross(b - a, c - a).length() * 0.5;
+            assert!(mesh.face_normal(face).1 > 0.999);
+            for i in 0..3 {
+                let (va, vb) = (face.vertices[i], face.vertices[(i + 1) % 3]);
+                let (pa, pb) = (mesh.vertices[va as usize], mesh.vertices[vb as usize]);
+                assert!((pb - pa).length() <= 0.3);
+                *edges.entry((va.min(vb), va.max(vb))).or_insert(0) += 1;
+                let V2(u, v) = mesh.uvs[face.uvs.unwrap()[i] as usize];
+                assert!((u - pa.0).abs() < 1.0e-5 && (v + pa.2).abs() < 1.0e-5);
+            }
+        }
+        assert!((area - 1.0).abs() < 1.0e-4, "{area}");
+        // No cracks: edges inside the square are shared by two faces, and only the outline is left open
+        let outline = edges.iter()
+            .filter(|&(_, &count)| count == 1)
+            .map(|((a, b), _)| (mesh.vertices[*a as usize] - mesh.vertices[*b as usize]).length())
+            .sum::<f32>();
+        assert!(edges.values().all(|&count| count <= 2));
+        assert!((outline - 4.0).abs() < 1.0e-4, "{outline}");
+
+        // Displacement moves the vertices along their normals
+        let vertex_count = mesh.vertices.len();
+        mesh.displace(&ColorTexture(V3::ONE * 0.5), 0.2, 0.5);
+        assert_eq!(mesh.vertices.len(), vertex_count);
+        assert!(mesh.vertices.iter().all(|v| (v.1 - 0.1).abs() < 1.0e-5));
+        assert!(mesh.faces.iter().all(|face| face.normals.is_some()));
+    }
+
+    #[test]
+    fn generate_normals_respects_crease_angle() {
+        // Two faces meeting at a right angle along the X axis
+        let tris = [
+            MeshTri::from_abc(V3(0.0, 0.0, 0.0), V3(0.0, 0.0, 1.0), V3(1.0, 0.0, 0.0)),
+            MeshTri::from_abc(V3(0.0, 0.0, 0.0), V3(1.0, 0.0, 0.0), V3(0.0, -1.0, 0.0)),
+        ];
+        let mut mesh = Mesh::from_tris(tris.clone());
+        mesh.generate_normals(60_f32.to_radians());
+        let [a, _, _] = mesh.faces[0].normals.unwrap();
+        assert_eq!(mesh.normals[a as usize], V3::POS_Y);
+
+        // Smooth across the edge when the crease angle is wider
+        let mut mesh = Mesh::from_tris(tris);
+        mesh.generate_normals(100_f32.to_radians());
+        let [a, _, _] = mesh.faces[0].normals.unwrap();
+        assert!((mesh.normals[a as usize] - V3(0.0, 1.0, -1.0).unit()).length() < 0.0001);
+    }
+}
@@ -1,7 +1,15 @@
 pub mod mesh;
 pub mod plane;
 pub mod sphere;
+pub mod sdf;
+pub mod heightfield;
+pub mod instance;
+pub mod subdivision;
 
-pub use mesh::{ MeshObject, Mesh, MeshTri };
+pub use mesh::{ MeshObject, Mesh, MeshFace, MeshTri };
 pub use plane::Plane;
-pub use sphere::Sphere;
\ No newline at end of file
+pub use sphere::Sphere;
+pub use sdf::{ SdfObject, Sdf, SdfSphere, SdfBox, SdfTorus, SdfMandelbulb, SdfTranslate, SdfSmoothUnion, SdfTwist, SdfRepeat, SdfNoiseDisplace };
+pub use heightfield::{ Heightfield, HeightMap };
+pub use instance::InstanceSet;
+pub use subdivision::{ SubdivisionCage, CageFace };
\ No newline at end of file
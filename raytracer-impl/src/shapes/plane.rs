@@ -83,6 +83,8 @@ impl Hitable for Plane {
             p,
             normal,
             uv,
+            tangent: self.u_basis,
+            bitangent: self.v_basis,
             mat_id: self.mat_id,
             tex_id: self.tex_id,
             tex_key: None,
@@ -0,0 +1,431 @@
+use std::sync::Arc;
+
+use crate::noise::Perlin;
+use crate::types::{ IntoArc, Ray, V2, V3 };
+use crate::implementation::{ Hitable, HitRecord, AABB, MatId, TexId };
+
+// Signed distance fields
+//
+// Shapes are described as a tree of distance functions, composed from primitives and operators.
+// See: https://iquilezles.org/articles/distfunctions/
+
+pub trait Sdf: Send + Sync {
+    /// Returns the signed distance from point {p} to the surface (negative inside the surface)
+    fn distance(&self, p: V3) -> f32;
+    /// Returns the bounds of the surface in local coordinates
+    fn aabb(&self) -> AABB;
+}
+
+crate::types::derive_into_arc!(trait Sdf);
+
+fn v3_abs(v: V3) -> V3 {
+    V3(v.0.abs(), v.1.abs(), v.2.abs())
+}
+
+fn v3_max(v: V3, f: f32) -> V3 {
+    V3(v.0.max(f), v.1.max(f), v.2.max(f))
+}
+
+fn expand_aabb(aabb: AABB, by: f32) -> AABB {
+    AABB::from_min_max(aabb.min - by, aabb.max + by)
+}
+
+// Primitives
+
+pub struct SdfSphere {
+    pub radius: f32,
+}
+
+impl Sdf for SdfSphere {
+    fn distance(&self, p: V3) -> f32 {
+        p.length() - self.radius
+    }
+
+    fn aabb(&self) -> AABB {
+        AABB::from_min_max(V3::ZERO - self.radius, V3::ZERO + self.radius)
+    }
+}
+
+/// A box with the given half-extents on each axis, with edges rounded by {radius}
+pub struct SdfBox {
+    pub half_extents: V3,
+    pub radius: f32,
+}
+
+impl SdfBox {
+    pub fn new(half_extents: V3) -> Self {
+        SdfBox { half_extents, radius: 0.0 }
+    }
+
+    pub fn rounded(half_extents: V3, radius: f32) -> Self {
+        SdfBox { half_extents, radius }
+    }
+}
+
+impl Sdf for SdfBox {
+    fn distance(&self, p: V3) -> f32 {
+        let q = v3_abs(p) - self.half_extents + self.radius;
+        v3_max(q, 0.0).length() + q.0.max(q.1.max(q.2)).min(0.0) - self.radius
+    }
+
+    fn aabb(&self) -> AABB {
+        AABB::from_min_max(-self.half_extents, self.half_extents)
+    }
+}
+
+/// A torus lying in the XZ plane
+pub struct SdfTorus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+impl Sdf for SdfTorus {
+    fn distance(&self, p: V3) -> f32 {
+        let q = V2((p.0 * p.0 + p.2 * p.2).sqrt() - self.major_radius, p.1);
+        (q.0 * q.0 + q.1 * q.1).sqrt() - self.minor_radius
+    }
+
+    fn aabb(&self) -> AABB {
+        let r = self.major_radius + self.minor_radius;
+        AABB::from_min_max(V3(-r, -self.minor_radius, -r), V3(r, self.minor_radius, r))
+    }
+}
+
+/// The Mandelbulb fractal, using the distance estimator described by
+/// http://blog.hvidtfeldts.net/index.php/2011/09/distance-estimated-3d-fractals-v-the-mandelbulb-different-de-approximations/
+pub struct SdfMandelbulb {
+    pub power: f32,
+    pub iterations: u32,
+}
+
+impl Default for SdfMandelbulb {
+    fn default() -> Self {
+        SdfMandelbulb { power: 8.0, iterations: 12 }
+    }
+}
+
+impl Sdf for SdfMandelbulb {
+    fn distance(&self, p: V3) -> f32 {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = z.length();
+        for _ in 0..self.iterations {
+            r = z.length();
+            // Escaped, or collapsed onto the origin
+            if !(1.0e-6..=2.0).contains(&r) {
+                break;
+            }
+            // Convert to polar coordinates, then scale and rotate the point
+            let theta = (z.2 / r).acos() * self.power;
+            let phi = z.1.atan2(z.0) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let zr = r.powf(self.power);
+            z = V3(theta.sin() * phi.cos(), phi.sin() * theta.sin(), theta.cos()) * zr + p;
+        }
+        0.5 * r.max(1.0e-6).ln() * r / dr
+    }
+
+    fn aabb(&self) -> AABB {
+        AABB::from_min_max(V3::ZERO - 1.2, V3::ZERO + 1.2)
+    }
+}
+
+// Operators
+
+pub struct SdfTranslate {
+    pub offset: V3,
+    pub inner: Arc<dyn Sdf>,
+}
+
+impl SdfTranslate {
+    pub fn new(offset: V3, inner: impl IntoArc<dyn Sdf>) -> Self {
+        SdfTranslate { offset, inner: inner.into_arc() }
+    }
+}
+
+impl Sdf for SdfTranslate {
+    fn distance(&self, p: V3) -> f32 {
+        self.inner.distance(p - self.offset)
+    }
+
+    fn aabb(&self) -> AABB {
+        let aabb = self.inner.aabb();
+        AABB::from_min_max(aabb.min + self.offset, aabb.max + self.offset)
+    }
+}
+
+/// Blends two surfaces together, with a blend radius of {k}
+pub struct SdfSmoothUnion {
+    pub a: Arc<dyn Sdf>,
+    pub b: Arc<dyn Sdf>,
+    pub k: f32,
+}
+
+impl SdfSmoothUnion {
+    pub fn new(a: impl IntoArc<dyn Sdf>, b: impl IntoArc<dyn Sdf>, k: f32) -> Self {
+        SdfSmoothUnion { a: a.into_arc(), b: b.into_arc(), k }
+    }
+}
+
+impl Sdf for SdfSmoothUnion {
+    fn distance(&self, p: V3) -> f32 {
+        let d1 = self.a.distance(p);
+        let d2 = self.b.distance(p);
+        if self.k <= 0.0 {
+            return d1.min(d2);
+        }
+        // Polynomial smooth minimum
+        let h = (0.5 + 0.5 * (d2 - d1) / self.k).clamp(0.0, 1.0);
+        (d2 + (d1 - d2) * h) - self.k * h * (1.0 - h)
+    }
+
+    fn aabb(&self) -> AABB {
+        // The smooth minimum pulls the surface out by at most k/4
+        expand_aabb(AABB::surrounding(self.a.aabb(), self.b.aabb()), self.k * 0.25)
+    }
+}
+
+/// Twists the inner surface about the Y axis by {rate} radians per unit of height.
+/// NOTE: twisting distorts the distance field, so objects using it should reduce their step scale.
+pub struct SdfTwist {
+    pub rate: f32,
+    pub inner: Arc<dyn Sdf>,
+}
+
+impl SdfTwist {
+    pub fn new(rate: f32, inner: impl IntoArc<dyn Sdf>) -> Self {
+        SdfTwist { rate, inner: inner.into_arc() }
+    }
+}
+
+impl Sdf for SdfTwist {
+    fn distance(&self, p: V3) -> f32 {
+        let q = p.rotate_about_axis(V3::POS_Y, -self.rate * p.1);
+        self.inner.distance(q)
+    }
+
+    fn aabb(&self) -> AABB {
+        // The surface may be rotated to any angle about Y, so bound the full circle swept by the inner bounds
+        let aabb = self.inner.aabb();
+        let r = aabb.corners().iter().map(|c| (c.0 * c.0 + c.2 * c.2).sqrt()).fold(0.0, f32::max);
+        AABB::from_min_max(V3(-r, aabb.min.1, -r), V3(r, aabb.max.1, r))
+    }
+}
+
+/// Repeats the inner surface {count} times in each direction (on each axis) with the given {spacing}.
+/// The repeated surface should fit within a single cell of the repetition grid.
+pub struct SdfRepeat {
+    pub spacing: V3,
+    pub count: [u32; 3],
+    pub inner: Arc<dyn Sdf>,
+}
+
+impl SdfRepeat {
+    pub fn new(spacing: V3, count: [u32; 3], inner: impl IntoArc<dyn Sdf>) -> Self {
+        SdfRepeat { spacing, count, inner: inner.into_arc() }
+    }
+}
+
+impl Sdf for SdfRepeat {
+    fn distance(&self, p: V3) -> f32 {
+        // Find the local position in the nearest cell
+        fn cell(p: f32, spacing: f32, count: u32) -> f32 {
+            if spacing <= 0.0 {
+                return p;
+            }
+            let c = count as f32;
+            p - spacing * (p / spacing).round().clamp(-c, c)
+        }
+        let q = V3(
+            cell(p.0, self.spacing.0, self.count[0]),
+            cell(p.1, self.spacing.1, self.count[1]),
+            cell(p.2, self.spacing.2, self.count[2]),
+        );
+        self.inner.distance(q)
+    }
+
+    fn aabb(&self) -> AABB {
+        let aabb = self.inner.aabb();
+        let extent = V3(
+            self.spacing.0 * self.count[0] as f32,
+            self.spacing.1 * self.count[1] as f32,
+            self.spacing.2 * self.count[2] as f32,
+        );
+        AABB::from_min_max(aabb.min - extent, aabb.max + extent)
+    }
+}
+
+/// Displaces the inner surface by up to {amplitude} using gradient noise sampled at {frequency}.
+/// NOTE: displacement distorts the distance field, so objects using it should reduce their step scale.
+pub struct SdfNoiseDisplace {
+    pub amplitude: f32,
+    pub frequency: f32,
+    pub octaves: u32,
+    pub noise: Perlin,
+    pub inner: Arc<dyn Sdf>,
+}
+
+impl SdfNoiseDisplace {
+    pub fn new(amplitude: f32, frequency: f32, inner: impl IntoArc<dyn Sdf>) -> Self {
+        SdfNoiseDisplace {
+            amplitude,
+            frequency,
+            octaves: 3,
+            noise: Perlin::new(0),
+            inner: inner.into_arc(),
+        }
+    }
+
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.noise = Perlin::new(seed);
+        self
+    }
+}
+
+impl Sdf for SdfNoiseDisplace {
+    fn distance(&self, p: V3) -> f32 {
+        self.inner.distance(p) + self.amplitude * self.noise.fbm(p * self.frequency, self.octaves)
+    }
+
+    fn aabb(&self) -> AABB {
+        expand_aabb(self.inner.aabb(), self.amplitude.abs())
+    }
+}
+
+// Hitable
+
+/// A surface described by a signed distance field, intersected by sphere tracing.
+/// See: https://graphics.stanford.edu/courses/cs348b-20-spring-content/uploads/hart.pdf
+pub struct SdfObject {
+    sdf: Arc<dyn Sdf>,
+    aabb: AABB,
+    max_steps: u32,
+    epsilon: f32,
+    step_scale: f32,
+    mat_id: MatId,
+    tex_id: TexId,
+}
+
+impl SdfObject {
+    pub fn new(sdf: impl IntoArc<dyn Sdf>, mat_id: MatId, tex_id: TexId) -> Self {
+        let sdf = sdf.into_arc();
+        let epsilon = 1.0e-4;
+        SdfObject {
+            aabb: expand_aabb(sdf.aabb(), epsilon),
+            sdf,
+            max_steps: 256,
+            epsilon,
+            step_scale: 1.0,
+            mat_id,
+            tex_id,
+        }
+    }
+
+    /// Sets the maximum number of steps taken along a ray before giving up
+    pub fn with_max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Sets the distance from the surface at which a ray is considered to have hit it
+    pub fn with_epsilon(mut self, epsilon: f32) -> Self {
+        self.epsilon = epsilon;
+        self.aabb = expand_aabb(self.sdf.aabb(), epsilon);
+        self
+    }
+
+    /// Scales each step taken along a ray.
+    /// Use values less than 1.0 for distance fields which overestimate the distance to the surface.
+    pub fn with_step_scale(mut self, step_scale: f32) -> Self {
+        assert!(step_scale > 0.0 && step_scale <= 1.0, "step_scale must be within the range of 0.0 to 1.0");
+        self.step_scale = step_scale;
+        self
+    }
+
+    fn normal_at(&self, p: V3) -> V3 {
+        // Estimate the gradient of the distance field using central differences
+        let h = self.epsilon;
+        let d = |offset: V3| self.sdf.distance(p + offset) - self.sdf.distance(p - offset);
+        V3(d(V3(h, 0.0, 0.0)), d(V3(0.0, h, 0.0)), d(V3(0.0, 0.0, h))).unit()
+    }
+}
+
+impl Hitable for SdfObject {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        // Only march through the part of the ray inside the bounding box
+        let (t_start, t_end) = self.aabb.hit_aabb_range(ray, t_min, t_max)?;
+        // Distances are measured in world units, but {t} is scaled by the length of the ray direction
+        let direction_length = ray.direction.length();
+        let mut t = t_start;
+        for _ in 0..self.max_steps {
+            let p = ray.point_at_parameter(t);
+            // NOTE: Use the absolute distance so that rays starting inside the surface (refraction) march to the exit
+            let d = self.sdf.distance(p).abs();
+            if d < self.epsilon {
+                return Some(HitRecord {
+                    entity_id: None,
+                    t,
+                    p,
+                    normal: self.normal_at(p),
+                    //  TODO: UV on a distance field
+                    uv: V2::ZERO,
+                    tangent: V3::ZERO,
+                    bitangent: V3::ZERO,
+                    mat_id: self.mat_id,
+                    tex_id: self.tex_id,
+                    tex_key: None,
+                });
+            }
+            t += d * self.step_scale / direction_length;
+            if t > t_end {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn aabb(&self) -> AABB {
+        self.aabb.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::implementation::{ Hitable, MatId, TexId };
+    use crate::types::{ Ray, V3 };
+    use super::{ SdfObject, SdfSphere, SdfBox };
+
+    fn make_object(sdf: impl crate::types::IntoArc<dyn super::Sdf>) -> SdfObject {
+        SdfObject::new(sdf, MatId::default(), TexId::default())
+    }
+
+    #[test]
+    fn sphere_trace_sphere() {
+        let object = make_object(SdfSphere { radius: 1.0 });
+        let ray = Ray::new(V3(0.0, 0.0, -5.0), V3(0.0, 0.0, 2.0));
+        let hit = object.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!((hit.t - 2.0).abs() < 0.001);
+        assert!((hit.normal - V3::NEG_Z).length() < 0.001);
+    }
+
+    #[test]
+    fn sphere_trace_miss() {
+        let object = make_object(SdfSphere { radius: 1.0 });
+        let ray = Ray::new(V3(0.0, 1.5, -5.0), V3(0.0, 0.0, 1.0));
+        assert!(object.hit(ray, 0.0, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn sphere_trace_from_inside() {
+        let object = make_object(SdfBox::new(V3::ONE));
+        let ray = Ray::new(V3::ZERO, V3::POS_X);
+        let hit = object.hit(ray, 0.0, f32::MAX).expect("hit");
+        assert!((hit.t - 1.0).abs() < 0.001);
+        assert!((hit.normal - V3::POS_X).length() < 0.001);
+    }
+}
@@ -46,6 +46,8 @@ impl Hitable for Sphere {
             normal,
             //  TODO: UV on a sphere
             uv: V2::ZERO,
+            tangent: V3::ZERO,
+            bitangent: V3::ZERO,
             mat_id: self.mat_id,
             tex_id: self.tex_id,
             tex_key: None,
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+
+use crate::types::{ V2, V3 };
+use super::mesh::{ Mesh, MeshFace };
+
+// Subdivision surfaces
+//
+// A control cage of polygons is refined into a smooth surface by repeatedly splitting each face
+// and moving each vertex towards a weighted average of its neighbours.
+// Cages made only of triangles use Loop subdivision, and any other cage (e.g. of quads) uses Catmull-Clark.
+// Creased edges follow the semi-sharp crease rules: an edge with sharpness s is split as a sharp edge for
+// s levels of subdivision before it smooths out, and boundary edges are always sharp.
+//
+// See: "Subdivision Surfaces in Character Animation", DeRose, Kass & Truong (1998)
+// See: https://graphics.pixar.com/opensubdiv/docs/subdivision_surfaces.html
+
+/// A polygon mesh to be refined into a smooth surface (see subdivide)
+#[derive(Clone, Default)]
+pub struct SubdivisionCage {
+    pub vertices: Vec<V3>,
+    pub faces: Vec<CageFace>,
+    /// The sharpness of creased edges, keyed by the indices of their vertices (lowest first)
+    pub creases: HashMap<(u32, u32), f32>,
+}
+
+#[derive(Clone, Default)]
+pub struct CageFace {
+    // Indices of the face vertices in the cage vertex collection, three or more
+    pub vertices: Vec<u32>,
+    // The uv coordinates of each face vertex
+    pub uvs: Option<Vec<V2>>,
+    pub tex_key: Option<usize>,
+}
+
+/// Identifies the edge between two vertices
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+/// The edges and faces around each vertex of a cage
+struct Topology {
+    // Each edge and the faces either side of it
+    edges: Vec<((u32, u32), Vec<usize>)>,
+    edge_indices: HashMap<(u32, u32), usize>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(cage: &SubdivisionCage) -> Self {
+        let mut topology = Topology {
+            edges: Vec::new(),
+            edge_indices: HashMap::new(),
+            vertex_edges: vec![Vec::new(); cage.vertices.len()],
+            vertex_faces: vec![Vec::new(); cage.vertices.len()],
+        };
+        for (i, face) in cage.faces.iter().enumerate() {
+            let n = face.vertices.len();
+            for j in 0..n {
+                let (a, b) = (face.vertices[j], face.vertices[(j + 1) % n]);
+                topology.vertex_faces[a as usize].push(i);
+                let key = edge_key(a, b);
+                let edge = *topology.edge_indices.entry(key).or_insert_with(|| {
+                    topology.edges.push((key, Vec::new()));
+                    topology.vertex_edges[a as usize].push(topology.edges.len() - 1);
+                    topology.vertex_edges[b as usize].push(topology.edges.len() - 1);
+                    topology.edges.len() - 1
+                });
+                topology.edges[edge].1.push(i);
+            }
+        }
+        topology
+    }
+
+    fn edge_point(&self, a: u32, b: u32) -> u32 {
+        self.edge_indices[&edge_key(a, b)] as u32
+    }
+}
+
+/// Linear interpolation from {a} to {b}
+fn lerp(a: V3, b: V3, t: f32) -> V3 {
+    a + (b - a) * t
+}
+
+impl SubdivisionCage {
+    /// The sharpness of an edge, where boundary (and non-manifold) edges are infinitely sharp
+    fn edge_sharpness(&self, key: (u32, u32), faces: &[usize]) -> f32 {
+        if faces.len() != 2 {
+            return f32::INFINITY;
+        }
+        self.creases.get(&key).copied().unwrap_or(0.0)
+    }
+
+    /// Applies {levels} levels of subdivision, using Loop subdivision if every face is a triangle
+    /// and Catmull-Clark subdivision otherwise. Each level of Catmull-Clark splits every face into quads,
+    /// one for each vertex of the face, and each level of Loop splits every triangle into four.
+    pub fn subdivide(&self, levels: u32) -> SubdivisionCage {
+        let triangles = self.faces.iter().all(|face| face.vertices.len() == 3);
+        let mut cage = self.clone();
+        for _ in 0..levels {
+            cage = if triangles { cage.loop_step() } else { cage.catmull_clark_step() };
+        }
+        cage
+    }
+
+    /// The creases of the next level: each half of a creased edge is one level less sharp
+    fn child_creases(&self, topology: &Topology, edge_offset: u32) -> HashMap<(u32, u32), f32> {
+        let mut creases = HashMap::new();
+        for (&(a, b), &sharpness) in self.creases.iter() {
+            let Some(&edge) = topology.edge_indices.get(&(a, b)) else { continue };
+            if sharpness > 1.0 {
+                let e = edge_offset + edge as u32;
+                creases.insert(edge_key(a, e), sharpness - 1.0);
+                creases.insert(edge_key(e, b), sharpness - 1.0);
+            }
+        }
+        creases
+    }
+
+    /// The refined position of {vertex}, from the position given by the smooth rule of the scheme.
+    /// Where two sharp edges meet the vertex moves along the crease, and where more meet it stays where it is,
+    /// as do vertices of a single face (the corners of boundaries).
+    fn vertex_point(&self, topology: &Topology, vertex: usize, smooth: V3) -> V3 {
+        let p = self.vertices[vertex];
+        if topology.vertex_faces[vertex].len() == 1 {
+            return p;
+        }
+        let sharp_edges = topology.vertex_edges[vertex].iter()
+            .map(|&edge| &topology.edges[edge])
+            .map(|&((a, b), ref faces)| {
+                let other = if a as usize == vertex { b } else { a };
+                (self.vertices[other as usize], self.edge_sharpness((a, b), faces))
+            })
+            .filter(|&(_, sharpness)| sharpness > 0.0)
+            .collect::<Vec<_>>();
+        let sharp = match sharp_edges.as_slice() {
+            [] | [_] => return smooth,
+            [(a, _), (b, _)] => p * 0.75 + (*a + *b) * 0.125,
+            _ => p,
+        };
+        // Blend towards the smooth rule for creases which are about to smooth out
+        let sharpness = sharp_edges.iter().map(|(_, s)| s).sum::<f32>() / sharp_edges.len() as f32;
+        lerp(smooth, sharp, sharpness.min(1.0))
+    }
+
+    // Catmull-Clark subdivision
+    // New vertices are ordered: refined cage vertices, then edge points, then face points.
+    fn catmull_clark_step(&self) -> SubdivisionCage {
+        let topology = Topology::new(self);
+        let face_points = self.faces.iter()
+            .map(|face| face.vertices.iter().fold(V3::ZERO, |sum, &v| sum + self.vertices[v as usize]) / face.vertices.len() as f32)
+            .collect::<Vec<_>>();
+
+        let edge_points = topology.edges.iter()
+            .map(|&(key, ref faces)| {
+                let (a, b) = (self.vertices[key.0 as usize], self.vertices[key.1 as usize]);
+                let sharp = (a + b) * 0.5;
+                let sharpness = self.edge_sharpness(key, faces);
+                if sharpness >= 1.0 {
+                    return sharp;
+                }
+                // The average of the ends of the edge and the face points either side
+                let smooth = (a + b + face_points[faces[0]] + face_points[faces[1]]) * 0.25;
+                lerp(smooth, sharp, sharpness)
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_points = self.vertices.iter().enumerate()
+            .map(|(i, &p)| {
+                let faces = &topology.vertex_faces[i];
+                let edges = &topology.vertex_edges[i];
+                if faces.is_empty() {
+                    return p;
+                }
+                // (Q + 2R + (n - 3)P) / n, for the average face point Q and average edge midpoint R
+                let n = edges.len() as f32;
+                let q = faces.iter().fold(V3::ZERO, |sum, &f| sum + face_points[f]) / faces.len() as f32;
+                let r = edges.iter()
+                    .map(|&e| topology.edges[e].0)
+                    .fold(V3::ZERO, |sum, (a, b)| sum + (self.vertices[a as usize] + self.vertices[b as usize]) * 0.5) / n;
+                let smooth = (q + r * 2.0 + p * (n - 3.0)) / n;
+                self.vertex_point(&topology, i, smooth)
+            })
+            .collect::<Vec<_>>();
+
+        let edge_offset = self.vertices.len() as u32;
+        let face_offset = edge_offset + topology.edges.len() as u32;
+        let mut faces = Vec::new();
+        for (i, face) in self.faces.iter().enumerate() {
+            let n = face.vertices.len();
+            let center_uv = face.uvs.as_ref().map(|uvs| uvs.iter().fold(V2::ZERO, |sum, &uv| sum + uv) / n as f32);
+            for j in 0..n {
+                let (prev, v, next) = (face.vertices[(j + n - 1) % n], face.vertices[j], face.vertices[(j + 1) % n]);
+                faces.push(CageFace {
+                    vertices: vec![v, edge_offset + topology.edge_point(v, next), face_offset + i as u32, edge_offset + topology.edge_point(prev, v)],
+                    uvs: face.uvs.as_ref().zip(center_uv).map(|(uvs, center_uv)| {
+                        let (prev, uv, next) = (uvs[(j + n - 1) % n], uvs[j], uvs[(j + 1) % n]);
+                        vec![uv, (uv + next) * 0.5, center_uv, (prev + uv) * 0.5]
+                    }),
+                    tex_key: face.tex_key,
+                });
+            }
+        }
+
+        SubdivisionCage {
+            vertices: [vertex_points, edge_points, face_points].concat(),
+            faces,
+            creases: self.child_creases(&topology, edge_offset),
+        }
+    }
+
+    // Loop subdivision
+    // New vertices are ordered: refined cage vertices, then edge points.
+    fn loop_step(&self) -> SubdivisionCage {
+        let topology = Topology::new(self);
+
+        let edge_points = topology.edges.iter()
+            .map(|&((a, b), ref faces)| {
+                let sharp = (self.vertices[a as usize] + self.vertices[b as usize]) * 0.5;
+                let sharpness = self.edge_sharpness((a, b), faces);
+                if sharpness >= 1.0 {
+                    return sharp;
+                }
+                // 3/8 of each end of the edge and 1/8 of the opposite vertex of each triangle
+                let opposite = faces.iter()
+                    .flat_map(|&f| self.faces[f].vertices.iter().copied().filter(|&v| v != a && v != b))
+                    .fold(V3::ZERO, |sum, v| sum + self.vertices[v as usize]);
+                let smooth = sharp * 0.75 + opposite * 0.125;
+                lerp(smooth, sharp, sharpness)
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_points = self.vertices.iter().enumerate()
+            .map(|(i, &p)| {
+                let edges = &topology.vertex_edges[i];
+                if edges.is_empty() {
+                    return p;
+                }
+                // (1 - nβ)P + β Σ neighbours, for n neighbours
+                let n = edges.len() as f32;
+                let beta = (0.625 - (0.375 + 0.25 * (std::f32::consts::TAU / n).cos()).powi(2)) / n;
+                let neighbours = edges.iter()
+                    .map(|&e| topology.edges[e].0)
+                    .fold(V3::ZERO, |sum, (a, b)| sum + self.vertices[if a as usize == i { b } else { a } as usize]);
+                let smooth = p * (1.0 - n * beta) + neighbours * beta;
+                self.vertex_point(&topology, i, smooth)
+            })
+            .collect::<Vec<_>>();
+
+        let edge_offset = self.vertices.len() as u32;
+        let mut faces = Vec::new();
+        for face in self.faces.iter() {
+            let [a, b, c] = [0, 1, 2].map(|j| face.vertices[j]);
+            let [ab, bc, ca] = [(a, b), (b, c), (c, a)].map(|(u, v)| edge_offset + topology.edge_point(u, v));
+            let uvs = face.uvs.as_ref().map(|uvs| {
+                let [a, b, c] = [uvs[0], uvs[1], uvs[2]];
+                [a, b, c, (a + b) * 0.5, (b + c) * 0.5, (c + a) * 0.5]
+            });
+            // A triangle in each corner and one in the middle, as indices into (a, b, c, ab, bc, ca)
+            for corners in [[0, 3, 5], [3, 1, 4], [5, 4, 2], [3, 4, 5]] {
+                let vertices = [a, b, c, ab, bc, ca];
+                faces.push(CageFace {
+                    vertices: corners.iter().map(|&k| vertices[k]).collect(),
+                    uvs: uvs.map(|uvs| corners.iter().map(|&k| uvs[k]).collect()),
+                    tex_key: face.tex_key,
+                });
+            }
+        }
+
+        SubdivisionCage {
+            vertices: [vertex_points, edge_points].concat(),
+            faces,
+            creases: self.child_creases(&topology, edge_offset),
+        }
+    }
+
+    /// Splits the faces of the cage into triangles, generating smooth normals (see Mesh::generate_normals)
+    pub fn to_mesh(&self, crease_angle: f32) -> Mesh {
+        let mut mesh = Mesh { vertices: self.vertices.clone(), ..Default::default() };
+        for face in self.faces.iter() {
+            for i in 1..face.vertices.len().saturating_sub(1) {
+                let corners = [0, i, i + 1];
+                let uvs = face.uvs.as_ref().map(|uvs| corners.map(|c| {
+                    mesh.uvs.push(uvs[c]);
+                    (mesh.uvs.len() - 1) as u32
+                }));
+                mesh.faces.push(MeshFace {
+                    vertices: corners.map(|c| face.vertices[c]),
+                    normals: None,
+                    uvs,
+                    tex_key: face.tex_key,
+                });
+            }
+        }
+        mesh.generate_normals(crease_angle);
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cage(vertices: Vec<V3>, faces: &[&[u32]]) -> SubdivisionCage {
+        SubdivisionCage {
+            vertices,
+            faces: faces.iter().map(|f| CageFace { vertices: f.to_vec(), ..Default::default() }).collect(),
+            creases: HashMap::new(),
+        }
+    }
+
+    /// The largest coordinate of each vertex of the cage
+    fn max_coords(cage: &SubdivisionCage) -> Vec<f32> {
+        cage.vertices.iter().map(|v| v.0.abs().max(v.1.abs()).max(v.2.abs())).collect()
+    }
+
+    #[test]
+    fn catmull_clark_cube() {
+        let vertices = (0..8).map(|i| V3(
+            if i & 1 == 0 { -1.0 } else { 1.0 },
+            if i & 2 == 0 { -1.0 } else { 1.0 },
+            if i & 4 == 0 { -1.0 } else { 1.0 },
+        )).collect();
+        let cube = cage(vertices, &[&[0, 2, 3, 1], &[4, 5, 7, 6], &[0, 1, 5, 4], &[2, 6, 7, 3], &[0, 4, 6, 2], &[1, 3, 7, 5]]);
+
+        // Each level adds a vertex on every edge & face, and splits every face into quads
+        let level1 = cube.subdivide(1);
+        assert_eq!((level1.vertices.len(), level1.faces.len()), (8 + 12 + 6, 24));
+        assert!(level1.faces.iter().all(|f| f.vertices.len() == 4));
+
+        // Smooth: the cube shrinks towards a sphere
+        let smooth = max_coords(&cube.subdivide(3));
+        assert!(smooth.iter().all(|&c| c < 0.99), "{smooth:?}");
+        let corner = cube.subdivide(3).vertices[7].length();
+        assert!(corner < 0.6 * 3f32.sqrt(), "{corner}");
+
+        // Sharp: creases on every edge keep the cube
+        let mut creased = cube.clone();
+        for face in cube.faces.iter() {
+            for j in 0..4 {
+                creased.creases.insert(edge_key(face.vertices[j], face.vertices[(j + 1) % 4]), f32::INFINITY);
+            }
+        }
+        let sharp = max_coords(&creased.subdivide(3));
+        assert!(sharp.iter().all(|&c| (c - 1.0).abs() < 1.0e-5), "{sharp:?}");
+
+        // Semi-sharp: creases which smooth out after a level round the edges less than no creases
+        for sharpness in creased.creases.values_mut() {
+            *sharpness = 1.0;
+        }
+        let corner_semi_sharp = creased.subdivide(3).vertices[7].length();
+        assert!(corner < corner_semi_sharp && corner_semi_sharp < 3f32.sqrt(), "{corner} {corner_semi_sharp}");
+    }
+
+    #[test]
+    fn loop_octahedron() {
+        let vertices = vec![V3::POS_X, V3::NEG_X, V3::POS_Y, V3::NEG_Y, V3::POS_Z, V3::NEG_Z];
+        let octahedron = cage(vertices, &[
+            &[0, 2, 4], &[2, 1, 4], &[1, 3, 4], &[3, 0, 4],
+            &[2, 0, 5], &[1, 2, 5], &[3, 1, 5], &[0, 3, 5],
+        ]);
+        let level1 = octahedron.subdivide(1);
+        assert_eq!((level1.vertices.len(), level1.faces.len()), (6 + 12, 32));
+        assert!(level1.faces.iter().all(|f| f.vertices.len() == 3));
+
+        // The surface stays inside the octahedron's sphere, rounding off its points
+        let mesh = octahedron.subdivide(3).to_mesh(1.0);
+        assert_eq!(mesh.faces.len(), 8 * 4 * 4 * 4);
+        for v in mesh.vertices.iter() {
+            assert!(v.length() < 0.9 && v.length() > 0.4, "{v:?}");
+        }
+        // Generated normals point out of the surface
+        for face in mesh.faces.iter() {
+            let [a, b, c] = mesh.face_vertices(face);
+            assert!(V3::dot(mesh.face_normal(face), a + b + c) > 0.0);
+        }
+    }
+
+    #[test]
+    fn boundaries_and_uvs() {
+        // A flat square keeps its outline, as boundary edges are sharp, and uvs follow the positions
+        let mut square = cage(vec![V3(0.0, 0.0, 0.0), V3(1.0, 0.0, 0.0), V3(1.0, 1.0, 0.0), V3(0.0, 1.0, 0.0)], &[&[0, 1, 2, 3]]);
+        square.faces[0].uvs = Some(vec![V2(0.0, 0.0), V2(1.0, 0.0), V2(1.0, 1.0), V2(0.0, 1.0)]);
+        let mesh = square.subdivide(2).to_mesh(1.0);
+        assert_eq!(mesh.faces.len(), 16 * 2);
+        for face in mesh.faces.iter() {
+            for (vertex, uv) in face.vertices.iter().zip(face.uvs.unwrap()) {
+                let V3(x, y, z) = mesh.vertices[*vertex as usize];
+                let V2(u, v) = mesh.uvs[uv as usize];
+                assert!((x - u).abs() < 1.0e-5 && (y - v).abs() < 1.0e-5 && z == 0.0);
+            }
+        }
+        assert!(mesh.vertices.contains(&V3(1.0, 1.0, 0.0)));
+    }
+}
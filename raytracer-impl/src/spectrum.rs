@@ -0,0 +1,213 @@
+use std::sync::LazyLock;
+
+use crate::types::V3;
+
+// Spectral rendering helpers
+//
+// Rays normally carry RGB light. Dispersive materials bend each wavelength by a different amount,
+// so they pick a single wavelength for the ray to carry from then on (see Wavelength).
+// The color seen along that ray is weighted by how the wavelength appears in RGB, divided by the
+// probability of picking it, so that averaging many samples converges on the RGB result.
+//
+// See: https://jcgt.org/published/0002/02/01/ (analytic fit of the CIE 1931 colour matching functions)
+// See: https://pbr-book.org/4ed/Radiometry,_Spectra,_and_Color/Sampling_the_Visible_Spectrum
+
+/// The range of wavelengths (in nanometres) which may be sampled
+pub const MIN_WAVELENGTH: f32 = 360.0;
+pub const MAX_WAVELENGTH: f32 = 830.0;
+
+/// The wavelength (in nanometres) of the sodium D line, at which refractive indices are usually quoted
+pub const SODIUM_D_WAVELENGTH: f32 = 589.3;
+
+/// The light carried by a ray
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Wavelength {
+    /// All wavelengths, as RGB.
+    /// Holds a sample from 0.0 to 1.0 used to pick a wavelength if the ray hits a dispersive material,
+    /// which lets the sampler spread wavelengths evenly over the samples of a pixel.
+    All(f32),
+    /// A single wavelength, in nanometres
+    Single(f32),
+}
+
+impl Default for Wavelength {
+    fn default() -> Self {
+        Wavelength::All(0.5)
+    }
+}
+
+/// Piecewise gaussian, with different widths either side of the peak
+fn gaussian(x: f32, mu: f32, sigma_lo: f32, sigma_hi: f32) -> f32 {
+    let t = (x - mu) / if x < mu { sigma_lo } else { sigma_hi };
+    (-0.5 * t * t).exp()
+}
+
+/// The CIE 1931 XYZ colour matching functions at {wavelength} (in nanometres)
+pub fn wavelength_to_xyz(wavelength: f32) -> V3 {
+    let l = wavelength;
+    V3(
+        1.056 * gaussian(l, 599.8, 37.9, 31.0) + 0.362 * gaussian(l, 442.0, 16.0, 26.7) - 0.065 * gaussian(l, 501.1, 20.4, 26.2),
+        0.821 * gaussian(l, 568.8, 46.9, 40.5) + 0.286 * gaussian(l, 530.9, 16.3, 31.1),
+        1.217 * gaussian(l, 437.0, 11.8, 36.0) + 0.681 * gaussian(l, 459.0, 26.0, 13.8),
+    )
+}
+
+/// Converts CIE XYZ to linear sRGB
+pub fn xyz_to_rgb(V3(x, y, z): V3) -> V3 {
+    V3(
+         3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+         0.0557 * x - 0.2040 * y + 1.0570 * z,
+    )
+}
+
+/// The RGB color of light at a single {wavelength}.
+/// NOTE: Spectral colors lie outside of sRGB, negative components are clipped to 0.0.
+fn wavelength_to_rgb(wavelength: f32) -> V3 {
+    let V3(r, g, b) = xyz_to_rgb(wavelength_to_xyz(wavelength));
+    V3(r.max(0.0), g.max(0.0), b.max(0.0))
+}
+
+/// Picks a wavelength from {u} (from 0.0 to 1.0), favouring the middle of the visible spectrum where the eye is most sensitive
+pub fn sample_wavelength(u: f32) -> f32 {
+    538.0 - 138.88889 * (0.85691062 - 1.827502 * u.clamp(0.0, 1.0)).atanh()
+}
+
+/// The probability density of sample_wavelength picking {wavelength}
+pub fn sample_wavelength_pdf(wavelength: f32) -> f32 {
+    if !(MIN_WAVELENGTH..=MAX_WAVELENGTH).contains(&wavelength) {
+        return 0.0;
+    }
+    let c = (0.0072 * (wavelength - 538.0)).cosh();
+    0.003939804 / (c * c)
+}
+
+struct SpectrumTable {
+    // The RGB color of white light with equal energy at each wavelength
+    white: V3,
+    // The largest component of any wavelength_weight
+    max_weight: f32,
+}
+
+static SPECTRUM_TABLE: LazyLock<SpectrumTable> = LazyLock::new(|| {
+    let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+    let white = (0..steps)
+        .map(|i| wavelength_to_rgb(MIN_WAVELENGTH + i as f32 + 0.5))
+        .fold(V3::ZERO, |sum, rgb| sum + rgb);
+    let max_weight = (0..=steps)
+        .map(|i| {
+            let wavelength = MIN_WAVELENGTH + i as f32;
+            let V3(r, g, b) = wavelength_to_rgb(wavelength) / (white * sample_wavelength_pdf(wavelength));
+            r.max(g).max(b)
+        })
+        .fold(0.0, f32::max);
+    SpectrumTable { white, max_weight }
+});
+
+/// The RGB weight of a ray carrying only {wavelength}, picked with sample_wavelength.
+/// Averaged over many samples the weight is white (1.0, 1.0, 1.0).
+pub fn wavelength_weight(wavelength: f32) -> V3 {
+    let pdf = sample_wavelength_pdf(wavelength);
+    if pdf == 0.0 {
+        return V3::ZERO;
+    }
+    wavelength_to_rgb(wavelength) / (SPECTRUM_TABLE.white * pdf)
+}
+
+/// The largest component of any wavelength_weight, and so of any single color sample in a scene with dispersive materials
+pub fn max_wavelength_weight() -> f32 {
+    SPECTRUM_TABLE.max_weight
+}
+
+/// How the refractive index of a material varies with wavelength
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    /// Cauchy's equation n = a + b / λ², with λ in micrometres
+    Cauchy { a: f32, b: f32 },
+    /// The Sellmeier equation n² = 1 + Σ b λ² / (λ² - c), with λ in micrometres
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Borosilicate crown glass (Schott N-BK7), common in lenses and prisms
+    pub const BK7: Dispersion = Dispersion::Sellmeier {
+        b: [1.0396122, 0.23179235, 1.0104694],
+        c: [0.0060006985, 0.020017914, 103.56065],
+    };
+    /// Flint glass (Schott F2), which disperses light more strongly than crown glass
+    pub const FLINT: Dispersion = Dispersion::Sellmeier {
+        b: [1.3453336, 0.20907317, 0.9373572],
+        c: [0.0099774385, 0.047045078, 111.886764],
+    };
+    /// Dense flint glass (Schott SF11)
+    pub const DENSE_FLINT: Dispersion = Dispersion::Sellmeier {
+        b: [1.737597, 0.31374735, 1.8987811],
+        c: [0.013188707, 0.062306814, 155.2363],
+    };
+    /// Fused silica (quartz glass), with very little dispersion
+    pub const FUSED_SILICA: Dispersion = Dispersion::Sellmeier {
+        b: [0.6961663, 0.4079426, 0.8974794],
+        c: [0.004679148, 0.013512063, 97.934006],
+    };
+    /// Diamond, with a high refractive index and strong dispersion ("fire")
+    pub const DIAMOND: Dispersion = Dispersion::Sellmeier {
+        b: [4.3356, 0.3306, 0.0],
+        c: [0.011236, 0.030625, 0.0],
+    };
+
+    pub fn cauchy(a: f32, b: f32) -> Self {
+        Dispersion::Cauchy { a, b }
+    }
+
+    pub fn sellmeier(b: [f32; 3], c: [f32; 3]) -> Self {
+        Dispersion::Sellmeier { b, c }
+    }
+
+    /// The refractive index at {wavelength} (in nanometres)
+    pub fn ref_index(&self, wavelength: f32) -> f32 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>();
+                n2.max(1.0).sqrt()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wavelength_weights_average_to_white() {
+        // Stratified samples of the wavelength weight integrate to white
+        let n = 10000;
+        let sum = (0..n)
+            .map(|i| wavelength_weight(sample_wavelength((i as f32 + 0.5) / n as f32)))
+            .fold(V3::ZERO, |sum, w| sum + w);
+        let V3(r, g, b) = sum / n as f32;
+        for c in [r, g, b] {
+            assert!((c - 1.0).abs() < 0.02, "{r} {g} {b}");
+        }
+        assert!(max_wavelength_weight() >= r.max(g).max(b));
+
+        // Sampled wavelengths stay in range and the pdf integrates to 1.0
+        assert!(sample_wavelength(0.0) >= MIN_WAVELENGTH - 1.0 && sample_wavelength(1.0) <= MAX_WAVELENGTH + 1.0);
+        let total = (MIN_WAVELENGTH as usize..MAX_WAVELENGTH as usize).map(|l| sample_wavelength_pdf(l as f32 + 0.5)).sum::<f32>();
+        assert!((total - 1.0).abs() < 0.01, "{total}");
+    }
+
+    #[test]
+    fn dispersion_ref_index() {
+        // Quoted refractive indices at the sodium D line
+        assert!((Dispersion::BK7.ref_index(SODIUM_D_WAVELENGTH) - 1.5168).abs() < 1.0e-3);
+        assert!((Dispersion::FLINT.ref_index(SODIUM_D_WAVELENGTH) - 1.6200).abs() < 1.0e-3);
+        assert!((Dispersion::DIAMOND.ref_index(SODIUM_D_WAVELENGTH) - 2.417).abs() < 1.0e-2);
+        // Blue light bends more than red light
+        for dispersion in [Dispersion::BK7, Dispersion::FLINT, Dispersion::cauchy(1.5046, 0.0042)] {
+            assert!(dispersion.ref_index(450.0) > dispersion.ref_index(650.0));
+        }
+    }
+}
@@ -4,6 +4,8 @@ use std::f32::INFINITY;
 use std::ops::{ Add, Sub, Mul, Div, Neg };
 use std::default::{ Default };
 
+use crate::spectrum::Wavelength;
+
 //
 // Vec3
 //
@@ -169,12 +171,18 @@ impl Default for V3 {
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub origin: V3,
-    pub direction: V3
+    pub direction: V3,
+    pub wavelength: Wavelength,
 }
 
 impl Ray {
     pub fn new(origin: V3, direction: V3) -> Ray {
-        Ray { origin, direction }
+        Ray { origin, direction, wavelength: Wavelength::default() }
+    }
+
+    pub fn with_wavelength(mut self, wavelength: Wavelength) -> Ray {
+        self.wavelength = wavelength;
+        self
     }
 
     pub fn point_at_parameter(&self, t: f32) -> V3 {
@@ -216,7 +224,7 @@ pub trait IntoArc<T: ?Sized> {
 }
 
 /// Implement IntoArc<T> for all Arc<T> types
-impl<T> IntoArc<T> for std::sync::Arc<T> {
+impl<T: ?Sized> IntoArc<T> for std::sync::Arc<T> {
     fn into_arc(self) -> std::sync::Arc<T> {
         self
     }
@@ -10,7 +10,8 @@ pub fn partition_by_key<T, K>(slice: &mut [T], split_on: K, selector: impl Fn(&T
     if len == 0 {
         panic!("partition_by_key: empty input slice");
     }
-    let (mut i, mut j) = (0, len - 1);
+    // NOTE: {j} is the start of the right partition
+    let (mut i, mut j) = (0, len);
     while i < j {
         match selector(&slice[i]) {
             v if v <= split_on => {
@@ -19,12 +20,12 @@ pub fn partition_by_key<T, K>(slice: &mut [T], split_on: K, selector: impl Fn(&T
             },
             _ => {
                 // Swap into right partition
-                slice.swap(i, j);
                 j -= 1;
+                slice.swap(i, j);
             }
         }
     }
-    slice.split_at_mut(i + 1)
+    slice.split_at_mut(i)
 }
 
 #[cfg(test)]
@@ -46,6 +47,14 @@ mod partition_by_key_tests {
         assert_eq!(rl, &[]);
     }
 
+    #[test]
+    fn test_1_right() {
+        let mut data: [i32; 1] = [10];
+        let (ll, rl) = partition_by_key(&mut data, 6, |n| *n);
+        assert_eq!(ll, &[]);
+        assert_eq!(rl, &[10]);
+    }
+
     #[test]
     fn test_2() {
         let mut data: [i32; 6] = [5, 3, 5, 11, 2, 8];
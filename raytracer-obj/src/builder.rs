@@ -2,27 +2,111 @@ use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::path::Path;
 
-use log::info;
+use log::{info, warn};
 
-use raytracer_impl::shapes::{Mesh, MeshTri};
+use raytracer_impl::bvh::Bvh;
+use raytracer_impl::implementation::{MatId, Material, TexId, Texture};
+use raytracer_impl::materials::{MatBumpMap, MatCutout, MatNormalMap, MatParam, MatPrincipled, MeshMaterialSet};
+use raytracer_impl::shapes::{CageFace, Mesh, MeshFace, MeshObject, SubdivisionCage};
 use raytracer_impl::texture::{MeshTexture, MeshTextureSet, ColorMap};
-use super::format::{ObjGroup, ObjMaterial, MtlFile, ObjFile};
+use raytracer_impl::types::{IntoArc, V3};
+use super::format::{ObjFace, ObjGroup, ObjMaterial, MtlFile, ObjFile};
 use crate::ObjError;
+use crate::color_map::ColorMapChannel;
 
 pub struct MeshAndTextureData {
     pub mesh: Arc<Mesh>,
     pub texture_set: Arc<MeshTextureSet>,
+    // Principled materials for each texture in the texture set, for rendering with the MTL PBR parameters,
+    // with any bump and normal maps applied.
+    // NOTE: The materials provide their own color, so use them with a white texture rather than the texture set.
+    pub material_set: Arc<MeshMaterialSet>,
+    // BVH over the mesh faces, when loaded from a cache (see load_obj_cached)
+    pub bvh: Option<Bvh>,
+}
+
+impl MeshAndTextureData {
+    /// Creates a MeshObject for the mesh, reusing the cached BVH if there is one
+    pub fn into_mesh_object(self, mat_id: MatId, tex_id: TexId) -> MeshObject {
+        match self.bvh {
+            Some(bvh) => MeshObject::new_with_bvh(self.mesh, bvh, mat_id, tex_id),
+            None => MeshObject::new(self.mesh, mat_id, tex_id),
+        }
+    }
+}
+
+/// The default angle between faces at which generated vertex normals stop being smoothed together
+const DEFAULT_CREASE_ANGLE_DEG: f32 = 60.0;
+
+/// Displaces the surface of built meshes (see ObjMeshBuilder::with_displacement)
+struct Displacement {
+    texture: Arc<dyn Texture>,
+    scale: f32,
+    max_edge_length: f32,
 }
 
-#[derive(Default)]
 pub struct ObjMeshBuilder {
+    mtllib: Option<String>,
     groups: Vec<ObjGroup>,
     materials: HashMap<String, ObjMaterial>,
-    color_maps: HashMap<String, Arc<ColorMap>>,
+    color_maps: HashMap<(String, ColorMapChannel), Arc<ColorMap>>,
+    crease_angle: f32,
+    subdivision_levels: u32,
+    displacement: Option<Displacement>,
+}
+
+impl Default for ObjMeshBuilder {
+    fn default() -> Self {
+        ObjMeshBuilder {
+            mtllib: None,
+            groups: Vec::default(),
+            materials: HashMap::default(),
+            color_maps: HashMap::default(),
+            crease_angle: DEFAULT_CREASE_ANGLE_DEG.to_radians(),
+            subdivision_levels: 0,
+            displacement: None,
+        }
+    }
 }
 
 impl ObjMeshBuilder {
 
+    /// Sets the crease angle (in radians) used when generating vertex normals for faces which don't specify them
+    pub fn with_crease_angle(mut self, crease_angle: f32) -> Self {
+        self.crease_angle = crease_angle;
+        self
+    }
+
+    pub fn crease_angle(&self) -> f32 {
+        self.crease_angle
+    }
+
+    /// Refines built meshes into smooth subdivision surfaces with {levels} levels of subdivision,
+    /// using the faces as the control cage: Loop subdivision for cages of triangles, and Catmull-Clark otherwise.
+    /// Creased edges (`t crease` tags) stay sharp, and uv coordinates are interpolated across each face.
+    /// NOTE: The vertex normals of the OBJ file are ignored, and generated for the subdivided surface instead.
+    pub fn with_subdivision(mut self, levels: u32) -> Self {
+        self.subdivision_levels = levels;
+        self
+    }
+
+    /// Displaces built meshes along their normals by the red channel of {texture} times {scale},
+    /// first splitting faces until no edge is longer than {max_edge_length} so that the surface can follow the texture.
+    /// The texture is sampled at each vertex, with the uv coordinates and texture key of the face (see Mesh::displace).
+    pub fn with_displacement(mut self, texture: impl IntoArc<dyn Texture>, scale: f32, max_edge_length: f32) -> Self {
+        self.displacement = Some(Displacement { texture: texture.into_arc(), scale, max_edge_length });
+        self
+    }
+
+    /// The material library file referenced by the OBJ file, relative to the OBJ file
+    pub fn mtllib(&self) -> Option<&str> {
+        self.mtllib.as_deref()
+    }
+
+    pub(crate) fn material(&self, material_name: &str) -> Option<&ObjMaterial> {
+        self.materials.get(material_name)
+    }
+
     pub fn group_names(&self) -> impl Iterator<Item=&str> {
         self.groups.iter().map(|k| k.name.as_str())
     }
@@ -47,6 +131,7 @@ impl ObjMeshBuilder {
             .collect::<HashSet<_>>();
 
         let mut textures = Vec::new();
+        let mut materials: Vec<Arc<dyn Material>> = Vec::new();
         for name in material_names {
             let mtl = match self.materials.get(name) {
                 Some(mtl) => mtl,
@@ -55,55 +140,216 @@ impl ObjMeshBuilder {
                     continue;
                 },
             };
-            let diffuse_color_map = match mtl.diffuse_color_map.as_ref() {
-                None => None,
-                Some(name) => match self.color_maps.get(name) {
-                    Some(map) => Some(map.clone()),
-                    None => {
-                        info!("WARNING: Unable to find color map {}", name);
-                        None
-                    },
-                }
-            };
+            textures.push(mesh_texture(mtl, &self.color_maps));
+            materials.push(mesh_material(mtl, &self.color_maps));
+        }
+
+        // Prepare the indexed mesh
+        // NOTE: Only vertex data referenced by the selected groups is copied into the mesh
+        let tex_key = |face: &ObjFace| face.mtl.as_ref().and_then(|name| textures.iter().position(|m| &m.name == name));
+        let mut mesh = match self.subdivision_levels {
+            0 => build_triangles(groups, &tex_key),
+            levels => build_cage(groups, &tex_key).subdivide(levels).to_mesh(self.crease_angle),
+        };
+
+        if mesh.faces.is_empty() {
+            panic!("[ObjMeshBuilder::inner_build_mesh] expected at least one face (are you building a vertex group with the wrong name?)");
+        }
 
-            textures.push(MeshTexture {
-                name: mtl.name.clone(),
-                ambient_color: mtl.ambient_color,
-                diffuse_color: mtl.diffuse_color,
-                diffuse_color_map,
-            });
+        // Generate smooth normals for any faces without them
+        mesh.generate_normals(self.crease_angle);
+
+        // Displace the surface before the BVH is built over it
+        if let Some(ref displacement) = self.displacement {
+            mesh.tessellate(displacement.max_edge_length);
+            mesh.displace(displacement.texture.as_ref(), displacement.scale, self.crease_angle);
+        }
+
+        MeshAndTextureData {
+            mesh: Arc::new(mesh),
+            texture_set: Arc::new(MeshTextureSet { textures }),
+            material_set: Arc::new(MeshMaterialSet { materials }),
+            bvh: None,
         }
+    }
+}
 
-        // Prepare mesh tris
-        let mut tris = Vec::new();
-        for group in groups {
-            for face in group.faces.iter() {
-                let tex_key = face.mtl.as_ref().and_then(|name| textures.iter().position(|m| &m.name == name));
-                let get_vertex = |i: usize| group.shared.vertices.get(i - 1).cloned().expect("vertex by index");
-                let get_uv_vertex = |oi: Option<usize>| oi.and_then(|i| group.shared.uv.get(i - 1).cloned()).unwrap_or_default();
-                tris.push(MeshTri {
-                    a: get_vertex(face.a.vertex_index),
-                    b: get_vertex(face.b.vertex_index),
-                    c: get_vertex(face.c.vertex_index),
-                    a_uv: get_uv_vertex(face.a.uv_index),
-                    b_uv: get_uv_vertex(face.b.uv_index),
-                    c_uv: get_uv_vertex(face.c.uv_index),
+/// Splits the faces of the groups into triangles
+fn build_triangles<'a>(groups: impl Iterator<Item=&'a ObjGroup>, tex_key: &dyn Fn(&ObjFace) -> Option<usize>) -> Mesh {
+    let mut mesh = Mesh::default();
+    let mut vertex_indices = IndexRemap::default();
+    let mut uv_indices = IndexRemap::default();
+    let mut normal_indices = IndexRemap::default();
+    for group in groups {
+        let shared = &group.shared;
+        for face in group.faces.iter() {
+            let tex_key = tex_key(face);
+            for corners in face.triangles() {
+                let vertices = corners.map(|v| {
+                    vertex_indices.remap(v.vertex_index, &shared.vertices, &mut mesh.vertices).expect("vertex by index")
+                });
+                // Only use uv coordinates and vertex normals if all three vertices specify them
+                let uvs = corners
+                    .map(|v| v.uv_index.and_then(|i| uv_indices.remap(i, &shared.uv, &mut mesh.uvs)));
+                let normals = corners
+                    .map(|v| v.normal_index.and_then(|i| normal_indices.remap(i, &shared.normals, &mut mesh.normals)));
+                mesh.faces.push(MeshFace {
+                    vertices,
+                    normals: all_some(normals),
+                    uvs: all_some(uvs),
                     tex_key,
                 });
             }
         }
+    }
+    mesh
+}
 
-        if tris.len() == 0 {
-            panic!("[ObjMeshBuilder::inner_build_mesh] expected at least one face (are you building a vertex group with the wrong name?)");
+/// Collects the faces of the groups into a subdivision cage, along with the creases between their vertices
+fn build_cage<'a>(groups: impl Iterator<Item=&'a ObjGroup>, tex_key: &dyn Fn(&ObjFace) -> Option<usize>) -> SubdivisionCage {
+    let mut cage = SubdivisionCage::default();
+    let mut vertex_indices = IndexRemap::default();
+    for group in groups {
+        let shared = &group.shared;
+        for face in group.faces.iter() {
+            let vertices = face.vertices.iter()
+                .map(|v| vertex_indices.remap(v.vertex_index, &shared.vertices, &mut cage.vertices).expect("vertex by index"))
+                .collect();
+            // Only use uv coordinates if every vertex specifies them
+            let uvs = face.vertices.iter()
+                .map(|v| v.uv_index.and_then(|i| shared.uv.get(i.checked_sub(1)?).copied()))
+                .collect();
+            cage.faces.push(CageFace { vertices, uvs, tex_key: tex_key(face) });
+        }
+        // NOTE: Creases between vertices outside of the selected groups are skipped
+        for crease in shared.creases.iter() {
+            if let (Some(a), Some(b)) = (vertex_indices.get(crease.a), vertex_indices.get(crease.b)) {
+                cage.creases.insert((a.min(b), a.max(b)), crease.sharpness);
+            }
         }
+    }
+    cage
+}
 
-        MeshAndTextureData {
-            mesh: Arc::new(Mesh { tris }),
-            texture_set: Arc::new(MeshTextureSet { textures }),
+fn find_color_map(name: Option<&String>, color_maps: &HashMap<(String, ColorMapChannel), Arc<ColorMap>>) -> Option<Arc<ColorMap>> {
+    find_channel_map(name, ColorMapChannel::Color, color_maps)
+}
+
+fn find_channel_map(name: Option<&String>, channel: ColorMapChannel, color_maps: &HashMap<(String, ColorMapChannel), Arc<ColorMap>>) -> Option<Arc<ColorMap>> {
+    let name = name?;
+    let map = color_maps.get(&(name.clone(), channel)).cloned();
+    if map.is_none() {
+        warn!("Unable to find color map {}", name);
+    }
+    map
+}
+
+pub(crate) fn mesh_texture(mtl: &ObjMaterial, color_maps: &HashMap<(String, ColorMapChannel), Arc<ColorMap>>) -> MeshTexture {
+    MeshTexture {
+        name: mtl.name.clone(),
+        ambient_color: unit_color(mtl.ambient_color),
+        diffuse_color: unit_color(mtl.diffuse_color),
+        diffuse_color_map: find_color_map(mtl.diffuse_color_map.as_ref(), color_maps),
+    }
+}
+
+/// Maps the MTL material onto a principled material.
+/// The PBR extension parameters (Pr, Pm, Ps, Pc & Pcr) are used when present, and otherwise the roughness
+/// is estimated from the Phong specular exponent (Ns). Dissolve (d) becomes transmission, with Ni as the ior.
+pub(crate) fn principled_material(mtl: &ObjMaterial, color_maps: &HashMap<(String, ColorMapChannel), Arc<ColorMap>>) -> MatPrincipled {
+    // Textured parameters are the map multiplied by the value, as with the diffuse color
+    let param = |value: Option<f32>, map: Option<&String>, default: f32| {
+        match find_color_map(map, color_maps) {
+            Some(map) => MatParam::texture(map).with_factor(V3::ONE * value.unwrap_or(1.0)),
+            None => MatParam::constant(value.unwrap_or(default)),
+        }
+    };
+    let diffuse_color = unit_color(mtl.diffuse_color);
+    let base_color = match find_color_map(mtl.diffuse_color_map.as_ref(), color_maps) {
+        Some(map) => MatParam::texture(map).with_factor(diffuse_color),
+        None => MatParam::color(diffuse_color),
+    };
+    // Match the width of the Phong lobe, where GGX alpha ~ sqrt(2 / (Ns + 2)) and alpha = roughness^2
+    let roughness = mtl.roughness.or_else(|| {
+        mtl.specular_exponent.map(|ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt().sqrt())
+    });
+
+    let mut material = MatPrincipled::default()
+        .with_base_color(base_color)
+        .with_roughness(param(roughness, mtl.roughness_map.as_ref(), 0.5))
+        .with_metallic(param(mtl.metallic, mtl.metallic_map.as_ref(), 0.0))
+        .with_sheen(param(mtl.sheen, mtl.sheen_map.as_ref(), 0.0))
+        .with_clearcoat(mtl.clearcoat.unwrap_or(0.0))
+        .with_transmission(1.0 - mtl.dissolve.unwrap_or(1.0).clamp(0.0, 1.0));
+    if let Some(clearcoat_roughness) = mtl.clearcoat_roughness {
+        material = material.with_clearcoat_roughness(clearcoat_roughness);
+    }
+    // NOTE: Exporters write Ni 1.0 for materials without an ior, which would make glass invisible
+    if let Some(ior) = mtl.ior.filter(|&ior| ior > 1.0) {
+        material = material.with_ior(ior);
+    }
+    material
+}
+
+/// MTL colors range from 0.0 to 1.0, but some exporters write 0-255 colors.
+/// NOTE: A channel above 1.0 can only be a 0-255 color, so the color is scaled down to 0-1.
+fn unit_color(color: V3) -> V3 {
+    if color.0 > 1.0 || color.1 > 1.0 || color.2 > 1.0 {
+        color / 255.0
+    } else {
+        color
+    }
+}
+
+/// Maps the MTL material onto a principled material (see principled_material),
+/// perturbing its shading normal with the bump map and normal map of the material, if there are any.
+pub(crate) fn mesh_material(mtl: &ObjMaterial, color_maps: &HashMap<(String, ColorMapChannel), Arc<ColorMap>>) -> Arc<dyn Material> {
+    let mut material: Arc<dyn Material> = Arc::new(principled_material(mtl, color_maps));
+    if let Some(map) = find_color_map(mtl.bump_map.as_ref(), color_maps) {
+        // Measure the slope across one texel of the map
+        let uv_step = 1.0 / map.width.max(map.height).max(1) as f32;
+        material = Arc::new(MatBumpMap::new(material, MatParam::texture(map))
+            .with_strength(mtl.bump_multiplier.unwrap_or(1.0))
+            .with_uv_step(uv_step));
+    }
+    if let Some(map) = find_color_map(mtl.normal_map.as_ref(), color_maps) {
+        material = Arc::new(MatNormalMap::new(material, map));
+    }
+    // NOTE: Opacity maps cut away the surface, such as around the leaves of a tree, rather than making it transparent
+    if let Some(map) = find_channel_map(mtl.dissolve_map.as_ref(), ColorMapChannel::Alpha, color_maps) {
+        material = Arc::new(MatCutout::new(material, MatParam::texture(map)));
+    }
+    material
+}
+
+/// Maps (1-based) OBJ indices into a shared vertex collection onto (0-based) indices into a mesh collection,
+/// copying each referenced element into the mesh the first time it is seen.
+#[derive(Default)]
+struct IndexRemap {
+    indices: HashMap<usize, u32>,
+}
+
+impl IndexRemap {
+    fn remap<T: Copy>(&mut self, obj_index: usize, source: &[T], target: &mut Vec<T>) -> Option<u32> {
+        if let Some(&index) = self.indices.get(&obj_index) {
+            return Some(index);
         }
+        let value = *source.get(obj_index.checked_sub(1)?)?;
+        let index = target.len() as u32;
+        target.push(value);
+        self.indices.insert(obj_index, index);
+        Some(index)
+    }
+
+    fn get(&self, obj_index: usize) -> Option<u32> {
+        self.indices.get(&obj_index).copied()
     }
 }
 
+fn all_some<T>([a, b, c]: [Option<T>; 3]) -> Option<[T; 3]> {
+    Some([a?, b?, c?])
+}
+
 pub fn load_obj_builder(path: impl AsRef<Path>) -> Result<ObjMeshBuilder, ObjError> {
     let mut builder = ObjMeshBuilder::default();
 
@@ -116,19 +362,24 @@ pub fn load_obj_builder(path: impl AsRef<Path>) -> Result<ObjMeshBuilder, ObjErr
 
     // Load associated materials
     if let Some(mtllib) = obj_file.mtllib {
-        let mtl_path = obj_path.parent().unwrap().join(mtllib);
+        let mtl_path = obj_path.parent().unwrap().join(&mtllib);
         let mtl_file = load_mtl(&mtl_path)?;
         for mtl in mtl_file.materials.into_iter() {
 
-            // Load associated color map
-            if let Some(ref colormap) = mtl.diffuse_color_map {
+            // Load associated color maps
+            for (colormap, channel) in mtl.maps() {
+                let key = (colormap.clone(), channel);
+                if builder.color_maps.contains_key(&key) {
+                    continue;
+                }
                 let path = mtl_path.parent().unwrap().join(colormap);
-                let data = load_color_map(&path)?;
-                builder.color_maps.insert(colormap.clone(), Arc::new(data));
+                let data = load_color_map(&path, channel)?;
+                builder.color_maps.insert(key, Arc::new(data));
             }
 
             builder.materials.insert(mtl.name.clone(), mtl);
         }
+        builder.mtllib = Some(mtllib);
     }
 
     Ok(builder)
@@ -156,7 +407,8 @@ pub fn load_mtl(path: impl AsRef<Path>) -> Result<MtlFile, ObjError> {
     Ok(mtl_file)
 }
 
-pub fn load_color_map(path: impl AsRef<Path>) -> Result<ColorMap, ObjError> {
+/// Loads an image as a color map, read from the {channel} of the image
+pub fn load_color_map(path: impl AsRef<Path>, channel: ColorMapChannel) -> Result<ColorMap, ObjError> {
     let path = path.as_ref();
     if !path.exists() {
         return Err(ObjError::General(format!("load_color_map: expected file at path {}", path.display())));
@@ -167,6 +419,6 @@ pub fn load_color_map(path: impl AsRef<Path>) -> Result<ColorMap, ObjError> {
         None      => Err(ObjError::General(format!("load_color_map: Color map type unknown")))?,
     };
     let file = std::fs::File::open(path)?;
-    let color_data = crate::color_map::load_color_map(file, format)?;
+    let color_data = crate::color_map::load_color_map(file, format, channel)?;
     Ok(color_data)
 }
@@ -0,0 +1,412 @@
+//! Binary cache of built OBJ meshes.
+//!
+//! The cache is written next to the OBJ file and holds the indexed mesh, its BVH and its materials,
+//! so later loads can skip parsing the OBJ and building the BVH. Color map images are not cached,
+//! and are loaded from their own files.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{ self, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use log::{ info, warn };
+
+use raytracer_impl::bvh::{ Bvh, BvhBuildStrategy };
+use raytracer_impl::shapes::{ Mesh, MeshFace };
+use raytracer_impl::materials::MeshMaterialSet;
+use raytracer_impl::texture::MeshTextureSet;
+use raytracer_impl::types::{ V2, V3 };
+
+use crate::builder::{ load_obj_builder, load_color_map, mesh_material, mesh_texture, MeshAndTextureData, ObjMeshBuilder };
+use crate::format::ObjMaterial;
+use crate::ObjError;
+
+const MAGIC: [u8; 4] = *b"RTOC";
+
+/// Identifies the cache layout. Increment when the layout, or how meshes are built, changes.
+const VERSION: u32 = 4;
+
+/// The extension appended to the OBJ file name to give the cache file name
+const CACHE_EXTENSION: &str = "rtcache";
+
+/// Loads the whole OBJ file as a single mesh (as load_obj_builder(path)?.build_mesh()), along with its BVH.
+/// Uses the cache next to the OBJ file when it was built from the same OBJ and MTL files,
+/// otherwise builds the mesh and BVH and writes a new cache.
+pub fn load_obj_cached(path: impl AsRef<Path>) -> Result<MeshAndTextureData, ObjError> {
+    let obj_path = path.as_ref();
+    if !obj_path.exists() {
+        return Err(ObjError::General(format!("load_obj_cached: expected obj file at path {}", obj_path.display())));
+    }
+    let builder = ObjMeshBuilder::default();
+    let key = CacheKey {
+        obj_hash: fnv1a(&std::fs::read(obj_path)?),
+        crease_angle: builder.crease_angle(),
+    };
+    let cache_path = cache_path(obj_path);
+
+    match std::fs::read(&cache_path) {
+        Ok(bytes) => match read_cache(&mut bytes.as_slice(), obj_path, &key) {
+            Ok(Some(data)) => {
+                info!("Loaded cached mesh from {:?}", cache_path);
+                return Ok(data);
+            },
+            Ok(None) => info!("Mesh cache {:?} is out of date", cache_path),
+            Err(err) => warn!("Unable to read mesh cache {:?}: {}", cache_path, err),
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+        Err(err) => warn!("Unable to read mesh cache {:?}: {}", cache_path, err),
+    }
+
+    let builder = load_obj_builder(obj_path)?;
+    let mut data = builder.build_mesh();
+    let bvh = data.mesh.build_bvh(BvhBuildStrategy::default());
+
+    // NOTE: Failing to write the cache only costs time on the next load, so isn't an error.
+    // Write to a temporary file first, so an interrupted write never leaves a truncated cache behind.
+    let temp_path = cache_path.with_extension(format!("{}.tmp", CACHE_EXTENSION));
+    let written = std::fs::File::create(&temp_path)
+        .and_then(|file| {
+            let mut writer = io::BufWriter::new(file);
+            write_cache(&mut writer, obj_path, &key, &builder, &data, &bvh)?;
+            writer.flush()
+        })
+        .and_then(|_| std::fs::rename(&temp_path, &cache_path));
+    match written {
+        Ok(()) => info!("Wrote mesh cache to {:?}", cache_path),
+        Err(err) => {
+            warn!("Unable to write mesh cache {:?}: {}", cache_path, err);
+            let _ = std::fs::remove_file(&temp_path);
+        },
+    }
+
+    data.bvh = Some(bvh);
+    Ok(data)
+}
+
+fn cache_path(obj_path: &Path) -> PathBuf {
+    let mut file_name = obj_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(CACHE_EXTENSION);
+    obj_path.with_file_name(file_name)
+}
+
+/// Identifies the inputs the cached mesh was built from
+struct CacheKey {
+    obj_hash: u64,
+    crease_angle: f32,
+}
+
+/// 64-bit FNV-1a hash. Unlike std's hashers, the result is stable between builds and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Hashes the MTL file the OBJ refers to, which must also be unchanged for the cache to be used
+fn mtl_hash(obj_path: &Path, mtllib: Option<&str>) -> io::Result<u64> {
+    match mtllib {
+        Some(mtllib) => Ok(fnv1a(&std::fs::read(obj_path.parent().unwrap().join(mtllib))?)),
+        None => Ok(0),
+    }
+}
+
+fn write_cache(writer: &mut dyn Write, obj_path: &Path, key: &CacheKey, builder: &ObjMeshBuilder, data: &MeshAndTextureData, bvh: &Bvh) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    write_u32(writer, VERSION)?;
+    write_u64(writer, key.obj_hash)?;
+    write_f32(writer, key.crease_angle)?;
+    write_option_str(writer, builder.mtllib())?;
+    write_u64(writer, mtl_hash(obj_path, builder.mtllib())?)?;
+
+    let textures = &data.texture_set.textures;
+    write_u32(writer, textures.len() as u32)?;
+    for texture in textures {
+        // NOTE: The MTL material is stored rather than the texture and principled material built from it,
+        // only the names of the color maps are stored.
+        let mtl = builder.material(&texture.name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "texture without a material"))?;
+        write_material(writer, mtl)?;
+    }
+
+    let mesh = &data.mesh;
+    write_u32(writer, mesh.vertices.len() as u32)?;
+    for &v in &mesh.vertices {
+        write_v3(writer, v)?;
+    }
+    write_u32(writer, mesh.normals.len() as u32)?;
+    for &n in &mesh.normals {
+        write_v3(writer, n)?;
+    }
+    write_u32(writer, mesh.uvs.len() as u32)?;
+    for &V2(u, v) in &mesh.uvs {
+        write_f32(writer, u)?;
+        write_f32(writer, v)?;
+    }
+    write_u32(writer, mesh.faces.len() as u32)?;
+    for face in &mesh.faces {
+        let flags = face.normals.is_some() as u8 | (face.uvs.is_some() as u8) << 1 | (face.tex_key.is_some() as u8) << 2;
+        writer.write_all(&[flags])?;
+        for indices in [Some(face.vertices), face.normals, face.uvs].into_iter().flatten() {
+            for index in indices {
+                write_u32(writer, index)?;
+            }
+        }
+        if let Some(tex_key) = face.tex_key {
+            write_u32(writer, tex_key as u32)?;
+        }
+    }
+
+    bvh.write(writer)
+}
+
+/// Reads the cached mesh, or None if the cache was built from different files or settings.
+/// The mesh is checked to be well formed, so corrupt caches are an error rather than a panic while rendering.
+fn read_cache(reader: &mut dyn Read, obj_path: &Path, key: &CacheKey) -> Result<Option<MeshAndTextureData>, ObjError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("not a mesh cache file"));
+    }
+    if read_u32(reader)? != VERSION || read_u64(reader)? != key.obj_hash || read_f32(reader)?.to_bits() != key.crease_angle.to_bits() {
+        return Ok(None);
+    }
+    let mtllib = read_option_string(reader)?;
+    if read_u64(reader)? != mtl_hash(obj_path, mtllib.as_deref())? {
+        return Ok(None);
+    }
+
+    let texture_count = read_u32(reader)? as usize;
+    let mut mtls = Vec::with_capacity(texture_count.min(1024));
+    let mut color_maps = HashMap::new();
+    for _ in 0..texture_count {
+        let mtl = read_material(reader)?;
+        for (color_map, channel) in mtl.maps() {
+            let Some(mtllib) = mtllib.as_ref() else {
+                return Err(invalid("color map without a material library"));
+            };
+            if let Entry::Vacant(entry) = color_maps.entry((color_map.clone(), channel)) {
+                let mtl_path = obj_path.parent().unwrap().join(mtllib);
+                let data = load_color_map(mtl_path.parent().unwrap().join(color_map), channel)?;
+                entry.insert(Arc::new(data));
+            }
+        }
+        mtls.push(mtl);
+    }
+    let textures = mtls.iter().map(|mtl| mesh_texture(mtl, &color_maps)).collect::<Vec<_>>();
+    let materials = mtls.iter().map(|mtl| mesh_material(mtl, &color_maps)).collect();
+
+    let mut mesh = Mesh {
+        vertices: read_vec(reader, read_v3)?,
+        normals: read_vec(reader, read_v3)?,
+        uvs: read_vec(reader, |reader| Ok(V2(read_f32(reader)?, read_f32(reader)?)))?,
+        faces: vec![],
+    };
+    let face_count = read_u32(reader)? as usize;
+    let in_range = |indices: [u32; 3], len: usize| indices.iter().all(|&i| (i as usize) < len);
+    mesh.faces.reserve(face_count.min(1 << 20));
+    for _ in 0..face_count {
+        let mut flags = [0];
+        reader.read_exact(&mut flags)?;
+        let [flags] = flags;
+        let vertices = read_indices(reader)?;
+        let normals = if flags & 0b001 != 0 { Some(read_indices(reader)?) } else { None };
+        let uvs = if flags & 0b010 != 0 { Some(read_indices(reader)?) } else { None };
+        let tex_key = if flags & 0b100 != 0 { Some(read_u32(reader)? as usize) } else { None };
+        if !in_range(vertices, mesh.vertices.len())
+            || normals.is_some_and(|normals| !in_range(normals, mesh.normals.len()))
+            || uvs.is_some_and(|uvs| !in_range(uvs, mesh.uvs.len()))
+            || tex_key.is_some_and(|tex_key| tex_key >= textures.len()) {
+            return Err(invalid("face index out of range"));
+        }
+        mesh.faces.push(MeshFace { vertices, normals, uvs, tex_key });
+    }
+
+    let bvh = Bvh::read(reader)?;
+    if bvh.object_count() != mesh.faces.len() {
+        return Err(invalid("BVH does not match the mesh"));
+    }
+
+    Ok(Some(MeshAndTextureData {
+        mesh: Arc::new(mesh),
+        texture_set: Arc::new(MeshTextureSet { textures }),
+        material_set: Arc::new(MeshMaterialSet { materials }),
+        bvh: Some(bvh),
+    }))
+}
+
+fn write_material(writer: &mut dyn Write, mtl: &ObjMaterial) -> io::Result<()> {
+    write_str(writer, &mtl.name)?;
+    write_v3(writer, mtl.ambient_color)?;
+    write_v3(writer, mtl.specular_color)?;
+    write_v3(writer, mtl.diffuse_color)?;
+    for value in [mtl.specular_exponent, mtl.ior, mtl.dissolve, mtl.roughness, mtl.metallic, mtl.sheen, mtl.clearcoat, mtl.clearcoat_roughness, mtl.bump_multiplier] {
+        write_option_f32(writer, value)?;
+    }
+    for map in [&mtl.diffuse_color_map, &mtl.roughness_map, &mtl.metallic_map, &mtl.sheen_map, &mtl.bump_map, &mtl.normal_map, &mtl.dissolve_map] {
+        write_option_str(writer, map.as_deref())?;
+    }
+    Ok(())
+}
+
+fn read_material(reader: &mut dyn Read) -> Result<ObjMaterial, ObjError> {
+    Ok(ObjMaterial {
+        name: read_string(reader)?,
+        ambient_color: read_v3(reader)?,
+        specular_color: read_v3(reader)?,
+        diffuse_color: read_v3(reader)?,
+        specular_exponent: read_option_f32(reader)?,
+        ior: read_option_f32(reader)?,
+        dissolve: read_option_f32(reader)?,
+        roughness: read_option_f32(reader)?,
+        metallic: read_option_f32(reader)?,
+        sheen: read_option_f32(reader)?,
+        clearcoat: read_option_f32(reader)?,
+        clearcoat_roughness: read_option_f32(reader)?,
+        bump_multiplier: read_option_f32(reader)?,
+        diffuse_color_map: read_option_string(reader)?,
+        roughness_map: read_option_string(reader)?,
+        metallic_map: read_option_string(reader)?,
+        sheen_map: read_option_string(reader)?,
+        bump_map: read_option_string(reader)?,
+        normal_map: read_option_string(reader)?,
+        dissolve_map: read_option_string(reader)?,
+    })
+}
+
+fn invalid(message: &str) -> ObjError {
+    ObjError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// Little endian encoding helpers
+
+fn write_u32(writer: &mut dyn Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut dyn Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32(writer: &mut dyn Write, value: f32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_v3(writer: &mut dyn Write, V3(x, y, z): V3) -> io::Result<()> {
+    write_f32(writer, x)?;
+    write_f32(writer, y)?;
+    write_f32(writer, z)
+}
+
+fn write_str(writer: &mut dyn Write, value: &str) -> io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn write_option_str(writer: &mut dyn Write, value: Option<&str>) -> io::Result<()> {
+    writer.write_all(&[value.is_some() as u8])?;
+    value.map_or(Ok(()), |value| write_str(writer, value))
+}
+
+fn write_option_f32(writer: &mut dyn Write, value: Option<f32>) -> io::Result<()> {
+    writer.write_all(&[value.is_some() as u8])?;
+    value.map_or(Ok(()), |value| write_f32(writer, value))
+}
+
+fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut dyn Read) -> io::Result<f32> {
+    read_u32(reader).map(f32::from_bits)
+}
+
+fn read_v3(reader: &mut dyn Read) -> io::Result<V3> {
+    Ok(V3(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?))
+}
+
+fn read_indices(reader: &mut dyn Read) -> io::Result<[u32; 3]> {
+    Ok([read_u32(reader)?, read_u32(reader)?, read_u32(reader)?])
+}
+
+fn read_string(reader: &mut dyn Read) -> Result<String, ObjError> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(invalid("truncated string"));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("string is not utf-8"))
+}
+
+fn read_option_string(reader: &mut dyn Read) -> Result<Option<String>, ObjError> {
+    let mut present = [0];
+    reader.read_exact(&mut present)?;
+    match present {
+        [0] => Ok(None),
+        [1] => Ok(Some(read_string(reader)?)),
+        _ => Err(invalid("malformed optional string")),
+    }
+}
+
+fn read_option_f32(reader: &mut dyn Read) -> Result<Option<f32>, ObjError> {
+    let mut present = [0];
+    reader.read_exact(&mut present)?;
+    match present {
+        [0] => Ok(None),
+        [1] => Ok(Some(read_f32(reader)?)),
+        _ => Err(invalid("malformed optional value")),
+    }
+}
+
+/// Reads a length prefixed list of values
+// NOTE: Don't trust the length to preallocate, a corrupt length would otherwise allocate huge amounts of memory
+fn read_vec<T>(reader: &mut dyn Read, read: fn(&mut dyn Read) -> io::Result<T>) -> io::Result<Vec<T>> {
+    let len = read_u32(reader)? as usize;
+    let mut values = Vec::with_capacity(len.min(1 << 20));
+    for _ in 0..len {
+        values.push(read(reader)?);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_and_read() {
+        let dir = std::env::temp_dir().join(format!("raytracer-obj-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let obj_path = dir.join("quad.obj");
+        std::fs::write(&obj_path, "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nf 1/1 2/2 3/3\nf 1/1 3/3 4/4\n").unwrap();
+
+        // The first load builds the mesh and writes the cache, the second reads the cache
+        let built = load_obj_cached(&obj_path).unwrap();
+        assert!(cache_path(&obj_path).exists());
+        let key = CacheKey { obj_hash: fnv1a(&std::fs::read(&obj_path).unwrap()), crease_angle: ObjMeshBuilder::default().crease_angle() };
+        let bytes = std::fs::read(cache_path(&obj_path)).unwrap();
+        let cached = read_cache(&mut bytes.as_slice(), &obj_path, &key).unwrap().expect("cache is up to date");
+        assert_eq!(cached.mesh.vertices, built.mesh.vertices);
+        assert_eq!(cached.mesh.normals, built.mesh.normals);
+        assert_eq!(cached.mesh.uvs, built.mesh.uvs);
+        assert_eq!(cached.mesh.faces.len(), built.mesh.faces.len());
+        for (a, b) in cached.mesh.faces.iter().zip(built.mesh.faces.iter()) {
+            assert_eq!((a.vertices, a.normals, a.uvs, a.tex_key), (b.vertices, b.normals, b.uvs, b.tex_key));
+        }
+
+        // A changed OBJ file makes the cache out of date, and a truncated cache is an error
+        let changed = CacheKey { obj_hash: key.obj_hash ^ 1, ..key };
+        assert!(read_cache(&mut bytes.as_slice(), &obj_path, &changed).unwrap().is_none());
+        assert!(read_cache(&mut &bytes[..bytes.len() - 1], &obj_path, &key).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
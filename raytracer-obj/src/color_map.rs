@@ -13,16 +13,34 @@ fn rgba_to_v3(rgba: Rgba<u8>) -> V3 {
     V3(r, g, b)
 }
 
-pub fn load_color_map<R: std::io::Read + std::io::Seek>(reader: R, format: image::ImageFormat) -> Result<ColorMap, ObjError> {
+fn alpha_to_v3(rgba: Rgba<u8>) -> V3 {
+    let a = rgba[3] as f32 / 255.0;
+    V3(a, a, a)
+}
+
+/// The channels of an image which a color map is read from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ColorMapChannel {
+    Color,
+    /// The alpha channel as a greyscale map, for cutting away parts of a surface (see MatCutout).
+    /// Images without an alpha channel are read as they are, to be used as greyscale masks.
+    Alpha,
+}
+
+pub fn load_color_map<R: std::io::Read + std::io::Seek>(reader: R, format: image::ImageFormat, channel: ColorMapChannel) -> Result<ColorMap, ObjError> {
     let dynamic = image::load(BufReader::new(reader), format)?;
     let width = dynamic.width();
     let height = dynamic.height();
     let mut pixels = Vec::with_capacity((width * height) as usize);
+    let to_v3 = match channel {
+        ColorMapChannel::Alpha if dynamic.color().has_alpha() => alpha_to_v3,
+        _ => rgba_to_v3,
+    };
 
     // Read all pixels into V3 format with 0,0 being top left
     for (_, _, pixel) in dynamic.pixels() {
         // Pixel data is encoded in RGBA (0-255) bytes
-        pixels.push(rgba_to_v3(pixel));
+        pixels.push(to_v3(pixel));
     }
 
     Ok(ColorMap {
@@ -31,3 +49,35 @@ pub fn load_color_map<R: std::io::Read + std::io::Seek>(reader: R, format: image
         pixels,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use image::{ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+    use raytracer_impl::types::V3;
+    use super::{load_color_map, ColorMapChannel};
+
+    fn encode_png(image: impl Into<image::DynamicImage>) -> Cursor<Vec<u8>> {
+        let mut png = Cursor::new(vec![]);
+        image.into().write_to(&mut png, ImageFormat::Png).unwrap();
+        png.set_position(0);
+        png
+    }
+
+    #[test]
+    fn load_color_and_alpha_channels() {
+        let rgba = RgbaImage::from_fn(2, 1, |x, _| if x == 0 { Rgba([255, 0, 0, 0]) } else { Rgba([0, 0, 255, 255]) });
+
+        let color = load_color_map(encode_png(rgba.clone()), ImageFormat::Png, ColorMapChannel::Color).unwrap();
+        assert_eq!((color.width, color.height), (2, 1));
+        assert_eq!(color.pixels, vec![V3(1.0, 0.0, 0.0), V3(0.0, 0.0, 1.0)]);
+
+        let alpha = load_color_map(encode_png(rgba), ImageFormat::Png, ColorMapChannel::Alpha).unwrap();
+        assert_eq!(alpha.pixels, vec![V3::ZERO, V3::ONE]);
+
+        // Images without alpha are used as greyscale masks
+        let mask = RgbImage::from_fn(2, 1, |x, _| Rgb([x as u8 * 255; 3]));
+        let alpha = load_color_map(encode_png(mask), ImageFormat::Png, ColorMapChannel::Alpha).unwrap();
+        assert_eq!(alpha.pixels, vec![V3::ZERO, V3::ONE]);
+    }
+}
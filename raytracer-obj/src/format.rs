@@ -3,6 +3,7 @@ use std::io::{BufRead, BufReader, Read};
 
 use raytracer_impl::types::{ V2, V3 };
 use crate::ObjError;
+use crate::color_map::ColorMapChannel;
 
 // Obj parser
 //
@@ -13,7 +14,6 @@ use crate::ObjError;
 // - Set "Include UVs"
 //
 // TODO(benf): Support other features of the OBJ format
-// - Vertex normals
 // - ???
 //
 // See: https://en.wikipedia.org/wiki/Wavefront_.obj_file
@@ -21,12 +21,28 @@ use crate::ObjError;
 // (even for the elements it supports) and makes some assumptions:
 // - every vertex has three components `v x y z`
 // - every texture coordinate has two components `vt u v`
-// - every face has three components `f a b c` (triangles only)
+// - every vertex normal has three components `vn x y z`
+// - every face has at least three components `f a b c ...` (polygons are split into triangles, see ObjFace::triangles)
+//
+// Creased edges of subdivision surfaces are read from OpenSubdiv style tags `t crease 2/1/0 a b sharpness`,
+// where a & b are 0-based vertex indices. A chain of edges may share one sharpness (`t crease 3/1/0 a b c sharpness`)
+// or give each edge its own (`t crease 3/2/0 a b c ab bc`).
 
 #[derive(Default)]
 pub struct ObjShared {
     pub vertices: Vec<V3>,
     pub uv: Vec<V2>,
+    pub normals: Vec<V3>,
+    pub creases: Vec<ObjCrease>,
+}
+
+/// The sharpness of the edge between two vertices, for subdivision surfaces
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ObjCrease {
+    /// 1-based vertex indices, as used by faces
+    pub a: usize,
+    pub b: usize,
+    pub sharpness: f32,
 }
 
 pub struct ObjGroup {
@@ -41,6 +57,40 @@ pub struct ObjMaterial {
     pub specular_color: V3,
     pub diffuse_color: V3,
     pub diffuse_color_map: Option<String>,
+    /// Specular exponent `Ns`
+    pub specular_exponent: Option<f32>,
+    /// Index of refraction `Ni`
+    pub ior: Option<f32>,
+    /// Opacity `d` (or `1 - Tr`)
+    pub dissolve: Option<f32>,
+    /// Opacity map `map_d`, read from the alpha channel of the image
+    pub dissolve_map: Option<String>,
+    /// PBR extension `Pr`, `Pm`, `Ps`, `Pc` & `Pcr` values
+    pub roughness: Option<f32>,
+    pub metallic: Option<f32>,
+    pub sheen: Option<f32>,
+    pub clearcoat: Option<f32>,
+    pub clearcoat_roughness: Option<f32>,
+    /// PBR extension `map_Pr`, `map_Pm` & `map_Ps` maps
+    pub roughness_map: Option<String>,
+    pub metallic_map: Option<String>,
+    pub sheen_map: Option<String>,
+    /// Greyscale height map `map_Bump` (or `bump`), scaled by the `-bm` option
+    pub bump_map: Option<String>,
+    pub bump_multiplier: Option<f32>,
+    /// PBR extension tangent space normal map `norm`
+    pub normal_map: Option<String>,
+}
+
+impl ObjMaterial {
+    /// Names of all of the maps referenced by this material, and the channels they are read from
+    pub fn maps(&self) -> impl Iterator<Item=(&String, ColorMapChannel)> {
+        [&self.diffuse_color_map, &self.roughness_map, &self.metallic_map, &self.sheen_map, &self.bump_map, &self.normal_map]
+            .into_iter()
+            .flatten()
+            .map(|name| (name, ColorMapChannel::Color))
+            .chain(self.dissolve_map.iter().map(|name| (name, ColorMapChannel::Alpha)))
+    }
 }
 
 #[derive(Default, Copy, Clone)]
@@ -85,12 +135,19 @@ impl std::str::FromStr for ObjVertex {
 }
 
 pub struct ObjFace {
-    pub a: ObjVertex,
-    pub b: ObjVertex,
-    pub c: ObjVertex,
+    /// The corners of the face, three or more
+    pub vertices: Vec<ObjVertex>,
     pub mtl: Option<String>,
 }
 
+impl ObjFace {
+    /// Splits the face into a fan of triangles around its first vertex
+    pub fn triangles(&self) -> impl Iterator<Item=[ObjVertex; 3]> + '_ {
+        (1..self.vertices.len().saturating_sub(1))
+            .map(|i| [self.vertices[0], self.vertices[i], self.vertices[i + 1]])
+    }
+}
+
 pub fn try_parse_elements<T, const N: usize>(line: &str) -> Option<[T; N]>
     where T: std::str::FromStr, T: Default, T: Copy
 {
@@ -106,6 +163,75 @@ pub fn try_parse_elements<T, const N: usize>(line: &str) -> Option<[T; N]>
     Some(values)
 }
 
+fn parse_scalar(directive: &str, line_no: usize, data: &str) -> Result<f32, ObjError> {
+    let [value] = try_parse_elements(data.trim())
+        .ok_or_else(|| ObjError::General(format!("Unable to parse {directive} on line {line_no}: {data}")))?;
+    Ok(value)
+}
+
+/// Splits a map directive into the file name and the bump multiplier (`-bm`), skipping any other options.
+/// e.g. `map_Bump -bm 0.5 -s 2 2 panels.png`
+fn parse_map(directive: &str, line_no: usize, data: &str) -> Result<(String, Option<f32>), ObjError> {
+    let error = || ObjError::General(format!("Unable to parse {directive} on line {line_no}: {data}"));
+    let mut rest = data.trim();
+    let mut bump_multiplier = None;
+    while rest.starts_with('-') {
+        let (option, args) = rest.split_once(char::is_whitespace).ok_or_else(error)?;
+        // The number of arguments of each option, where the last two arguments of -o, -s & -t are optional
+        let (min_args, max_args) = match option {
+            "-o" | "-s" | "-t" => (1, 3),
+            "-mm" => (2, 2),
+            _ => (1, 1),
+        };
+        let mut args = args.trim_start();
+        for i in 0..max_args {
+            let (arg, next) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            if i >= min_args && arg.parse::<f32>().is_err() {
+                break;
+            }
+            if arg.is_empty() {
+                return Err(error());
+            }
+            if option == "-bm" {
+                bump_multiplier = Some(arg.parse().map_err(|_| error())?);
+            }
+            args = next.trim_start();
+        }
+        rest = args;
+    }
+    if rest.is_empty() {
+        return Err(error());
+    }
+    Ok((clean(rest), bump_multiplier))
+}
+
+/// Parses an OpenSubdiv style crease tag `2/1/0 a b sharpness` into the creased edges
+fn parse_creases(line_no: usize, data: &str) -> Result<Vec<ObjCrease>, ObjError> {
+    let error = || ObjError::General(format!("Unable to parse crease on line {line_no}: {data}"));
+    let mut parts = data.split_whitespace();
+    let mut counts = parts.next().ok_or_else(error)?.split('/');
+    let int_count: usize = counts.next().and_then(|n| n.parse().ok()).ok_or_else(error)?;
+    let float_count: usize = counts.next().and_then(|n| n.parse().ok()).ok_or_else(error)?;
+    if int_count < 2 || (float_count != 1 && float_count != int_count - 1) {
+        return Err(error());
+    }
+    let indices = parts.by_ref().take(int_count)
+        .map(|i| i.parse::<usize>().map(|i| i + 1))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| error())?;
+    let sharpness = parts.by_ref().take(float_count)
+        .map(|s| s.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| error())?;
+    if indices.len() != int_count || sharpness.len() != float_count {
+        return Err(error());
+    }
+    Ok(indices.windows(2)
+        .enumerate()
+        .map(|(i, edge)| ObjCrease { a: edge[0], b: edge[1], sharpness: sharpness[i.min(float_count - 1)] })
+        .collect())
+}
+
 fn clean(line: &str) -> String {
     line.trim().to_string()
 }
@@ -116,13 +242,15 @@ pub struct ObjFile {
     pub shared: Arc<ObjShared>,
 }
 
-/// Braindead OBJ parser, supports o, v, vt & f directives only.
+/// Braindead OBJ parser, supports o, v, vt, vn, f & t crease directives only.
 #[derive(Default)]
 struct ObjFileParseState {
     // File-level directives
     mtllib: Option<String>,
     vertices: Vec<V3>,
     uv: Vec<V2>,
+    normals: Vec<V3>,
+    creases: Vec<ObjCrease>,
 
     // Object-level directives
     group_name: Option<String>,
@@ -156,7 +284,7 @@ impl ObjFileParseState {
         let mut groups = self.groups;
         
         // Fix shared data references
-        let shared = Arc::new(ObjShared { vertices: self.vertices, uv: self.uv });
+        let shared = Arc::new(ObjShared { vertices: self.vertices, uv: self.uv, normals: self.normals, creases: self.creases });
         for group in groups.iter_mut() {
             group.shared = shared.clone();
         }
@@ -204,15 +332,27 @@ pub fn parse_obj_file(source: &mut dyn Read) -> Result<ObjFile, ObjError> {
                 state.uv.push(V2(u, v));
             },
             // Vertex normals
-            Some(("vn", _)) => {
-                // TODO
+            Some(("vn", data)) => {
+                let [x, y, z] = try_parse_elements(data)
+                    .ok_or_else(|| ObjError::General(format!("Unable to parse vertex normal on line {line_no}: {data}")))?;
+                state.normals.push(V3(x, y, z));
             },
             // Face
             Some(("f", data)) => {
                 let mtl = state.mtl.clone();
-                let [a, b, c] = try_parse_elements(data)
+                let vertices = data.split_whitespace()
+                    .map(|v| v.parse())
+                    .collect::<Result<Vec<ObjVertex>, _>>()
+                    .ok()
+                    .filter(|vertices| vertices.len() >= 3)
                     .ok_or_else(|| ObjError::General(format!("Unable to parse face on line {line_no}: {data}")))?;
-                state.faces.push(ObjFace { a, b, c, mtl });
+                state.faces.push(ObjFace { vertices, mtl });
+            },
+            // Creased edges
+            Some(("t", data)) => {
+                if let Some(("crease", data)) = data.trim().split_once(char::is_whitespace) {
+                    state.creases.extend(parse_creases(line_no, data)?);
+                }
             },
             _ => {}
         }
@@ -228,7 +368,8 @@ pub struct MtlFile {
     pub materials: Vec<ObjMaterial>,
 }
 
-/// Braindead MTL parser, supports newmtl, Kd and Kd_map directives only.
+/// Braindead MTL parser, supports newmtl, Ka, Ks, Kd, map_Kd, map_Bump, bump, Ns, Ni, d, Tr & map_d directives
+/// and the PBR extension Pr, Pm, Ps, Pc, Pcr, map_Pr, map_Pm, map_Ps & norm directives only.
 #[derive(Default)]
 struct MtlFileParseState {
     name: Option<String>,
@@ -236,6 +377,21 @@ struct MtlFileParseState {
     specular_color: V3,
     diffuse_color: V3,
     diffuse_color_map: Option<String>,
+    specular_exponent: Option<f32>,
+    ior: Option<f32>,
+    dissolve: Option<f32>,
+    dissolve_map: Option<String>,
+    roughness: Option<f32>,
+    metallic: Option<f32>,
+    sheen: Option<f32>,
+    clearcoat: Option<f32>,
+    clearcoat_roughness: Option<f32>,
+    roughness_map: Option<String>,
+    metallic_map: Option<String>,
+    sheen_map: Option<String>,
+    bump_map: Option<String>,
+    bump_multiplier: Option<f32>,
+    normal_map: Option<String>,
 
     materials: Vec<ObjMaterial>,
 }
@@ -253,6 +409,21 @@ impl MtlFileParseState {
             specular_color: std::mem::take(&mut self.specular_color),
             diffuse_color: std::mem::take(&mut self.diffuse_color),
             diffuse_color_map: self.diffuse_color_map.take(),
+            specular_exponent: self.specular_exponent.take(),
+            ior: self.ior.take(),
+            dissolve: self.dissolve.take(),
+            dissolve_map: self.dissolve_map.take(),
+            roughness: self.roughness.take(),
+            metallic: self.metallic.take(),
+            sheen: self.sheen.take(),
+            clearcoat: self.clearcoat.take(),
+            clearcoat_roughness: self.clearcoat_roughness.take(),
+            roughness_map: self.roughness_map.take(),
+            metallic_map: self.metallic_map.take(),
+            sheen_map: self.sheen_map.take(),
+            bump_map: self.bump_map.take(),
+            bump_multiplier: self.bump_multiplier.take(),
+            normal_map: self.normal_map.take(),
         });
     }
 
@@ -302,6 +473,28 @@ pub fn parse_mtl_file(source: &mut dyn Read) -> Result<MtlFile, ObjError> {
             Some(("map_Kd", data)) => { 
                 state.diffuse_color_map = Some(clean(data));
             },
+            // Specular exponent, index of refraction & opacity
+            Some(("Ns", data)) => state.specular_exponent = Some(parse_scalar("Ns", line_no, data)?),
+            Some(("Ni", data)) => state.ior = Some(parse_scalar("Ni", line_no, data)?),
+            Some(("d", data)) => state.dissolve = Some(parse_scalar("d", line_no, data)?),
+            Some(("Tr", data)) => state.dissolve = Some(1.0 - parse_scalar("Tr", line_no, data)?),
+            Some(("map_d", data)) => state.dissolve_map = Some(parse_map("map_d", line_no, data)?.0),
+            // PBR extension
+            Some(("Pr", data)) => state.roughness = Some(parse_scalar("Pr", line_no, data)?),
+            Some(("Pm", data)) => state.metallic = Some(parse_scalar("Pm", line_no, data)?),
+            Some(("Ps", data)) => state.sheen = Some(parse_scalar("Ps", line_no, data)?),
+            Some(("Pc", data)) => state.clearcoat = Some(parse_scalar("Pc", line_no, data)?),
+            Some(("Pcr", data)) => state.clearcoat_roughness = Some(parse_scalar("Pcr", line_no, data)?),
+            Some(("map_Pr", data)) => state.roughness_map = Some(clean(data)),
+            Some(("map_Pm", data)) => state.metallic_map = Some(clean(data)),
+            Some(("map_Ps", data)) => state.sheen_map = Some(clean(data)),
+            // Bump & normal maps
+            Some((directive @ ("map_Bump" | "bump"), data)) => {
+                let (map, bump_multiplier) = parse_map(directive, line_no, data)?;
+                state.bump_map = Some(map);
+                state.bump_multiplier = bump_multiplier;
+            },
+            Some(("norm", data)) => state.normal_map = Some(parse_map("norm", line_no, data)?.0),
             _ => {}
         }
     }
@@ -311,3 +504,71 @@ pub fn parse_mtl_file(source: &mut dyn Read) -> Result<MtlFile, ObjError> {
 
     Ok(state.complete())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_mtl_pbr() {
+        let source = "newmtl plain\nKd 0.5 0.5 0.5\nNs 250\nTr 0.25\n\nnewmtl pbr\nPr 0.2\nPm 1\nPc 0.5\nPcr 0.1\nmap_Pr rough.png\nNi 1.45\nd 1.0\n";
+        let mtl_file = parse_mtl_file(&mut source.as_bytes()).unwrap();
+        let [plain, pbr] = mtl_file.materials.as_slice() else { panic!("expected two materials") };
+
+        assert_eq!(plain.diffuse_color, V3(0.5, 0.5, 0.5));
+        assert_eq!(plain.specular_exponent, Some(250.0));
+        assert_eq!(plain.dissolve, Some(0.75));
+        assert_eq!(plain.roughness, None);
+
+        assert_eq!((pbr.roughness, pbr.metallic, pbr.sheen), (Some(0.2), Some(1.0), None));
+        assert_eq!((pbr.clearcoat, pbr.clearcoat_roughness), (Some(0.5), Some(0.1)));
+        assert_eq!((pbr.ior, pbr.dissolve), (Some(1.45), Some(1.0)));
+        assert_eq!(pbr.maps().collect::<Vec<_>>(), vec![(&"rough.png".to_string(), ColorMapChannel::Color)]);
+    }
+
+    #[test]
+    fn parse_mtl_bump_and_normal_maps() {
+        let source = "newmtl hull\nmap_Bump -bm 0.25 -s 2 2 panels.png\nnorm -clamp on hull_normal.png\n\nnewmtl trim\nbump trim.png\n";
+        let mtl_file = parse_mtl_file(&mut source.as_bytes()).unwrap();
+        let [hull, trim] = mtl_file.materials.as_slice() else { panic!("expected two materials") };
+
+        assert_eq!((hull.bump_map.as_deref(), hull.bump_multiplier), (Some("panels.png"), Some(0.25)));
+        assert_eq!(hull.normal_map.as_deref(), Some("hull_normal.png"));
+        assert_eq!(hull.maps().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["panels.png", "hull_normal.png"]);
+        assert_eq!((trim.bump_map.as_deref(), trim.bump_multiplier), (Some("trim.png"), None));
+
+        assert!(parse_mtl_file(&mut "newmtl broken\nmap_Bump -bm\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_mtl_dissolve_map() {
+        // The same image may be used for the color, and for the opacity in its alpha channel
+        let source = "newmtl leaves\nmap_Kd leaves.png\nmap_d -imfchan m leaves.png\n";
+        let mtl_file = parse_mtl_file(&mut source.as_bytes()).unwrap();
+        let [leaves] = mtl_file.materials.as_slice() else { panic!("expected one material") };
+
+        assert_eq!(leaves.dissolve_map.as_deref(), Some("leaves.png"));
+        let maps = leaves.maps().map(|(name, channel)| (name.as_str(), channel)).collect::<Vec<_>>();
+        assert_eq!(maps, vec![("leaves.png", ColorMapChannel::Color), ("leaves.png", ColorMapChannel::Alpha)]);
+    }
+
+    #[test]
+    fn parse_obj_polygons_and_creases() {
+        let source = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 2 0\nf 1 2 3 4\nf 4 3 5\nt crease 2/1/0 0 1 4.5\nt crease 3/2/0 1 2 3 1 2\n";
+        let obj_file = parse_obj_file(&mut source.as_bytes()).unwrap();
+        let faces = &obj_file.groups[0].faces;
+        assert_eq!(faces.iter().map(|f| f.vertices.len()).collect::<Vec<_>>(), vec![4, 3]);
+
+        // Polygons are split into a fan of triangles
+        let triangles = faces[0].triangles().map(|t| t.map(|v| v.vertex_index)).collect::<Vec<_>>();
+        assert_eq!(triangles, vec![[1, 2, 3], [1, 3, 4]]);
+
+        // Crease indices are converted to 1-based
+        let creases = &obj_file.shared.creases;
+        assert_eq!(creases[0], ObjCrease { a: 1, b: 2, sharpness: 4.5 });
+        assert_eq!(creases[1..], [ObjCrease { a: 2, b: 3, sharpness: 1.0 }, ObjCrease { a: 3, b: 4, sharpness: 2.0 }]);
+
+        assert!(parse_obj_file(&mut "v 0 0 0\nf 1 1\n".as_bytes()).is_err());
+        assert!(parse_obj_file(&mut "t crease 2/1/0 0 1\n".as_bytes()).is_err());
+    }
+}
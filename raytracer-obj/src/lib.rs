@@ -1,8 +1,11 @@
 mod builder;
 mod format;
 mod color_map;
+mod cache;
 
 pub use builder::{ ObjMeshBuilder, MeshAndTextureData, load_obj_builder, load_obj, load_mtl, load_color_map };
+pub use color_map::ColorMapChannel;
+pub use cache::load_obj_cached;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ObjError {
@@ -14,6 +14,14 @@ mod scene_interceptor_spin;
 mod scene_point_cloud;
 mod scene_entity_transform_test;
 mod scene_uv_test;
+mod scene_sdf;
+mod scene_terrain;
+mod scene_asteroid_field;
+mod scene_materials;
+mod scene_displacement;
+mod scene_subdivision;
+mod scene_subsurface;
+mod scene_volumes;
 
 pub fn make_sample_scene_factories() -> Vec<Arc<dyn SceneFactory + Send + Sync>> {
     vec![
@@ -33,6 +41,14 @@ pub fn make_sample_scene_factories() -> Vec<Arc<dyn SceneFactory + Send + Sync>>
         Arc::new(scene_dootdoot::SceneDootDoot),
         Arc::new(scene_entity_transform_test::EntityTransformTest),
         Arc::new(scene_uv_test::SceneUvTest),
+        Arc::new(scene_sdf::SceneSdf),
+        Arc::new(scene_terrain::SceneTerrain),
+        Arc::new(scene_asteroid_field::SceneAsteroidField),
+        Arc::new(scene_materials::SceneMaterials),
+        Arc::new(scene_displacement::SceneDisplacement),
+        Arc::new(scene_subdivision::SceneSubdivision),
+        Arc::new(scene_subsurface::SceneSubsurface),
+        Arc::new(scene_volumes::SceneVolumes),
     ]
 }
 
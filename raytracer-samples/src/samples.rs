@@ -8,10 +8,11 @@ use std::path::Path;
 use raytracer_impl::texture::{ ColorTexture, CheckerTexture, UvTestTexture, XyzTestTexture, MeshTextureSet };
 use raytracer_impl::types::{ V3, Ray };
 use raytracer_impl::materials::{ MatLambertian, MatDielectric, MatSpecular };
-use raytracer_impl::shapes::{ Sphere, Plane, MeshObject, MeshTri, Mesh, mesh };
+use raytracer_impl::shapes::{ Sphere, Plane, MeshObject, MeshTri, Mesh, InstanceSet, mesh };
+use raytracer_impl::matrix::{ Matrix, MatrixBuilder };
 use raytracer_impl::lights::{ PointLight, DirectionalLight, LampLight };
 use raytracer_impl::implementation::{ Camera, Entity, MatId, Material, Scene, SceneSky, TexId };
-use raytracer_obj::{ load_obj_builder, load_color_map };
+use raytracer_obj::{ load_obj_builder, load_obj_cached, load_color_map };
 use rand::{ Rng };
 
 // Positioning helpers
@@ -407,29 +408,25 @@ pub fn triangle_world(config: &CameraConfiguration) -> Result<Scene, CreateScene
     let tri_pos = position!(Origin);
     let tri_mat = scene.add_material(MatLambertian::default().with_reflectivity(0.0));
     let tri_tex = scene.add_texture(ColorTexture(rgb(200, 100, 80)));
-    let tri_mesh = Mesh {
-        tris: vec![
-            MeshTri::from_abc(
-                position!(Up(0.3), South(1.0)),
-                position!(Up(0.6), East(1.0)),
-                position!(Up(0.8), West(1.0))
-            )
-        ],
-    };
+    let tri_mesh = Mesh::from_tris([
+        MeshTri::from_abc(
+            position!(Up(0.3), South(1.0)),
+            position!(Up(0.6), East(1.0)),
+            position!(Up(0.8), West(1.0))
+        )
+    ]);
     scene.add_entity(Entity::new(MeshObject::new(tri_mesh, tri_mat, tri_tex)).translate(tri_pos));
 
     let tri_pos = position!(Up(1.0));
     let tri_mat = scene.add_material(MatLambertian::default().with_reflectivity(0.0));
     let tri_tex = scene.add_texture(ColorTexture(rgb(100, 100, 200)));
-    let tri_mesh = Mesh {
-        tris: vec![
-            MeshTri::from_abc(
-                position!(Up(0.4), North(1.0)),
-                position!(Up(0.8), South(1.0)),
-                position!(Up(0.6), East(1.0))
-            )
-        ],
-    };
+    let tri_mesh = Mesh::from_tris([
+        MeshTri::from_abc(
+            position!(Up(0.4), North(1.0)),
+            position!(Up(0.8), South(1.0)),
+            position!(Up(0.6), East(1.0))
+        )
+    ]);
     scene.add_entity(Entity::new(MeshObject::new(tri_mesh, tri_mat, tri_tex)).translate(tri_pos));
 
     Ok(scene)
@@ -481,9 +478,9 @@ pub fn mesh_demo(config: &CameraConfiguration) -> Result<Scene, CreateSceneError
     let thing_mat = scene.add_material(MatSpecular::default().with_reflectivity(0.8).with_fuzz(0.02));
     let thing_tex = scene.add_texture(ColorTexture(rgb(89, 172, 255)));
     let thing_origin = position!(North(1.5), East(1.5));
-    let thing_mesh_data = load_obj_builder(crate::mesh_path!("simple/thing.obj"))?.build_mesh();
+    let thing_mesh_data = load_obj_cached(crate::mesh_path!("simple/thing.obj"))?;
     scene.add_entity(
-        Entity::new(MeshObject::new(thing_mesh_data.mesh, thing_mat, thing_tex))
+        Entity::new(thing_mesh_data.into_mesh_object(thing_mat, thing_tex))
             .translate(thing_origin)
             .id(2)
     );
@@ -492,9 +489,9 @@ pub fn mesh_demo(config: &CameraConfiguration) -> Result<Scene, CreateSceneError
     let suz_mat = scene.add_material(MatDielectric::default().with_opacity(0.2).with_ref_index(0.8).with_reflectivity(0.0));
     let suz_tex = scene.add_texture(ColorTexture(rgb(255, 137, 58)));
     let suz_origin = position!(Origin);
-    let suz_mesh_data = load_obj_builder(crate::mesh_path!("simple/suzanne.obj"))?.build_mesh();
+    let suz_mesh_data = load_obj_cached(crate::mesh_path!("simple/suzanne.obj"))?;
     scene.add_entity(
-        Entity::new(MeshObject::new(suz_mesh_data.mesh, suz_mat, suz_tex))
+        Entity::new(suz_mesh_data.into_mesh_object(suz_mat, suz_tex))
             .translate(suz_origin)
             .id(3)
     );
@@ -529,18 +526,18 @@ pub fn spaceships(config: &CameraConfiguration) -> Result<Scene, CreateSceneErro
     );
 
     // Destroyer (facing EAST)
-    let dest_mesh_data = load_obj_builder(crate::mesh_path!("Destroyer-K/Standarddestroyer.obj"))?.build_mesh();
+    let dest_mesh_data = load_obj_cached(crate::mesh_path!("Destroyer-K/Standarddestroyer.obj"))?;
     let dest_mat = scene.add_material(MatLambertian::default());
-    let dest_tex = scene.add_texture(dest_mesh_data.texture_set);
+    let dest_tex = scene.add_texture(dest_mesh_data.texture_set.clone());
     // NOTE: Destroyer model is facing +Z rotated on its side (X UP)
-    let dest_mesh = Entity::new(MeshObject::new(dest_mesh_data.mesh, dest_mat, dest_tex)).rotate(V3::POS_Z, deg_to_rad(90.0));
+    let dest_mesh = Entity::new(dest_mesh_data.into_mesh_object(dest_mat, dest_tex)).rotate(V3::POS_Z, deg_to_rad(90.0));
 
     // Interceptor (facing EAST)
-    let int_mesh_data = load_obj_builder(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?.build_mesh();
+    let int_mesh_data = load_obj_cached(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?;
     let int_mat = scene.add_material(MatLambertian::default());
-    let int_tex = scene.add_texture(int_mesh_data.texture_set);
+    let int_tex = scene.add_texture(int_mesh_data.texture_set.clone());
     // NOTE: Interceptor model is facing +Z rotated on its side (X UP)
-    let int_mesh = Entity::new(MeshObject::new(int_mesh_data.mesh, int_mat, int_tex)).rotate(V3::POS_Z, deg_to_rad(90.0));
+    let int_mesh = Entity::new(int_mesh_data.into_mesh_object(int_mat, int_tex)).rotate(V3::POS_Z, deg_to_rad(90.0));
 
     // Spawn a few interceptors across the bow of the Destroyer
     let int_origin = look_to + position!(Up(200.0), East(300.0), South(30.0));
@@ -582,12 +579,12 @@ pub fn capsule(config: &CameraConfiguration) -> Result<Scene, CreateSceneError>
     );
 
     // Capsule
-    let capsule_mesh_data = load_obj_builder(crate::mesh_path!("capsule/capsule.obj"))?.build_mesh();
+    let capsule_mesh_data = load_obj_cached(crate::mesh_path!("capsule/capsule.obj"))?;
     let capsule_mat = scene.add_material(MatLambertian::default());
-    let capsule_tex = scene.add_texture(capsule_mesh_data.texture_set);
+    let capsule_tex = scene.add_texture(capsule_mesh_data.texture_set.clone());
     let capsule_origin = position!(Up(4.0));
     scene.add_entity(
-        Entity::new(MeshObject::new(capsule_mesh_data.mesh, capsule_mat, capsule_tex))
+        Entity::new(capsule_mesh_data.into_mesh_object(capsule_mat, capsule_tex))
             .translate(capsule_origin)
     );
 
@@ -614,16 +611,15 @@ pub fn mega_cube(config: &CameraConfiguration) -> Result<Scene, CreateSceneError
 
     let range = (-100..=100).step_by(25);
 
+    let mut transforms = vec![];
     for x in range.clone() {
         for y in range.clone() {
             for z in range.clone() {
-                scene.add_entity(
-                    Entity::new(Sphere::new(10.0, int_mat, int_tex))
-                        .translate(V3(x as f32, y as f32, z as f32))
-                );
+                transforms.push(Matrix::translate(x as f32, y as f32, z as f32));
             }
         }
     }
+    scene.add_entity(Entity::new(InstanceSet::new(Sphere::new(10.0, int_mat, int_tex), transforms)));
 
     Ok(scene)
 }
@@ -643,23 +639,28 @@ pub fn fleet(config: &CameraConfiguration) -> Result<Scene, CreateSceneError> {
     // Lights
     scene.add_light(PointLight::with_origin(look_from).with_intensity(2000.0));
 
-    let int_mesh_data = load_obj_builder(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?.build_mesh();
+    let int_mesh_data = load_obj_cached(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?;
     let int_mat = scene.add_material(MatLambertian::default());
-    let int_tex = scene.add_texture(int_mesh_data.texture_set);
-    let int_mesh = Entity::new(MeshObject::new(int_mesh_data.mesh, int_mat, int_tex))
-        // Interceptor model is facing +Z rotated on its side (X UP?)
-        .rotate(V3::POS_Z, deg_to_rad(90.0));
+    let int_tex = scene.add_texture(int_mesh_data.texture_set.clone());
+    let int_mesh = int_mesh_data.into_mesh_object(int_mat, int_tex);
 
     let range = (-600..=0).step_by(60);
 
+    let mut transforms = vec![];
     for x in range.clone() {
         for y in range.clone() {
             for z in range.clone() {
-                let origin = V3(x as f32, y as f32, z as f32);
-                scene.add_entity(int_mesh.clone().translate(origin));
+                transforms.push(
+                    MatrixBuilder::new()
+                        // Interceptor model is facing +Z rotated on its side (X UP?)
+                        .rotate(V3::POS_Z, deg_to_rad(90.0))
+                        .translate(x as f32, y as f32, z as f32)
+                        .done()
+                );
             }
         }
     }
+    scene.add_entity(Entity::new(InstanceSet::new(int_mesh, transforms)));
 
     Ok(scene)
 }
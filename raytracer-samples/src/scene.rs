@@ -70,8 +70,16 @@ pub trait SceneFactory {
     fn name(&self) -> &str;
     fn create_controls(&self) -> SceneControlCollection;
     fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<raytracer_impl::implementation::Scene, CreateSceneError>;
+
+    /// Moves the entities of a {scene} made by create_scene with the same {config} to the current time, for scenes which animate.
+    /// Returns false if the scene can't be updated in place, and must be created again.
+    /// NOTE: The caller refits the BVH of an updated scene (see Scene::refit_bvh)
+    fn update_scene(&self, _scene: &mut raytracer_impl::implementation::Scene, _config: &SceneConfiguration) -> Result<bool, CreateSceneError> {
+        Ok(false)
+    }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct SceneConfiguration {
     values: HashMap<String, f32>
 }
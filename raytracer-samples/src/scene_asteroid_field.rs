@@ -0,0 +1,124 @@
+use rand::Rng;
+use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::matrix::MatrixBuilder;
+use raytracer_impl::noise::Perlin;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use raytracer_obj::load_obj_cached;
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneAsteroidField;
+
+impl SceneFactory for SceneAsteroidField {
+    fn name(&self) -> &str {
+        "Asteroid Field"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::select_list("Instance Object", vec!["Asteroid".into(), "Interceptor".into()]),
+                SceneControl::range("Camera Distance", 10.0, 5000.0).with_default(1400.0),
+                SceneControl::range("Instance Count", 1.0, 1_000_000.0).with_default(100_000.0),
+                SceneControl::range("Field Radius", 10.0, 5000.0).with_default(1000.0),
+                SceneControl::range("Field Thickness", 1.0, 1000.0).with_default(120.0),
+                SceneControl::range("Instance Scale", 0.1, 20.0).with_default(3.0),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let dist = config.get("Camera Distance")?;
+        let look_to   = V3::ZERO;
+        let look_from = V3(0.0, 0.35, 1.0).unit() * dist;
+        let camera    = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Black);
+
+        // Lights
+        scene.add_light(DirectionalLight::with_direction(V3(-1.0, -0.4, -0.3)).with_intensity(1.0));
+
+        // Planet
+        let planet_mat = scene.add_material(MatLambertian::default());
+        let planet_tex = scene.add_texture(ColorTexture(V3(0.8, 0.6, 0.4)));
+        let field_radius = config.get("Field Radius")?;
+        scene.add_entity(Entity::new(Sphere::new(field_radius * 0.4, planet_mat, planet_tex)).id(0));
+
+        // Scatter instances through a ring around the planet
+        let mut rng = create_rng_from_seed(982374982734);
+        let count = config.get("Instance Count")? as usize;
+        let thickness = config.get("Field Thickness")?;
+        let mut make_transforms = |scale: f32| {
+            (0..count)
+                .map(|_| {
+                    let theta = rng.random::<f32>() * std::f32::consts::TAU;
+                    let radius = field_radius * (0.6 + 0.4 * rng.random::<f32>());
+                    let height = (rng.random::<f32>() - 0.5) * thickness;
+                    let s = scale * (0.3 + rng.random::<f32>());
+                    let axis = V3(rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5, rng.random::<f32>() - 0.5).unit();
+                    MatrixBuilder::new()
+                        .scale(s, s * (0.6 + 0.4 * rng.random::<f32>()), s)
+                        .rotate(axis, deg_to_rad(rng.random::<f32>() * 360.0))
+                        .translate(theta.cos() * radius, height, theta.sin() * radius)
+                        .done()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Instanced object
+        let scale = config.get("Instance Scale")?;
+        let mat = scene.add_material(MatLambertian::default());
+        let instances = match config.get("Instance Object")? as usize {
+            0 => {
+                let tex = scene.add_texture(ColorTexture(V3(0.45, 0.42, 0.4)));
+                InstanceSet::new(MeshObject::new(make_asteroid_mesh(1234), mat, tex), make_transforms(scale))
+            },
+            _ => {
+                let int_mesh_data = load_obj_cached(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?;
+                let tex = scene.add_texture(int_mesh_data.texture_set.clone());
+                // Interceptor model is roughly 10x larger than the asteroid
+                InstanceSet::new(int_mesh_data.into_mesh_object(mat, tex), make_transforms(scale * 0.1))
+            },
+        };
+        scene.add_entity(Entity::new(instances).id(1));
+
+        Ok(scene)
+    }
+}
+
+/// Creates a lumpy unit sphere by displacing the vertices of a UV sphere with noise
+fn make_asteroid_mesh(seed: u64) -> Mesh {
+    const RINGS: usize = 12;
+    const SEGMENTS: usize = 16;
+    let noise = Perlin::new(seed);
+    let point = |ring: usize, segment: usize| {
+        let phi = ring as f32 / RINGS as f32 * std::f32::consts::PI;
+        let theta = (segment % SEGMENTS) as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+        let p = V3(phi.sin() * theta.cos(), phi.cos(), phi.sin() * theta.sin());
+        p * (1.0 + 0.35 * noise.fbm(p * 1.5, 3))
+    };
+
+    let mut tris = vec![];
+    for ring in 0..RINGS {
+        for segment in 0..SEGMENTS {
+            let (a, b) = (point(ring, segment), point(ring, segment + 1));
+            let (c, d) = (point(ring + 1, segment), point(ring + 1, segment + 1));
+            if ring != 0 {
+                tris.push(MeshTri::from_abc(a, b, d));
+            }
+            if ring != RINGS - 1 {
+                tris.push(MeshTri::from_abc(a, d, c));
+            }
+        }
+    }
+    let mut mesh = Mesh::from_tris(tris);
+    mesh.generate_normals(deg_to_rad(60.0));
+    mesh
+}
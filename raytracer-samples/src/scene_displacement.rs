@@ -0,0 +1,104 @@
+use raytracer_impl::implementation::{ Entity, HitRecord, Scene, SceneSky, Texture };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::noise::Perlin;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use raytracer_obj::load_obj_builder;
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneDisplacement;
+
+impl SceneFactory for SceneDisplacement {
+    fn name(&self) -> &str {
+        "Displacement"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::range("Camera Distance", 1.0, 20.0).with_default(3.5),
+                SceneControl::range("Rock Displacement", 0.0, 1.0).with_default(0.35),
+                SceneControl::range("Panel Displacement", 0.0, 0.2).with_default(0.04),
+                SceneControl::range("Max Edge Length", 0.005, 0.5).with_default(0.02),
+                SceneControl::range_angle_deg("Rock Yaw"),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let look_to = V3(0.0, 0.5, 0.0);
+        let look_from = look_to + V3(0.0, 0.4, 1.0).unit() * config.get("Camera Distance")?;
+        let camera = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Day);
+        scene.add_light(PointLight::with_origin(V3(-3.0, 4.0, 4.0)).with_intensity(150.0));
+
+        // Floor
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(ColorTexture(V3(0.6, 0.6, 0.6)));
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex)));
+
+        let max_edge_length = config.get("Max Edge Length")?;
+
+        // Rock: a cube displaced by noise
+        // NOTE: The cube has no uv coordinates, so the noise follows the position of each vertex.
+        // Its normals are smoothed over the hard edges, otherwise the faces either side of an edge would pull apart.
+        let rock_data = load_obj_builder(crate::mesh_path!("simple/cube.obj"))?
+            .with_crease_angle(deg_to_rad(180.0))
+            .with_displacement(RockTexture(Perlin::new(3)), config.get("Rock Displacement")?, max_edge_length)
+            .build_mesh();
+        let rock_mat = scene.add_material(MatLambertian::default());
+        let rock_tex = scene.add_texture(ColorTexture(V3(0.45, 0.4, 0.35)));
+        scene.add_entity(
+            Entity::new(rock_data.into_mesh_object(rock_mat, rock_tex))
+                .rotate(V3::POS_Y, deg_to_rad(config.get("Rock Yaw")?))
+                .translate(V3(-0.8, 0.0, 0.0))
+        );
+
+        // Hull panel: a plane with raised plates between recessed seams
+        let panel_data = load_obj_builder(crate::mesh_path!("simple/plane.obj"))?
+            .with_displacement(PanelTexture(4.0), config.get("Panel Displacement")?, max_edge_length)
+            .build_mesh();
+        let panel_mat = scene.add_material(MatConductor::titanium().with_roughness(0.4));
+        let panel_tex = scene.add_texture(ColorTexture(V3::ONE));
+        scene.add_entity(
+            Entity::new(panel_data.into_mesh_object(panel_mat, panel_tex))
+                .rotate(V3::POS_Y, deg_to_rad(-30.0))
+                .translate(V3(0.8, 0.5, 0.0))
+        );
+
+        Ok(scene)
+    }
+}
+
+/// Lumpy heights from 0.0 to 1.0, following a noise field
+struct RockTexture(Perlin);
+
+impl Texture for RockTexture {
+    fn value(&self, hit_record: &HitRecord) -> V3 {
+        let n = self.0.fbm(hit_record.p * 2.5, 5);
+        V3::ONE * (0.5 + 0.5 * n).clamp(0.0, 1.0)
+    }
+}
+
+/// A grid of plates, {0} across the uv square, at a few different heights with seams of 0.0 between them
+struct PanelTexture(f32);
+
+impl Texture for PanelTexture {
+    fn value(&self, hit_record: &HitRecord) -> V3 {
+        let V2(u, v) = hit_record.uv * self.0;
+        let seam = 0.06;
+        let in_seam = |x: f32| x.fract() < seam || x.fract() > 1.0 - seam;
+        if in_seam(u) || in_seam(v) {
+            return V3::ZERO;
+        }
+        let plate = (u.floor() as i32 * 7 + v.floor() as i32 * 13).rem_euclid(3);
+        V3::ONE * (0.6 + 0.2 * plate as f32)
+    }
+}
@@ -4,8 +4,7 @@ use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
 use raytracer_impl::lights::*;
 use raytracer_impl::materials::*;
 use raytracer_impl::types::*;
-use raytracer_impl::shapes::*;
-use raytracer_obj::load_obj_builder;
+use raytracer_obj::load_obj_cached;
 use crate::util::*;
 use crate::scene::*;
 
@@ -54,10 +53,10 @@ impl SceneFactory for SceneDootDoot {
         });
 
         let mat = scene.add_material(MatLambertian::default());
-        let mesh_data = load_obj_builder(crate::mesh_path!("skeleton/SKELETON.obj"))?.build_mesh();
-        let tex = scene.add_texture(mesh_data.texture_set);
+        let mesh_data = load_obj_cached(crate::mesh_path!("skeleton/SKELETON.obj"))?;
+        let tex = scene.add_texture(mesh_data.texture_set.clone());
 
-        let mesh = Entity::new(MeshObject::new(mesh_data.mesh, mat, tex))
+        let mesh = Entity::new(mesh_data.into_mesh_object(mat, tex))
             .rotate(V3::POS_Z, deg_to_rad(config.get("Doot Doot Roll")?))
             .rotate(V3::POS_X, deg_to_rad(config.get("Doot Doot Pitch")?))
             .rotate(V3::POS_Y, deg_to_rad(config.get("Doot Doot Yaw")?));
@@ -1,9 +1,11 @@
-use raytracer_impl::implementation::{Entity, Scene, SceneSky};
+use std::sync::Arc;
+
+use raytracer_impl::implementation::{Entity, Material, Scene, SceneSky};
 use raytracer_impl::lights::*;
 use raytracer_impl::materials::*;
+use raytracer_impl::texture::*;
 use raytracer_impl::types::*;
-use raytracer_impl::shapes::*;
-use raytracer_obj::load_obj_builder;
+use raytracer_obj::load_obj_cached;
 use crate::util::*;
 use crate::scene::*;
 
@@ -19,12 +21,17 @@ impl SceneFactory for SceneDreadnaught {
             name: self.name().into(),
             controls: vec![
                 SceneControl::range("Camera Distance", 50.0, 1500.0).with_default(800.0),
-                SceneControl::range("Global Light Intensity", 1.0, 200.0).with_default(20.0),
-                SceneControl::range("Spotlight Intensity", 1.0, 200.0).with_default(80.0),
+                SceneControl::range("Global Light Intensity", 1.0, 50000.0).with_default(5000.0),
+                SceneControl::range("Spotlight Intensity", 1.0, 50000.0).with_default(20000.0),
                 SceneControl::range("Spotlight Beam Angle", 1.0, 90.0).with_default(10.0),
                 SceneControl::range_angle_deg("Dreadnaught Yaw"),
                 SceneControl::range_angle_deg("Dreadnaught Roll"),
                 SceneControl::range_angle_deg("Dreadnaught Pitch"),
+                SceneControl::select_list("Hull Material", vec![
+                    "Lambertian".into(),
+                    "Principled (MTL)".into(),
+                ]),
+                SceneControl::range("Hull Bump Strength", 0.0, 20.0).with_default(0.0),
             ],
         }
     }
@@ -54,11 +61,24 @@ impl SceneFactory for SceneDreadnaught {
                 .with_angle(config.get("Spotlight Beam Angle")?)
         });
 
-        let mat = scene.add_material(MatLambertian::default());
-        let mesh_data = load_obj_builder(crate::mesh_path!("Dreadnaught/Dreadnaught.obj"))?.build_mesh();
-        let tex = scene.add_texture(mesh_data.texture_set);
+        let mesh_data = load_obj_cached(crate::mesh_path!("Dreadnaught/Dreadnaught.obj"))?;
+        let (material, tex): (Arc<dyn Material>, _) = if config.get("Hull Material")? as usize == 1 {
+            // NOTE: The principled materials take their color from the MTL file themselves
+            (mesh_data.material_set.clone(), scene.add_texture(ColorTexture(V3::ONE)))
+        } else {
+            (Arc::new(MatLambertian::default()), scene.add_texture(mesh_data.texture_set.clone()))
+        };
+        // The MTL file has no bump maps, so emboss the hull plating using the brightness of its color maps as height
+        let bump_strength = config.get("Hull Bump Strength")?;
+        let material = if bump_strength > 0.0 {
+            let height = MatParam::texture(mesh_data.texture_set.clone());
+            Arc::new(MatBumpMap::new(material, height).with_strength(bump_strength).with_uv_step(1.0 / 512.0))
+        } else {
+            material
+        };
+        let mat = scene.add_material(material);
         scene.add_entity(
-            Entity::new(MeshObject::new(mesh_data.mesh, mat, tex))
+            Entity::new(mesh_data.into_mesh_object(mat, tex))
                 .rotate(V3::POS_Z, deg_to_rad(config.get("Dreadnaught Roll")?))
                 .rotate(V3::POS_X, deg_to_rad(config.get("Dreadnaught Pitch")?))
                 .rotate(V3::POS_Y, deg_to_rad(config.get("Dreadnaught Yaw")?))
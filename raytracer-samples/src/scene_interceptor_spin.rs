@@ -3,9 +3,10 @@ use std::time::SystemTime;
 use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
 use raytracer_impl::lights::*;
 use raytracer_impl::materials::*;
+use raytracer_impl::medium::Medium;
 use raytracer_impl::types::*;
-use raytracer_impl::shapes::*;
-use raytracer_obj::load_obj_builder;
+use raytracer_impl::texture::*;
+use raytracer_obj::load_obj_cached;
 use crate::util::*;
 use crate::scene::*;
 
@@ -25,19 +26,23 @@ impl SceneFactory for SceneInterceptorSpin {
                 SceneControl::range("Global Light Intensity", 1.0, 800.0).with_default(20.0),
                 SceneControl::range("Spotlight Intensity", 0.0, 2000.0).with_default(1200.0),
                 SceneControl::range("Spotlight Beam Angle", 1.0, 90.0).with_default(60.0),
+                SceneControl::range("Fog Density", 0.0, 0.02).with_default(0.0),
+                SceneControl::select_list("Hull Material", vec![
+                    "Painted".into(),
+                    "Gold".into(),
+                    "Copper".into(),
+                    "Aluminium".into(),
+                    "Chrome".into(),
+                    "Titanium".into(),
+                ]),
+                SceneControl::range("Hull Roughness", 0.0, 1.0).with_default(0.3),
             ],
         }
     }
 
     fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
         // Time and rotation angle
-        let ms_per_rotation = config.get("Seconds Per Rotation")? * 1000.0;
-        let ms_since_epoch = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|err| CreateSceneError(err.to_string()))?
-            .as_millis();
-
-        let rot_rads = ((ms_since_epoch % ms_per_rotation as u128) as f32 / ms_per_rotation) * PI * 2.0;
+        let rot_rads = rotation_at_current_time(config)?;
 
         // Camera
         let dist = config.get("Camera Distance")?;
@@ -62,17 +67,56 @@ impl SceneFactory for SceneInterceptorSpin {
                 .with_angle(config.get("Spotlight Beam Angle")?)
         });
 
-        let int_mesh_data = load_obj_builder(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?.build_mesh();
-        let int_mat = scene.add_material(MatLambertian::default());
-        let int_tex = scene.add_texture(int_mesh_data.texture_set);
-        let int_mesh = Entity::new(MeshObject::new(int_mesh_data.mesh, int_mat, int_tex))
-            // Interceptor model spins as time passes
-            .rotate(V3::POS_Y, rot_rads)
-            // Interceptor model is facing +Z rotated on its side
-            .rotate(V3::POS_Z, deg_to_rad(90.0));
+        // Fog, showing the beam of the spotlight
+        let fog_density = config.get("Fog Density")?;
+        if fog_density > 0.0 {
+            scene.set_fog(Medium::new(V3::ONE * fog_density * 0.1, V3::ONE * fog_density).with_anisotropy(0.5));
+        }
+
+        let int_mesh_data = load_obj_cached(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?;
+        let roughness = config.get("Hull Roughness")?;
+        let (int_mat, int_tex) = match config.get("Hull Material")? as usize {
+            0 => (scene.add_material(MatLambertian::default()), scene.add_texture(int_mesh_data.texture_set.clone())),
+            metal => {
+                let conductor = match metal {
+                    1 => MatConductor::gold(),
+                    2 => MatConductor::copper(),
+                    3 => MatConductor::aluminium(),
+                    4 => MatConductor::chrome(),
+                    _ => MatConductor::titanium(),
+                };
+                (scene.add_material(conductor.with_roughness(roughness)), scene.add_texture(ColorTexture(V3::ONE)))
+            },
+        };
+        let int_mesh = Entity::new(int_mesh_data.into_mesh_object(int_mat, int_tex));
 
-        scene.add_entity(int_mesh);
+        scene.add_entity(place_interceptor(int_mesh, rot_rads));
 
         Ok(scene)
     }
+
+    fn update_scene(&self, scene: &mut Scene, config: &SceneConfiguration) -> Result<bool, CreateSceneError> {
+        // The interceptor is the only entity, so spin it on to the current time rather than loading the model again
+        let rot_rads = rotation_at_current_time(config)?;
+        scene.update_entities(|_, int_mesh| place_interceptor(int_mesh.clear_transforms(), rot_rads));
+        Ok(true)
+    }
+}
+
+fn rotation_at_current_time(config: &SceneConfiguration) -> Result<f32, CreateSceneError> {
+    let ms_per_rotation = config.get("Seconds Per Rotation")? * 1000.0;
+    let ms_since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|err| CreateSceneError(err.to_string()))?
+        .as_millis();
+
+    Ok(((ms_since_epoch % ms_per_rotation as u128) as f32 / ms_per_rotation) * PI * 2.0)
+}
+
+fn place_interceptor(int_mesh: Entity, rot_rads: f32) -> Entity {
+    int_mesh
+        // Interceptor model spins as time passes
+        .rotate(V3::POS_Y, rot_rads)
+        // Interceptor model is facing +Z rotated on its side
+        .rotate(V3::POS_Z, deg_to_rad(90.0))
 }
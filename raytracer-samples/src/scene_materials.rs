@@ -0,0 +1,189 @@
+use raytracer_impl::implementation::{ Entity, HitRecord, MatId, Scene, SceneSky, Texture };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::noise::Perlin;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::spectrum::Dispersion;
+use raytracer_impl::texture::*;
+use crate::scene::*;
+
+pub struct SceneMaterials;
+
+impl SceneFactory for SceneMaterials {
+    fn name(&self) -> &str {
+        "Materials"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::range("Camera Distance", 1.0, 100.0).with_default(12.0),
+                SceneControl::range("Light Intensity", 0.0, 5000.0).with_default(800.0),
+                SceneControl::range("Roughness", 0.0, 1.0).with_default(0.3),
+                SceneControl::range("Glass Absorption Distance", 0.1, 10.0).with_default(1.0),
+                SceneControl::select_list("Glass Dispersion", vec![
+                    "None".into(),
+                    "Crown (BK7)".into(),
+                    "Flint".into(),
+                    "Dense Flint".into(),
+                    "Diamond".into(),
+                ]).with_default(3.0),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let look_to = V3(0.0, 0.5, 0.0);
+        let look_from = look_to + V3(0.0, 0.6, 1.0).unit() * config.get("Camera Distance")?;
+        let camera = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Day);
+        scene.add_light(
+            PointLight::with_origin(V3(-5.0, 8.0, 6.0))
+                .with_intensity(config.get("Light Intensity")?)
+        );
+
+        // Floor
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(CheckerTexture::new(
+            1.0,
+            ColorTexture(V3(0.8, 0.8, 0.8)),
+            ColorTexture(V3(0.2, 0.2, 0.2))
+        ));
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex)).translate(V3::NEG_Y));
+
+        // NOTE: These materials take their color from the material itself, so don't tint them with a texture
+        let white = scene.add_texture(ColorTexture(V3::ONE));
+        let roughness = config.get("Roughness")?;
+        let add_row = |scene: &mut Scene, z: f32, materials: Vec<(MatId, f32)>| {
+            let spacing = 2.2;
+            let x0 = -spacing * (materials.len() - 1) as f32 / 2.0;
+            for (i, (mat, radius)) in materials.into_iter().enumerate() {
+                let center = V3(x0 + spacing * i as f32, radius - 1.0, z);
+                scene.add_entity(Entity::new(Sphere::new(radius, mat, white)).translate(center));
+            }
+        };
+
+        // Back row: metals
+        let metals = [
+            MatConductor::gold(),
+            MatConductor::copper(),
+            MatConductor::aluminium(),
+            MatConductor::chrome(),
+            MatConductor::titanium(),
+        ];
+        let metals = metals.into_iter()
+            .map(|metal| (scene.add_material(metal.with_roughness(roughness)), 1.0))
+            .collect();
+        add_row(&mut scene, -2.5, metals);
+
+        // Far row: combined materials
+        let paint = MatPrincipled::default().with_base_color(V3(0.7, 0.1, 0.1)).with_roughness(0.5);
+        let scratches = MatParam::texture(ScratchTexture(Perlin::new(7)));
+        let combined = vec![
+            // Paint scratched through to the metal beneath
+            (scene.add_material(MatMix::new(paint.clone(), MatConductor::aluminium().with_roughness(roughness), scratches)), 1.0),
+            // Lacquered paint
+            (scene.add_material(MatLayered::new(MatDielectric::default(), paint.with_roughness(1.0))), 1.0),
+            // Half gold, half glass
+            (scene.add_material(MatMix::new(MatConductor::gold(), MatDielectric::default(), 0.5)), 1.0),
+        ];
+        add_row(&mut scene, -4.5, combined);
+
+        // Middle row: principled materials
+        let principled = vec![
+            // Plastic
+            (scene.add_material(MatPrincipled::default().with_base_color(V3(0.8, 0.1, 0.1)).with_roughness(roughness)), 0.6),
+            // Car paint
+            (scene.add_material(MatPrincipled::default().with_base_color(V3(0.1, 0.2, 0.7)).with_metallic(0.5).with_clearcoat(1.0)), 0.6),
+            // Velvet
+            (scene.add_material(MatPrincipled::default().with_base_color(V3(0.5, 0.1, 0.4)).with_roughness(1.0).with_sheen(1.0)), 0.6),
+            // Brushed gold
+            (scene.add_material(MatPrincipled::default().with_base_color(V3(1.0, 0.78, 0.34)).with_metallic(1.0).with_roughness(roughness)), 0.6),
+            // Glass
+            (scene.add_material(MatPrincipled::default().with_base_color(V3::ONE).with_roughness(0.0).with_transmission(1.0)), 0.6),
+        ];
+        add_row(&mut scene, -0.75, principled);
+
+        // Front row: glass
+        let clear = match config.get("Glass Dispersion")? as usize {
+            0 => MatDielectric::default(),
+            1 => MatDielectric::default().with_dispersion(Dispersion::BK7),
+            2 => MatDielectric::default().with_dispersion(Dispersion::FLINT),
+            3 => MatDielectric::default().with_dispersion(Dispersion::DENSE_FLINT),
+            _ => MatDielectric::default().with_dispersion(Dispersion::DIAMOND),
+        };
+        let clear = scene.add_material(clear);
+        let absorption_distance = config.get("Glass Absorption Distance")?;
+        let tinted = MatDielectric::default().with_absorption(V3(0.2, 0.7, 0.3), absorption_distance);
+        let glass = vec![
+            (clear, 1.0),
+            (scene.add_material(MatDielectric::default().with_roughness(roughness)), 1.0),
+            (scene.add_material(tinted.clone()), 1.0),
+            (scene.add_material(tinted.with_roughness(roughness)), 0.5),
+        ];
+        add_row(&mut scene, 1.0, glass);
+
+        // Thin walled window, which doesn't bend light passing through it
+        let window_mat = scene.add_material(MatDielectric::default().with_thin_walled(true));
+        scene.add_entity(
+            Entity::new(Plane::new(V3::POS_Z, window_mat, white).with_radius(1.0))
+                .translate(V3(4.4, 0.2, 1.0))
+        );
+
+        // Grate, cut out of a metal disc
+        let holes = MatParam::texture(CheckerTexture::new(12.0, ColorTexture(V3::ZERO), ColorTexture(V3::ONE)));
+        let grate_mat = scene.add_material(MatCutout::new(MatConductor::titanium().with_roughness(roughness), holes));
+        scene.add_entity(
+            Entity::new(Plane::new(V3::POS_Z, grate_mat, white).with_radius(1.2))
+                .translate(V3(5.0, 0.3, -1.5))
+        );
+
+        // Prism, which splits light into a rainbow when it is dispersive
+        scene.add_entity(
+            Entity::new(MeshObject::new(make_prism_mesh(2.0, 1.2), clear, white))
+                .translate(V3(-4.6, 0.0, 1.0))
+        );
+
+        Ok(scene)
+    }
+}
+
+/// Thin streaks of 1.0 on 0.0, following the zero crossings of a stretched noise field
+struct ScratchTexture(Perlin);
+
+impl Texture for ScratchTexture {
+    fn value(&self, hit_record: &HitRecord) -> V3 {
+        let V3(x, y, z) = hit_record.p;
+        let n = self.0.fbm(V3(x * 12.0, y * 1.5, z * 12.0), 3);
+        if n.abs() < 0.04 { V3::ONE } else { V3::ZERO }
+    }
+}
+
+/// Creates an upright triangular prism, with faces of {width} and {height} centered on the origin
+fn make_prism_mesh(height: f32, width: f32) -> Mesh {
+    let radius = width / 3.0f32.sqrt();
+    let corner = |i: usize, y: f32| {
+        let theta = i as f32 * std::f32::consts::TAU / 3.0;
+        V3(radius * theta.cos(), y, radius * theta.sin())
+    };
+    let (bottom, top) = (-height / 2.0, height / 2.0);
+
+    let mut tris = vec![
+        MeshTri::from_abc(corner(0, bottom), corner(1, bottom), corner(2, bottom)),
+        MeshTri::from_abc(corner(0, top), corner(2, top), corner(1, top)),
+    ];
+    for i in 0..3 {
+        let (a, b) = (i, (i + 1) % 3);
+        tris.push(MeshTri::from_abc(corner(a, bottom), corner(b, top), corner(b, bottom)));
+        tris.push(MeshTri::from_abc(corner(a, bottom), corner(a, top), corner(b, top)));
+    }
+    let mut mesh = Mesh::from_tris(tris);
+    // NOTE: Keep every face flat, the faces meet at 60 or 90 degrees
+    mesh.generate_normals(30.0f32.to_radians());
+    mesh
+}
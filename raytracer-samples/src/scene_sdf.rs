@@ -0,0 +1,125 @@
+use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneSdf;
+
+impl SceneFactory for SceneSdf {
+    fn name(&self) -> &str {
+        "Distance Fields"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::select_list("Shape", vec![
+                    "Mandelbulb".into(),
+                    "Blobs".into(),
+                    "Twisted Torus".into(),
+                    "Repeated Boxes".into(),
+                    "Rock".into(),
+                ]),
+                SceneControl::range("Camera Distance", 1.0, 20.0).with_default(3.5),
+                SceneControl::range("Mandelbulb Power", 2.0, 16.0).with_default(8.0),
+                SceneControl::range("Mandelbulb Iterations", 1.0, 30.0).with_default(12.0),
+                SceneControl::range("Twist Rate", 0.0, 5.0).with_default(1.5),
+                SceneControl::range("Noise Amplitude", 0.0, 0.5).with_default(0.15),
+                SceneControl::range_angle_deg("Shape Yaw"),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let dist = config.get("Camera Distance")?;
+        let look_to   = V3::ZERO;
+        let look_from = V3(0.0, 0.5, -1.0).unit() * dist;
+        let camera    = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Day);
+
+        // Lights
+        let lamp_pos = V3(2.0, 6.0, -4.0);
+        scene.add_light(
+            LampLight::with_origin_and_direction(lamp_pos, look_to - lamp_pos)
+                .with_intensity(120.0)
+                .with_angle(40.0)
+        );
+        scene.add_light(DirectionalLight::with_direction(V3(-1.0, -1.0, 1.0)).with_intensity(0.3));
+
+        // Floor
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(CheckerTexture::new(
+            4.0,
+            ColorTexture(V3(0.3, 0.3, 0.3)),
+            ColorTexture(V3(0.8, 0.8, 0.8))
+        ));
+        scene.add_entity(
+            Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex))
+                .translate(V3::NEG_Y * 1.25)
+        );
+
+        // Shape
+        let mat = scene.add_material(MatLambertian::default().with_reflectivity(0.1));
+        let tex = scene.add_texture(ColorTexture(V3(0.9, 0.55, 0.3)));
+        let object = match config.get("Shape")? as usize {
+            0 => {
+                let bulb = SdfMandelbulb {
+                    power: config.get("Mandelbulb Power")?,
+                    iterations: config.get("Mandelbulb Iterations")? as u32,
+                };
+                SdfObject::new(bulb, mat, tex).with_step_scale(0.9)
+            },
+            1 => {
+                let blobs = SdfSmoothUnion::new(
+                    SdfSmoothUnion::new(
+                        SdfTranslate::new(V3(-0.4, 0.0, 0.0), SdfSphere { radius: 0.5 }),
+                        SdfTranslate::new(V3(0.4, 0.2, 0.0), SdfSphere { radius: 0.4 }),
+                        0.3
+                    ),
+                    SdfTranslate::new(V3(0.0, -0.5, 0.2), SdfBox::rounded(V3(0.6, 0.2, 0.4), 0.1)),
+                    0.3
+                );
+                SdfObject::new(blobs, mat, tex)
+            },
+            2 => {
+                let torus = SdfTwist::new(
+                    config.get("Twist Rate")?,
+                    SdfBox::rounded(V3(0.3, 1.0, 0.3), 0.05)
+                );
+                let ring = SdfTranslate::new(V3::NEG_Y * 0.8, SdfTorus { major_radius: 0.8, minor_radius: 0.15 });
+                SdfObject::new(SdfSmoothUnion::new(torus, ring, 0.2), mat, tex).with_step_scale(0.5)
+            },
+            3 => {
+                let boxes = SdfRepeat::new(
+                    V3(0.5, 0.0, 0.5),
+                    [2, 0, 2],
+                    SdfBox::rounded(V3(0.15, 0.4, 0.15), 0.05)
+                );
+                SdfObject::new(boxes, mat, tex)
+            },
+            _ => {
+                let rock = SdfNoiseDisplace::new(
+                    config.get("Noise Amplitude")?,
+                    3.0,
+                    SdfBox::rounded(V3(0.6, 0.5, 0.6), 0.3)
+                ).with_octaves(4).with_seed(1234);
+                SdfObject::new(rock, mat, tex).with_step_scale(0.5)
+            },
+        };
+
+        scene.add_entity(
+            Entity::new(object)
+                .rotate(V3::POS_Y, deg_to_rad(config.get("Shape Yaw")?))
+        );
+
+        Ok(scene)
+    }
+}
@@ -0,0 +1,67 @@
+use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use raytracer_obj::load_obj_builder;
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneSubdivision;
+
+impl SceneFactory for SceneSubdivision {
+    fn name(&self) -> &str {
+        "Subdivision"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::range("Camera Distance", 1.0, 20.0).with_default(7.0),
+                SceneControl::range("Subdivision Levels", 0.0, 4.0).with_default(2.0),
+                SceneControl::range_angle_deg("Mesh Yaw"),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let look_to = V3(0.0, 0.6, 0.0);
+        let look_from = look_to + V3(0.0, 0.3, 1.0).unit() * config.get("Camera Distance")?;
+        let camera = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Day);
+        scene.add_light(PointLight::with_origin(V3(-3.0, 5.0, 5.0)).with_intensity(200.0));
+
+        // Floor
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(ColorTexture(V3(0.6, 0.6, 0.6)));
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex)));
+
+        // Control cages, refined with Catmull-Clark (the creased quad cage) and Loop subdivision (the triangle meshes)
+        let levels = config.get("Subdivision Levels")?.round() as u32;
+        let yaw = deg_to_rad(config.get("Mesh Yaw")?);
+        let white = scene.add_texture(ColorTexture(V3::ONE));
+        let meshes = [
+            (crate::mesh_path!("simple/cage.obj"), V3(-2.2, 0.0, 0.0), V3(0.14, 0.76, 0.35)),
+            (crate::mesh_path!("simple/suzanne.obj"), V3(0.0, 0.0, 0.0), V3(1.0, 0.54, 0.23)),
+            (crate::mesh_path!("simple/thing.obj"), V3(2.2, 0.0, 0.0), V3(0.35, 0.67, 1.0)),
+        ];
+        for (path, origin, color) in meshes {
+            let mesh_data = load_obj_builder(path)?
+                .with_subdivision(levels)
+                .build_mesh();
+            let mat = scene.add_material(MatPrincipled::default().with_base_color(color).with_roughness(0.35));
+            scene.add_entity(
+                Entity::new(mesh_data.into_mesh_object(mat, white))
+                    .rotate(V3::POS_Y, yaw)
+                    .translate(origin)
+            );
+        }
+
+        Ok(scene)
+    }
+}
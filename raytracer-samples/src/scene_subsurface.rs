@@ -0,0 +1,88 @@
+use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::medium::Medium;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use raytracer_obj::load_obj_cached;
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneSubsurface;
+
+impl SceneFactory for SceneSubsurface {
+    fn name(&self) -> &str {
+        "Subsurface"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::range("Camera Distance", 10.0, 300.0).with_default(120.0),
+                SceneControl::range("Mean Free Path", 0.1, 20.0).with_default(2.0),
+                SceneControl::range("Anisotropy", -0.9, 0.9).with_default(0.0),
+                SceneControl::range("Light Intensity", 0.0, 10000.0).with_default(2500.0),
+                SceneControl::range_angle_deg("Skeleton Yaw"),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let look_to = V3(0.0, 30.0, 0.0);
+        let look_from = look_to + V3(0.0, 0.2, 1.0).unit() * config.get("Camera Distance")?;
+        let camera = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        // NOTE: The lamp sits behind the props, so light shining through the thin parts shows off the scattering
+        let mut scene = Scene::new(camera, SceneSky::Black);
+        let intensity = config.get("Light Intensity")?;
+        scene.add_light(PointLight::with_origin(V3(-40.0, 90.0, -60.0)).with_intensity(intensity));
+        scene.add_light(PointLight::with_origin(V3(60.0, 60.0, 100.0)).with_intensity(intensity * 0.3));
+
+        // Floor
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(ColorTexture(V3(0.6, 0.6, 0.6)));
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex)));
+
+        // Mean free paths are given relative to red light, which travels furthest through skin and wax
+        let mean_free_path = config.get("Mean Free Path")?;
+        let anisotropy = config.get("Anisotropy")?;
+        let white = scene.add_texture(ColorTexture(V3::ONE));
+
+        // Skeleton: bone, standing on the floor
+        let bone = scene.add_material(
+            MatSubsurface::new(V3(0.9, 0.85, 0.7), V3(1.0, 0.7, 0.4) * mean_free_path)
+                .with_anisotropy(anisotropy)
+        );
+        let mesh_data = load_obj_cached(crate::mesh_path!("skeleton/SKELETON.obj"))?;
+        scene.add_entity(
+            Entity::new(mesh_data.into_mesh_object(bone, white))
+                .rotate(V3::POS_Y, deg_to_rad(config.get("Skeleton Yaw")?))
+                .translate(V3(0.0, 40.9, 0.0))
+        );
+
+        // Wax, skin and murky water, in spheres either side
+        let radius = 8.0;
+        let wax = MatSubsurface::new(V3(0.95, 0.75, 0.4), V3(1.0, 0.8, 0.5) * mean_free_path)
+            .with_anisotropy(anisotropy)
+            .with_roughness(0.5);
+        let skin = MatSubsurface::new(V3(0.85, 0.55, 0.45), V3(1.0, 0.4, 0.2) * mean_free_path)
+            .with_anisotropy(anisotropy);
+        let murky_water = MatDielectric::default()
+            .with_ref_index(1.33)
+            .with_medium(Medium::from_albedo(V3(0.4, 0.8, 0.7), V3::ONE * mean_free_path * 4.0).with_anisotropy(0.6));
+        let spheres = [
+            (scene.add_material(wax), V3(-30.0, radius, 10.0)),
+            (scene.add_material(skin), V3(30.0, radius, 10.0)),
+            (scene.add_material(murky_water), V3(18.0, radius, 30.0)),
+        ];
+        for (mat, origin) in spheres {
+            scene.add_entity(Entity::new(Sphere::new(radius, mat, white)).translate(origin));
+        }
+
+        Ok(scene)
+    }
+}
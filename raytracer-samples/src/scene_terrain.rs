@@ -0,0 +1,88 @@
+use raytracer_impl::implementation::{ Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::noise::Perlin;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use raytracer_obj::{ load_color_map, load_obj_cached, ColorMapChannel };
+use crate::util::*;
+use crate::scene::*;
+
+pub struct SceneTerrain;
+
+impl SceneFactory for SceneTerrain {
+    fn name(&self) -> &str {
+        "Terrain"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::select_list("Height Source", vec!["Noise".into(), "Image".into()]),
+                SceneControl::range("Camera Distance", 10.0, 2000.0).with_default(400.0),
+                SceneControl::range("Terrain Size", 100.0, 10_000.0).with_default(2000.0),
+                SceneControl::range("Terrain Height", 0.0, 1000.0).with_default(250.0),
+                SceneControl::range("Terrain Resolution", 2.0, 4096.0).with_default(512.0),
+                SceneControl::range("Noise Frequency", 0.1, 50.0).with_default(6.0),
+                SceneControl::range("Noise Octaves", 1.0, 10.0).with_default(6.0),
+                SceneControl::range("Noise Seed", 0.0, 1000.0).with_default(42.0),
+                SceneControl::toggle("Show Fleet").with_default(1.0),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let dist = config.get("Camera Distance")?;
+        let look_to   = V3::POS_Y * 300.0;
+        let look_from = look_to + V3(1.0, 0.3, 1.0).unit() * dist;
+        let camera    = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Day);
+
+        // Lights
+        scene.add_light(DirectionalLight::with_direction(V3(-1.0, -0.6, -0.2)).with_intensity(0.9));
+
+        // Terrain
+        let height_map = match config.get("Height Source")? as usize {
+            0 => {
+                let resolution = config.get("Terrain Resolution")? as usize;
+                let noise = Perlin::new(config.get("Noise Seed")? as u64);
+                HeightMap::from_noise(
+                    &noise,
+                    resolution,
+                    resolution,
+                    config.get("Noise Frequency")?,
+                    config.get("Noise Octaves")? as u32
+                )
+            },
+            _ => HeightMap::from_color_map(&load_color_map(crate::mesh_path!("simple/test.bmp"), ColorMapChannel::Color)?),
+        };
+        let size = config.get("Terrain Size")?;
+        let terrain_mat = scene.add_material(MatLambertian::default());
+        let terrain_tex = scene.add_texture(ColorTexture(V3(0.55, 0.5, 0.42)));
+        scene.add_entity(
+            Entity::new(Heightfield::new(height_map, V3(size, config.get("Terrain Height")?, size), terrain_mat, terrain_tex))
+                .id(0)
+        );
+
+        // Fleet
+        if config.get("Show Fleet")? != 0.0 {
+            let int_mesh_data = load_obj_cached(crate::mesh_path!("Interceptor-T/Heavyinterceptor.obj"))?;
+            let int_mat = scene.add_material(MatLambertian::default());
+            let int_tex = scene.add_texture(int_mesh_data.texture_set.clone());
+            let int_mesh = Entity::new(int_mesh_data.into_mesh_object(int_mat, int_tex))
+                // Interceptor model is facing +Z rotated on its side
+                .rotate(V3::POS_Z, deg_to_rad(90.0));
+
+            for (i, offset) in [V3(0.0, 0.0, 0.0), V3(40.0, -10.0, 30.0), V3(-35.0, 5.0, 45.0)].into_iter().enumerate() {
+                scene.add_entity(int_mesh.clone().translate(look_to + offset).id(1 + i as u32));
+            }
+        }
+
+        Ok(scene)
+    }
+}
@@ -4,7 +4,7 @@ use raytracer_impl::materials::*;
 use raytracer_impl::types::*;
 use raytracer_impl::shapes::*;
 use raytracer_impl::texture::*;
-use raytracer_obj::{ load_color_map, load_obj_builder };
+use raytracer_obj::{ load_color_map, load_obj_builder, ColorMapChannel };
 use crate::util::*;
 use crate::scene::*;
 
@@ -69,7 +69,7 @@ impl SceneFactory for SceneUvTest {
         );
 
         // Mesh Plane
-        let mesh_tex = scene.add_texture(load_color_map(crate::mesh_path!("simple/test.bmp"))?);
+        let mesh_tex = scene.add_texture(load_color_map(crate::mesh_path!("simple/test.bmp"), ColorMapChannel::Color)?);
         let mesh_origin = look_to + (V3::POS_Y * 0.5);
         let mesh_mesh_data = load_obj_builder(crate::mesh_path!("simple/plane.obj"))?.build_mesh();
         scene.add_entity(
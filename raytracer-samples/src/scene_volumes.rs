@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::BufReader;
+use raytracer_impl::implementation::{ AABB, Entity, Scene, SceneSky };
+use raytracer_impl::lights::*;
+use raytracer_impl::materials::*;
+use raytracer_impl::medium::*;
+use raytracer_impl::types::*;
+use raytracer_impl::shapes::*;
+use raytracer_impl::texture::*;
+use crate::scene::*;
+
+pub struct SceneVolumes;
+
+impl SceneFactory for SceneVolumes {
+    fn name(&self) -> &str {
+        "Volumes"
+    }
+
+    fn create_controls(&self) -> SceneControlCollection {
+        SceneControlCollection {
+            name: self.name().into(),
+            controls: vec![
+                SceneControl::range("Camera Distance", 1.0, 50.0).with_default(14.0),
+                SceneControl::range("Fog Density", 0.0, 0.2).with_default(0.02),
+                SceneControl::range("Smoke Density", 0.0, 10.0).with_default(2.0),
+                SceneControl::range("Smoke Anisotropy", -0.9, 0.9).with_default(0.3),
+                SceneControl::range("Glow", 0.0, 10.0).with_default(3.0),
+                SceneControl::range("Spotlight Intensity", 0.0, 5000.0).with_default(400.0),
+                SceneControl::range("Spotlight Beam Angle", 1.0, 90.0).with_default(25.0),
+            ],
+        }
+    }
+
+    fn create_scene(&self, camera_config: &CameraConfiguration, config: &SceneConfiguration) -> Result<Scene, CreateSceneError> {
+        // Camera
+        let look_to = V3(0.0, 2.0, 0.0);
+        let look_from = look_to + V3(0.3, 0.25, 1.0).unit() * config.get("Camera Distance")?;
+        let camera = camera_config.make_camera(look_to, look_from);
+
+        // Scene
+        let mut scene = Scene::new(camera, SceneSky::Black);
+
+        // Spotlight shining down through the fog, past a row of pillars which cast shafts of shadow
+        let spotlight_from = V3(-6.0, 10.0, -4.0);
+        scene.add_light(
+            LampLight::with_origin_and_direction(spotlight_from, V3(1.0, 0.0, 0.5) - spotlight_from)
+                .with_intensity(config.get("Spotlight Intensity")?)
+                .with_angle(config.get("Spotlight Beam Angle")?)
+        );
+        scene.add_light(PointLight::with_origin(V3(8.0, 6.0, 10.0)).with_intensity(100.0));
+
+        // Homogeneous fog, scattering light slightly forwards
+        let fog_density = config.get("Fog Density")?;
+        if fog_density > 0.0 {
+            scene.set_fog(Medium::new(V3::ONE * fog_density * 0.1, V3::ONE * fog_density).with_anisotropy(0.5));
+        }
+
+        // Floor and pillars
+        let floor_mat = scene.add_material(MatLambertian::default());
+        let floor_tex = scene.add_texture(ColorTexture(V3(0.6, 0.6, 0.6)));
+        scene.add_entity(Entity::new(Plane::new(V3::POS_Y, floor_mat, floor_tex)));
+        for i in 0..3 {
+            let origin = V3(-3.5 + i as f32 * 1.2, 6.0, -2.5);
+            scene.add_entity(Entity::new(Sphere::new(0.4, floor_mat, floor_tex)).translate(origin));
+        }
+
+        // Smoke: a billowing noise field, and a smoke ring read from a voxel grid file
+        let smoke_density = config.get("Smoke Density")?;
+        let smoke = Medium::new(V3::ONE * smoke_density * 0.2, V3::ONE * smoke_density)
+            .with_anisotropy(config.get("Smoke Anisotropy")?);
+        scene.add_volume(Volume::new(
+            AABB::from_min_max(V3(-4.0, 0.0, -1.5), V3(-1.0, 3.0, 1.5)),
+            NoiseDensity::new(7).with_frequency(3.0).with_coverage(0.55),
+            smoke,
+        ));
+        let ring_path = crate::mesh_path!("volumes/smoke_ring.vgrid");
+        let ring = File::open(ring_path)
+            .and_then(|file| VoxelGrid::read(&mut BufReader::new(file)))
+            .map_err(|err| CreateSceneError(format!("{ring_path}: {err}")))?;
+        scene.add_volume(Volume::new(AABB::from_min_max(V3(0.0, 3.0, -1.5), V3(4.0, 4.5, 2.5)), ring, smoke));
+
+        // Glowing gas, which absorbs more than it scatters
+        let glow = config.get("Glow")?;
+        scene.add_volume(
+            Volume::new(
+                AABB::from_min_max(V3(1.0, 0.0, 1.0), V3(3.0, 2.0, 3.0)),
+                NoiseDensity::new(13).with_frequency(2.0).with_coverage(0.6),
+                Medium::new(V3(0.5, 1.0, 2.0), V3::ONE * 0.5),
+            )
+            .with_emission(V3(1.0, 0.4, 0.1) * glow)
+        );
+
+        Ok(scene)
+    }
+}
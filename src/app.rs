@@ -75,7 +75,13 @@ impl App {
         let scene_factory = self.scene_factories[self.settings.scene].clone();
         let scene_config = self.scene_configs[self.settings.scene].collect_configuration();
 
-        let state = start_render_job_construction(self.settings.clone(), scene_config, scene_factory);
+        // Reuse the last scene rendered if nothing has changed, so animated scenes can be updated in place
+        let previous_scene = match std::mem::replace(&mut self.state, AppState::None) {
+            AppState::RenderJobComplete(complete) => complete.take_scene(&self.settings, &scene_config),
+            _ => None,
+        };
+
+        let state = start_render_job_construction(self.settings.clone(), scene_config, scene_factory, previous_scene);
         self.state = AppState::RenderJobConstructing(state);
     }
 
@@ -1,8 +1,28 @@
+use std::sync::Arc;
+
 use eframe::egui::{TextureHandle};
+use raytracer_impl::implementation::{RenderSettings, Scene};
+use raytracer_samples::scene::SceneConfiguration;
 
+use crate::render::SceneSource;
+use crate::settings::Settings;
 use crate::thread_stats::ThreadStats;
 
 pub struct RenderJobCompleteState {
     pub output_tex: TextureHandle,
     pub thread_stats: Vec<ThreadStats>,
+    pub render_args: Arc<(Scene, RenderSettings)>,
+    pub scene_source: Arc<SceneSource>,
+}
+
+impl RenderJobCompleteState {
+    /// Takes back the rendered scene if it was created with the same {settings} and {scene_config},
+    /// so an animated scene can be updated in place rather than created again (see SceneFactory::update_scene).
+    pub fn take_scene(self, settings: &Settings, scene_config: &SceneConfiguration) -> Option<Scene> {
+        if self.scene_source.settings != *settings || self.scene_source.scene_config != *scene_config {
+            return None;
+        }
+        // NOTE: Fails if a render thread still holds the scene
+        Arc::try_unwrap(self.render_args).ok().map(|(scene, _)| scene)
+    }
 }
@@ -5,14 +5,14 @@ use std::time::Instant;
 
 use log::{error, info};
 
-use raytracer_impl::implementation::RenderSettings;
+use raytracer_impl::implementation::{RenderSettings, Scene};
 use raytracer_impl::viewport::{ create_render_chunks };
 use raytracer_samples::scene::{ CameraConfiguration, SceneFactory, SceneConfiguration, CreateSceneError };
 
 use crate::app::{AppStateUpdateResult, AppState};
 use crate::format::FormattedDuration;
 use crate::job_running::RenderJobRunningState;
-use crate::render::{RenderJob, start_background_render_threads};
+use crate::render::{RenderJob, SceneSource, start_background_render_threads};
 use crate::settings::Settings;
 
 pub struct RenderJobConstructingState {
@@ -64,12 +64,16 @@ fn try_extract_panic_argument(panic: &Box<dyn Any + Send>) -> Option<&str> {
 pub fn start_render_job_construction(
     settings: Settings,
     scene_config: SceneConfiguration,
-    scene_factory: Arc<dyn SceneFactory + Send + Sync>
+    scene_factory: Arc<dyn SceneFactory + Send + Sync>,
+    previous_scene: Option<Scene>,
 ) -> RenderJobConstructingState {
     let work = move || {
 
         info!("Constructing scene {}", scene_factory.name());
 
+        // Build acceleration structures across the render threads, which are idle until the scene is ready
+        raytracer_impl::bvh::set_build_thread_count(settings.thread_count as usize);
+
         // Create render work arguments
         let camera_config = CameraConfiguration {
             width: settings.width as f32,
@@ -83,15 +87,35 @@ pub fn start_render_job_construction(
 
         let start = Instant::now();
 
-        let mut scene = scene_factory.create_scene(&camera_config, &scene_config)?;
+        let updated_scene = match previous_scene {
+            Some(mut scene) => scene_factory.update_scene(&mut scene, &scene_config)?.then_some(scene),
+            None => None,
+        };
 
-        info!("Constructed Scene in {}", FormattedDuration(start.elapsed()));
+        let scene = match updated_scene {
+            Some(mut scene) => {
+                info!("Updated Scene in {}", FormattedDuration(start.elapsed()));
 
-        let start = Instant::now();
+                let start = Instant::now();
+
+                scene.refit_bvh();
+
+                info!("Refitted Bounding Volume Hierachy in {}", FormattedDuration(start.elapsed()));
+                scene
+            },
+            None => {
+                let mut scene = scene_factory.create_scene(&camera_config, &scene_config)?;
+
+                info!("Constructed Scene in {}", FormattedDuration(start.elapsed()));
+
+                let start = Instant::now();
 
-        scene.build_bvh();
+                scene.build_bvh();
 
-        info!("Constructed Bounding Volume Hierachy in {}", FormattedDuration(start.elapsed()));
+                info!("Constructed Bounding Volume Hierachy in {}", FormattedDuration(start.elapsed()));
+                scene
+            },
+        };
 
         let render_settings = RenderSettings {
             width: settings.width,
@@ -110,6 +134,7 @@ pub fn start_render_job_construction(
             started: Instant::now(),
             updates: vec![],
             worker_handle: start_background_render_threads(settings.thread_count),
+            scene_source: Arc::new(SceneSource { settings, scene_config }),
         })
     };
 
@@ -46,6 +46,8 @@ impl RenderJobRunningState {
                 crate::app::AppState::RenderJobComplete(RenderJobCompleteState {
                     output_tex: self.output_tex.take().unwrap(),
                     thread_stats: self.job.thread_stats().collect(),
+                    render_args: self.job.render_args.clone(),
+                    scene_source: self.job.scene_source.clone(),
                 })
             );
         }
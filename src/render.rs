@@ -8,8 +8,10 @@ use cancellation::{CancellationToken, CancellationTokenSource};
 use flume::{Receiver, Sender};
 use raytracer_impl::implementation::{RenderSettings, Scene};
 use raytracer_impl::viewport::{RenderChunk};
+use raytracer_samples::scene::SceneConfiguration;
 
 use crate::rgba::{RgbaBuffer, v3_to_rgba};
+use crate::settings::Settings;
 use crate::thread_stats::ThreadStats;
 use crate::timer::Timer;
 
@@ -17,6 +19,7 @@ const RNG_SEED: u64 = 12345;
 
 pub struct RenderJob {
     pub render_args: Arc<(Scene, RenderSettings)>,
+    pub scene_source: Arc<SceneSource>,
     pub chunks: Vec<RenderChunk>,
     pub next_chunk_index: usize,
     pub started: Instant,
@@ -25,6 +28,12 @@ pub struct RenderJob {
     pub worker_handle: RenderJobWorkerHandle,
 }
 
+// The settings a scene was created with, to decide if it can be updated in place for the next render
+pub struct SceneSource {
+    pub settings: Settings,
+    pub scene_config: SceneConfiguration,
+}
+
 #[derive(Eq, PartialEq)]
 pub enum RenderJobUpdateResult {
     Updated,
@@ -158,6 +167,9 @@ fn start_render_thread(
             }
         }
         let elapsed = frame_time.elapsed();
+        // Release the scene before reporting the chunk complete,
+        // so the scene is free to be reused once every chunk is complete (see RenderJobCompleteState::take_scene)
+        drop(args);
         // Send final frame and results
         result_sender.send(FrameUpdated(chunk, buffer))?;
         result_sender.send(FrameCompleted(id, elapsed))?;
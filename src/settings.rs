@@ -55,7 +55,7 @@ static CHUNK_RATIO_OPTIONS: LazyLock<Vec<CRatio>> = LazyLock::new(|| {
 // Settings
 //
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Settings {
     pub scene: usize,
     pub width: usize,